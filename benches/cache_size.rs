@@ -0,0 +1,73 @@
+//! Compares the size of a cache with 10,000 files when written as JSON and as MessagePack, both
+//! uncompressed and compressed with Zstandard, and how long writing and reading it takes.
+//!
+//! Run with `cargo bench --bench cache_size`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant, SystemTime};
+
+use assert_fs::TempDir;
+use assert_fs::prelude::*;
+use crazy_deduper::{
+    DedupCacheBuilder, FileChunk, FileWithChunks, HashingAlgorithm, HydratorBuilder,
+};
+
+const FILES: u64 = 10_000;
+const CHUNKS_PER_FILE: u64 = 4;
+const CHUNK_SIZE: u64 = 1024 * 1024;
+const FILE_NAMES: [&str; 4] = [
+    "cache.json",
+    "cache.msgpack",
+    "cache.json.zst",
+    "cache.msgpack.zst",
+];
+
+fn main() -> anyhow::Result<()> {
+    let temp = TempDir::new()?;
+
+    let algorithm = HashingAlgorithm::SHA1;
+    let cache = DedupCacheBuilder::new()
+        .files((0..FILES).map(|file| {
+            let chunks = (0..CHUNKS_PER_FILE)
+                .map(|chunk| {
+                    let hash = algorithm.hash_hex(format!("{file}-{chunk}").as_bytes());
+                    FileChunk::new(chunk * CHUNK_SIZE, CHUNK_SIZE, hash)
+                })
+                .collect();
+            FileWithChunks::from_chunks(
+                format!("dir-{}/file-{file}", file % 100),
+                CHUNKS_PER_FILE * CHUNK_SIZE,
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + file),
+                algorithm,
+                chunks,
+            )
+        }))
+        .build();
+
+    for name in FILE_NAMES {
+        let path = temp.child(name);
+
+        let start = Instant::now();
+        cache.write_to_file(&path)?;
+        let write = start.elapsed();
+
+        let start = Instant::now();
+        let read = black_box(
+            HydratorBuilder::new(temp.path())
+                .cache_paths(vec![path.path()])
+                .try_build()?
+                .cache,
+        );
+        let read_time = start.elapsed();
+        assert_eq!(read.len(), cache.len());
+
+        println!(
+            "{name:<20} {:>10} bytes   write {:>8.3} s   read {:>8.3} s",
+            std::fs::metadata(&path)?.len(),
+            write.as_secs_f64(),
+            read_time.as_secs_f64()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,82 @@
+//! Compares [`HashingParallelism::PerChunk`] with [`HashingParallelism::PerFile`], once for many
+//! files smaller than a chunk and once for few files spanning many chunks.
+//!
+//! Run with `cargo bench --bench hashing_parallelism`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use assert_fs::TempDir;
+use assert_fs::fixture::ChildPath;
+use assert_fs::prelude::*;
+use crazy_deduper::{DeduperBuilder, HashingAlgorithm, HashingParallelism};
+
+const RUNS: u32 = 3;
+
+/// Scenarios as name, number of files and size of each file.
+const SCENARIOS: [(&str, usize, usize); 2] = [
+    ("many small files", 20_000, 4 * 1024),
+    ("few large files", 4, 64 * 1024 * 1024),
+];
+
+fn main() -> anyhow::Result<()> {
+    let temp = TempDir::new()?;
+
+    for (name, files, file_size) in SCENARIOS {
+        let origin = temp.child(name.replace(' ', "-"));
+        for file in 0..files {
+            origin
+                .child(format!("{}/file-{file}", file % 100))
+                .write_binary(&content(file, file_size))?;
+        }
+
+        let total = (files * file_size) as f64 / 1024.0 / 1024.0;
+        println!("{name}: {files} files of {file_size} bytes");
+        for hashing_parallelism in [HashingParallelism::PerChunk, HashingParallelism::PerFile] {
+            let elapsed = measure(&temp, &origin, hashing_parallelism)?;
+            println!(
+                "  {:<10} {:>8.3} s {:>10.1} MiB/s",
+                format!("{hashing_parallelism:?}"),
+                elapsed.as_secs_f64(),
+                total / elapsed.as_secs_f64()
+            );
+        }
+
+        std::fs::remove_dir_all(origin.path())?;
+    }
+
+    Ok(())
+}
+
+/// Returns `size` bytes that differ for every `file`, so that no chunks are deduplicated.
+fn content(file: usize, size: usize) -> Vec<u8> {
+    let mut content = (0..u8::MAX).cycle().take(size).collect::<Vec<_>>();
+    content[..8].copy_from_slice(&(file as u64).to_le_bytes());
+    content
+}
+
+/// Returns the average time of `RUNS` runs writing the chunks of `origin`, each with a fresh
+/// target directory and without a cache, so that all files are hashed. Scanning the source
+/// directory is not included.
+fn measure(
+    temp: &TempDir,
+    origin: &ChildPath,
+    hashing_parallelism: HashingParallelism,
+) -> anyhow::Result<Duration> {
+    let mut total = Duration::ZERO;
+    for _ in 0..RUNS {
+        let target = temp.child("target");
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .hashing_algorithm(HashingAlgorithm::SHA1)
+            .hashing_parallelism(hashing_parallelism)
+            .try_build()?;
+        let start = Instant::now();
+        deduper.write_chunks(target.path(), 2)?;
+        total += black_box(start.elapsed());
+
+        std::fs::remove_dir_all(target.path())?;
+    }
+
+    Ok(total / RUNS)
+}
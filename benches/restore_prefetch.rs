@@ -0,0 +1,78 @@
+//! Compares [`Hydrator::restore_files`] with and without [`HydratorBuilder::prefetch_parallelism`].
+//!
+//! Run with `cargo bench --bench restore_prefetch`. Prefetching pays off when reading a chunk has
+//! a high latency, set `BENCH_DIR` to a directory on network storage, or on a device with injected
+//! latency like a `dm-delay` mapping, to measure it there. On local storage, the numbers mostly
+//! show the overhead of the prefetching threads.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use assert_fs::TempDir;
+use assert_fs::prelude::*;
+use crazy_deduper::{DeduperBuilder, HydratorBuilder};
+
+const FILES: usize = 64;
+const FILE_SIZE: usize = 4 * 1024 * 1024;
+const RUNS: u32 = 3;
+const PREFETCH: [usize; 4] = [0, 2, 8, 32];
+
+fn main() -> anyhow::Result<()> {
+    let temp = match std::env::var_os("BENCH_DIR") {
+        Some(dir) => TempDir::new_in(dir)?,
+        None => TempDir::new()?,
+    };
+
+    let origin = temp.child("origin");
+    for file in 0..FILES {
+        let content = (0..u8::MAX)
+            .cycle()
+            .skip(file)
+            .take(FILE_SIZE)
+            .collect::<Vec<_>>();
+        origin
+            .child(format!("file-{file}"))
+            .write_binary(&content)?;
+    }
+
+    let deduped = temp.child("deduped");
+    let cache = temp.child("cache.json.zst");
+    let mut deduper = DeduperBuilder::new(origin.path())
+        .cache_paths(vec![cache.path()])
+        .try_build()?;
+    deduper.write_chunks(deduped.path(), 2)?;
+    deduper.write_cache()?;
+
+    let total = (FILES * FILE_SIZE) as f64 / 1024.0 / 1024.0;
+    for prefetch in PREFETCH {
+        let elapsed = measure(&temp, prefetch)?;
+        println!(
+            "prefetch {prefetch:<15} {:>8.3} s {:>10.1} MiB/s",
+            elapsed.as_secs_f64(),
+            total / elapsed.as_secs_f64()
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the average time of `RUNS` restores with `prefetch` chunks read ahead, each into a
+/// fresh target directory.
+fn measure(temp: &TempDir, prefetch: usize) -> anyhow::Result<Duration> {
+    let mut total = Duration::ZERO;
+    for run in 0..RUNS {
+        let target = temp.child(format!("restored-{prefetch}-{run}"));
+
+        let start = Instant::now();
+        HydratorBuilder::new(temp.child("deduped").path())
+            .cache_paths(vec![temp.child("cache.json.zst").path()])
+            .prefetch_parallelism(prefetch)
+            .try_build()?
+            .restore_files(target.path(), 2)?;
+        total += black_box(start.elapsed());
+
+        std::fs::remove_dir_all(target.path())?;
+    }
+
+    Ok(total / RUNS)
+}
@@ -0,0 +1,81 @@
+//! Compares [`Deduper::write_chunks`] with [`Deduper::write_chunks_two_pass`].
+//!
+//! Run with `cargo bench --bench write_chunks`. The difference is most visible when the source
+//! and target directories are on spinning disks or network storage, set `BENCH_DIR` to a directory
+//! on such a device to measure it there.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use assert_fs::TempDir;
+use assert_fs::prelude::*;
+use crazy_deduper::{Deduper, DeduperBuilder, HashingAlgorithm, WriteStats};
+
+const FILES: usize = 32;
+const FILE_SIZE: usize = 8 * 1024 * 1024;
+const RUNS: u32 = 3;
+
+fn main() -> anyhow::Result<()> {
+    let temp = match std::env::var_os("BENCH_DIR") {
+        Some(dir) => TempDir::new_in(dir)?,
+        None => TempDir::new()?,
+    };
+
+    let origin = temp.child("origin");
+    for file in 0..FILES {
+        // Every fourth file is a duplicate of the previous one.
+        let content = if file % 4 == 3 {
+            std::fs::read(origin.child(format!("file-{}", file - 1)))?
+        } else {
+            (0..u8::MAX).cycle().skip(file).take(FILE_SIZE).collect()
+        };
+        origin
+            .child(format!("file-{file}"))
+            .write_binary(&content)?;
+    }
+
+    let interleaved = measure(&temp, "interleaved", |deduper, target| {
+        deduper.write_chunks(target, 2)
+    })?;
+    let two_pass = measure(&temp, "two-pass", |deduper, target| {
+        deduper.write_chunks_two_pass(target, 2)
+    })?;
+
+    let total = (FILES * FILE_SIZE) as f64 / 1024.0 / 1024.0;
+    for (name, elapsed) in [
+        ("write_chunks", interleaved),
+        ("write_chunks_two_pass", two_pass),
+    ] {
+        println!(
+            "{name:<24} {:>8.3} s {:>10.1} MiB/s",
+            elapsed.as_secs_f64(),
+            total / elapsed.as_secs_f64()
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the average time of `RUNS` runs of `write`, each with a fresh target directory and
+/// without a cache, so that all files are hashed.
+fn measure(
+    temp: &TempDir,
+    name: &str,
+    write: impl Fn(&mut Deduper, &std::path::Path) -> Result<WriteStats, crazy_deduper::Error>,
+) -> anyhow::Result<Duration> {
+    let mut total = Duration::ZERO;
+    for run in 0..RUNS {
+        let target = temp.child(format!("{name}-{run}"));
+
+        let start = Instant::now();
+        let mut deduper = DeduperBuilder::new(temp.child("origin").path())
+            .hashing_algorithm(HashingAlgorithm::SHA1)
+            .try_build()?;
+        write(&mut deduper, target.path())?;
+        total += black_box(start.elapsed());
+
+        std::fs::remove_dir_all(target.path())?;
+    }
+
+    Ok(total / RUNS)
+}
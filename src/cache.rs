@@ -1,33 +1,173 @@
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::path::Path;
+use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
 
-use crate::{DedupCache, FileWithChunks};
+use crate::{DedupCache, Error, Result};
 
+mod manifest;
 mod v0;
 mod v1;
-use v1 as latest;
+mod v2;
+use v2 as latest;
 
-/// Reads a cache file from the specified path and returns its content as a `String`.
+pub(crate) use manifest::{read_manifest, write_manifest};
+
+/// Size of the internal buffers of the Brotli encoder and decoder.
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+/// Brotli compression level, a reasonable balance between speed and ratio.
+const BROTLI_QUALITY: u32 = 6;
+
+/// Base 2 logarithm of the Brotli window size, the recommended default.
+const BROTLI_WINDOW_SIZE: u32 = 22;
+
+/// Path that stands for stdin when reading a cache, and for stdout when writing it.
+pub(crate) const STDIO_PATH: &str = "-";
+
+/// Compression of a cache file.
+///
+/// For files, it is determined by their extension. Caches read from stdin or written to stdout
+/// have no file name, so their compression must be given explicitly, see
+/// [`DeduperBuilder::stdio_cache_compression`](crate::DeduperBuilder::stdio_cache_compression).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CacheCompression {
+    /// Plain JSON.
+    #[default]
+    None,
+    /// Zstandard, used for files with a `.zst` extension.
+    Zstd,
+    /// Brotli, used for files with a `.br` extension.
+    Brotli,
+}
+
+impl CacheCompression {
+    /// All compressions, in the order of their declaration.
+    pub const ALL: [CacheCompression; 3] = [
+        CacheCompression::None,
+        CacheCompression::Zstd,
+        CacheCompression::Brotli,
+    ];
+
+    /// Returns the lowercase name of the compression, as accepted by [`FromStr`].
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Zstd => "zstd",
+            Self::Brotli => "brotli",
+        }
+    }
+
+    /// Returns the compression for the cache at `path`, which is `stdio_compression` for stdin and
+    /// stdout.
+    fn for_path(path: &Path, stdio_compression: CacheCompression) -> Self {
+        if path == Path::new(STDIO_PATH) {
+            stdio_compression
+        } else if path.extension() == Some("zst".as_ref()) {
+            Self::Zstd
+        } else if path.extension() == Some("br".as_ref()) {
+            Self::Brotli
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// Serialization format of a cache file, determined by its file name.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CacheFormat {
+    Json,
+    /// Used for files with a `.msgpack` extension, which may be followed by the extension of the
+    /// compression, like `.msgpack.zst`.
+    MessagePack,
+}
+
+impl CacheFormat {
+    /// Returns the format of the cache at `path`. Caches on stdin and stdout are always JSON.
+    fn for_path(path: &Path) -> Self {
+        let is_msgpack = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            // Also matches temporary files like `cache.msgpack.tmp.123.zst`.
+            .is_some_and(|name| name.split('.').skip(1).any(|ext| ext == "msgpack"));
+
+        if is_msgpack {
+            Self::MessagePack
+        } else {
+            Self::Json
+        }
+    }
+
+    /// Serializes `value` in this format.
+    fn serialize(self, value: &impl Serialize) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Json => Ok(serde_json::to_vec(value)?),
+            // Structs are written as maps, since positional fields would break the fields that are
+            // skipped when they have their default value.
+            Self::MessagePack => rmp_serde::to_vec_named(value).map_err(std::io::Error::other),
+        }
+    }
+
+    /// Deserializes a value in this format from `data`, borrowing from it where possible.
+    fn deserialize<'a, T: Deserialize<'a>>(self, data: &'a [u8]) -> Option<T> {
+        match self {
+            Self::Json => serde_json::from_slice(data).ok(),
+            Self::MessagePack => rmp_serde::from_slice(data).ok(),
+        }
+    }
+}
+
+impl Display for CacheCompression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CacheCompression {
+    type Err = Error;
+
+    /// Parses the name of a compression, case-insensitively.
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|compression| compression.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| Error::UnknownCacheCompression(s.to_string()))
+    }
+}
+
+/// Creates a cache reader for the specified path.
 ///
-/// This function can handle regular text files as well as compressed files with
-/// a `.zst` extension (Zstandard-compressed files). If the file is compressed,
-/// it will automatically decompress it before returning the content.
-fn read_cache_file(path: &Path) -> std::io::Result<String> {
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-
-    let mut reader: Box<dyn Read> = if path.extension() == Some("zst".as_ref()) {
-        let decoder = zstd::Decoder::with_buffer(reader)?;
-        Box::new(decoder)
+/// This function can handle regular text files as well as compressed files with a `.zst`
+/// extension (Zstandard-compressed files) or a `.br` extension (Brotli-compressed files). If the
+/// file is compressed, the reader will automatically decompress it. The path `-` reads from
+/// stdin, compressed with `stdio_compression`.
+fn get_cache_reader(
+    path: &Path,
+    stdio_compression: CacheCompression,
+) -> std::io::Result<Box<dyn Read>> {
+    let reader: Box<dyn BufRead> = if path == Path::new(STDIO_PATH) {
+        Box::new(std::io::stdin().lock())
     } else {
-        Box::new(reader)
+        Box::new(BufReader::new(File::open(path)?))
     };
 
-    let mut buffer = String::new();
-    reader.read_to_string(&mut buffer)?;
+    Ok(match CacheCompression::for_path(path, stdio_compression) {
+        CacheCompression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        CacheCompression::Brotli => Box::new(brotli::Decompressor::new(reader, BROTLI_BUFFER_SIZE)),
+        CacheCompression::None => reader,
+    })
+}
+
+/// Reads a cache file from the specified path and returns its decompressed content.
+fn read_cache_file(path: &Path, stdio_compression: CacheCompression) -> std::io::Result<Vec<u8>> {
+    let mut reader = get_cache_reader(path, stdio_compression)?;
+
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer)?;
 
     Ok(buffer)
 }
@@ -35,46 +175,70 @@ fn read_cache_file(path: &Path) -> std::io::Result<String> {
 /// Creates a cache writer for the specified path.
 ///
 /// This function creates a writer that writes to the given path. If the file extension of the path
-/// is `.zst`, the writer will compress the data using Zstandard compression. Otherwise, it will
-/// use a buffered writer without compression.
-fn get_cache_writer(path: &Path) -> std::io::Result<Box<dyn Write>> {
-    let file = File::create(&path)?;
-    let writer = BufWriter::new(file);
-
-    Ok(if path.extension() == Some("zst".as_ref()) {
-        let encoder = zstd::Encoder::new(writer, 0)?.auto_finish();
-        Box::new(encoder)
+/// is `.zst`, the writer will compress the data using Zstandard compression, if it is `.br`, using
+/// Brotli compression. Otherwise, it will use a buffered writer without compression. The path `-`
+/// writes to stdout, compressed with `stdio_compression`.
+fn get_cache_writer(
+    path: &Path,
+    stdio_compression: CacheCompression,
+) -> std::io::Result<Box<dyn Write>> {
+    let writer: Box<dyn Write> = if path == Path::new(STDIO_PATH) {
+        Box::new(BufWriter::new(std::io::stdout().lock()))
     } else {
-        Box::new(writer)
+        Box::new(BufWriter::new(File::create(path)?))
+    };
+
+    Ok(match CacheCompression::for_path(path, stdio_compression) {
+        CacheCompression::Zstd => Box::new(zstd::Encoder::new(writer, 0)?.auto_finish()),
+        // The encoder finishes the stream when it is dropped.
+        CacheCompression::Brotli => Box::new(brotli::CompressorWriter::new(
+            writer,
+            BROTLI_BUFFER_SIZE,
+            BROTLI_QUALITY,
+            BROTLI_WINDOW_SIZE,
+        )),
+        CacheCompression::None => writer,
     })
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "v")]
 enum CacheOnDisk<'a> {
+    #[serde(rename = "2")]
+    V2 {
+        #[serde(borrow)]
+        c: v2::CacheOnDisk<'a>,
+        #[serde(borrow)]
+        #[serde(default)]
+        crazydeduper_version: Option<Cow<'a, str>>,
+    },
     #[serde(rename = "1")]
     V1 {
         #[serde(borrow)]
         c: v1::CacheOnDisk<'a>,
     },
-    #[serde(untagged, skip_serializing)]
+    #[serde(untagged)]
     V0(v0::CacheOnDisk<'a>),
 }
 
 impl<'a> CacheOnDisk<'a> {
-    fn parse(json: &'a str) -> serde_json::Result<CacheOnDisk<'a>> {
-        serde_json::from_str(json)
+    fn parse(data: &'a [u8], format: CacheFormat) -> Option<CacheOnDisk<'a>> {
+        format.deserialize(data)
     }
 
     fn migrate(self) -> Option<Self> {
         match self {
             CacheOnDisk::V0(v0) => Some(CacheOnDisk::V1 { c: v0.into() }),
-            CacheOnDisk::V1 { .. } => None,
+            CacheOnDisk::V1 { c: v1 } => Some(CacheOnDisk::V2 {
+                c: v1.into(),
+                crazydeduper_version: None,
+            }),
+            CacheOnDisk::V2 { .. } => None,
         }
     }
 
     fn into_latest(self) -> latest::CacheOnDisk<'a> {
-        if let CacheOnDisk::V1 { c: cache } = self {
+        if let CacheOnDisk::V2 { c: cache, .. } = self {
             cache
         } else {
             // We are checking for the latest, so we can safely unwrap.
@@ -83,38 +247,254 @@ impl<'a> CacheOnDisk<'a> {
     }
 }
 
-pub(crate) fn read_from_file(path: impl AsRef<Path>) -> Vec<FileWithChunks> {
+/// Checks if the cache key `path` is `prefix` itself or lies below it. Prefixes match whole path
+/// components, so `dir` matches `dir/file`, but not `dir2/file`.
+pub(crate) fn in_prefix(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Format version of a cache file.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum CacheVersion {
+    /// Unversioned list of files, written before the format was versioned.
+    V0,
+    /// File tree with the hashing algorithm stored once.
+    V1,
+    /// Like [`CacheVersion::V1`], with the chunking settings and the version of crazy-deduper.
+    V2,
+}
+
+impl CacheVersion {
+    /// Version that is written by this version of crazy-deduper.
+    pub const LATEST: CacheVersion = CacheVersion::V2;
+
+    /// All supported versions, from oldest to newest.
+    pub const ALL: [CacheVersion; 3] = [CacheVersion::V0, CacheVersion::V1, CacheVersion::V2];
+}
+
+impl FromStr for CacheVersion {
+    type Err = Error;
+
+    /// Parses the version number as printed by [`Display`].
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "0" => Ok(CacheVersion::V0),
+            "1" => Ok(CacheVersion::V1),
+            "2" => Ok(CacheVersion::V2),
+            _ => Err(Error::UnknownCacheVersion(s.to_string())),
+        }
+    }
+}
+
+impl Display for CacheVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheVersion::V0 => write!(f, "0"),
+            CacheVersion::V1 => write!(f, "1"),
+            CacheVersion::V2 => write!(f, "2"),
+        }
+    }
+}
+
+/// The format version of a cache file, which is stored in the first field.
+#[derive(Deserialize)]
+struct VersionHeader {
+    v: Option<String>,
+}
+
+/// Determines the format version of the cache file at `path`, to decide whether it needs to be
+/// migrated.
+///
+/// Only the version is parsed, the rest of the content is skipped without being loaded into
+/// memory. Fails with [`Error::CacheParse`] if the file is not a cache file of a known version.
+pub fn detect_cache_version(path: impl AsRef<Path>) -> Result<CacheVersion> {
     let path = path.as_ref();
+    let parse_error = || Error::CacheParse {
+        path: path.to_path_buf(),
+    };
 
-    let cache_from_file = read_cache_file(path);
-    cache_from_file
-        .ok()
-        .and_then(|s| {
-            CacheOnDisk::parse(&s)
-                .map(CacheOnDisk::into_latest)
-                .map(latest::CacheOnDisk::into_owned)
-                .ok()
-        })
-        .unwrap_or_default()
+    let mut reader = BufReader::new(get_cache_reader(path, CacheCompression::None)?);
+    let format = CacheFormat::for_path(path);
+
+    // Unversioned caches are plain lists, while all later versions are objects.
+    let first = loop {
+        let buffer = reader.fill_buf()?;
+        let Some(&first) = buffer.first() else {
+            return Err(parse_error());
+        };
+        if format == CacheFormat::Json && first.is_ascii_whitespace() {
+            reader.consume(1);
+        } else {
+            break first;
+        }
+    };
+    let is_list = match format {
+        CacheFormat::Json => first == b'[',
+        // Markers of the fixarray, array 16 and array 32 types.
+        CacheFormat::MessagePack => matches!(first, 0x90..=0x9f | 0xdc | 0xdd),
+    };
+    if is_list {
+        return Ok(CacheVersion::V0);
+    }
+
+    let header = match format {
+        CacheFormat::Json => serde_json::from_reader::<_, VersionHeader>(reader).ok(),
+        CacheFormat::MessagePack => rmp_serde::from_read::<_, VersionHeader>(reader).ok(),
+    }
+    .ok_or_else(parse_error)?;
+    match header.v.as_deref() {
+        Some("1") => Ok(CacheVersion::V1),
+        Some("2") => Ok(CacheVersion::V2),
+        _ => Err(parse_error()),
+    }
+}
+
+/// The version of crazy-deduper that wrote a cache file, read before the actual content.
+#[derive(Deserialize)]
+struct Header<'a> {
+    #[serde(borrow)]
+    crazydeduper_version: Option<Cow<'a, str>>,
+}
+
+/// Parses the numeric part of a version like `1.2.3-SNAPSHOT` into comparable components.
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let version = version.split(['-', '+']).next()?;
+    let mut components = version.split('.').map(|component| component.parse().ok());
+    Some((
+        components.next()??,
+        components.next()??,
+        components.next()??,
+    ))
+}
+
+/// Checks that the cache was not written by a newer version than the running one, since the
+/// content might not be compatible.
+fn check_version(data: &[u8], format: CacheFormat) -> Result<()> {
+    let binary_version = env!("CARGO_PKG_VERSION");
+
+    let Some(cache_version) = format
+        .deserialize::<Header>(data)
+        .and_then(|header| header.crazydeduper_version)
+    else {
+        return Ok(());
+    };
+
+    match (parse_version(&cache_version), parse_version(binary_version)) {
+        (Some(cache), Some(binary)) if cache <= binary => Ok(()),
+        _ => Err(Error::IncompatibleCacheVersion {
+            cache_version: cache_version.into_owned(),
+            binary_version: binary_version.to_string(),
+        }),
+    }
 }
 
-pub(crate) fn write_to_file(path: impl AsRef<Path>, cache: &DedupCache) {
+/// Reads the cache file at `path`, or from stdin if it is `-`. Returns `None` if the file does
+/// not exist.
+///
+/// With a `prefix`, only the files and tombstones under it are kept, see [`in_prefix`]. The
+/// others are dropped right after parsing, before owned entries are created for them.
+pub(crate) fn read_from_file(
+    path: impl AsRef<Path>,
+    stdio_compression: CacheCompression,
+    prefix: Option<&str>,
+) -> Result<Option<DedupCache>> {
     let path = path.as_ref();
 
-    if path.file_name().is_none() {
-        return;
+    let data = match read_cache_file(path, stdio_compression) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let format = CacheFormat::for_path(path);
+
+    check_version(&data, format)?;
+
+    CacheOnDisk::parse(&data, format)
+        .map(CacheOnDisk::into_latest)
+        .map(|cache| cache.into_owned(prefix))
+        .map(Some)
+        .ok_or_else(|| Error::CacheParse {
+            path: path.to_path_buf(),
+        })
+}
+
+/// Fails with [`Error::UnsupportedCacheVersion`] if the format of `version` cannot record a
+/// setting of `cache` that is needed to find its chunks.
+pub(crate) fn check_supported(cache: &DedupCache, version: CacheVersion) -> Result<()> {
+    if cache.hash_includes_size && version < CacheVersion::V2 {
+        return Err(Error::UnsupportedCacheVersion {
+            version,
+            setting: "chunk file names including the size",
+        });
     }
 
-    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    Ok(())
+}
 
-    let writer = get_cache_writer(&path);
+/// Writes `cache` to `path`, or to stdout if it is `-`, in the format of `version`. Older formats
+/// cannot express everything, the parts that are not supported by the chosen format are left out,
+/// unless they are needed to find the chunks, see [`check_supported`].
+pub(crate) fn write_to_file(
+    path: impl AsRef<Path>,
+    cache: &DedupCache,
+    version: CacheVersion,
+    stdio_compression: CacheCompression,
+) -> Result<()> {
+    check_supported(cache, version)?;
 
-    let versioned_cache = CacheOnDisk::V1 {
-        c: latest::CacheOnDisk::from(cache),
+    let path = path.as_ref();
+
+    let (Some(_), Some(parent)) = (path.file_name(), path.parent()) else {
+        return Ok(());
     };
 
-    writer
-        .map(|writer| serde_json::to_writer(writer, &versioned_cache))
-        .unwrap()
-        .unwrap();
+    std::fs::create_dir_all(parent)?;
+
+    let mut writer = get_cache_writer(path, stdio_compression)?;
+
+    match CacheFormat::for_path(path) {
+        CacheFormat::Json => serde_json::to_writer(writer, &versioned(cache, version))
+            .map_err(std::io::Error::from)?,
+        CacheFormat::MessagePack => {
+            rmp_serde::encode::write_named(&mut writer, &versioned(cache, version))
+                .map_err(std::io::Error::other)?
+        }
+    }
+
+    Ok(())
+}
+
+/// Serializes `cache` like [`write_to_file`] in the format for `path`, but into memory, so that
+/// it can be written by another thread with [`write_serialized`] while the cache changes.
+pub(crate) fn serialize(cache: &DedupCache, version: CacheVersion, path: &Path) -> Result<Vec<u8>> {
+    check_supported(cache, version)?;
+
+    Ok(CacheFormat::for_path(path).serialize(&versioned(cache, version))?)
+}
+
+/// Writes a cache serialized by [`serialize`] to the file `path`, compressed according to its
+/// extension.
+pub(crate) fn write_serialized(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut writer = get_cache_writer(path, CacheCompression::None)?;
+    writer.write_all(data)?;
+    writer.flush()
+}
+
+/// Converts `cache` into the format of `version`.
+fn versioned(cache: &DedupCache, version: CacheVersion) -> CacheOnDisk<'_> {
+    match version {
+        CacheVersion::V0 => CacheOnDisk::V0(v0::CacheOnDisk::from(cache)),
+        CacheVersion::V1 => CacheOnDisk::V1 {
+            c: v2::CacheOnDisk::from(cache).into(),
+        },
+        CacheVersion::V2 => CacheOnDisk::V2 {
+            c: latest::CacheOnDisk::from(cache),
+            crazydeduper_version: Some(env!("CARGO_PKG_VERSION").into()),
+        },
+    }
 }
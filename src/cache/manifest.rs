@@ -0,0 +1,155 @@
+use std::io::{Read, Write};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{DedupCache, DedupCacheBuilder, Error, FileChunk, FileWithChunks, HashingAlgorithm};
+
+/// A file in a manifest, a plain JSON array of files that is meant to be read and written by
+/// other tools, unlike the cache formats.
+#[derive(Debug, Deserialize, Serialize)]
+struct ManifestEntry {
+    path: String,
+    size: u64,
+    /// Modification time in seconds since the Unix epoch.
+    mtime: u64,
+    /// Sub-second part of the modification time.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_zero")]
+    mtime_nanos: u32,
+    /// Chunks of the file, missing for files whose chunks were never computed.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunks: Option<Vec<ManifestChunk>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ManifestChunk {
+    start: u64,
+    size: u64,
+    hash: String,
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+/// Returns the hashing algorithm whose hex encoded hashes have the length of `hash`.
+fn algorithm_for_hash(hash: &str) -> Option<HashingAlgorithm> {
+    HashingAlgorithm::ALL
+        .into_iter()
+        .find(|algorithm| algorithm.select_hasher().output_size() * 2 == hash.len())
+}
+
+impl ManifestEntry {
+    fn into_file(self) -> crate::Result<FileWithChunks> {
+        if self.mtime_nanos >= 1_000_000_000 {
+            return Err(Error::ManifestParse(format!(
+                "mtime_nanos {} of {} is not below one second",
+                self.mtime_nanos, self.path
+            )));
+        }
+        let mtime = Duration::from_secs(self.mtime)
+            .checked_add(Duration::from_nanos(self.mtime_nanos.into()))
+            .and_then(|mtime| SystemTime::UNIX_EPOCH.checked_add(mtime))
+            .ok_or_else(|| {
+                Error::ManifestParse(format!(
+                    "mtime {} of {} is out of range",
+                    self.mtime, self.path
+                ))
+            })?;
+
+        let Some(chunks) = self.chunks else {
+            let mut fwc = FileWithChunks::from_chunks(
+                self.path,
+                self.size,
+                mtime,
+                HashingAlgorithm::default(),
+                Vec::new(),
+            );
+            fwc.chunks.take();
+            return Ok(fwc);
+        };
+
+        // The manifest does not name the hashing algorithm, but it follows from the hash length.
+        let hashing_algorithm = match chunks.first() {
+            Some(chunk) => algorithm_for_hash(&chunk.hash).ok_or_else(|| {
+                Error::ManifestParse(format!(
+                    "unknown hashing algorithm for chunk {} of {}",
+                    chunk.hash, self.path
+                ))
+            })?,
+            None => HashingAlgorithm::default(),
+        };
+        if let Some(chunk) = chunks
+            .iter()
+            .find(|chunk| algorithm_for_hash(&chunk.hash) != Some(hashing_algorithm))
+        {
+            return Err(Error::ManifestParse(format!(
+                "chunk {} of {} has a different hashing algorithm",
+                chunk.hash, self.path
+            )));
+        }
+
+        let chunks = chunks
+            .into_iter()
+            .map(|chunk| FileChunk::new(chunk.start, chunk.size, chunk.hash))
+            .collect();
+        let fwc =
+            FileWithChunks::from_chunks(self.path, self.size, mtime, hashing_algorithm, chunks);
+        if let Some(chunks) = fwc.get_chunks() {
+            fwc.check_chunked_size(chunks)?;
+        }
+
+        Ok(fwc)
+    }
+}
+
+impl From<&FileWithChunks> for ManifestEntry {
+    fn from(value: &FileWithChunks) -> Self {
+        let mtime = value.mtime.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+
+        Self {
+            path: value.path.clone(),
+            size: value.size,
+            mtime: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            chunks: value.get_chunks().map(|chunks| {
+                chunks
+                    .iter()
+                    .map(|chunk| ManifestChunk {
+                        start: chunk.start,
+                        size: chunk.size,
+                        hash: chunk.hash.clone(),
+                    })
+                    .collect()
+            }),
+        }
+    }
+}
+
+/// Reads a manifest from `reader`, see [`DedupCache::from_manifest`].
+pub(crate) fn read_manifest(reader: impl Read) -> crate::Result<DedupCache> {
+    let entries = serde_json::from_reader::<_, Vec<ManifestEntry>>(reader)
+        .map_err(|err| Error::ManifestParse(err.to_string()))?;
+
+    Ok(DedupCacheBuilder::new()
+        .files(
+            entries
+                .into_iter()
+                .map(ManifestEntry::into_file)
+                .collect::<crate::Result<Vec<_>>>()?,
+        )
+        .build())
+}
+
+/// Writes the files of `cache` as a manifest to `writer`, see [`DedupCache::write_manifest`].
+pub(crate) fn write_manifest(cache: &DedupCache, writer: impl Write) -> std::io::Result<()> {
+    let entries = cache
+        .iter_sorted()
+        .map(ManifestEntry::from)
+        .collect::<Vec<_>>();
+    serde_json::to_writer_pretty(writer, &entries)?;
+
+    Ok(())
+}
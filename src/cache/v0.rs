@@ -1,11 +1,11 @@
 use std::borrow::Cow;
 use std::time::SystemTime;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::HashingAlgorithm;
+use crate::{DedupCache, HashingAlgorithm};
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct FileWithChunksOnDisk<'a> {
     #[serde(borrow)]
     pub(crate) path: Cow<'a, str>,
@@ -15,13 +15,40 @@ pub(crate) struct FileWithChunksOnDisk<'a> {
     pub(crate) hashing_algorithm: HashingAlgorithm,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct FileChunkOnDisk<'a> {
     pub(crate) start: u64,
     pub(crate) size: u64,
     pub(crate) hash: &'a str,
 }
 
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(transparent)]
 pub(crate) struct CacheOnDisk<'a>(#[serde(borrow)] pub(crate) Vec<FileWithChunksOnDisk<'a>>);
+
+impl<'a> From<&'a DedupCache> for CacheOnDisk<'a> {
+    fn from(value: &'a DedupCache) -> Self {
+        let mut files = value
+            .values()
+            .map(|fwc| FileWithChunksOnDisk {
+                path: Cow::Borrowed(&fwc.path),
+                size: fwc.size,
+                mtime: fwc.mtime,
+                chunks: fwc.get_chunks().map(|chunks| {
+                    chunks
+                        .iter()
+                        .map(|chunk| FileChunkOnDisk {
+                            start: chunk.start,
+                            size: chunk.size,
+                            hash: &chunk.hash,
+                        })
+                        .collect()
+                }),
+                hashing_algorithm: fwc.hashing_algorithm,
+            })
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Self(files)
+    }
+}
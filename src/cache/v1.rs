@@ -1,13 +1,12 @@
 use std::borrow::Cow;
-use std::cell::OnceCell;
 use std::collections::BTreeMap;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::time::{Duration, SystemTime};
 
 use serde::{Deserialize, Serialize};
 
 use crate::cache::v0;
-use crate::{DedupCache, FileChunk, FileWithChunks, HashingAlgorithm};
+use crate::{DEFAULT_DECLUTTER_SEGMENT_LENGTH, FileChunk, FileWithChunks, HashingAlgorithm};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct SystemTimeOnDisk {
@@ -35,12 +34,30 @@ impl From<SystemTimeOnDisk> for SystemTime {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct FileWithChunksOnDisk<'a> {
     #[serde(rename = "s")]
-    size: u64,
+    pub(crate) size: u64,
     #[serde(rename = "m")]
-    mtime: SystemTimeOnDisk,
+    pub(crate) mtime: SystemTimeOnDisk,
     #[serde(borrow)]
     #[serde(rename = "c")]
-    chunks: Option<Vec<FileChunkOnDisk<'a>>>,
+    pub(crate) chunks: Option<Vec<FileChunkOnDisk<'a>>>,
+    #[serde(borrow)]
+    #[serde(rename = "t")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) mime_type: Option<Cow<'a, str>>,
+    #[serde(borrow)]
+    #[serde(rename = "f")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) file_hash: Option<Cow<'a, str>>,
+    #[serde(rename = "u")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) uid: Option<u32>,
+    #[serde(rename = "g")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) gid: Option<u32>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -89,6 +106,10 @@ impl<'a> From<v0::FileWithChunksOnDisk<'a>> for FileWithChunksOnDisk<'a> {
                     })
                     .collect()
             }),
+            mime_type: None,
+            file_hash: None,
+            uid: None,
+            gid: None,
         }
     }
 }
@@ -108,6 +129,10 @@ impl<'a> From<&'a FileWithChunks> for FileWithChunksOnDisk<'a> {
                     })
                     .collect()
             }),
+            mime_type: value.mime_type.as_deref().map(Cow::Borrowed),
+            file_hash: value.file_hash().map(Cow::Borrowed),
+            uid: value.uid,
+            gid: value.gid,
         }
     }
 }
@@ -123,12 +148,24 @@ pub(crate) enum Node<'a> {
 pub(crate) struct CacheOnDisk<'a> {
     #[serde(borrow)]
     #[serde(rename = "f")]
-    files: BTreeMap<Cow<'a, str>, Box<Node<'a>>>,
+    pub(crate) files: BTreeMap<Cow<'a, str>, Box<Node<'a>>>,
     #[serde(rename = "h")]
-    hashing_algorithm: HashingAlgorithm,
+    pub(crate) hashing_algorithm: HashingAlgorithm,
+    #[serde(rename = "l")]
+    #[serde(default = "default_declutter_segment_length")]
+    #[serde(skip_serializing_if = "is_default_declutter_segment_length")]
+    pub(crate) declutter_segment_length: usize,
 }
 
-fn create_empty_path_node_box<'a>() -> Box<Node<'a>> {
+pub(crate) fn default_declutter_segment_length() -> usize {
+    DEFAULT_DECLUTTER_SEGMENT_LENGTH
+}
+
+pub(crate) fn is_default_declutter_segment_length(declutter_segment_length: &usize) -> bool {
+    *declutter_segment_length == DEFAULT_DECLUTTER_SEGMENT_LENGTH
+}
+
+pub(crate) fn create_empty_path_node_box<'a>() -> Box<Node<'a>> {
     Box::new(Node::Path(BTreeMap::new()))
 }
 
@@ -169,89 +206,7 @@ impl<'a> From<v0::CacheOnDisk<'a>> for CacheOnDisk<'a> {
         Self {
             hashing_algorithm,
             files,
-        }
-    }
-}
-
-impl<'a> CacheOnDisk<'a> {
-    pub(crate) fn into_owned(self) -> Vec<FileWithChunks> {
-        let hashing_algorithm = self.hashing_algorithm;
-
-        let mut files = Vec::new();
-
-        fn walk(
-            files_list: &mut Vec<FileWithChunks>,
-            files_map: BTreeMap<Cow<str>, Box<Node>>,
-            path_base: PathBuf,
-            hashing_algorithm: HashingAlgorithm,
-        ) {
-            for (path, node) in files_map.into_iter() {
-                let path_buf = path_base.join(path.as_ref());
-                match *node {
-                    Node::Path(files_map) => {
-                        walk(files_list, files_map, path_buf, hashing_algorithm)
-                    }
-                    Node::File(fwcd) => files_list.push(FileWithChunks {
-                        base: Default::default(),
-                        path: path_buf.into_os_string().into_string().unwrap(),
-                        size: fwcd.size,
-                        mtime: fwcd.mtime.into(),
-                        chunks: fwcd
-                            .chunks
-                            .map(|chunks| {
-                                OnceCell::from(
-                                    chunks.into_iter().map(FileChunk::from).collect::<Vec<_>>(),
-                                )
-                            })
-                            .unwrap_or_default(),
-                        hashing_algorithm,
-                    }),
-                }
-            }
-        }
-
-        walk(&mut files, self.files, PathBuf::new(), hashing_algorithm);
-
-        files
-    }
-}
-
-impl<'a> From<&'a DedupCache> for CacheOnDisk<'a> {
-    fn from(value: &'a DedupCache) -> Self {
-        let hashing_algorithm = value
-            .values()
-            .map(|fwc| fwc.hashing_algorithm)
-            .next()
-            .unwrap_or_default();
-
-        let mut files = BTreeMap::new();
-        for fwc in value.values() {
-            let mut leaf = &mut files;
-            let path = Path::new(&fwc.path);
-            for component in path.parent().unwrap().iter() {
-                leaf = if let Node::Path(map) = leaf
-                    .entry(component.to_string_lossy().into_owned().into())
-                    .or_insert_with(create_empty_path_node_box)
-                    .as_mut()
-                {
-                    map
-                } else {
-                    unreachable!()
-                };
-            }
-            leaf.insert(
-                path.file_name()
-                    .unwrap()
-                    .to_string_lossy()
-                    .into_owned()
-                    .into(),
-                Box::new(Node::File(fwc.into())),
-            );
-        }
-
-        Self {
-            hashing_algorithm,
-            files,
+            declutter_segment_length: DEFAULT_DECLUTTER_SEGMENT_LENGTH,
         }
     }
 }
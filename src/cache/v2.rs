@@ -0,0 +1,222 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::v1::{
+    Node, create_empty_path_node_box, default_declutter_segment_length,
+    is_default_declutter_segment_length,
+};
+use crate::cache::{in_prefix, v1};
+use crate::{Chunking, DedupCache, FileChunk, FileWithChunks, HashingAlgorithm};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct CacheOnDisk<'a> {
+    #[serde(borrow)]
+    #[serde(rename = "f")]
+    files: BTreeMap<Cow<'a, str>, Box<Node<'a>>>,
+    #[serde(rename = "h")]
+    hashing_algorithm: HashingAlgorithm,
+    #[serde(rename = "l")]
+    #[serde(default = "default_declutter_segment_length")]
+    #[serde(skip_serializing_if = "is_default_declutter_segment_length")]
+    declutter_segment_length: usize,
+    #[serde(rename = "s")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    hash_includes_size: bool,
+    #[serde(rename = "c")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_default_chunking")]
+    chunking: Chunking,
+    #[serde(borrow)]
+    #[serde(rename = "d")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    deleted: BTreeSet<Cow<'a, str>>,
+    #[serde(borrow)]
+    #[serde(rename = "r")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_root: Option<Cow<'a, str>>,
+}
+
+fn is_default_chunking(chunking: &Chunking) -> bool {
+    *chunking == Chunking::default()
+}
+
+impl<'a> From<v1::CacheOnDisk<'a>> for CacheOnDisk<'a> {
+    fn from(value: v1::CacheOnDisk<'a>) -> Self {
+        Self {
+            files: value.files,
+            hashing_algorithm: value.hashing_algorithm,
+            declutter_segment_length: value.declutter_segment_length,
+            hash_includes_size: false,
+            chunking: Chunking::Fixed,
+            deleted: BTreeSet::new(),
+            source_root: None,
+        }
+    }
+}
+
+impl<'a> From<CacheOnDisk<'a>> for v1::CacheOnDisk<'a> {
+    /// Drops everything that version 1 cannot express.
+    fn from(value: CacheOnDisk<'a>) -> Self {
+        Self {
+            files: value.files,
+            hashing_algorithm: value.hashing_algorithm,
+            declutter_segment_length: value.declutter_segment_length,
+        }
+    }
+}
+
+impl<'a> CacheOnDisk<'a> {
+    /// Converts the cache into a [`DedupCache`], keeping only the entries under `prefix`, if
+    /// given. Directories outside of it are skipped as a whole.
+    pub(crate) fn into_owned(self, prefix: Option<&str>) -> DedupCache {
+        let hashing_algorithm = self.hashing_algorithm;
+        let chunking = self.chunking;
+
+        let mut files = Vec::new();
+
+        fn walk(
+            files_list: &mut Vec<FileWithChunks>,
+            files_map: BTreeMap<Cow<str>, Box<Node>>,
+            path_base: String,
+            prefix: Option<&str>,
+            hashing_algorithm: HashingAlgorithm,
+            chunking: Chunking,
+        ) {
+            for (path, node) in files_map.into_iter() {
+                // Keys always use `/` as separator, independent of the platform.
+                let path = if path_base.is_empty() {
+                    path.into_owned()
+                } else {
+                    format!("{path_base}/{path}")
+                };
+                let in_scope = prefix.is_none_or(|prefix| in_prefix(&path, prefix));
+                match *node {
+                    Node::Path(files_map)
+                        if in_scope || prefix.is_some_and(|prefix| in_prefix(prefix, &path)) =>
+                    {
+                        walk(
+                            files_list,
+                            files_map,
+                            path,
+                            prefix,
+                            hashing_algorithm,
+                            chunking,
+                        )
+                    }
+                    Node::Path(_) => {}
+                    Node::File(_) if !in_scope => {}
+                    Node::File(fwcd) => files_list.push(FileWithChunks {
+                        source_file: Default::default(),
+                        path,
+                        size: fwcd.size,
+                        mtime: fwcd.mtime.into(),
+                        chunks: fwcd
+                            .chunks
+                            .map(|chunks| {
+                                OnceLock::from(
+                                    chunks.into_iter().map(FileChunk::from).collect::<Vec<_>>(),
+                                )
+                            })
+                            .unwrap_or_default(),
+                        hashing_algorithm,
+                        chunking,
+                        mime_type: fwcd.mime_type.map(Cow::into_owned),
+                        uid: fwcd.uid,
+                        gid: fwcd.gid,
+                        file_hash: fwcd
+                            .file_hash
+                            .map(|file_hash| OnceLock::from(file_hash.into_owned()))
+                            .unwrap_or_default(),
+                        hash_whole_file: false,
+                        open_files_limit: None,
+                        #[cfg(feature = "parallel")]
+                        thread_pool: None,
+                    }),
+                }
+            }
+        }
+
+        walk(
+            &mut files,
+            self.files,
+            String::new(),
+            prefix,
+            hashing_algorithm,
+            chunking,
+        );
+
+        let mut cache = DedupCache::new();
+        cache.declutter_segment_length = self.declutter_segment_length;
+        cache.hash_includes_size = self.hash_includes_size;
+        cache.deleted = self
+            .deleted
+            .into_iter()
+            .filter(|path| prefix.is_none_or(|prefix| in_prefix(path, prefix)))
+            .map(Cow::into_owned)
+            .collect();
+        cache.source_root = self.source_root.map(|root| PathBuf::from(root.as_ref()));
+        for fwc in files {
+            cache.insert(fwc.path.clone(), fwc);
+        }
+
+        cache
+    }
+}
+
+impl<'a> From<&'a DedupCache> for CacheOnDisk<'a> {
+    fn from(value: &'a DedupCache) -> Self {
+        let hashing_algorithm = value
+            .values()
+            .map(|fwc| fwc.hashing_algorithm)
+            .next()
+            .unwrap_or_default();
+
+        let chunking = value
+            .values()
+            .map(|fwc| fwc.chunking)
+            .next()
+            .unwrap_or_default();
+
+        let mut files = BTreeMap::new();
+        for fwc in value.values() {
+            let mut leaf = &mut files;
+            let path = Path::new(&fwc.path);
+            for component in path.parent().unwrap().iter() {
+                leaf = if let Node::Path(map) = leaf
+                    .entry(component.to_string_lossy().into_owned().into())
+                    .or_insert_with(create_empty_path_node_box)
+                    .as_mut()
+                {
+                    map
+                } else {
+                    unreachable!()
+                };
+            }
+            leaf.insert(
+                path.file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+                    .into(),
+                Box::new(Node::File(fwc.into())),
+            );
+        }
+
+        Self {
+            hashing_algorithm,
+            files,
+            declutter_segment_length: value.declutter_segment_length,
+            hash_includes_size: value.hash_includes_size(),
+            chunking,
+            deleted: value.deleted.iter().map(|path| path.into()).collect(),
+            source_root: value.source_root.as_deref().map(Path::to_string_lossy),
+        }
+    }
+}
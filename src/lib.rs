@@ -21,10 +21,10 @@
 //! > cache.
 //!
 //! *Crazy Deduper* is a Rust tool that splits files into fixed-size chunks, identifies them using configurable hash
-//! algorithms (MD5, SHA1, SHA256, SHA512), and deduplicates redundant data into a content-addressed store. It maintains an
-//! incremental cache for speed, supports atomic cache updates, and can reverse the process (hydrate) to reconstruct
-//! original files. Optional decluttering of chunk paths and filesystem boundary awareness make it flexible for real-world
-//! workflows.
+//! algorithms (MD5, SHA1, SHA256, SHA512, or the fast but non-cryptographic CRC32), and deduplicates redundant data into a
+//! content-addressed store. It maintains an incremental cache for speed, supports atomic cache updates, and can reverse the
+//! process (hydrate) to reconstruct original files. Optional decluttering of chunk paths and filesystem boundary awareness
+//! make it flexible for real-world workflows.
 //!
 //! This crate is split into an [Application](#application) part and a [Library](#library) part.
 //!
@@ -47,29 +47,83 @@
 //!
 //! [gh-releases]: https://github.com/FloGa/crazy-deduper/releases
 //!
+//! On Linux with kernel 5.1 or newer, the optional `io-uring` feature writes chunks via `io_uring`, which reduces the
+//! syscall overhead for write-heavy workloads:
+//!
+//! ```shell
+//! cargo install --locked crazy-deduper --features io-uring
+//! ```
+//!
 //! ### Usage
 //!
 //! ```text
-//! Usage: crazy-deduper [OPTIONS] <SOURCE> <TARGET>
+//! Usage: crazy-deduper [OPTIONS] [SOURCE] [TARGET]
 //!
 //! Arguments:
-//!   <SOURCE>
+//!   [SOURCE]
 //!           Source directory
 //!
-//!   <TARGET>
+//!   [TARGET]
 //!           Target directory
 //!
 //! Options:
 //!       --cache-file <CACHE_FILE>
 //!           Path to cache file
 //!
-//!           Can be used multiple times. The files are read in reverse order, so they should be sorted with the most accurate ones in the beginning. The first given will be written.
+//!           Can be used multiple times. The files are read in reverse order, so they should be sorted with the most accurate ones in the beginning. The first given will be written, unless --cache-write-file is given. An empty path disables the cache like --no-cache, and - reads the cache from stdin, which is never written.
+//!
+//!       --cache-write-file <CACHE_WRITE_FILE>
+//!           Write the cache to this file instead of the first --cache-file, which is then only read
+//!
+//!           The written cache contains all entries, including those read from the other cache files. Use - to write the cache to stdout.
+//!
+//!           [aliases: --output-cache]
+//!
+//!       --stdio-cache-compression <STDIO_CACHE_COMPRESSION>
+//!           Compression of caches read from stdin or written to stdout
+//!
+//!           Cache files are compressed according to their extension, but stdin and stdout have none.
+//!
+//!           [default: none]
+//!           [possible values: none, zstd, brotli]
+//!
+//!       --no-cache
+//!           Run statelessly: hash all files and do not read or write any cache
+//!
+//!           Useful for one-off deduplication, where the cache would only waste disk space. This is also the behavior if no --cache-file is given, or only empty ones.
+//!
+//!       --assume-unchanged <GLOB>
+//!           Trust the cache for files matching this glob, even if their size or modification time changed
+//!
+//!           Can be used multiple times. Matching files are not re-hashed if they are in the cache, which helps against modification times that are touched without changing the content. Use with care: if the content did change, the cache and the chunks silently become wrong. The patterns match paths relative to the source directory, * does not cross directories, ** does.
 //!
 //!       --hashing-algorithm <HASHING_ALGORITHM>
 //!           Hashing algorithm to use for chunk filenames
 //!
+//!           crc32 is only meant for performance: it is much faster, but not collision-resistant, so different chunks may be mixed up. Only use it for local stores where this is acceptable.
+//!
 //!           [default: sha1]
-//!           [possible values: md5, sha1, sha256, sha512]
+//!           [possible values: md5, sha1, sha256, sha512, crc32]
+//!
+//!       --cdc
+//!           Use content-defined chunks of variable size instead of fixed 1 MiB chunks
+//!
+//!           Inserting or removing data in a file then only changes the chunks around the modification. Only used when deduping, the chunk sizes are stored in the cache.
+//!
+//!       --cdc-min-size <CDC_MIN_SIZE>
+//!           Minimum size of content-defined chunks in bytes
+//!
+//!           [default: 262144]
+//!
+//!       --cdc-avg-size <CDC_AVG_SIZE>
+//!           Average size of content-defined chunks in bytes
+//!
+//!           [default: 1048576]
+//!
+//!       --cdc-max-size <CDC_MAX_SIZE>
+//!           Maximum size of content-defined chunks in bytes
+//!
+//!           [default: 4194304]
 //!
 //!       --same-file-system
 //!           Limit file listing to same file system
@@ -79,11 +133,211 @@
 //!
 //!           [default: 0]
 //!
+//!       --declutter-segment-length <DECLUTTER_SEGMENT_LENGTH>
+//!           Number of hash characters per declutter level
+//!
+//!           Only used when deduping, the value is stored in the cache and read from there when hydrating.
+//!
+//!           [default: 1]
+//!
+//!       --hash-includes-size
+//!           Name chunk files after their hash and size, to avoid collisions of chunks with different sizes
+//!
+//!           Only used when deduping, the setting is stored in the cache and read from there when hydrating.
+//!
+//!       --chunk-base-path <CHUNK_BASE_PATH>
+//!           Directory of the chunk files, instead of the data subdirectory of the deduped directory
+//!
+//!           Allows to keep the chunks on another file system. Needs to be given when deduping and hydrating.
+//!
+//!       --since-cache <SINCE_CACHE>
+//!           Only write chunks that are not referenced by this baseline cache
+//!
+//!           Can be used to create an incremental delta store relative to an earlier run. The baseline cache has to exist.
+//!
+//!       --min-savings-bytes <MIN_SAVINGS_BYTES>
+//!           Store files as a single chunk if deduplication saves less than this many bytes
+//!
+//!           Avoids lots of useless chunk files for data that does not deduplicate, like encrypted or compressed files.
+//!
+//!       --min-size <MIN_SIZE>
+//!           Only consider files with at least this size, like 4K or 1.5MiB
+//!
+//!           Units are powers of 1024. Cached files outside the size range are dropped from the cache.
+//!
+//!       --max-size <MAX_SIZE>
+//!           Only consider files with at most this size, like 1G, see --min-size
+//!
+//!       --temp-dir <TEMP_DIR>
+//!           Directory for temporary files, instead of next to the cache file
+//!
+//!           If it is on a different device than the cache file, the cache is copied into place instead of renamed, so the write is no longer atomic.
+//!
+//!       --chunk-mode <CHUNK_MODE>
+//!           Octal permissions of created chunk files, like 0640 (Unix only)
+//!
+//!           Created chunk directories get the same permissions, plus the execute bit wherever the read bit is set.
+//!
+//!       --no-cache-write
+//!           Only read the cache files, do not update them
+//!
+//!           For caches on read-only file systems, or auditing runs that must not change them.
+//!
+//!       --cache-format-version <CACHE_FORMAT_VERSION>
+//!           Format version in which the cache file is written
+//!
+//!           Allows to create cache files for older versions of crazy-deduper. Older formats cannot record everything, like the chunking settings or deleted files.
+//!
+//!           [default: 2]
+//!           [possible values: 0, 1, 2]
+//!
+//!       --absolute-paths
+//!           Record the absolute source directory in the cache, or restore files to it when decoding
+//!
+//!           When decoding, the files are written to their original locations instead of into the target directory. The original locations must then be inside the target directory, which can be `/` to allow any location.
+//!
+//!       --allow-absolute
+//!           Allow --absolute-paths to restore files outside the target directory
+//!
+//!       --detect-mime
+//!           Detect the MIME type of each file and store it in the cache
+//!
+//!           Allows to restore only some types of files with --filter-mime.
+//!
+//!       --file-hashes
+//!           Record the hash of the whole content of each file in the cache, besides the chunk hashes
+//!
+//!           When resuming a restore with --verify-resumed, files are then verified against it.
+//!
+//!       --source-encoding <SOURCE_ENCODING>
+//!           Encoding of the file names in the source directory, like latin1 or windows-1252
+//!
+//!           The names are decoded from it and stored as UTF-8 in the cache. By default, names are expected to be UTF-8 and invalid characters are replaced.
+//!
+//!       --normalize-paths <NORMALIZE_PATHS>
+//!           Unicode normalization form of the file names in the cache
+//!
+//!           Allows to re-use caches between file systems that store names in different forms, like HFS+, which decomposes them.
+//!
+//!           [default: none]
+//!           [possible values: none, nfc, nfd]
+//!
+//!       --filter-mime <FILTER_MIME>
+//!           Only restore files whose MIME type matches this glob pattern, like image/*
+//!
+//!           Requires that the MIME types were detected with --detect-mime when deduping. Files without a detected MIME type, like plain text files, are never restored with this option.
+//!
+//!       --find-duplicates
+//!           Print groups of files with identical content after deduping
+//!
+//!           The groups are separated by empty lines. Modification times are not considered, and empty files are not reported.
+//!
+//!       --json-lines <PATH>
+//!           Stream one JSON object per processed chunk to this file while deduping, use - for stdout
+//!
+//!           Each line contains the path, hash, start and size of the chunk, and whether it was deduplicated instead of written.
+//!
+//!       --metrics <PATH>
+//!           Write metrics of the dedup run to this file in the Prometheus text format
+//!
+//!           The gauges cover the number and size of the files, the unique chunks, the dedup ratio and the duration of the run. The file is replaced atomically, so it can be picked up by the textfile collector of the node exporter.
+//!
+//!       --export-manifest <PATH>
+//!           Write the deduplicated files to this file as a JSON manifest
+//!
+//!           The manifest can be restored with --import-manifest and read by other tools, which do not need to understand the cache formats. Use - to write it to stdout.
+//!
+//!       --max-open-files <MAX_OPEN_FILES>
+//!           Maximum number of source files to keep open at the same time while hashing
+//!
+//!       --tar
+//!           Write the chunk store as a zstd-compressed tar archive to the target instead of a directory
+//!
+//!           Use - as target to write the archive to stdout, for example to pipe it to remote storage. No other output may be written to stdout then. Unpacking the archive creates the same directory as deduping without this option.
+//!
+//!       --timings
+//!           Print the time spent scanning, hashing and writing chunks, and the achieved throughput
+//!
+//!   -q, --quiet
+//!           Suppress all output except errors
+//!
+//!   -v, --verbose...
+//!           Print additional diagnostic output
+//!
+//!           Given twice, also list each file with its number of chunks, how many of them were new or deduplicated, and whether they were hashed or taken from the cache.
+//!
+//!       --chunk-store-stats
+//!           Print the number and size of the chunk files and the number of inodes in the chunk store and exit
+//!
+//!           Unlike the cache, this measures the actual chunk store on disk.
+//!
+//!       --compare-store <COMPARE_STORE>
+//!           Compare the chunk store with the one in another deduplicated directory and exit
+//!
+//!           Lists the chunks referenced by the cache that are only present in one of both stores, for example to verify a replica. Fails if the stores differ.
+//!
+//!       --detect-cache-version
+//!           Print the format version of each cache file and exit
+//!
 //!   -d, --decode
 //!           Invert behavior, restore tree from deduplicated data
 //!
 //!           [aliases: --hydrate]
 //!
+//!       --resume
+//!           Resume an interrupted restore, skip files whose size and modification time already match
+//!
+//!       --sparse
+//!           Do not write chunks that consist of zeros only, create sparse files with holes instead
+//!
+//!       --no-preserve-mtime
+//!           Do not restore the original modification times, keep the time of the restore
+//!
+//!       --preserve-ownership
+//!           Restore the original owner and group of the files, usually requires root privileges
+//!
+//!           Files whose ownership cannot be set are restored anyway, with a warning.
+//!
+//!       --remove-deleted
+//!           Remove files from the target that are recorded as deleted in the cache files
+//!
+//!           Deleted files are recorded when deduping with --since-cache. Without this flag, they are only not restored.
+//!
+//!       --import-manifest <PATH>
+//!           Restore the files listed in this JSON manifest, in addition to those in the cache files
+//!
+//!           The manifest is an array of files like [{"path", "size", "mtime", "chunks": [{"start", "size", "hash"}]}], as written by --export-manifest or by other tools from a chunk store without a cache.
+//!
+//!       --path-prefix <PATH>
+//!           Only restore the files below this path relative to the source directory
+//!
+//!           The other entries are skipped while reading the caches, which saves memory when restoring a subtree of a large backup.
+//!
+//!       --zip <ZIP>
+//!           Restore into a zip archive at this path instead of a target directory
+//!
+//!       --prefetch <PREFETCH>
+//!           Read this many chunks ahead of writing the restored files, in as many background threads
+//!
+//!           Hides the latency of chunk stores on network or cloud storage. Disabled with 0.
+//!
+//!           [default: 0]
+//!
+//!       --retries <RETRIES>
+//!           Retry chunk and cache IO this many times on transient errors, with exponential backoff
+//!
+//!           For flaky network mounts. Errors like missing files are not retried.
+//!
+//!           [default: 0]
+//!
+//!       --verify-resumed
+//!           Re-hash skipped files when resuming, to verify that their content matches
+//!
+//!       --ignore-mtime
+//!           Ignore modification times when resuming, compare existing files by size and content only
+//!
+//!           For file systems with unreliable modification times.
+//!
 //!   -h, --help
 //!           Print help (see a summary with '-h')
 //!
@@ -98,7 +352,12 @@
 //! ```
 //!
 //! If the cache file ends with `.zst`, it will be encoded (or decoded in the case of hydrating) using the ZSTD compression
-//! algorithm. For any other extension, plain JSON will be used.
+//! algorithm. If it ends with `.br`, Brotli compression is used instead, which is best for caches that are transferred over
+//! the web, since Brotli is widely supported there. For any other extension, plain JSON will be used.
+//!
+//! Caches with a `.msgpack` extension, optionally followed by the compression, like `cache.msgpack.zst`, are written as
+//! MessagePack instead of JSON. This binary format is more compact, especially for the many numbers in a cache, and faster
+//! to parse.
 //!
 //! To restore (hydrate) the directory again into the directory `hydrated`, you can use:
 //!
@@ -109,13 +368,32 @@
 //! Please note that for now you need to specify the same decluttering level as you did when deduping the source directory.
 //! This limitation will be lifted in a future version.
 //!
+//! With `--hash-includes-size`, chunk files are named after their hash and their size, like `{hash}-{size}`. Chunks of
+//! different sizes can then never overwrite each other, even if their hashes collide. The price is slightly longer file
+//! names. The setting is stored in the cache file, so it does not need to be given again when hydrating.
+//!
+//! If you dedupe with `--absolute-paths`, the absolute source directory is recorded in the cache file. Hydrating with
+//! `--absolute-paths` then restores the files to their original locations. The target directory only limits where files
+//! may be written: the original locations must be inside of it, unless `--allow-absolute` is given:
+//!
+//! ```shell
+//! crazy-deduper --declutter-levels 3 --cache-file cache.json.zst --absolute-paths deduped /
+//! ```
+//!
+//! To distribute a restored tree as a single file, it can be written into a zip archive instead of a target directory:
+//!
+//! ```shell
+//! crazy-deduper --declutter-levels 3 --cache-file cache.json.zst --decode deduped --zip snapshot.zip
+//! ```
+//!
 //! ### Cache Files
 //!
 //! The cache file is necessary to keep track of all file chunks and hashes. Without the cache you would not be able to
 //! restore your files.
 //!
 //! The cache file can be re-used, even if the source directory changed. It keeps track of the file sizes and modification
-//! times and only re-hashes new or changed files. Deleted files are deleted from the cache.
+//! times and only re-hashes new or changed files. Deleted files are deleted from the cache. Files that were only renamed
+//! or moved, but kept their size and modification time, are detected as well and do not need to be re-hashed.
 //!
 //! You can also use older cache files in addition to a new one:
 //!
@@ -131,6 +409,24 @@
 //! `cache-from-yesterday.json.zst` so that only new and modified files need to be re-hashed. The result is then written
 //! into `cache.json.zst`.
 //!
+//! When deduping with `--since-cache`, files of the baseline cache that were deleted from the source directory are
+//! recorded as deleted in the new cache. These records follow the same precedence: a deletion in an earlier cache file
+//! hides the file in all later ones, and a file in an earlier cache file overrides a deletion in a later one. So restoring
+//! with the new cache followed by the baseline cache does not resurrect deleted files. With `--remove-deleted`, such files
+//! are also removed from the target directory, if they exist there from an earlier restore.
+//!
+//! Each cache file records the version of crazy-deduper that wrote it. Cache files written by a newer version are rejected
+//! with an error, since their content might not be understood correctly. To print the format version of cache files, use
+//! `--detect-cache-version`:
+//!
+//! ```shell
+//! crazy-deduper --detect-cache-version --cache-file cache.json.zst
+//! ```
+//!
+//! To create cache files for older versions of crazy-deduper, the format version can be chosen with
+//! `--cache-format-version`. Older formats cannot record everything, for example version 1 does not know about
+//! content-defined chunks and deleted files, so such caches should only be written for trees that do not need them.
+//!
 //! ## Library
 //!
 //! ### Installation
@@ -141,6 +437,22 @@
 //! cargo add crazy-deduper
 //! ```
 //!
+//! Chunks are hashed in parallel via `rayon` by default. For minimal or single-threaded builds, disable the default
+//! `parallel` feature to hash sequentially, with identical results:
+//!
+//! ```shell
+//! cargo add crazy-deduper --no-default-features
+//! ```
+//!
+//! The `tracing` feature emits [`tracing`](https://docs.rs/tracing) spans for the phases of a run: `cache_load`,
+//! `directory_scan`, `chunk_hash` per file, `chunk_write` and `cache_write`. The default `cli` feature enables it and lets
+//! the application print the spans to stderr when `RUST_LOG` is set, for example `RUST_LOG=crazy_deduper=info`. To emit
+//! the spans without pulling in `tracing-subscriber`, only enable the features you need:
+//!
+//! ```shell
+//! cargo add crazy-deduper --no-default-features --features parallel,tracing
+//! ```
+//!
 //! ### Usage
 //!
 //! The following is a short summary of how this library is intended to be used.
@@ -150,21 +462,19 @@
 //! This is an example of how to re-create the main functionality of the [Application](#application).
 //!
 //! ```rust no_run
-//! fn main() {
-//!     // Deduplicate
-//!     let mut deduper = crazy_deduper::Deduper::new(
-//!         "source",
-//!         vec!["cache.json.zst"],
-//!         crazy_deduper::HashingAlgorithm::MD5,
-//!         true,
-//!     );
-//!     deduper.write_chunks("deduped", 3).unwrap();
-//!     deduper.write_cache();
-//!
-//!     // Hydrate again
-//!     let hydrator = crazy_deduper::Hydrator::new("deduped", vec!["cache.json.zst"]);
-//!     hydrator.restore_files("hydrated", 3);
-//! }
+//! // Deduplicate
+//! let mut deduper = crazy_deduper::Deduper::new(
+//!     "source",
+//!     vec!["cache.json.zst"],
+//!     crazy_deduper::HashingAlgorithm::MD5,
+//!     true,
+//! );
+//! deduper.write_chunks("deduped", 3).unwrap();
+//! deduper.write_cache().unwrap();
+//!
+//! // Hydrate again
+//! let hydrator = crazy_deduper::Hydrator::new("deduped", vec!["cache.json.zst"]);
+//! hydrator.restore_files("hydrated", 3).unwrap();
 //! ```
 //!
 //! #### Get File Chunks as an Iterator
@@ -172,55 +482,306 @@
 //! This method can be used if you want to implement your own logic and you only need the chunk objects.
 //!
 //! ```rust no_run
-//! fn main() {
-//!     let deduper = crazy_deduper::Deduper::new(
-//!         "source",
-//!         vec!["cache.json.zst"],
-//!         crazy_deduper::HashingAlgorithm::MD5,
-//!         true,
-//!     );
-//!
-//!     for (hash, chunk, dirty) in deduper.cache.get_chunks().unwrap() {
-//!         // Chunks and hashes are calculated on the fly, so you don't need to wait for the whole
-//!         // directory tree to be hashed.
-//!         println!("{hash:?}: {chunk:?}");
-//!         if dirty {
-//!             // This is just a simple example. Please do not write after every hash calculation, the
-//!             // IO overhead will slow things down dramatically. You should write only every 10
-//!             // seconds or so. Please be aware that you can kill the execution at any time. Since
-//!             // the cache will be written atomically and re-used on subsequent calls, you can
-//!             // terminate and resume at any point.
-//!             deduper.write_cache();
-//!         }
+//! let deduper = crazy_deduper::Deduper::new(
+//!     "source",
+//!     vec!["cache.json.zst"],
+//!     crazy_deduper::HashingAlgorithm::MD5,
+//!     true,
+//! );
+//!
+//! for chunk in deduper.cache().get_chunks().unwrap() {
+//!     let (hash, chunk, dirty) = chunk.unwrap();
+//!     // Chunks and hashes are calculated on the fly, so you don't need to wait for the whole
+//!     // directory tree to be hashed.
+//!     println!("{hash:?}: {chunk:?}");
+//!     if dirty {
+//!         // This is just a simple example. Please do not write after every hash calculation, the
+//!         // IO overhead will slow things down dramatically. You should write only every 10
+//!         // seconds or so. Please be aware that you can kill the execution at any time. Since
+//!         // the cache will be written atomically and re-used on subsequent calls, you can
+//!         // terminate and resume at any point.
+//!         deduper.write_cache().unwrap();
 //!     }
 //! }
 //! ```
 
+use std::borrow::Cow;
 use std::cell::OnceCell;
-use std::collections::hash_map::IntoIter;
-use std::collections::{HashMap, HashSet};
+use std::collections::hash_map;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::time::SystemTime;
-
-use file_declutter::FileDeclutter;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, mpsc};
+use std::time::{Duration, Instant, SystemTime};
+
+use encoding_rs::Encoding;
+#[cfg(feature = "parallel")]
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
 use walkdir::WalkDir;
 
 mod cache;
+pub use cache::{CacheCompression, CacheVersion, detect_cache_version};
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error(transparent)]
     Io(#[from] std::io::Error),
+
+    #[error("Unknown hashing algorithm: {0}")]
+    UnknownHashingAlgorithm(String),
+
+    #[error("Unknown cache format version: {0}")]
+    UnknownCacheVersion(String),
+
+    #[error("Unknown normalization form: {0}")]
+    UnknownNormalizationForm(String),
+
+    #[error("Unknown cache compression: {0}")]
+    UnknownCacheCompression(String),
+
+    #[error("Could not scan {path}")]
+    Scan {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Path {path} is not inside the source directory {base}")]
+    OutsideSource { path: PathBuf, base: PathBuf },
+
+    #[error("Path {path} is not inside the allowed directory {root}")]
+    OutsideTarget { path: PathBuf, root: PathBuf },
+
+    #[error("Cache does not record the absolute source directory")]
+    NoSourceRoot,
+
+    #[error("No target directory given for the source directory of {path}")]
+    MissingSourceTarget { path: PathBuf },
+
+    #[error("Invalid MIME type pattern: {0}")]
+    InvalidMimePattern(#[from] globset::Error),
+
+    #[error("Could not write zip archive: {0}")]
+    Zip(#[from] zip::result::ZipError),
+
+    #[error("Chunk {hash} is missing from the chunk store")]
+    ChunkMissing { hash: String },
+
+    #[error("File {path} is shorter than when it was hashed, cannot write chunk {hash}")]
+    SourceTruncated { path: PathBuf, hash: String },
+
+    #[error("Could not parse cache file {path}")]
+    CacheParse { path: PathBuf },
+
+    #[error("Chunk {hash} has a size of {actual} bytes, expected {expected}")]
+    ChunkSizeMismatch {
+        hash: String,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[error("Cache was written by version {cache_version}, which is newer than {binary_version}")]
+    IncompatibleCacheVersion {
+        cache_version: String,
+        binary_version: String,
+    },
+
+    #[error("Cancelled")]
+    Cancelled,
+
+    #[error("Target directory {target} and source directory {source_dir} are inside each other")]
+    CircularPath {
+        source_dir: PathBuf,
+        target: PathBuf,
+    },
+
+    #[error("Modification time of {path} is in the future")]
+    FutureModificationTime { path: String },
+
+    #[error("File {path} is not in the cache")]
+    FileNotFound { path: String },
+
+    #[error("Chunks of {path} add up to {actual} bytes, expected {expected}")]
+    ChunkedSizeMismatch {
+        path: String,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[error("Could not parse manifest: {0}")]
+    ManifestParse(String),
+
+    #[error("Invalid path pattern: {0}")]
+    InvalidPathPattern(globset::Error),
+
+    #[error("Invalid chunk sizes: {0}")]
+    InvalidChunkSizes(String),
+
+    #[error("Cache version {version} cannot record {setting}")]
+    UnsupportedCacheVersion {
+        version: CacheVersion,
+        setting: &'static str,
+    },
 }
 
 type Result<R> = std::result::Result<R, Error>;
 
+/// Enters a `tracing` span with the given name and fields until the returned guard is dropped.
+/// Without the `tracing` feature, this does nothing.
+macro_rules! span {
+    ($($args:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        let guard = tracing::info_span!($($args)*).entered();
+        #[cfg(not(feature = "tracing"))]
+        let guard = NoSpan;
+        guard
+    }};
+}
+
+/// Stand-in for the guard of an entered span without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+struct NoSpan;
+
+/// Fails with [`Error::Cancelled`] if `cancel_flag` is set.
+fn check_cancelled(cancel_flag: &Option<Arc<AtomicBool>>) -> Result<()> {
+    match cancel_flag {
+        Some(cancel_flag) if cancel_flag.load(Ordering::Relaxed) => Err(Error::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+/// Number of hash characters consumed by each declutter level, unless configured otherwise.
+pub const DEFAULT_DECLUTTER_SEGMENT_LENGTH: usize = 1;
+
+/// Default minimum chunk size for content-defined chunking.
+pub const DEFAULT_CDC_MIN_SIZE: u32 = 256 * 1024;
+
+/// Default average chunk size for content-defined chunking.
+pub const DEFAULT_CDC_AVG_SIZE: u32 = 1024 * 1024;
+
+/// Default maximum chunk size for content-defined chunking.
+pub const DEFAULT_CDC_MAX_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Size of the chunks with fixed-size chunking.
+const FIXED_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Returns the path of the chunk file for `hash` inside `data_dir`.
+///
+/// The hash is decluttered into `declutter_levels` nested subdirectories, each named after the
+/// next `segment_length` characters of the hash. With a `segment_length` of 1, the chunk `abcdef`
+/// at 2 levels ends up in `a/b/abcdef`, with a `segment_length` of 2 in `ab/cd/abcdef`.
+fn chunk_path(
+    data_dir: &Path,
+    hash: &str,
+    declutter_levels: usize,
+    segment_length: usize,
+) -> PathBuf {
+    let mut path = data_dir.to_path_buf();
+
+    let mut chars = hash.chars();
+    for _ in 0..declutter_levels {
+        let segment = chars.by_ref().take(segment_length).collect::<String>();
+        if segment.is_empty() {
+            break;
+        }
+        path.push(segment);
+    }
+
+    path.push(hash);
+    path
+}
+
+/// Layout of the chunk files inside a chunk store.
+///
+/// The number of declutter levels is not part of the layout, it is passed to each call of
+/// [`Deduper`] and [`Hydrator`] methods and can differ between writing and reading anyway. The
+/// layout of the deduper is recorded in the cache, except for the name of the data directory,
+/// and used by the hydrator unless it is given its own layout with
+/// [`HydratorBuilder::store_layout`], like for a reorganized chunk store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StoreLayout {
+    /// Name of the chunk store directory inside the deduplicated directory. Ignored if a chunk
+    /// base path is set, see [`DeduperBuilder::chunk_base_path`].
+    pub data_dir_name: String,
+    /// Number of hash characters each declutter level consumes, see
+    /// [`DeduperBuilder::declutter_segment_length`].
+    pub declutter_segment_length: usize,
+    /// Whether chunk files are named after their hash and size, see
+    /// [`DeduperBuilder::hash_includes_size`].
+    pub hash_includes_size: bool,
+}
+
+impl Default for StoreLayout {
+    fn default() -> Self {
+        Self {
+            data_dir_name: "data".to_string(),
+            declutter_segment_length: DEFAULT_DECLUTTER_SEGMENT_LENGTH,
+            hash_includes_size: false,
+        }
+    }
+}
+
+impl StoreLayout {
+    /// Splits the data of `reader` into chunks like [`Chunking::chunk_reader`] and writes the
+    /// chunks that are not present yet into the chunk store `data_dir`, decluttered into
+    /// `declutter_levels` subdirectories.
+    ///
+    /// To restore the data later, record the returned chunks in a cache, for example with
+    /// [`FileWithChunks::from_chunks`] and [`DedupCacheBuilder`].
+    pub fn store_reader(
+        &self,
+        reader: impl Read,
+        data_dir: &Path,
+        declutter_levels: usize,
+        hashing_algorithm: HashingAlgorithm,
+        chunking: Chunking,
+    ) -> Result<Vec<FileChunk>> {
+        chunking.for_each_chunk(reader, hashing_algorithm, |chunk, data| {
+            let chunk_file = self.chunk_path(data_dir, chunk, declutter_levels);
+            if !chunk_file.exists() {
+                std::fs::create_dir_all(chunk_file.parent().unwrap())?;
+                std::fs::write(chunk_file, data)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns the path of the chunk file for `chunk` in `data_dir`, decluttered into
+    /// `declutter_levels` subdirectories.
+    pub fn chunk_path(
+        &self,
+        data_dir: &Path,
+        chunk: &FileChunk,
+        declutter_levels: usize,
+    ) -> PathBuf {
+        let name = if self.hash_includes_size {
+            Cow::Owned(format!("{}-{}", chunk.hash, chunk.size))
+        } else {
+            Cow::Borrowed(chunk.hash.as_str())
+        };
+
+        chunk_path(
+            data_dir,
+            &name,
+            declutter_levels,
+            self.declutter_segment_length.max(1),
+        )
+    }
+
+    /// Returns what distinguishes the chunk files of chunks in this layout: the hash, and the
+    /// size with [`Self::hash_includes_size`].
+    fn chunk_key<'a>(&self, chunk: &'a FileChunk) -> (&'a str, Option<u64>) {
+        (&chunk.hash, self.hash_includes_size.then_some(chunk.size))
+    }
+}
+
 #[cfg(unix)]
 fn read_at_chunk(file: &File, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
     use std::os::unix::fs::FileExt;
@@ -255,673 +816,9087 @@ fn read_at_chunk(file: &File, offset: u64, len: usize) -> std::io::Result<Vec<u8
     Ok(buf)
 }
 
-/// Supported hashing algorithms used to identify chunks.
-#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
-pub enum HashingAlgorithm {
-    MD5,
-    #[default]
-    SHA1,
-    SHA256,
-    SHA512,
-}
+/// Copies `size` bytes starting at `start` from the `source` file into a newly created `target`
+/// file.
+///
+/// Fails with [`std::io::ErrorKind::UnexpectedEof`] if `source` is too short, in which case no
+/// `target` file is left behind.
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+fn copy_chunk(source: &Path, start: u64, size: u64, target: &Path) -> std::io::Result<()> {
+    let src = File::open(source)?;
+    if src.metadata()?.len() < start + size {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
 
-impl HashingAlgorithm {
-    /// Returns a dynamically dispatched hasher instance corresponding to `self`.
-    fn select_hasher(&self) -> Box<dyn sha2::digest::DynDigest> {
-        match self {
-            Self::MD5 => Box::new(md5::Md5::default()),
-            Self::SHA1 => Box::new(sha1::Sha1::default()),
-            Self::SHA256 => Box::new(sha2::Sha256::default()),
-            Self::SHA512 => Box::new(sha2::Sha512::default()),
-        }
+    let out = File::create(target)?;
+    if sendfile_copy(&src, &out, start, size)? < size {
+        // The file was truncated while copying.
+        drop(out);
+        std::fs::remove_file(target)?;
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
     }
-}
 
-/// Represents a file in the source tree along with its chunked representation.
-#[derive(Clone, Debug)]
-pub struct FileWithChunks {
-    base: PathBuf,
-    /// Path of the file relative to the source root.
-    pub path: String,
-    /// File size in bytes.
-    pub size: u64,
-    /// Modification time of the file.
-    pub mtime: SystemTime,
-    chunks: OnceCell<Vec<FileChunk>>,
-    hashing_algorithm: HashingAlgorithm,
+    Ok(())
 }
 
-impl PartialEq for FileWithChunks {
-    fn eq(&self, other: &Self) -> bool {
-        self.path == other.path && self.size == other.size && self.mtime == other.mtime
+/// Copies up to `len` bytes starting at `offset` from `src` to the current position of `dst`,
+/// without passing the data through a user-space buffer. Returns the number of bytes copied.
+#[cfg(target_os = "linux")]
+fn sendfile_copy(src: &File, dst: &File, offset: u64, len: u64) -> std::io::Result<u64> {
+    // Linux transfers at most this many bytes per call.
+    const MAX_COUNT: u64 = 0x7fff_f000;
+
+    let mut offset = offset as i64;
+    let mut copied = 0;
+    while copied < len {
+        let count = (len - copied).min(MAX_COUNT) as usize;
+        match nix::sys::sendfile::sendfile64(dst, src, Some(&mut offset), count)? {
+            0 => break,
+            sent => copied += sent as u64,
+        }
     }
+    Ok(copied)
 }
 
-impl Eq for FileWithChunks {}
-
-impl FileWithChunks {
-    /// Creates a new instance by reading metadata from `path` under `source_path`.
-    pub fn try_new(
-        source_path: impl Into<PathBuf>,
-        path: impl Into<PathBuf>,
-        hashing_algorithm: HashingAlgorithm,
-    ) -> Result<Self> {
-        let base = source_path.into();
-
-        let path = path.into();
-        let metadata = path.metadata()?;
+/// Copies up to `len` bytes starting at `offset` from `src` to the current position of `dst`.
+/// Returns the number of bytes copied.
+///
+/// Other platforms either lack `sendfile` or, like macOS, only support sockets as destination, so
+/// this falls back to a buffered copy.
+#[cfg(not(target_os = "linux"))]
+fn sendfile_copy(mut src: &File, mut dst: &File, offset: u64, len: u64) -> std::io::Result<u64> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    src.seek(SeekFrom::Start(offset))?;
+    std::io::copy(&mut src.take(len), &mut dst)
+}
 
-        let path = path
-            .strip_prefix(&base)
-            .unwrap()
-            .to_string_lossy()
-            .to_string();
-        let size = metadata.len();
-        let mtime = metadata.modified()?;
+/// Size of the buffer for copying a chunk with `io_uring`, so that large chunks are not held in
+/// memory as a whole.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+const COPY_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Copies chunks from their source files into the chunk store.
+///
+/// With the `io-uring` feature on Linux, the copier owns an `io_uring` runtime, so that the reads
+/// and writes of all chunks of a run are submitted to the same ring instead of setting up a new
+/// ring per chunk. It is created once before writing the chunks of a run.
+struct ChunkCopier {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    runtime: tokio_uring::Runtime,
+}
 
+impl ChunkCopier {
+    fn new() -> std::io::Result<Self> {
         Ok(Self {
-            base,
-            path,
-            size,
-            mtime,
-            chunks: Default::default(),
-            hashing_algorithm,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            runtime: tokio_uring::Runtime::new(&tokio_uring::builder())?,
         })
     }
 
-    /// Returns already computed chunks if present.
-    pub fn get_chunks(&self) -> Option<&Vec<FileChunk>> {
-        self.chunks.get()
+    /// Copies `size` bytes starting at `start` from the `source` file into a newly created
+    /// `target` file.
+    ///
+    /// Fails with [`std::io::ErrorKind::UnexpectedEof`] if `source` is too short, in which case
+    /// no `target` file is left behind.
+    #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+    fn copy(&self, source: &Path, start: u64, size: u64, target: &Path) -> std::io::Result<()> {
+        copy_chunk(source, start, size, target)
     }
 
-    /// Returns already computed chunks if present and consumes them.
-    pub fn take_chunks(&mut self) -> Option<Vec<FileChunk>> {
-        self.chunks.take()
-    }
+    /// Copies `size` bytes starting at `start` from the `source` file into a newly created
+    /// `target` file, through a buffer of at most [`COPY_BUFFER_SIZE`] bytes.
+    ///
+    /// Fails with [`std::io::ErrorKind::UnexpectedEof`] if `source` is too short, in which case
+    /// no `target` file is left behind.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn copy(&self, source: &Path, start: u64, size: u64, target: &Path) -> std::io::Result<()> {
+        use tokio_uring::buf::IoBuf;
+        use tokio_uring::fs::File;
+
+        if std::fs::metadata(source)?.len() < start + size {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
 
-    /// Returns existing chunks or computes them if absent.
-    pub fn get_or_calculate_chunks(&self) -> Result<&Vec<FileChunk>> {
-        if self.chunks.get().is_none() {
-            let chunks = self.calculate_chunks()?;
+        self.runtime.block_on(async {
+            let src = File::open(source).await?;
+            let out = File::create(target).await?;
+
+            let mut buf = Vec::with_capacity(COPY_BUFFER_SIZE.min(size as usize));
+            let mut copied = 0;
+            while copied < size {
+                let len = (size - copied).min(buf.capacity() as u64) as usize;
+                buf.clear();
+                let (read, slice) = src.read_at(buf.slice(..len), start + copied).await;
+                buf = slice.into_inner();
+                if read? == 0 {
+                    break;
+                }
 
-            // Cannot panic, we already checked that it is empty.
-            self.chunks.set(chunks).unwrap();
-        }
+                let mut pos = 0;
+                while pos < buf.len() {
+                    let (written, slice) =
+                        out.write_at(buf.slice(pos..), copied + pos as u64).await;
+                    buf = slice.into_inner();
+                    match written? {
+                        0 => return Err(std::io::ErrorKind::WriteZero.into()),
+                        written => pos += written,
+                    }
+                }
+                copied += buf.len() as u64;
+            }
 
-        Ok(self.chunks.get().unwrap())
+            src.close().await?;
+            out.close().await?;
+
+            if copied < size {
+                // The file was truncated while copying.
+                std::fs::remove_file(target)?;
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+
+            Ok(())
+        })
     }
+}
 
-    fn calculate_chunks(&self) -> Result<Vec<FileChunk>> {
-        let path = self.base.join(&self.path);
+/// Number of bytes at the beginning of a file that are inspected to detect its MIME type.
+const MIME_DETECTION_BYTES: u64 = 8192;
 
-        let size = path.metadata()?.len();
+/// Detects the MIME type of the file at `path` from the magic bytes at its beginning.
+fn detect_mime_type(path: &Path) -> Option<String> {
+    let mut buf = Vec::new();
+    File::open(path)
+        .ok()?
+        .take(MIME_DETECTION_BYTES)
+        .read_to_end(&mut buf)
+        .ok()?;
 
-        let hashing_algorithm = self.hashing_algorithm;
+    infer::get(&buf).map(|kind| kind.mime_type().to_string())
+}
 
-        // Process file in MiB chunks.
-        let chunk_size = 1024 * 1024;
-        if size == 0 {
-            let hasher = hashing_algorithm.select_hasher();
-            let hash = hasher.finalize();
-            let hash = base16ct::lower::encode_string(&hash);
+/// Returns the location of the file with the cache key `path`. With multiple source directories,
+/// the first component of the key is the index of the source directory.
+fn resolve_source_file(
+    source_paths: &[PathBuf],
+    path: &str,
+    source_encoding: Option<&'static Encoding>,
+) -> Option<PathBuf> {
+    let path = encode_path(path, source_encoding);
+
+    if let [source_path] = source_paths {
+        return Some(source_path.join(path));
+    }
 
-            std::iter::once(Ok::<FileChunk, Error>(FileChunk::new(0, 0, hash))).collect()
-        } else {
-            // Open file once and read it in parallel.
-            let file = Arc::new(File::open(&path)?);
-            let total_chunks = (size + chunk_size - 1) / chunk_size;
+    let (index, path) = split_source_index(&path)?;
+    Some(source_paths.get(index)?.join(path))
+}
 
-            (0..total_chunks)
-                .into_par_iter()
-                .map(|chunk_idx| {
-                    let offset = chunk_idx * chunk_size;
-                    let len = chunk_size.min(size.saturating_sub(offset)) as usize;
+/// Converts a file name of the source tree into a cache key, see
+/// [`DeduperBuilder::source_encoding`].
+fn decode_path(path: &Path, source_encoding: Option<&'static Encoding>) -> String {
+    #[cfg(unix)]
+    if let Some(encoding) = source_encoding {
+        use std::os::unix::ffi::OsStrExt;
+
+        return encoding
+            .decode_without_bom_handling(path.as_os_str().as_bytes())
+            .0
+            .into_owned();
+    }
 
-                    let data = read_at_chunk(&file, offset, len)?;
+    #[cfg(not(unix))]
+    let _ = source_encoding;
 
-                    let mut hasher = hashing_algorithm.select_hasher();
-                    hasher.update(&data);
-                    let hash = hasher.finalize();
-                    let hash = base16ct::lower::encode_string(&hash);
+    path_to_key(path)
+}
 
-                    Ok::<FileChunk, Error>(FileChunk::new(offset, data.len() as u64, hash))
-                })
-                .collect()
-        }
+/// Converts a relative path into a cache key, which always uses `/` as separator, so that caches
+/// can be shared between platforms.
+fn path_to_key(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    if std::path::MAIN_SEPARATOR == '/' {
+        path.into_owned()
+    } else {
+        path.replace(std::path::MAIN_SEPARATOR, "/")
     }
 }
 
-/// A single chunk of a file, including its offset in the original file, size, and hash.
-#[derive(Clone, Debug)]
-pub struct FileChunk {
-    pub start: u64,
-    pub size: u64,
-    pub hash: String,
-    pub path: Option<String>,
+/// Converts a cache key back into the file name of the source tree, the inverse of
+/// [`decode_path`].
+fn encode_path(path: &str, source_encoding: Option<&'static Encoding>) -> PathBuf {
+    #[cfg(unix)]
+    if let Some(encoding) = source_encoding {
+        use std::os::unix::ffi::OsStrExt;
+
+        return PathBuf::from(std::ffi::OsStr::from_bytes(&encoding.encode(path).0));
+    }
+
+    #[cfg(not(unix))]
+    let _ = source_encoding;
+
+    PathBuf::from(path)
 }
 
-impl FileChunk {
-    /// Constructs a new `FileChunk`.
-    pub fn new(start: u64, size: u64, hash: String) -> Self {
-        Self {
-            start,
-            size,
-            hash,
-            path: None,
+/// Splits a cache key of a multi-source cache into the index of the source directory and the path
+/// relative to it.
+fn split_source_index(path: &Path) -> Option<(usize, &Path)> {
+    let mut components = path.components();
+    let index = components.next()?.as_os_str().to_str()?.parse().ok()?;
+    Some((index, components.as_path()))
+}
+
+/// Like [`Path::canonicalize`], but `path` does not need to exist. Only the longest existing
+/// prefix is resolved, the missing components are appended as they are.
+fn canonicalize_missing(path: &Path) -> std::io::Result<PathBuf> {
+    let mut existing = std::path::absolute(path)?;
+    let mut missing = Vec::new();
+
+    loop {
+        match existing.canonicalize() {
+            Ok(canonical) => {
+                return Ok(canonical.join(missing.into_iter().rev().collect::<PathBuf>()));
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let Some(name) = existing.file_name() else {
+                    return Err(err);
+                };
+                missing.push(name.to_os_string());
+                existing.pop();
+            }
+            Err(err) => return Err(err),
         }
     }
 }
 
-/// In-memory cache of `FileWithChunks` indexed by their relative paths.
-pub struct DedupCache(HashMap<String, FileWithChunks>);
+/// Returns whether `path` is a regular file that is part of the source tree. Symlinks are not
+/// followed.
+fn is_source_file(path: &Path) -> bool {
+    path.is_file() && !path.is_symlink()
+}
 
-impl DedupCache {
-    /// Creates an empty dedup cache.
-    fn new() -> Self {
-        Self(HashMap::new())
+/// Renames `from` to `to`. If both are on different devices, `from` is copied to `to` and removed
+/// afterwards instead.
+fn rename_or_copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            std::fs::copy(from, to)?;
+            std::fs::remove_file(from)
+        }
+        result => result,
     }
+}
 
-    /// Constructs a cache from an existing hashmap.
-    fn from_hashmap(hash_map: HashMap<String, FileWithChunks>) -> Self {
-        Self(hash_map)
-    }
+/// Returns the total size of `chunks`.
+fn chunked_size(chunks: &[FileChunk]) -> u64 {
+    chunks.iter().map(|chunk| chunk.size).sum()
+}
 
-    /// Reads cache entries from a file. Supports optional zstd compression based on extension.
-    fn read_from_file(&mut self, path: impl AsRef<Path>) {
-        let cache_from_file = cache::read_from_file(path);
+/// Delay before the first retry of failed IO, which doubles with every further attempt.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How often IO on flaky storage, like network mounts, is retried, see
+/// [`DeduperBuilder::retries`].
+#[derive(Clone, Copy, Debug)]
+struct RetryPolicy {
+    retries: u32,
+    backoff: Duration,
+}
 
-        for x in cache_from_file {
-            self.insert(x.path.clone(), x);
+impl RetryPolicy {
+    /// Creates a policy retrying failed IO up to `retries` times.
+    fn new(retries: u32) -> Self {
+        Self {
+            retries,
+            backoff: RETRY_BACKOFF,
         }
     }
 
-    /// Writes the cache to a file, optionally compressing with zstd if extension suggests.
-    fn write_to_file(&self, path: impl AsRef<Path>) {
-        cache::write_to_file(path, self);
+    /// Runs `op` and retries it while it fails with a transient IO error, see [`is_transient`],
+    /// waiting exponentially longer between attempts.
+    fn run<T>(&self, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut backoff = self.backoff;
+        for _ in 0..self.retries {
+            match op() {
+                Err(Error::Io(err)) if is_transient(&err) => {
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+        op()
     }
+}
 
-    /// Iterates over all chunks, yielding the chunk hash, enriched `FileChunk` with path, and a
-    /// flag indicating if it was freshly calculated.
-    pub fn get_chunks(&self) -> Result<impl Iterator<Item = (String, FileChunk, bool)> + '_> {
-        Ok(self.values().flat_map(|fwc| {
-            let mut dirty = fwc.get_chunks().is_none();
-
-            fwc.get_or_calculate_chunks()
-                .unwrap()
-                .iter()
-                .map(move |chunk| {
-                    let result = (
-                        chunk.hash.clone(),
-                        FileChunk {
-                            path: Some(fwc.path.clone()),
-                            ..chunk.clone()
-                        },
-                        dirty,
-                    );
+/// Returns whether retrying the IO that failed with `err` may succeed. Errors that will not go
+/// away by themselves, like missing files or invalid data, are not transient.
+fn is_transient(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+
+    !matches!(
+        err.kind(),
+        ErrorKind::NotFound
+            | ErrorKind::PermissionDenied
+            | ErrorKind::AlreadyExists
+            | ErrorKind::InvalidInput
+            | ErrorKind::InvalidData
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::Unsupported
+    )
+}
 
-                    dirty = false;
+/// Counting semaphore that limits how many source files are open at the same time.
+#[derive(Debug)]
+struct OpenFilesLimit {
+    available: Mutex<usize>,
+    released: Condvar,
+}
 
-                    result
-                })
-        }))
+impl OpenFilesLimit {
+    /// Creates a limit allowing `max_open_files` files to be open at once. At least one file is
+    /// always allowed.
+    fn new(max_open_files: usize) -> Self {
+        Self {
+            available: Mutex::new(max_open_files.max(1)),
+            released: Condvar::new(),
+        }
     }
 
-    pub fn get(&self, path: &str) -> Option<&FileWithChunks> {
-        self.0.get(path)
+    /// Blocks until a file may be opened. The returned permit must be held while the file is
+    /// open.
+    fn acquire(&self) -> OpenFilePermit<'_> {
+        self.wait();
+        OpenFilePermit(self)
     }
 
-    pub fn get_mut(&mut self, path: &str) -> Option<&mut FileWithChunks> {
-        self.0.get_mut(path)
+    /// Like [`Self::acquire`], but the permit keeps the limit alive, so it can be handed to other
+    /// threads.
+    fn acquire_owned(self: &Arc<Self>) -> OwnedOpenFilePermit {
+        self.wait();
+        OwnedOpenFilePermit(Arc::clone(self))
     }
 
-    fn insert(&mut self, path: String, fwc: FileWithChunks) {
-        self.0.insert(path, fwc);
+    /// Blocks until a permit is available and takes it.
+    fn wait(&self) {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
     }
 
-    pub fn contains_key(&self, path: &str) -> bool {
-        self.0.contains_key(path)
+    /// Returns a permit taken by [`Self::wait`].
+    fn release(&self) {
+        *self.available.lock().unwrap() += 1;
+        self.released.notify_one();
     }
+}
 
-    pub fn into_iter(self) -> IntoIter<String, FileWithChunks> {
-        self.0.into_iter()
-    }
+/// Permit to keep a file open, released on drop.
+struct OpenFilePermit<'a>(&'a OpenFilesLimit);
 
-    pub fn values(&self) -> impl Iterator<Item = &FileWithChunks> {
-        self.0.values()
+impl Drop for OpenFilePermit<'_> {
+    fn drop(&mut self) {
+        self.0.release();
     }
+}
 
-    pub fn len(&self) -> usize {
-        self.0.len()
+/// Permit taken by [`OpenFilesLimit::acquire_owned`], released on drop.
+struct OwnedOpenFilePermit(Arc<OpenFilesLimit>);
+
+impl Drop for OwnedOpenFilePermit {
+    fn drop(&mut self) {
+        self.0.release();
     }
 }
 
-/// Primary deduper: scans a source directory, maintains a chunk cache, and writes deduplicated
-/// chunk data to a target location.
-pub struct Deduper {
-    source_path: PathBuf,
-    cache_path: PathBuf,
-    pub cache: DedupCache,
+/// Supported hashing algorithms used to identify chunks.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum HashingAlgorithm {
+    MD5,
+    #[default]
+    SHA1,
+    SHA256,
+    SHA512,
+    /// CRC32 checksum, which is much faster than the other algorithms, but only meant to detect
+    /// corruption. Since it is not collision-resistant, different chunks may get the same name and
+    /// are then silently mixed up. Only use it for small, local stores, where speed matters more.
+    CRC32,
 }
 
-impl Deduper {
-    /// Initializes a new `Deduper`:
-    /// - Loads provided cache files in reverse order (so later ones override earlier),
-    /// - Prunes missing entries,
-    /// - Scans the source tree and updates or inserts modified/new files.
-    pub fn new(
-        source_path: impl Into<PathBuf>,
-        cache_paths: Vec<impl Into<PathBuf>>,
-        hashing_algorithm: HashingAlgorithm,
-        same_file_system: bool,
-    ) -> Self {
-        let source_path = source_path.into();
+impl HashingAlgorithm {
+    /// Returns a dynamically dispatched hasher instance corresponding to `self`.
+    fn select_hasher(&self) -> Box<dyn sha2::digest::DynDigest> {
+        match self {
+            Self::MD5 => Box::new(md5::Md5::default()),
+            Self::SHA1 => Box::new(sha1::Sha1::default()),
+            Self::SHA256 => Box::new(sha2::Sha256::default()),
+            Self::SHA512 => Box::new(sha2::Sha512::default()),
+            Self::CRC32 => Box::new(Crc32::default()),
+        }
+    }
 
-        let mut cache = DedupCache::new();
+    /// All supported hashing algorithms.
+    pub const ALL: [HashingAlgorithm; 5] = [
+        Self::MD5,
+        Self::SHA1,
+        Self::SHA256,
+        Self::SHA512,
+        Self::CRC32,
+    ];
+
+    /// Returns the lowercase name of the algorithm, as accepted by [`FromStr`].
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::MD5 => "md5",
+            Self::SHA1 => "sha1",
+            Self::SHA256 => "sha256",
+            Self::SHA512 => "sha512",
+            Self::CRC32 => "crc32",
+        }
+    }
 
-        let cache_path = {
-            let mut cache_path = Default::default();
-            for cache_path_from_iter in cache_paths.into_iter().rev() {
-                cache_path = cache_path_from_iter.into();
-                cache.read_from_file(&cache_path);
-            }
-            cache_path
-        };
+    /// Hashes `data` and returns the hash as lowercase hex string.
+    pub fn hash_hex(&self, data: &[u8]) -> String {
+        let mut hasher = self.select_hasher();
+        hasher.update(data);
+        base16ct::lower::encode_string(&hasher.finalize())
+    }
+}
 
-        let valid_entry = |path: &PathBuf| path.is_file() && !path.is_symlink();
+/// Adapter to use [`crc32fast::Hasher`] like the digests of the SHA family, which outputs the
+/// checksum in big-endian byte order.
+#[derive(Clone, Default)]
+struct Crc32(crc32fast::Hasher);
 
-        cache = DedupCache::from_hashmap(
-            cache
-                .into_iter()
-                .filter(|(path, _)| valid_entry(&source_path.join(path)))
-                .collect(),
-        );
+impl sha2::digest::OutputSizeUser for Crc32 {
+    type OutputSize = sha2::digest::consts::U4;
+}
 
-        let dir_walker = WalkDir::new(&source_path)
-            .min_depth(1)
-            .same_file_system(same_file_system);
+impl sha2::digest::Update for Crc32 {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+}
 
-        for entry in dir_walker {
-            let entry = entry.unwrap().into_path();
+impl sha2::digest::FixedOutput for Crc32 {
+    fn finalize_into(self, out: &mut sha2::digest::Output<Self>) {
+        out.copy_from_slice(&self.0.finalize().to_be_bytes());
+    }
+}
 
-            if !valid_entry(&entry) {
-                continue;
-            }
+impl sha2::digest::Reset for Crc32 {
+    fn reset(&mut self) {
+        self.0.reset();
+    }
+}
 
-            let fwc = FileWithChunks::try_new(&source_path, &entry, hashing_algorithm).unwrap();
+impl sha2::digest::FixedOutputReset for Crc32 {
+    fn finalize_into_reset(&mut self, out: &mut sha2::digest::Output<Self>) {
+        out.copy_from_slice(&std::mem::take(&mut self.0).finalize().to_be_bytes());
+    }
+}
 
-            if let Some(fwc_cache) = cache.get_mut(&fwc.path) {
-                if fwc == *fwc_cache {
-                    fwc_cache.base = source_path.clone();
-                    continue;
-                }
-            }
+impl Display for HashingAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
 
-            cache.insert(fwc.path.clone(), fwc);
-        }
+impl FromStr for HashingAlgorithm {
+    type Err = Error;
 
-        Self {
-            source_path,
-            cache_path,
-            cache,
-        }
+    /// Parses the name of an algorithm, case-insensitively.
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| Error::UnknownHashingAlgorithm(s.to_string()))
     }
+}
 
-    /// Atomically writes the internal cache back to its backing file.
-    pub fn write_cache(&self) {
-        if self.cache_path.file_name().is_none() {
-            return;
-        }
+/// Strategy used to split files into chunks.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum Chunking {
+    /// Chunks of a fixed size of 1 MiB.
+    #[default]
+    Fixed,
+    /// Content-defined chunks via FastCDC, with sizes between `min_size` and `max_size` bytes.
+    ///
+    /// Since chunk boundaries depend on the content, inserting or removing data only changes the
+    /// chunks around the modification, instead of shifting all following chunks.
+    ContentDefined {
+        min_size: u32,
+        avg_size: u32,
+        max_size: u32,
+    },
+}
 
-        let temp_path = self.cache_path.clone().with_extension(format!(
-            "tmp.{}.{}",
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis(),
-            self.cache_path
-                .extension()
-                .unwrap_or("ext".as_ref())
-                .to_str()
-                .unwrap()
-        ));
-        self.cache.write_to_file(&temp_path);
-        std::fs::rename(temp_path, &self.cache_path).unwrap();
-    }
+impl Chunking {
+    /// Content-defined chunking with the default chunk sizes.
+    pub const CONTENT_DEFINED: Chunking = Chunking::ContentDefined {
+        min_size: DEFAULT_CDC_MIN_SIZE,
+        avg_size: DEFAULT_CDC_AVG_SIZE,
+        max_size: DEFAULT_CDC_MAX_SIZE,
+    };
+
+    /// Checks that the chunk sizes of content-defined chunking are ordered and within the limits
+    /// of FastCDC, see [`fastcdc::v2020::MINIMUM_MIN`] and the following constants. Fails with
+    /// [`Error::InvalidChunkSizes`] otherwise.
+    pub fn validate(self) -> Result<()> {
+        use fastcdc::v2020::{
+            AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX, MINIMUM_MIN,
+        };
 
-    /// Writes all chunks from the current cache to `target_path/data`, applying optional
-    /// decluttering (path splitting) to reduce directory entropy.
-    pub fn write_chunks(
-        &mut self,
-        target_path: impl Into<PathBuf>,
-        declutter_levels: usize,
-    ) -> Result<()> {
-        let target_path = target_path.into();
-        let data_dir = target_path.join("data");
-        std::fs::create_dir_all(&data_dir)?;
-        for (_, chunk, _) in self.cache.get_chunks()? {
-            let mut chunk_file = PathBuf::from(&chunk.hash);
-            if declutter_levels > 0 {
-                chunk_file = FileDeclutter::oneshot(chunk_file, declutter_levels);
-            }
-            chunk_file = data_dir.join(chunk_file);
+        let Chunking::ContentDefined {
+            min_size,
+            avg_size,
+            max_size,
+        } = self
+        else {
+            return Ok(());
+        };
 
-            if !chunk_file.exists() {
-                std::fs::create_dir_all(&chunk_file.parent().unwrap())?;
-                let mut out = File::create(chunk_file)?;
-                let mut src = BufReader::new(File::open(
-                    self.source_path.join(chunk.path.as_ref().unwrap()),
-                )?);
-                src.seek(SeekFrom::Start(chunk.start))?;
-                let mut limited = src.take(chunk.size);
-                std::io::copy(&mut limited, &mut out)?;
+        for (name, size, min, max) in [
+            ("minimum", min_size, MINIMUM_MIN, MINIMUM_MAX),
+            ("average", avg_size, AVERAGE_MIN, AVERAGE_MAX),
+            ("maximum", max_size, MAXIMUM_MIN, MAXIMUM_MAX),
+        ] {
+            if !(min..=max).contains(&size) {
+                return Err(Error::InvalidChunkSizes(format!(
+                    "{name} size {size} is not between {min} and {max}"
+                )));
             }
         }
+        if min_size > avg_size || avg_size > max_size {
+            return Err(Error::InvalidChunkSizes(format!(
+                "sizes must satisfy minimum {min_size} <= average {avg_size} <= maximum {max_size}"
+            )));
+        }
 
         Ok(())
     }
-}
 
-/// Rebuilds original files from deduplicated chunk storage using a cache.
-pub struct Hydrator {
-    source_path: PathBuf,
-    pub cache: DedupCache,
-}
+    /// Splits the data of `reader` into chunks, like the content of a source file, so that data
+    /// that is not on disk, like an in-memory buffer or a network stream, can be deduplicated.
+    /// The data is read sequentially.
+    ///
+    /// See [`StoreLayout::store_reader`] to write the chunks into a chunk store at the same time.
+    pub fn chunk_reader(
+        self,
+        reader: impl Read,
+        hashing_algorithm: HashingAlgorithm,
+    ) -> Result<Vec<FileChunk>> {
+        self.for_each_chunk(reader, hashing_algorithm, |_, _| Ok(()))
+    }
 
-impl Hydrator {
-    /// Loads the cache(s) and prepares for hydration.
-    pub fn new(source_path: impl Into<PathBuf>, cache_paths: Vec<impl Into<PathBuf>>) -> Self {
-        let source_path = source_path.into();
+    /// Does the actual work of [`Self::chunk_reader`], passing each chunk and its data to
+    /// `on_chunk`.
+    fn for_each_chunk(
+        self,
+        mut reader: impl Read,
+        hashing_algorithm: HashingAlgorithm,
+        mut on_chunk: impl FnMut(&FileChunk, &[u8]) -> Result<()>,
+    ) -> Result<Vec<FileChunk>> {
+        self.validate()?;
+
+        let mut chunks = Vec::new();
+        let mut add_chunk = |start, data: &[u8]| {
+            let chunk = FileChunk::new(start, data.len() as u64, hashing_algorithm.hash_hex(data));
+            on_chunk(&chunk, data)?;
+            chunks.push(chunk);
+            Ok::<_, Error>(())
+        };
 
-        let mut cache = DedupCache::new();
+        match self {
+            Chunking::Fixed => {
+                let mut data = Vec::with_capacity(FIXED_CHUNK_SIZE as usize);
+                let mut start = 0;
+                loop {
+                    data.clear();
+                    (&mut reader)
+                        .take(FIXED_CHUNK_SIZE)
+                        .read_to_end(&mut data)?;
+                    if data.is_empty() {
+                        break;
+                    }
+                    add_chunk(start, &data)?;
+                    start += data.len() as u64;
+                }
+            }
+            Chunking::ContentDefined {
+                min_size,
+                avg_size,
+                max_size,
+            } => {
+                for chunk in fastcdc::v2020::StreamCDC::new(reader, min_size, avg_size, max_size) {
+                    let chunk = chunk.map_err(std::io::Error::from)?;
+                    add_chunk(chunk.offset, &chunk.data)?;
+                }
+            }
+        }
 
-        for cache_path in cache_paths.into_iter().rev() {
-            let cache_path = cache_path.into();
-            cache.read_from_file(&cache_path);
+        // Empty data is represented by a single empty chunk, like empty files.
+        if chunks.is_empty() {
+            chunks.push(FileChunk::new(0, 0, hashing_algorithm.hash_hex(&[])));
         }
 
-        Self { source_path, cache }
+        Ok(chunks)
     }
+}
 
-    /// Restores files into `target_path` by concatenating their chunks. `declutter_levels` must
-    /// match the level used during deduplication.
-    pub fn restore_files(&self, target_path: impl Into<PathBuf>, declutter_levels: usize) {
-        let data_dir = self.source_path.join("data");
-        let target_path = target_path.into();
-        std::fs::create_dir_all(&target_path).unwrap();
-        for fwc in self.cache.values() {
-            let target = target_path.join(&fwc.path);
-            std::fs::create_dir_all(&target.parent().unwrap()).unwrap();
-            let target_file = File::create(&target).unwrap();
-            let mut target = BufWriter::new(&target_file);
-            for chunk in fwc.get_chunks().unwrap() {
-                let mut chunk_file = PathBuf::from(&chunk.hash);
-                if declutter_levels > 0 {
-                    chunk_file = FileDeclutter::oneshot(chunk_file, declutter_levels);
-                }
-                chunk_file = data_dir.join(chunk_file);
+/// Unicode normalization form applied to the paths of source files, see
+/// [`DeduperBuilder::normalize_paths`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum NormalizationForm {
+    /// Paths are used as they are.
+    #[default]
+    None,
+    /// Canonical composition, as used by most file systems.
+    NFC,
+    /// Canonical decomposition, as used by HFS+.
+    NFD,
+}
 
-                let mut source = File::open(chunk_file).unwrap();
-                std::io::copy(&mut source, &mut target).unwrap();
-            }
-            target.flush().unwrap();
-            target_file.set_modified(fwc.mtime).unwrap()
+impl NormalizationForm {
+    /// All normalization forms, in the order of their declaration.
+    pub const ALL: [NormalizationForm; 3] = [
+        NormalizationForm::None,
+        NormalizationForm::NFC,
+        NormalizationForm::NFD,
+    ];
+
+    /// Returns the lowercase name of the normalization form, as accepted by [`FromStr`].
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::NFC => "nfc",
+            Self::NFD => "nfd",
         }
     }
 
-    /// List missing chunks or chunks with wrong size.
-    pub fn list_missing_chunks(
-        &self,
-        declutter_levels: usize,
-    ) -> impl Iterator<Item = (PathBuf, String)> {
-        let mut hashes_and_chunks = self
-            .cache
-            .get_chunks()
-            .unwrap()
-            .map(|(hash, chunk, ..)| (PathBuf::from(hash), chunk))
-            .collect::<Vec<_>>();
-        hashes_and_chunks.sort_by(|a, b| a.0.cmp(&b.0));
-        hashes_and_chunks.dedup_by(|a, b| a.0 == b.0);
+    /// Returns `path` in this normalization form.
+    pub fn normalize(&self, path: &str) -> String {
+        match self {
+            Self::None => path.to_string(),
+            Self::NFC => path.nfc().collect(),
+            Self::NFD => path.nfd().collect(),
+        }
+    }
+}
 
-        let (hashes, chunks): (Vec<_>, Vec<_>) = hashes_and_chunks.into_iter().unzip();
+impl Display for NormalizationForm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
 
-        let files_in_cache = FileDeclutter::new_from_iter(hashes.into_iter())
-            .base(&self.source_path.join("data"))
-            .levels(declutter_levels)
-            .map(|(_, path)| path);
+impl FromStr for NormalizationForm {
+    type Err = Error;
 
-        files_in_cache
-            .zip(chunks)
+    /// Parses the name of a normalization form, case-insensitively.
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
             .into_iter()
-            .filter_map(|(path, chunk)| {
-                if !path.exists() {
-                    Some((path, "Does not exist".to_string()))
-                } else if path.metadata().unwrap().len() != chunk.size {
-                    Some((
-                        path,
-                        format!("Does not have expected size of {}", chunk.size),
-                    ))
-                } else {
-                    None
-                }
-            })
+            .find(|form| form.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| Error::UnknownNormalizationForm(s.to_string()))
     }
+}
 
-    /// Check if all chunk files listed in the cache are present in source directory.
-    pub fn check_cache(&self, declutter_levels: usize) -> bool {
-        self.list_missing_chunks(declutter_levels).next().is_none()
-    }
+/// Represents a file in the source tree along with its chunked representation.
+#[derive(Clone, Debug)]
+pub struct FileWithChunks {
+    /// Location of the file on disk, which is only known for files found in a source directory.
+    source_file: PathBuf,
+    /// Path of the file relative to the source root.
+    pub path: String,
+    /// File size in bytes.
+    pub size: u64,
+    /// Modification time of the file.
+    pub mtime: SystemTime,
+    chunks: OnceLock<Vec<FileChunk>>,
+    hashing_algorithm: HashingAlgorithm,
+    chunking: Chunking,
+    /// MIME type of the file content, if it was detected during deduplication.
+    pub mime_type: Option<String>,
+    /// ID of the user owning the file, recorded on Unix.
+    pub uid: Option<u32>,
+    /// ID of the group owning the file, recorded on Unix.
+    pub gid: Option<u32>,
+    file_hash: OnceLock<String>,
+    hash_whole_file: bool,
+    open_files_limit: Option<Arc<OpenFilesLimit>>,
+    #[cfg(feature = "parallel")]
+    thread_pool: Option<Arc<rayon::ThreadPool>>,
+}
 
-    /// List files in source directory that are not listed in cache.
-    pub fn list_extra_files(&self, declutter_levels: usize) -> impl Iterator<Item = PathBuf> {
-        let files_in_cache = FileDeclutter::new_from_iter(
-            self.cache
-                .get_chunks()
+impl PartialEq for FileWithChunks {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path && self.size == other.size && self.mtime == other.mtime
+    }
+}
+
+impl Eq for FileWithChunks {}
+
+impl FileWithChunks {
+    /// Creates a new instance from known metadata and chunks, without accessing the file system.
+    ///
+    /// Such entries can be used for restoring, but their chunks cannot be re-calculated, since
+    /// the location of the file is not known.
+    pub fn from_chunks(
+        path: impl Into<String>,
+        size: u64,
+        mtime: SystemTime,
+        hashing_algorithm: HashingAlgorithm,
+        chunks: Vec<FileChunk>,
+    ) -> Self {
+        Self {
+            source_file: PathBuf::new(),
+            path: path.into(),
+            size,
+            mtime,
+            chunks: OnceLock::from(chunks),
+            hashing_algorithm,
+            chunking: Chunking::default(),
+            mime_type: None,
+            uid: None,
+            gid: None,
+            file_hash: OnceLock::new(),
+            hash_whole_file: false,
+            open_files_limit: None,
+            #[cfg(feature = "parallel")]
+            thread_pool: None,
+        }
+    }
+
+    /// Creates a new instance by reading metadata from `path` under `source_path`.
+    pub fn try_new(
+        source_path: impl Into<PathBuf>,
+        path: impl Into<PathBuf>,
+        hashing_algorithm: HashingAlgorithm,
+    ) -> Result<Self> {
+        let base = source_path.into();
+
+        let source_file = path.into();
+        let metadata = source_file.metadata()?;
+
+        let path =
+            path_to_key(
+                source_file
+                    .strip_prefix(&base)
+                    .map_err(|_| Error::OutsideSource {
+                        path: source_file.clone(),
+                        base: base.clone(),
+                    })?,
+            );
+        let size = metadata.len();
+        let mtime = metadata.modified()?;
+
+        #[cfg(unix)]
+        let (uid, gid) = {
+            use std::os::unix::fs::MetadataExt;
+            (Some(metadata.uid()), Some(metadata.gid()))
+        };
+        #[cfg(not(unix))]
+        let (uid, gid) = (None, None);
+
+        Ok(Self {
+            source_file,
+            path,
+            size,
+            mtime,
+            chunks: Default::default(),
+            hashing_algorithm,
+            chunking: Chunking::default(),
+            mime_type: None,
+            uid,
+            gid,
+            file_hash: OnceLock::new(),
+            hash_whole_file: false,
+            open_files_limit: None,
+            #[cfg(feature = "parallel")]
+            thread_pool: None,
+        })
+    }
+
+    /// Returns already computed chunks if present.
+    pub fn get_chunks(&self) -> Option<&Vec<FileChunk>> {
+        self.chunks.get()
+    }
+
+    /// Returns already computed chunks if present and consumes them.
+    pub fn take_chunks(&mut self) -> Option<Vec<FileChunk>> {
+        self.chunks.take()
+    }
+
+    /// Returns the hash of the whole file content, if it was calculated together with the chunks,
+    /// see [`DeduperBuilder::file_hashes`].
+    pub fn file_hash(&self) -> Option<&str> {
+        self.file_hash.get().map(String::as_str)
+    }
+
+    /// Returns the time that passed since the recorded modification time of the file. Fails with
+    /// [`Error::FutureModificationTime`] if the modification time lies in the future.
+    pub fn age(&self) -> Result<Duration> {
+        SystemTime::now()
+            .duration_since(self.mtime)
+            .map_err(|_| Error::FutureModificationTime {
+                path: self.path.clone(),
+            })
+    }
+
+    /// Sets the hash of the whole file content, for entries created with [`Self::from_chunks`].
+    pub fn set_file_hash(&mut self, file_hash: impl Into<String>) {
+        self.file_hash = OnceLock::from(file_hash.into());
+    }
+
+    /// Calculates the chunks of the file lazily, without storing them, so that even files with
+    /// millions of chunks need little memory. At most `max_in_flight` fixed-size chunks are read
+    /// at once, in parallel if enabled, and content-defined chunks are read one after the other.
+    ///
+    /// The chunks are the same as calculated by [`Self::get_or_calculate_chunks`], but neither
+    /// merged nor recorded in the cache.
+    pub fn stream_chunks(&self, max_in_flight: usize) -> Result<ChunkStream<'_>> {
+        let size = self.source_file.metadata()?.len();
+        let permit = self
+            .open_files_limit
+            .as_deref()
+            .map(OpenFilesLimit::acquire);
+
+        let source = if size == 0 {
+            let hash = self.hashing_algorithm.hash_hex(&[]);
+            ChunkSource::Empty(Some(FileChunk::new(0, 0, hash)))
+        } else if let Chunking::ContentDefined {
+            min_size,
+            avg_size,
+            max_size,
+        } = self.chunking
+        {
+            self.chunking.validate()?;
+            let file = BufReader::new(File::open(&self.source_file)?);
+            ChunkSource::ContentDefined(fastcdc::v2020::StreamCDC::new(
+                file, min_size, avg_size, max_size,
+            ))
+        } else {
+            ChunkSource::Fixed {
+                file: File::open(&self.source_file)?,
+                size,
+                next_chunk: 0,
+                max_in_flight: max_in_flight.max(1) as u64,
+                ready: Default::default(),
+            }
+        };
+
+        Ok(ChunkStream {
+            source,
+            hashing_algorithm: self.hashing_algorithm,
+            _permit: permit,
+        })
+    }
+
+    /// Returns existing chunks or computes them if absent.
+    ///
+    /// Fails with [`Error::ChunkedSizeMismatch`] if the computed chunks do not cover exactly
+    /// [`Self::size`] bytes, which happens if the file was modified since it was scanned.
+    pub fn get_or_calculate_chunks(&self) -> Result<&Vec<FileChunk>> {
+        self.get_or_calculate_chunks_with(true)
+    }
+
+    /// Does the actual work of [`Self::get_or_calculate_chunks`]. With `parallel_chunks`, the
+    /// chunks of the file are hashed in parallel, otherwise one after the other.
+    fn get_or_calculate_chunks_with(&self, parallel_chunks: bool) -> Result<&Vec<FileChunk>> {
+        if self.chunks.get().is_none() {
+            let _span = span!("chunk_hash", file = %self.path);
+            let (chunks, file_hash) = self.calculate_chunks(parallel_chunks)?;
+            self.check_chunked_size(&chunks)?;
+
+            // Cannot panic, we already checked that it is empty.
+            self.chunks.set(chunks).unwrap();
+            if let Some(file_hash) = file_hash {
+                // A hash read from the cache is for the same content.
+                let _ = self.file_hash.set(file_hash);
+            }
+        }
+
+        Ok(self.chunks.get().unwrap())
+    }
+
+    /// Returns the total size of the chunks, calculating them if necessary. For consistent
+    /// entries, this is the same as [`Self::size`].
+    pub fn chunked_size(&self) -> Result<u64> {
+        Ok(chunked_size(self.get_or_calculate_chunks()?))
+    }
+
+    /// Fails with [`Error::ChunkedSizeMismatch`] if `chunks` do not add up to [`Self::size`].
+    fn check_chunked_size(&self, chunks: &[FileChunk]) -> Result<()> {
+        let actual = chunked_size(chunks);
+        if actual != self.size {
+            return Err(Error::ChunkedSizeMismatch {
+                path: self.path.clone(),
+                expected: self.size,
+                actual,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Compares the content of both files chunk by chunk, calculating the chunks if necessary.
+    ///
+    /// In contrast to `==`, which only compares path, size and modification time, this detects
+    /// files with the same content even if they were touched or live at different paths.
+    pub fn content_equal(&self, other: &FileWithChunks) -> Result<bool> {
+        if self.size != other.size
+            || self.hashing_algorithm != other.hashing_algorithm
+            || self.chunking != other.chunking
+        {
+            return Ok(false);
+        }
+
+        let chunks = self.get_or_calculate_chunks()?;
+        let other_chunks = other.get_or_calculate_chunks()?;
+
+        Ok(chunks.len() == other_chunks.len()
+            && chunks
+                .iter()
+                .zip(other_chunks)
+                .all(|(a, b)| a.start == b.start && a.size == b.size && a.hash == b.hash))
+    }
+
+    /// Takes over the settings of the current run and the metadata that does not invalidate the
+    /// chunks from `fresh`, the entry just scanned for the same file, so that a cached entry can
+    /// be kept.
+    fn adopt_runtime_settings(&mut self, fresh: FileWithChunks) {
+        self.source_file = fresh.source_file;
+        // Changing the ownership does not change the modification time.
+        self.uid = fresh.uid;
+        self.gid = fresh.gid;
+        self.hash_whole_file = fresh.hash_whole_file;
+        self.open_files_limit = fresh.open_files_limit;
+        #[cfg(feature = "parallel")]
+        {
+            self.thread_pool = fresh.thread_pool;
+        }
+    }
+
+    /// Replaces the chunks with a single chunk spanning the whole file.
+    fn merge_chunks(&mut self) -> Result<()> {
+        let path = &self.source_file;
+
+        let _permit = self.open_files_limit.as_ref().map(|limit| limit.acquire());
+        let mut file = BufReader::new(File::open(path)?);
+
+        let mut hasher = self.hashing_algorithm.select_hasher();
+        let mut buffer = vec![0; 64 * 1024];
+        let mut size = 0;
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            size += read as u64;
+        }
+        let hash = base16ct::lower::encode_string(&hasher.finalize());
+
+        if self.hash_whole_file {
+            self.file_hash = OnceLock::from(hash.clone());
+        }
+        self.chunks = OnceLock::from(vec![FileChunk::new(0, size, hash)]);
+
+        Ok(())
+    }
+
+    /// Calculates the chunks and, if enabled, the hash of the whole file, which is fed with the
+    /// chunks in order. Chunks are hashed in the configured thread pool, or in the global one.
+    fn calculate_chunks(&self, parallel_chunks: bool) -> Result<(Vec<FileChunk>, Option<String>)> {
+        let source_file = &self.source_file;
+        let hashing_algorithm = self.hashing_algorithm;
+        let chunking = self.chunking;
+        let hash_whole_file = self.hash_whole_file;
+        let open_files_limit = self.open_files_limit.as_deref();
+        let hash_chunks = || {
+            Self::hash_chunks(
+                source_file,
+                hashing_algorithm,
+                chunking,
+                hash_whole_file,
+                open_files_limit,
+                parallel_chunks,
+            )
+        };
+
+        #[cfg(feature = "parallel")]
+        if let Some(thread_pool) = &self.thread_pool {
+            return thread_pool.install(hash_chunks);
+        }
+
+        hash_chunks()
+    }
+
+    #[cfg_attr(not(feature = "parallel"), allow(unused_variables))]
+    fn hash_chunks(
+        path: &Path,
+        hashing_algorithm: HashingAlgorithm,
+        chunking: Chunking,
+        hash_whole_file: bool,
+        open_files_limit: Option<&OpenFilesLimit>,
+        parallel_chunks: bool,
+    ) -> Result<(Vec<FileChunk>, Option<String>)> {
+        let size = path.metadata()?.len();
+
+        let mut file_hasher = hash_whole_file.then(|| hashing_algorithm.select_hasher());
+
+        let chunks = if size == 0 {
+            let hasher = hashing_algorithm.select_hasher();
+            let hash = hasher.finalize();
+            let hash = base16ct::lower::encode_string(&hash);
+
+            vec![FileChunk::new(0, 0, hash)]
+        } else if let Chunking::ContentDefined {
+            min_size,
+            avg_size,
+            max_size,
+        } = chunking
+        {
+            chunking.validate()?;
+            let _permit = open_files_limit.map(OpenFilesLimit::acquire);
+            let file = BufReader::new(File::open(path)?);
+
+            fastcdc::v2020::StreamCDC::new(file, min_size, avg_size, max_size)
+                .map(|chunk| {
+                    let chunk = chunk.map_err(std::io::Error::from)?;
+                    if let Some(file_hasher) = &mut file_hasher {
+                        file_hasher.update(&chunk.data);
+                    }
+                    let hash = hashing_algorithm.hash_hex(&chunk.data);
+                    Ok(FileChunk::new(chunk.offset, chunk.length as u64, hash))
+                })
+                .collect::<Result<_>>()?
+        } else {
+            // Process file in MiB chunks.
+            let chunk_size = FIXED_CHUNK_SIZE;
+
+            // Open file once and read it in parallel, if enabled.
+            let _permit = open_files_limit.map(OpenFilesLimit::acquire);
+            let file = Arc::new(File::open(path)?);
+            let total_chunks = size.div_ceil(chunk_size);
+
+            let hash_chunk =
+                |chunk_idx| hash_fixed_chunk(&file, size, chunk_idx, hashing_algorithm);
+
+            #[cfg(feature = "parallel")]
+            let batch_size = if parallel_chunks {
+                rayon::current_num_threads() as u64
+            } else {
+                1
+            };
+
+            #[cfg(not(feature = "parallel"))]
+            let batch_size = 1;
+
+            match &mut file_hasher {
+                // The chunk data has to be fed to the file hasher in order, so the chunks are
+                // hashed in batches of one chunk per thread.
+                Some(file_hasher) => {
+                    let mut chunks = Vec::with_capacity(total_chunks as usize);
+                    for batch_start in (0..total_chunks).step_by(batch_size as usize) {
+                        let batch = batch_start..total_chunks.min(batch_start + batch_size);
+
+                        #[cfg(feature = "parallel")]
+                        let batch = batch.into_par_iter().map(hash_chunk);
+
+                        #[cfg(not(feature = "parallel"))]
+                        let batch = batch.map(hash_chunk);
+
+                        for (chunk, data) in batch.collect::<Result<Vec<_>>>()? {
+                            file_hasher.update(&data);
+                            chunks.push(chunk);
+                        }
+                    }
+                    chunks
+                }
+                None => {
+                    let hash_chunk = |chunk_idx| hash_chunk(chunk_idx).map(|(chunk, _)| chunk);
+
+                    #[cfg(feature = "parallel")]
+                    let chunks: Result<_> = if parallel_chunks {
+                        (0..total_chunks).into_par_iter().map(hash_chunk).collect()
+                    } else {
+                        (0..total_chunks).map(hash_chunk).collect()
+                    };
+
+                    #[cfg(not(feature = "parallel"))]
+                    let chunks: Result<_> = (0..total_chunks).map(hash_chunk).collect();
+
+                    chunks?
+                }
+            }
+        };
+
+        let file_hash =
+            file_hasher.map(|file_hasher| base16ct::lower::encode_string(&file_hasher.finalize()));
+
+        Ok((chunks, file_hash))
+    }
+}
+
+/// Reads and hashes the fixed-size chunk with index `chunk_idx` of `file`, which is `size` bytes
+/// long, returning the chunk together with its data.
+fn hash_fixed_chunk(
+    file: &File,
+    size: u64,
+    chunk_idx: u64,
+    hashing_algorithm: HashingAlgorithm,
+) -> Result<(FileChunk, Vec<u8>)> {
+    let offset = chunk_idx * FIXED_CHUNK_SIZE;
+    let len = FIXED_CHUNK_SIZE.min(size.saturating_sub(offset)) as usize;
+
+    let data = read_at_chunk(file, offset, len)?;
+
+    let mut hasher = hashing_algorithm.select_hasher();
+    hasher.update(&data);
+    let hash = hasher.finalize();
+    let hash = base16ct::lower::encode_string(&hash);
+
+    Ok((FileChunk::new(offset, data.len() as u64, hash), data))
+}
+
+/// Lazily calculated chunks of a file, see [`FileWithChunks::stream_chunks`].
+pub struct ChunkStream<'a> {
+    source: ChunkSource,
+    hashing_algorithm: HashingAlgorithm,
+    _permit: Option<OpenFilePermit<'a>>,
+}
+
+enum ChunkSource {
+    /// The single empty chunk of an empty file, until it is taken.
+    Empty(Option<FileChunk>),
+    ContentDefined(fastcdc::v2020::StreamCDC<BufReader<File>>),
+    Fixed {
+        file: File,
+        size: u64,
+        next_chunk: u64,
+        max_in_flight: u64,
+        ready: std::collections::VecDeque<Result<FileChunk>>,
+    },
+}
+
+impl Iterator for ChunkStream<'_> {
+    type Item = Result<FileChunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let hashing_algorithm = self.hashing_algorithm;
+
+        match &mut self.source {
+            ChunkSource::Empty(chunk) => chunk.take().map(Ok),
+            ChunkSource::ContentDefined(chunks) => {
+                let chunk = match chunks.next()? {
+                    Ok(chunk) => chunk,
+                    Err(err) => return Some(Err(std::io::Error::from(err).into())),
+                };
+                let hash = hashing_algorithm.hash_hex(&chunk.data);
+                Some(Ok(FileChunk::new(chunk.offset, chunk.length as u64, hash)))
+            }
+            ChunkSource::Fixed {
+                file,
+                size,
+                next_chunk,
+                max_in_flight,
+                ready,
+            } => {
+                let total_chunks = size.div_ceil(FIXED_CHUNK_SIZE);
+                if ready.is_empty() && *next_chunk < total_chunks {
+                    // Only this batch of chunks is held in memory at once.
+                    let batch = *next_chunk..total_chunks.min(*next_chunk + *max_in_flight);
+                    *next_chunk = batch.end;
+                    let hash_chunk = |chunk_idx| {
+                        hash_fixed_chunk(file, *size, chunk_idx, hashing_algorithm)
+                            .map(|(chunk, _)| chunk)
+                    };
+
+                    #[cfg(feature = "parallel")]
+                    let batch = batch.into_par_iter().map(hash_chunk).collect::<Vec<_>>();
+
+                    #[cfg(not(feature = "parallel"))]
+                    let batch = batch.map(hash_chunk).collect::<Vec<_>>();
+
+                    ready.extend(batch);
+                }
+
+                let chunk = ready.pop_front()?;
+                if chunk.is_err() {
+                    // Stop after the first error.
+                    *next_chunk = total_chunks;
+                    ready.clear();
+                }
+                Some(chunk)
+            }
+        }
+    }
+}
+
+/// A single chunk of a file, including its offset in the original file, size, and hash.
+#[derive(Clone, Debug)]
+pub struct FileChunk {
+    pub start: u64,
+    pub size: u64,
+    pub hash: String,
+    pub path: Option<String>,
+}
+
+impl FileChunk {
+    /// Constructs a new `FileChunk`.
+    pub fn new(start: u64, size: u64, hash: String) -> Self {
+        Self {
+            start,
+            size,
+            hash,
+            path: None,
+        }
+    }
+
+    /// Reads the whole chunk from the chunk store in the deduplicated directory `source_dir`,
+    /// written with `declutter_levels` and the default [`StoreLayout`].
+    ///
+    /// Fails with [`Error::ChunkMissing`] or [`Error::ChunkSizeMismatch`] if the chunk file is
+    /// not present or has the wrong size.
+    pub fn data(&self, source_dir: &Path, declutter_levels: usize) -> Result<Vec<u8>> {
+        read_chunk(&self.store_path(source_dir, declutter_levels), self)
+    }
+
+    /// Opens the chunk for streaming, like [`Self::data`] without reading it into memory.
+    pub fn reader(&self, source_dir: &Path, declutter_levels: usize) -> Result<impl Read> {
+        open_chunk(&self.store_path(source_dir, declutter_levels), self)
+    }
+
+    fn store_path(&self, source_dir: &Path, declutter_levels: usize) -> PathBuf {
+        let layout = StoreLayout::default();
+        layout.chunk_path(
+            &source_dir.join(&layout.data_dir_name),
+            self,
+            declutter_levels,
+        )
+    }
+}
+
+/// In-memory cache of `FileWithChunks` indexed by their relative paths.
+///
+/// Besides the files, the cache can hold tombstones: paths of files that were deleted. When
+/// several caches are layered, a tombstone removes the file from all caches with lower precedence,
+/// so that restoring a chain of snapshots does not resurrect deleted files.
+#[derive(Clone)]
+pub struct DedupCache {
+    files: HashMap<String, FileWithChunks>,
+    deleted: BTreeSet<String>,
+    declutter_segment_length: usize,
+    hash_includes_size: bool,
+    source_root: Option<PathBuf>,
+    /// Paths of the files by modification time, built on the first temporal query and dropped
+    /// whenever the files change, see [`Self::files_mut`].
+    mtime_index: OnceCell<BTreeMap<SystemTime, Vec<String>>>,
+}
+
+impl DedupCache {
+    /// Creates an empty dedup cache.
+    fn new() -> Self {
+        Self {
+            files: HashMap::new(),
+            deleted: BTreeSet::new(),
+            declutter_segment_length: DEFAULT_DECLUTTER_SEGMENT_LENGTH,
+            hash_includes_size: false,
+            source_root: None,
+            mtime_index: OnceCell::new(),
+        }
+    }
+
+    /// Gives mutable access to the files, dropping the modification time index.
+    fn files_mut(&mut self) -> &mut HashMap<String, FileWithChunks> {
+        self.mtime_index.take();
+        &mut self.files
+    }
+
+    /// Removes the files whose paths match `predicate` and returns them.
+    fn take_files(
+        &mut self,
+        mut predicate: impl FnMut(&str) -> bool,
+    ) -> HashMap<String, FileWithChunks> {
+        let (taken, kept) = std::mem::take(self.files_mut())
+            .into_iter()
+            .partition(|(path, _)| predicate(path));
+        self.files = kept;
+        taken
+    }
+
+    /// Reads cache entries from a file. Supports optional zstd compression based on extension.
+    ///
+    /// Entries from the file override existing entries with the same path. The store settings
+    /// like the declutter segment length are taken over from the file.
+    ///
+    /// Missing files are ignored. Files that cannot be read or parsed, or that were written by a
+    /// newer, possibly incompatible version, result in an error. Returns whether the file was
+    /// found.
+    fn read_from_file(&mut self, path: impl AsRef<Path>) -> Result<bool> {
+        let Some(cache_from_file) = cache::read_from_file(path, CacheCompression::None, None)?
+        else {
+            return Ok(false);
+        };
+        self.merge(cache_from_file);
+
+        Ok(true)
+    }
+
+    /// Reads multiple cache files like [`Self::read_from_file`], in parallel if enabled. The files
+    /// are merged in reverse order, so entries from earlier files take precedence. The path `-`
+    /// reads from stdin, compressed with `stdio_compression`.
+    ///
+    /// With a `prefix`, only the entries below it are loaded, see [`HydratorBuilder::path_prefix`].
+    fn read_from_files(
+        &mut self,
+        paths: &[PathBuf],
+        stdio_compression: CacheCompression,
+        prefix: Option<&str>,
+    ) -> Result<()> {
+        let read = |path| cache::read_from_file(path, stdio_compression, prefix);
+
+        #[cfg(feature = "parallel")]
+        let caches = paths.par_iter().map(read).collect::<Vec<_>>();
+
+        #[cfg(not(feature = "parallel"))]
+        let caches = paths.iter().map(read).collect::<Vec<_>>();
+
+        for cache_from_file in caches.into_iter().rev() {
+            if let Some(cache_from_file) = cache_from_file? {
+                self.merge(cache_from_file);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Takes over all entries and store settings from `other`, overriding existing entries with
+    /// the same path. Tombstones in `other` remove the corresponding existing entries, and entries
+    /// in `other` replace existing tombstones.
+    fn merge(&mut self, other: DedupCache) {
+        self.declutter_segment_length = other.declutter_segment_length;
+        self.hash_includes_size = other.hash_includes_size;
+        if other.source_root.is_some() {
+            self.source_root = other.source_root;
+        }
+        for path in &other.deleted {
+            self.files_mut().remove(path);
+        }
+        for path in other.files.keys() {
+            self.deleted.remove(path);
+        }
+        self.files_mut().extend(other.files);
+        self.deleted.extend(other.deleted);
+    }
+
+    /// Writes the cache to a file, optionally compressing with zstd if extension suggests.
+    ///
+    /// Unlike [`Deduper::write_cache`], the file is written directly, not atomically. The path
+    /// `-` writes uncompressed to stdout.
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.write_to_file_with_version(path, CacheVersion::LATEST)
+    }
+
+    /// Writes the cache like [`Self::write_to_file`], but in the format of `version`, so that it
+    /// can be read by older versions of crazy-deduper.
+    ///
+    /// Older formats cannot express everything: version 1 does not record the chunking, deleted
+    /// files and the absolute source directory. Version 0 additionally drops the declutter
+    /// segment length, MIME types, whole-file hashes and ownership.
+    ///
+    /// Fails with [`Error::UnsupportedCacheVersion`] for versions before 2 if the chunk files are
+    /// named after their hash and size, see [`DeduperBuilder::hash_includes_size`], since the
+    /// chunks could not be found with such a cache.
+    pub fn write_to_file_with_version(
+        &self,
+        path: impl AsRef<Path>,
+        version: CacheVersion,
+    ) -> Result<()> {
+        cache::write_to_file(path, self, version, CacheCompression::None)
+    }
+
+    /// Reads a cache from a manifest, a JSON array of files like
+    /// `[{"path": …, "size": …, "mtime": …, "chunks": [{"start": …, "size": …, "hash": …}]}]`,
+    /// with the modification time in seconds since the Unix epoch and an optional `mtime_nanos`.
+    ///
+    /// Unlike the cache formats, manifests are meant to be produced by other tools, for example
+    /// from an inventory of a remote chunk store, to restore files without a cache file. The
+    /// hashing algorithm is derived from the length of the hashes. Since a manifest does not
+    /// record the layout of the chunk store, the default one is assumed, see
+    /// [`HydratorBuilder::store_layout`].
+    ///
+    /// Fails with [`Error::ManifestParse`] if the manifest is malformed, and with
+    /// [`Error::ChunkedSizeMismatch`] if the chunks of a file do not add up to its size.
+    pub fn from_manifest(reader: impl Read) -> Result<Self> {
+        cache::read_manifest(reader)
+    }
+
+    /// Writes the files as a manifest that can be read by [`Self::from_manifest`], sorted by
+    /// path. Tombstones and store settings are not part of a manifest.
+    pub fn write_manifest(&self, writer: impl Write) -> Result<()> {
+        Ok(cache::write_manifest(self, writer)?)
+    }
+
+    /// Iterates over all chunks, yielding the chunk hash, enriched `FileChunk` with path, and a
+    /// flag indicating if it was freshly calculated. The files are visited in the order of
+    /// [`Self::iter_sorted`].
+    ///
+    /// If the chunks of a file cannot be calculated, for example with
+    /// [`Error::ChunkedSizeMismatch`], the error is yielded in place of its chunks.
+    pub fn get_chunks(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<(String, FileChunk, bool)>> + '_> {
+        Ok(self.iter_sorted().flat_map(|fwc| {
+            let (chunks, err) = match Self::enriched_chunks(fwc) {
+                Ok(chunks) => (Some(chunks.map(Ok)), None),
+                Err(err) => (None, Some(Err(err))),
+            };
+            chunks.into_iter().flatten().chain(err)
+        }))
+    }
+
+    /// Iterates over the chunks of the single file `path`, like [`Self::get_chunks`]. Fails with
+    /// [`Error::FileNotFound`] if the file is not in the cache, and with the error of calculating
+    /// its chunks otherwise.
+    pub fn get_chunks_for_file(
+        &self,
+        path: &str,
+    ) -> Result<impl Iterator<Item = (String, FileChunk, bool)> + '_> {
+        let fwc = self.get(path).ok_or_else(|| Error::FileNotFound {
+            path: path.to_string(),
+        })?;
+
+        Self::enriched_chunks(fwc)
+    }
+
+    fn enriched_chunks(
+        fwc: &FileWithChunks,
+    ) -> Result<impl Iterator<Item = (String, FileChunk, bool)> + '_> {
+        let mut dirty = fwc.get_chunks().is_none();
+
+        Ok(fwc.get_or_calculate_chunks()?.iter().map(move |chunk| {
+            let result = (
+                chunk.hash.clone(),
+                FileChunk {
+                    path: Some(fwc.path.clone()),
+                    ..chunk.clone()
+                },
+                dirty,
+            );
+
+            dirty = false;
+
+            result
+        }))
+    }
+
+    pub fn get(&self, path: &str) -> Option<&FileWithChunks> {
+        self.files.get(path)
+    }
+
+    pub fn get_mut(&mut self, path: &str) -> Option<&mut FileWithChunks> {
+        self.files_mut().get_mut(path)
+    }
+
+    fn insert(&mut self, path: String, fwc: FileWithChunks) {
+        self.deleted.remove(&path);
+        self.files_mut().insert(path, fwc);
+    }
+
+    /// Converts all paths to the Unicode normalization form `form`. If several paths are equal
+    /// after the conversion, only one of the entries is kept.
+    fn normalize_paths(&mut self, form: NormalizationForm) {
+        *self.files_mut() = std::mem::take(&mut self.files)
+            .into_values()
+            .map(|mut fwc| {
+                fwc.path = form.normalize(&fwc.path);
+                (fwc.path.clone(), fwc)
+            })
+            .collect();
+        self.deleted = std::mem::take(&mut self.deleted)
+            .iter()
+            .map(|path| form.normalize(path))
+            .collect();
+    }
+
+    /// Removes the entry for `path`, if any, and records a tombstone for it instead.
+    pub fn mark_deleted(&mut self, path: impl Into<String>) {
+        let path = path.into();
+        self.files_mut().remove(&path);
+        self.deleted.insert(path);
+    }
+
+    /// Returns whether `path` is recorded as deleted.
+    pub fn is_deleted(&self, path: &str) -> bool {
+        self.deleted.contains(path)
+    }
+
+    /// Returns the paths of all tombstones, sorted.
+    pub fn deleted_paths(&self) -> impl Iterator<Item = &str> {
+        self.deleted.iter().map(String::as_str)
+    }
+
+    pub fn contains_key(&self, path: &str) -> bool {
+        self.files.contains_key(path)
+    }
+
+    /// Returns whether a file with the given path, relative to the source directory, is tracked.
+    ///
+    /// ```rust no_run
+    /// let deduper = crazy_deduper::DeduperBuilder::new("source").build();
+    /// assert!(deduper.cache().contains_path("README.md"));
+    /// ```
+    pub fn contains_path(&self, path: &str) -> bool {
+        self.contains_key(path)
+    }
+
+    /// Returns the paths of all tracked files, relative to the source directory, in arbitrary
+    /// order.
+    ///
+    /// ```rust no_run
+    /// let deduper = crazy_deduper::DeduperBuilder::new("source").build();
+    /// for path in deduper.cache().paths() {
+    ///     println!("{path}");
+    /// }
+    /// ```
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(String::as_str)
+    }
+
+    /// Returns all tracked files whose whole-file hash is `file_hash`, to check if a file with
+    /// exactly this content is already stored. Only files with a recorded whole-file hash are
+    /// considered, see [`DeduperBuilder::file_hashes`].
+    pub fn find_by_file_hash<'a>(
+        &'a self,
+        file_hash: &'a str,
+    ) -> impl Iterator<Item = &'a FileWithChunks> {
+        self.values()
+            .filter(move |fwc| fwc.file_hash() == Some(file_hash))
+    }
+
+    /// Removes all entries whose files no longer exist as regular files below `base`. The entries
+    /// are removed in place, without building a new map.
+    pub fn prune_missing(&mut self, base: &Path) {
+        self.files_mut()
+            .retain(|path, _| is_source_file(&base.join(path)));
+    }
+
+    /// Returns the sorted paths of all entries whose files no longer exist as regular files below
+    /// `base`, without removing them from the cache.
+    pub fn find_missing_files(&self, base: &Path) -> Vec<String> {
+        let mut missing = self
+            .files
+            .keys()
+            .filter(|path| !is_source_file(&base.join(path)))
+            .cloned()
+            .collect::<Vec<_>>();
+        missing.sort();
+        missing
+    }
+
+    /// Checks that the chunks of each file exactly cover the file from offset `0` to its size,
+    /// without gaps or overlaps. Only the cache is inspected, the chunk files are not read.
+    /// Files whose chunks are not computed are skipped.
+    ///
+    /// Returns all problems found, sorted by path and offset.
+    pub fn validate_coverage(&self) -> Vec<CoverageError> {
+        let mut errors = Vec::new();
+
+        for fwc in self.files.values() {
+            let Some(chunks) = fwc.get_chunks() else {
+                continue;
+            };
+            let path = &fwc.path;
+
+            let mut chunks = chunks.iter().collect::<Vec<_>>();
+            chunks.sort_by_key(|chunk| chunk.start);
+
+            let mut end = 0;
+            for chunk in chunks {
+                if chunk.start > end {
+                    errors.push(CoverageError::Gap {
+                        path: path.clone(),
+                        start: end,
+                        end: chunk.start,
+                    });
+                } else if chunk.start < end && chunk.size > 0 {
+                    errors.push(CoverageError::Overlap {
+                        path: path.clone(),
+                        start: chunk.start,
+                        end: end.min(chunk.start + chunk.size),
+                    });
+                }
+                end = end.max(chunk.start + chunk.size);
+            }
+
+            if end < fwc.size {
+                errors.push(CoverageError::Gap {
+                    path: path.clone(),
+                    start: end,
+                    end: fwc.size,
+                });
+            } else if end > fwc.size {
+                errors.push(CoverageError::BeyondEnd {
+                    path: path.clone(),
+                    size: fwc.size,
+                    end,
+                });
+            }
+        }
+
+        errors.sort_by(|a, b| (a.path(), a.start()).cmp(&(b.path(), b.start())));
+        errors
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &FileWithChunks> {
+        self.files.values()
+    }
+
+    /// Iterates over all files sorted by path, unlike [`Self::values`], so that the order is the
+    /// same for every run.
+    pub fn iter_sorted(&self) -> impl Iterator<Item = &FileWithChunks> {
+        let mut files = self.values().collect::<Vec<_>>();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files.into_iter()
+    }
+
+    /// Returns the files whose modification time lies in `[start, end)`, sorted by modification
+    /// time and path, for example to find the files that changed since the last backup.
+    ///
+    /// The files are looked up in an index by modification time, which is built by the first call
+    /// and kept until the files change.
+    pub fn files_modified_between(
+        &self,
+        start: SystemTime,
+        end: SystemTime,
+    ) -> Vec<&FileWithChunks> {
+        if start >= end {
+            return Vec::new();
+        }
+
+        let index = self.mtime_index.get_or_init(|| {
+            let mut index = BTreeMap::<_, Vec<_>>::new();
+            for fwc in self.files.values() {
+                index.entry(fwc.mtime).or_default().push(fwc.path.clone());
+            }
+            for paths in index.values_mut() {
+                paths.sort();
+            }
+            index
+        });
+
+        index
+            .range(start..end)
+            .flat_map(|(_, paths)| paths)
+            // Cannot panic, the index is dropped whenever the files change.
+            .map(|path| &self.files[path])
+            .collect()
+    }
+
+    /// Iterates over the files that were last modified more than `age` ago, see
+    /// [`FileWithChunks::age`]. Files with a modification time in the future are skipped.
+    pub fn older_than(&self, age: Duration) -> impl Iterator<Item = &FileWithChunks> {
+        self.values()
+            .filter(move |fwc| fwc.age().is_ok_and(|file_age| file_age > age))
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Returns whether no file in the cache has chunks, so that it only tracks the files with
+    /// their metadata, like caches written with [`DeduperBuilder::metadata_only`]. Such a cache
+    /// cannot be used to restore files.
+    pub fn is_metadata_only(&self) -> bool {
+        self.files.values().all(|fwc| fwc.get_chunks().is_none())
+    }
+
+    /// Returns the number of chunks of all files, or `None` if the chunks of any file are not
+    /// computed yet. Chunks occurring in several files are counted each time.
+    pub fn total_chunk_count(&self) -> Option<usize> {
+        self.files
+            .values()
+            .map(|fwc| fwc.get_chunks().map(Vec::len))
+            .sum()
+    }
+
+    /// Returns the sum of the sizes of all files. Unlike [`Self::total_chunk_count`], this does
+    /// not depend on computed chunks.
+    pub fn total_file_size(&self) -> u64 {
+        self.files.values().map(|fwc| fwc.size).sum()
+    }
+
+    /// Detects files that were renamed, by matching entries of `removed` (files that vanished from
+    /// the source) against entries in this cache whose chunks are not computed yet (new or
+    /// modified files). Returns pairs of `(old_path, new_path)`.
+    ///
+    /// Entries are matched by size and modification time, the same criteria that are used to
+    /// detect unchanged files. Only if several removed entries match, the chunks of the new file
+    /// are calculated and compared to find the right one.
+    pub fn detect_renames(&self, removed: &DedupCache) -> Vec<(String, String)> {
+        let mut candidates_by_metadata = HashMap::<_, Vec<_>>::new();
+        for fwc in removed.values().filter(|fwc| fwc.get_chunks().is_some()) {
+            candidates_by_metadata
+                .entry((fwc.size, fwc.mtime, fwc.hashing_algorithm, fwc.chunking))
+                .or_default()
+                .push(fwc);
+        }
+        for candidates in candidates_by_metadata.values_mut() {
+            candidates.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+
+        let mut new_files = self
+            .values()
+            .filter(|fwc| fwc.get_chunks().is_none())
+            .collect::<Vec<_>>();
+        new_files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut renames = Vec::new();
+        for fwc in new_files {
+            let key = (fwc.size, fwc.mtime, fwc.hashing_algorithm, fwc.chunking);
+            let Some(candidates) = candidates_by_metadata.get_mut(&key) else {
+                continue;
+            };
+
+            let position = if candidates.len() == 1 {
+                Some(0)
+            } else {
+                let Ok(chunks) = fwc.get_or_calculate_chunks() else {
+                    continue;
+                };
+                candidates.iter().position(|candidate| {
+                    let candidate_chunks = candidate.get_chunks().unwrap();
+                    candidate_chunks.len() == chunks.len()
+                        && candidate_chunks
+                            .iter()
+                            .zip(chunks)
+                            .all(|(a, b)| a.hash == b.hash)
+                })
+            };
+
+            if let Some(position) = position {
+                let old = candidates.remove(position);
+                renames.push((old.path.clone(), fwc.path.clone()));
+                if candidates.is_empty() {
+                    candidates_by_metadata.remove(&key);
+                }
+            }
+        }
+
+        renames
+    }
+
+    /// Number of hash characters each declutter level of the chunk store consumes.
+    pub fn declutter_segment_length(&self) -> usize {
+        self.declutter_segment_length
+    }
+
+    /// Absolute source directory the paths are relative to, if it was recorded during
+    /// deduplication.
+    pub fn source_root(&self) -> Option<&Path> {
+        self.source_root.as_deref()
+    }
+
+    /// Whether the names of chunk files consist of the hash and the size of the chunk, see
+    /// [`DeduperBuilder::hash_includes_size`].
+    pub fn hash_includes_size(&self) -> bool {
+        self.hash_includes_size
+    }
+
+    /// Returns the store layout recorded in this cache, with the default data directory name.
+    pub fn store_layout(&self) -> StoreLayout {
+        StoreLayout {
+            declutter_segment_length: self.declutter_segment_length,
+            hash_includes_size: self.hash_includes_size,
+            ..Default::default()
+        }
+    }
+}
+
+/// How the hashing of files is spread over the threads, see
+/// [`DeduperBuilder::hashing_parallelism`].
+#[cfg(feature = "parallel")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum HashingParallelism {
+    /// Files are hashed one after the other, the chunks of each file in parallel. Suited for few
+    /// large files.
+    #[default]
+    PerChunk,
+    /// Files are hashed in parallel, the chunks of each file one after the other. Suited for many
+    /// files smaller than a chunk, where there is nothing to parallelize within a file.
+    ///
+    /// All files are hashed before the first chunk is written, so each file is read twice, like
+    /// with [`Deduper::write_chunks_two_pass`].
+    PerFile,
+}
+
+/// A problem found by [`DedupCache::validate_coverage`]. Offsets are byte offsets in the file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CoverageError {
+    /// No chunk covers the range from `start` to `end`.
+    Gap { path: String, start: u64, end: u64 },
+    /// The range from `start` to `end` is covered by more than one chunk.
+    Overlap { path: String, start: u64, end: u64 },
+    /// The chunks extend up to `end`, beyond the file size `size`.
+    BeyondEnd { path: String, size: u64, end: u64 },
+}
+
+impl CoverageError {
+    /// Path of the affected file.
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Gap { path, .. } | Self::Overlap { path, .. } | Self::BeyondEnd { path, .. } => {
+                path
+            }
+        }
+    }
+
+    /// Offset at which the problem starts.
+    fn start(&self) -> u64 {
+        match self {
+            Self::Gap { start, .. } | Self::Overlap { start, .. } => *start,
+            Self::BeyondEnd { size, .. } => *size,
+        }
+    }
+}
+
+/// Builder for a [`DedupCache`] from known file records, without scanning a directory.
+///
+/// ```rust no_run
+/// use std::time::SystemTime;
+///
+/// use crazy_deduper::{DedupCacheBuilder, FileChunk, FileWithChunks, HashingAlgorithm};
+///
+/// let hash = HashingAlgorithm::SHA1.hash_hex(b"Hello, world!");
+/// let cache = DedupCacheBuilder::new()
+///     .file(FileWithChunks::from_chunks(
+///         "README.md",
+///         13,
+///         SystemTime::now(),
+///         HashingAlgorithm::SHA1,
+///         vec![FileChunk::new(0, 13, hash)],
+///     ))
+///     .build();
+/// cache.write_to_file("cache.json.zst").unwrap();
+/// ```
+#[derive(Default)]
+pub struct DedupCacheBuilder {
+    files: Vec<FileWithChunks>,
+    declutter_segment_length: Option<usize>,
+}
+
+impl DedupCacheBuilder {
+    /// Creates a builder for an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file record. Records with the same path replace earlier ones.
+    pub fn file(mut self, fwc: FileWithChunks) -> Self {
+        self.files.push(fwc);
+        self
+    }
+
+    /// Adds several file records, see [`Self::file`].
+    pub fn files(mut self, files: impl IntoIterator<Item = FileWithChunks>) -> Self {
+        self.files.extend(files);
+        self
+    }
+
+    /// Adds file records produced by a parallel iterator, for example records constructed from
+    /// many sources at once. The records keep the order of the iterator, so later records still
+    /// replace earlier ones with the same path, see [`Self::file`].
+    #[cfg(feature = "parallel")]
+    pub fn par_files(mut self, files: impl IntoParallelIterator<Item = FileWithChunks>) -> Self {
+        self.files.par_extend(files);
+        self
+    }
+
+    /// Sets the number of hash characters per declutter level of the chunk store the records
+    /// refer to.
+    pub fn declutter_segment_length(mut self, declutter_segment_length: usize) -> Self {
+        self.declutter_segment_length = Some(declutter_segment_length.max(1));
+        self
+    }
+
+    /// Creates the cache.
+    pub fn build(self) -> DedupCache {
+        let mut cache = self
+            .files
+            .into_iter()
+            .map(|fwc| (fwc.path.clone(), fwc))
+            .collect::<DedupCache>();
+        if let Some(declutter_segment_length) = self.declutter_segment_length {
+            cache.declutter_segment_length = declutter_segment_length;
+        }
+
+        cache
+    }
+}
+
+impl IntoIterator for DedupCache {
+    type Item = (String, FileWithChunks);
+    type IntoIter = hash_map::IntoIter<String, FileWithChunks>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.files.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a DedupCache {
+    type Item = (&'a String, &'a FileWithChunks);
+    type IntoIter = hash_map::Iter<'a, String, FileWithChunks>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.files.iter()
+    }
+}
+
+/// Collects entries into a cache with the default declutter segment length. Later entries replace
+/// earlier ones with the same path.
+impl FromIterator<(String, FileWithChunks)> for DedupCache {
+    fn from_iter<T: IntoIterator<Item = (String, FileWithChunks)>>(iter: T) -> Self {
+        Self {
+            files: iter.into_iter().collect(),
+            ..Self::new()
+        }
+    }
+}
+
+/// Collects entries from a parallel iterator into a cache with the default declutter segment
+/// length. Like for [`FromIterator`], later entries in the order of the iterator replace earlier
+/// ones with the same path.
+#[cfg(feature = "parallel")]
+impl FromParallelIterator<(String, FileWithChunks)> for DedupCache {
+    fn from_par_iter<I: IntoParallelIterator<Item = (String, FileWithChunks)>>(iter: I) -> Self {
+        iter.into_par_iter()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Builder for a [`Deduper`] with more configuration options than [`Deduper::new`].
+///
+/// ```rust no_run
+/// let deduper = crazy_deduper::DeduperBuilder::new("source")
+///     .cache_paths(vec!["cache.json.zst"])
+///     .hashing_algorithm(crazy_deduper::HashingAlgorithm::SHA256)
+///     .declutter_segment_length(2)
+///     .build();
+/// ```
+pub struct DeduperBuilder {
+    source_path: PathBuf,
+    additional_source_paths: Vec<PathBuf>,
+    cache_paths: Vec<PathBuf>,
+    cache_write_path: Option<PathBuf>,
+    hashing_algorithm: HashingAlgorithm,
+    chunking: Chunking,
+    same_file_system: bool,
+    declutter_segment_length: usize,
+    max_open_files: Option<usize>,
+    use_content_equality: bool,
+    known_hashes: HashSet<String>,
+    #[cfg(feature = "parallel")]
+    hashing_parallelism: HashingParallelism,
+    baseline_cache_paths: Vec<PathBuf>,
+    temp_dir: Option<PathBuf>,
+    chunk_mode: Option<u32>,
+    retries: u32,
+    min_savings_bytes: Option<u64>,
+    min_file_size: Option<u64>,
+    max_file_size: Option<u64>,
+    canonicalize_source: bool,
+    absolute_paths: bool,
+    detect_mime: bool,
+    file_hashes: bool,
+    source_encoding: Option<&'static Encoding>,
+    normalize_paths: NormalizationForm,
+    chunk_exists: Option<ChunkExistsFn>,
+    on_chunk: Option<ChunkEventFn>,
+    cancel_flag: Option<Arc<AtomicBool>>,
+    #[cfg(feature = "parallel")]
+    thread_pool: Option<Arc<rayon::ThreadPool>>,
+    chunk_base_path: Option<PathBuf>,
+    read_only_cache: bool,
+    metadata_only: bool,
+    assume_unchanged: Vec<String>,
+    cache_format_version: CacheVersion,
+    stdio_cache_compression: CacheCompression,
+    hash_includes_size: bool,
+    data_dir_name: String,
+}
+
+/// Callback deciding whether the chunk with the given hash already exists in the chunk store, see
+/// [`DeduperBuilder::chunk_exists`].
+type ChunkExistsFn = Box<dyn FnMut(&str) -> bool + Send>;
+
+/// Callback receiving each chunk processed by [`Deduper::write_chunks`], see
+/// [`DeduperBuilder::on_chunk`].
+type ChunkEventFn = Box<dyn FnMut(&ChunkEvent) + Send>;
+
+/// A chunk processed while writing the chunk store, see [`DeduperBuilder::on_chunk`].
+#[derive(Clone, Debug, Serialize)]
+pub struct ChunkEvent<'a> {
+    /// Path of the file the chunk belongs to, relative to the source root.
+    pub path: &'a str,
+    /// Hash of the chunk.
+    pub hash: &'a str,
+    /// Offset of the chunk in the file.
+    pub start: u64,
+    /// Size of the chunk in bytes.
+    pub size: u64,
+    /// Whether no data was written for the chunk, because it was already stored or is empty.
+    pub deduped: bool,
+}
+
+impl DeduperBuilder {
+    /// Creates a builder for a deduper working on `source_path`, with default settings.
+    pub fn new(source_path: impl Into<PathBuf>) -> Self {
+        Self {
+            source_path: source_path.into(),
+            additional_source_paths: Vec::new(),
+            cache_paths: Vec::new(),
+            cache_write_path: None,
+            hashing_algorithm: HashingAlgorithm::default(),
+            chunking: Chunking::default(),
+            same_file_system: false,
+            declutter_segment_length: DEFAULT_DECLUTTER_SEGMENT_LENGTH,
+            max_open_files: None,
+            use_content_equality: false,
+            known_hashes: HashSet::new(),
+            #[cfg(feature = "parallel")]
+            hashing_parallelism: HashingParallelism::default(),
+            baseline_cache_paths: Vec::new(),
+            temp_dir: None,
+            chunk_mode: None,
+            retries: 0,
+            min_savings_bytes: None,
+            min_file_size: None,
+            max_file_size: None,
+            canonicalize_source: false,
+            absolute_paths: false,
+            detect_mime: false,
+            file_hashes: false,
+            source_encoding: None,
+            normalize_paths: NormalizationForm::None,
+            chunk_exists: None,
+            on_chunk: None,
+            cancel_flag: None,
+            #[cfg(feature = "parallel")]
+            thread_pool: None,
+            chunk_base_path: None,
+            read_only_cache: false,
+            metadata_only: false,
+            assume_unchanged: Vec::new(),
+            cache_format_version: CacheVersion::LATEST,
+            stdio_cache_compression: CacheCompression::None,
+            hash_includes_size: false,
+            data_dir_name: StoreLayout::default().data_dir_name,
+        }
+    }
+
+    /// Adds another source directory, whose files share the chunk store with the other source
+    /// directories. Can be called multiple times.
+    ///
+    /// With multiple source directories, the paths in the cache are prefixed with the index of
+    /// their source directory, starting with `0` for the one given to [`Self::new`]. Use
+    /// [`Hydrator::restore_all`] to restore each source directory into its own target. Cannot be
+    /// combined with [`Self::absolute_paths`].
+    pub fn add_source(mut self, source_path: impl Into<PathBuf>) -> Self {
+        self.additional_source_paths.push(source_path.into());
+        self
+    }
+
+    /// Sets the cache files to read. The first one will be written by [`Deduper::write_cache`],
+    /// unless [`Self::cache_write_path`] is set.
+    ///
+    /// Without cache files, which is the default, the deduper runs statelessly: all files are
+    /// hashed and no cache is written. Empty paths are ignored.
+    pub fn cache_paths(mut self, cache_paths: Vec<impl Into<PathBuf>>) -> Self {
+        self.cache_paths = cache_paths.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Writes the cache to `cache_write_path` instead of the first of the
+    /// [`cache files`](Self::cache_paths), which are then only read. This allows to use a shared
+    /// cache, like on a read-only network share, and keep the updates in a local cache.
+    ///
+    /// The written cache contains all entries, including those read from the other cache files.
+    /// It is not read itself, add it to the cache files to re-use it in later runs.
+    pub fn cache_write_path(mut self, cache_write_path: impl Into<PathBuf>) -> Self {
+        self.cache_write_path = Some(cache_write_path.into());
+        self
+    }
+
+    /// Sets the hashing algorithm used for new chunks.
+    pub fn hashing_algorithm(mut self, hashing_algorithm: HashingAlgorithm) -> Self {
+        self.hashing_algorithm = hashing_algorithm;
+        self
+    }
+
+    /// Sets how new files are split into chunks.
+    ///
+    /// The setting is stored in the cache. Files whose chunks are already cached keep them.
+    /// [`Self::try_build`] fails with [`Error::InvalidChunkSizes`] if the sizes of
+    /// content-defined chunking are not accepted by [`Chunking::validate`].
+    pub fn chunking(mut self, chunking: Chunking) -> Self {
+        self.chunking = chunking;
+        self
+    }
+
+    /// Limits the file listing to the file system of the source directory.
+    pub fn same_file_system(mut self, same_file_system: bool) -> Self {
+        self.same_file_system = same_file_system;
+        self
+    }
+
+    /// Sets how many hash characters each declutter level consumes when writing chunks.
+    ///
+    /// The value is stored in the cache, so that hydration uses the same layout. Values below 1
+    /// are treated as 1.
+    pub fn declutter_segment_length(mut self, declutter_segment_length: usize) -> Self {
+        self.declutter_segment_length = declutter_segment_length.max(1);
+        self
+    }
+
+    /// Limits how many source files may be open at the same time while hashing, to prevent
+    /// "too many open files" errors. By default, there is no limit.
+    pub fn max_open_files(mut self, max_open_files: usize) -> Self {
+        self.max_open_files = Some(max_open_files);
+        self
+    }
+
+    /// If enabled, files whose size or modification time changed are hashed and compared with
+    /// the cached chunks (see [`FileWithChunks::content_equal`]). If the content is unchanged,
+    /// the cache entry is re-used and the file is not treated as modified.
+    pub fn use_content_equality(mut self, use_content_equality: bool) -> Self {
+        self.use_content_equality = use_content_equality;
+        self
+    }
+
+    /// Treats the given chunk hashes as already present, so that [`Deduper::write_chunks`] does
+    /// not write them, regardless of the content of the chunk store.
+    pub fn known_hashes(mut self, known_hashes: impl IntoIterator<Item = String>) -> Self {
+        self.known_hashes.extend(known_hashes);
+        self
+    }
+
+    /// Treats all chunks referenced by the baseline cache at `path` as already present, so that
+    /// only chunks that are new relative to the baseline are written. This way, the chunk store
+    /// only receives an incremental delta to the baseline.
+    ///
+    /// Files of the baseline that no longer exist in the source directory are recorded as
+    /// tombstones in the written cache. Restoring the chain of this cache and the baseline then
+    /// does not resurrect them.
+    ///
+    /// Building the deduper fails with [`std::io::ErrorKind::NotFound`] if the baseline cache
+    /// does not exist.
+    pub fn since_cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.baseline_cache_paths.push(path.into());
+        self
+    }
+
+    /// Sets whether the chunks of each file or the files themselves are hashed in parallel when
+    /// writing chunks. The default [`HashingParallelism::PerChunk`] only pays off for files
+    /// spanning several chunks, while [`HashingParallelism::PerFile`] can keep all threads busy
+    /// with many small files. Which one is faster depends on the files and the machine, run
+    /// `cargo bench --bench hashing_parallelism` to compare both.
+    #[cfg(feature = "parallel")]
+    pub fn hashing_parallelism(mut self, hashing_parallelism: HashingParallelism) -> Self {
+        self.hashing_parallelism = hashing_parallelism;
+        self
+    }
+
+    /// Sets the directory for temporary files created by [`Deduper::write_cache`]. By default,
+    /// they are created next to the cache file.
+    ///
+    /// If the directory is on a different device than the cache file, the temporary file cannot
+    /// be renamed. It is then copied to the final path and removed, so the write is no longer
+    /// atomic.
+    pub fn temp_dir(mut self, temp_dir: impl Into<PathBuf>) -> Self {
+        self.temp_dir = Some(temp_dir.into());
+        self
+    }
+
+    /// Sets the permission bits of the chunk files created by [`Deduper::write_chunks`], like
+    /// `0o640`, instead of the defaults derived from the umask. This keeps the chunk data of a
+    /// store shared by multiple users private.
+    ///
+    /// Created chunk directories get the same mode, with the execute bit added wherever the read
+    /// bit is set, so that they can be traversed. Only supported on Unix, ignored elsewhere.
+    pub fn chunk_mode(mut self, mode: u32) -> Self {
+        self.chunk_mode = Some(mode);
+        self
+    }
+
+    /// Retries copying a chunk and moving the written cache into place up to `retries` times if
+    /// they fail with a transient IO error, waiting 100 ms before the first retry and twice as
+    /// long before each further one. This keeps runs on flaky network mounts from aborting.
+    /// Errors like missing files are never retried. The default of 0 disables retries.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Writes the chunk files directly into `chunk_base_path` instead of the `data` subdirectory
+    /// of the target directory given to [`Deduper::write_chunks`]. This allows to keep the chunk
+    /// store on another file system than the cache, like chunks on a large disk and the cache on a
+    /// fast SSD. Hydrate with the same path in [`HydratorBuilder::chunk_base_path`].
+    pub fn chunk_base_path(mut self, chunk_base_path: impl Into<PathBuf>) -> Self {
+        self.chunk_base_path = Some(chunk_base_path.into());
+        self
+    }
+
+    /// If enabled, the cache files are only read and used for deduplication, but
+    /// [`Deduper::write_cache`] does not write anything. Useful for caches on read-only file
+    /// systems, or for auditing runs that must not change them.
+    pub fn read_only_cache(mut self, read_only_cache: bool) -> Self {
+        self.read_only_cache = read_only_cache;
+        self
+    }
+
+    /// If enabled, the cache only tracks which files exist, with their sizes and modification
+    /// times, but no chunks are calculated. [`Deduper::write_chunks`] and its variants do not
+    /// write any chunks then, while [`Deduper::write_cache`] still writes the cache. This is much
+    /// faster for creating a manifest of a large directory tree, for example to compare it later.
+    ///
+    /// Chunks of unchanged files that were already in the cache are kept, see
+    /// [`DedupCache::is_metadata_only`].
+    pub fn metadata_only(mut self, metadata_only: bool) -> Self {
+        self.metadata_only = metadata_only;
+        self
+    }
+
+    /// Treats files whose paths match one of the glob `patterns` as unchanged if they are in the
+    /// cache, even if their size or modification time differ. Their cached chunks are used as they
+    /// are, without re-hashing the files. The patterns match the paths relative to the source
+    /// directory, where `*` does not cross directory boundaries, but `**` does.
+    ///
+    /// This is an escape hatch for files whose modification time is touched without changing
+    /// their content. Ensuring that the content really did not change is the responsibility of
+    /// the caller: otherwise, the cache and the written chunks silently do not match the files.
+    /// Invalid patterns make [`Self::try_build`] fail with [`Error::InvalidPathPattern`].
+    pub fn assume_unchanged(
+        mut self,
+        patterns: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.assume_unchanged = patterns.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the format in which [`Deduper::write_cache`] writes the cache, to produce caches for
+    /// older versions of crazy-deduper. Defaults to [`CacheVersion::LATEST`]. See
+    /// [`DedupCache::write_to_file_with_version`] for what older formats cannot express.
+    pub fn cache_format_version(mut self, cache_format_version: CacheVersion) -> Self {
+        self.cache_format_version = cache_format_version;
+        self
+    }
+
+    /// Sets the compression of caches read from stdin or written to stdout, which are given as
+    /// the path `-`. Other cache files are compressed according to their extension.
+    ///
+    /// The cache is only written to stdout if `-` is set with [`Self::cache_write_path`], a cache
+    /// read from stdin is never written back.
+    pub fn stdio_cache_compression(mut self, stdio_cache_compression: CacheCompression) -> Self {
+        self.stdio_cache_compression = stdio_cache_compression;
+        self
+    }
+
+    /// Names chunk files after their hash and size, like `{hash}-{size}`, instead of the hash
+    /// alone. Chunks of different sizes then never share a file, even if their hashes collide,
+    /// at the cost of slightly longer file names. Chunks with the same content are still stored
+    /// once.
+    ///
+    /// The setting is stored in the cache and used from there when hydrating. Callbacks like
+    /// [`DeduperBuilder::chunk_exists`] still only receive the hash.
+    pub fn hash_includes_size(mut self, hash_includes_size: bool) -> Self {
+        self.hash_includes_size = hash_includes_size;
+        self
+    }
+
+    /// Sets the whole layout of the chunk store at once, replacing
+    /// [`Self::declutter_segment_length`] and [`Self::hash_includes_size`].
+    ///
+    /// The name of the data directory is not recorded in the cache, hydrate with the same layout
+    /// in [`HydratorBuilder::store_layout`] if it differs from the default.
+    pub fn store_layout(mut self, store_layout: StoreLayout) -> Self {
+        let StoreLayout {
+            data_dir_name,
+            declutter_segment_length,
+            hash_includes_size,
+        } = store_layout;
+        self.data_dir_name = data_dir_name;
+        self.hash_includes_size = hash_includes_size;
+        self.declutter_segment_length(declutter_segment_length)
+    }
+
+    /// Stores files whose chunks save less than `min_savings_bytes` through deduplication as a
+    /// single chunk, instead of splitting them up.
+    ///
+    /// The savings of a file are the sizes of all its chunks that also occur elsewhere in the
+    /// file, in other files, or in the [known hashes](Self::known_hashes). This avoids lots of
+    /// useless chunk files for data that does not deduplicate at all, like encrypted or compressed
+    /// files. Determining the savings requires hashing all files before writing any chunks.
+    pub fn min_savings_bytes(mut self, min_savings_bytes: u64) -> Self {
+        self.min_savings_bytes = Some(min_savings_bytes);
+        self
+    }
+
+    /// Only considers files with at least `min_file_size` bytes, for example to ignore small files
+    /// that do not deduplicate meaningfully.
+    ///
+    /// Files outside the size range are skipped by the scan. Cached files that are now outside
+    /// the range are pruned from the cache like removed files, and are hashed again once they are
+    /// back in range.
+    pub fn min_file_size(mut self, min_file_size: u64) -> Self {
+        self.min_file_size = Some(min_file_size);
+        self
+    }
+
+    /// Only considers files with at most `max_file_size` bytes, see [`Self::min_file_size`].
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = Some(max_file_size);
+        self
+    }
+
+    /// Resolves the source directory to an absolute path without symlinks before scanning.
+    ///
+    /// This fails early if the source directory does not exist, and makes sure that the source
+    /// path is stored in the same form, no matter if it was given relative, with a trailing slash,
+    /// or via a symlink.
+    pub fn canonicalize_source(mut self, canonicalize_source: bool) -> Self {
+        self.canonicalize_source = canonicalize_source;
+        self
+    }
+
+    /// Records the absolute source directory in the cache, so that the files can be restored to
+    /// their original locations, see [`HydratorBuilder::absolute_paths`]. Implies
+    /// [`Self::canonicalize_source`].
+    pub fn absolute_paths(mut self, absolute_paths: bool) -> Self {
+        self.absolute_paths = absolute_paths;
+        self
+    }
+
+    /// Detects the MIME type of each file from its first bytes and stores it in the cache, so that
+    /// files can be restored selectively with [`Hydrator::restore_by_mime`].
+    ///
+    /// Files whose type cannot be detected, like plain text files, have no MIME type and are
+    /// checked again on every run.
+    pub fn detect_mime(mut self, detect_mime: bool) -> Self {
+        self.detect_mime = detect_mime;
+        self
+    }
+
+    /// Records the hash of the whole content of each file in the cache, in addition to the chunk
+    /// hashes. It is calculated from the same reads as the chunks, see
+    /// [`FileWithChunks::file_hash`].
+    ///
+    /// Files whose chunks are taken from an existing cache without a whole-file hash are re-hashed
+    /// once.
+    pub fn file_hashes(mut self, file_hashes: bool) -> Self {
+        self.file_hashes = file_hashes;
+        self
+    }
+
+    /// Decodes file names in the source directories from `encoding`, like
+    /// [`encoding_rs::WINDOWS_1252`], instead of UTF-8. The decoded names are stored as UTF-8 in
+    /// the cache, so they are restored with UTF-8 names.
+    ///
+    /// Without it, invalid UTF-8 in file names is replaced by the replacement character. Only has
+    /// an effect on Unix, where file names are arbitrary bytes.
+    pub fn source_encoding(mut self, encoding: &'static Encoding) -> Self {
+        self.source_encoding = Some(encoding);
+        self
+    }
+
+    /// Normalizes the paths of source files, and the paths in the loaded caches, to the Unicode
+    /// normalization form `form`. Defaults to [`NormalizationForm::None`].
+    ///
+    /// Without it, a cache written on a file system that decomposes file names, like HFS+, does
+    /// not match the composed names of another file system, so all files would be re-hashed.
+    pub fn normalize_paths(mut self, form: NormalizationForm) -> Self {
+        self.normalize_paths = form;
+        self
+    }
+
+    /// Decides whether a chunk already exists in the chunk store by calling `chunk_exists` with
+    /// its hash, instead of checking the file system. Chunks for which it returns `false` are
+    /// written, even if the chunk file is already present.
+    ///
+    /// This allows to consult a fast external index of the chunk store.
+    pub fn chunk_exists(mut self, chunk_exists: impl FnMut(&str) -> bool + Send + 'static) -> Self {
+        self.chunk_exists = Some(Box::new(chunk_exists));
+        self
+    }
+
+    /// Calls `on_chunk` for every chunk while the chunk store is written, in the order the chunks
+    /// are processed. Chunks occurring multiple times are reported each time, with
+    /// [`ChunkEvent::deduped`] set if they were not written again.
+    ///
+    /// This allows to follow the progress of huge runs without waiting for the final cache.
+    pub fn on_chunk(mut self, on_chunk: impl FnMut(&ChunkEvent) + Send + 'static) -> Self {
+        self.on_chunk = Some(Box::new(on_chunk));
+        self
+    }
+
+    /// Stops writing chunks as soon as `cancel_flag` is set, failing with [`Error::Cancelled`].
+    /// The flag is checked between chunks, so the chunk store stays consistent. Write the cache
+    /// afterwards to resume from there in the next run.
+    ///
+    /// This allows applications to shut down gracefully, like on a signal or a user request.
+    pub fn cancel_flag(mut self, cancel_flag: Arc<AtomicBool>) -> Self {
+        self.cancel_flag = Some(cancel_flag);
+        self
+    }
+
+    /// Hashes the chunks of each file in `thread_pool` instead of the global rayon thread pool.
+    /// This avoids contention when the deduper is embedded in an application that uses the
+    /// global pool for other work.
+    #[cfg(feature = "parallel")]
+    pub fn thread_pool(mut self, thread_pool: Arc<rayon::ThreadPool>) -> Self {
+        self.thread_pool = Some(thread_pool);
+        self
+    }
+
+    /// Loads the caches, scans the source directory and creates the deduper.
+    ///
+    /// Fails if a cache file was written by a newer, possibly incompatible version.
+    pub fn try_build(self) -> Result<Deduper> {
+        Deduper::from_builder(self)
+    }
+
+    /// Loads the caches, scans the source directory and creates the deduper.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a cache file was written by a newer, possibly incompatible version. Use
+    /// [`Self::try_build`] to handle this case.
+    pub fn build(self) -> Deduper {
+        self.try_build().unwrap()
+    }
+}
+
+/// Primary deduper: scans a source directory, maintains a chunk cache, and writes deduplicated
+/// chunk data to a target location.
+pub struct Deduper {
+    source_paths: Vec<PathBuf>,
+    cache_path: Option<PathBuf>,
+    hashing_algorithm: HashingAlgorithm,
+    chunking: Chunking,
+    same_file_system: bool,
+    open_files_limit: Option<Arc<OpenFilesLimit>>,
+    use_content_equality: bool,
+    known_hashes: HashSet<String>,
+    #[cfg(feature = "parallel")]
+    hashing_parallelism: HashingParallelism,
+    temp_dir: Option<PathBuf>,
+    chunk_mode: Option<u32>,
+    retry_policy: RetryPolicy,
+    min_savings_bytes: Option<u64>,
+    min_file_size: Option<u64>,
+    max_file_size: Option<u64>,
+    baseline_paths: HashSet<String>,
+    detect_mime: bool,
+    file_hashes: bool,
+    source_encoding: Option<&'static Encoding>,
+    normalize_paths: NormalizationForm,
+    chunk_exists: Option<Mutex<ChunkExistsFn>>,
+    on_chunk: Option<Mutex<ChunkEventFn>>,
+    cancel_flag: Option<Arc<AtomicBool>>,
+    #[cfg(feature = "parallel")]
+    thread_pool: Option<Arc<rayon::ThreadPool>>,
+    chunk_base_path: Option<PathBuf>,
+    read_only_cache: bool,
+    metadata_only: bool,
+    assume_unchanged: Option<globset::GlobSet>,
+    cache_format_version: CacheVersion,
+    stdio_cache_compression: CacheCompression,
+    store_layout: StoreLayout,
+    timings: TimingReport,
+    cache: DedupCache,
+}
+
+/// Time spent in the different phases of a [`Deduper`] run.
+///
+/// Since chunks are hashed lazily, hashing and writing are interleaved. The phases are attributed
+/// as follows:
+/// - Scanning covers the directory walk and the comparison with the cache in [`Deduper::new`]
+///   and [`Deduper::sync`].
+/// - Hashing covers the calculation of chunks for files that were not cached yet, when triggered
+///   by [`Deduper::write_chunks`] or [`Deduper::sync`]. Chunks calculated by other means, for
+///   example by iterating over [`DedupCache::get_chunks`] directly, are not accounted for.
+/// - Writing covers copying the chunks into the chunk store, including the existence checks of
+///   chunks that are already present.
+#[derive(Clone, Debug, Default)]
+pub struct TimingReport {
+    /// Time spent scanning the source directory.
+    pub scan: Duration,
+    /// Time spent calculating chunk hashes.
+    pub hash: Duration,
+    /// Number of bytes that were hashed.
+    pub bytes_hashed: u64,
+    /// Time spent writing chunks into the chunk store.
+    pub write: Duration,
+    /// Number of bytes that were written into the chunk store.
+    pub bytes_written: u64,
+}
+
+impl TimingReport {
+    fn add(&mut self, other: &TimingReport) {
+        self.scan += other.scan;
+        self.hash += other.hash;
+        self.bytes_hashed += other.bytes_hashed;
+        self.write += other.write;
+        self.bytes_written += other.bytes_written;
+    }
+
+    /// Hashing throughput in MiB/s.
+    pub fn hash_throughput(&self) -> f64 {
+        throughput(self.bytes_hashed, self.hash)
+    }
+
+    /// Writing throughput in MiB/s.
+    pub fn write_throughput(&self) -> f64 {
+        throughput(self.bytes_written, self.write)
+    }
+}
+
+fn throughput(bytes: u64, duration: Duration) -> f64 {
+    if duration.is_zero() {
+        return 0.0;
+    }
+
+    bytes as f64 / (1024.0 * 1024.0) / duration.as_secs_f64()
+}
+
+impl Display for TimingReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+
+        writeln!(f, "Scanning: {:.3} s", self.scan.as_secs_f64())?;
+        writeln!(
+            f,
+            "Hashing:  {:.3} s, {:.2} MiB, {:.2} MiB/s",
+            self.hash.as_secs_f64(),
+            mib(self.bytes_hashed),
+            self.hash_throughput()
+        )?;
+        write!(
+            f,
+            "Writing:  {:.3} s, {:.2} MiB, {:.2} MiB/s",
+            self.write.as_secs_f64(),
+            mib(self.bytes_written),
+            self.write_throughput()
+        )
+    }
+}
+
+/// Chunks handled by [`Deduper::write_chunks`] and [`Deduper::write_chunks_two_pass`].
+#[derive(Debug, Default)]
+pub struct WriteStats {
+    /// Number of chunk files that were newly written to the chunk store.
+    pub written: usize,
+    /// Number of chunks that were not written because they were already present, either in the
+    /// chunk store, earlier in the same run or in the known hashes. Empty chunks count here, too.
+    pub deduped: usize,
+    /// Number of bytes that were written into the chunk store.
+    pub bytes_written: u64,
+    /// Number of bytes of the chunks that were not written, see [`Self::deduped`].
+    pub bytes_deduped: u64,
+    /// The same numbers for each file, in the order the files were processed.
+    pub files: Vec<FileWriteStats>,
+}
+
+impl WriteStats {
+    /// Counts `chunk` of `fwc`, which was `written` or deduplicated. Chunks of the same file must
+    /// be counted one after the other.
+    fn record(&mut self, fwc: &FileWithChunks, hashed: bool, chunk: &FileChunk, written: bool) {
+        if self.files.last().is_none_or(|file| file.path != fwc.path) {
+            self.files.push(FileWriteStats {
+                path: fwc.path.clone(),
+                hashed,
+                ..Default::default()
+            });
+        }
+        // Cannot panic, an entry was pushed above if necessary.
+        let file = self.files.last_mut().unwrap();
+
+        if written {
+            self.written += 1;
+            self.bytes_written += chunk.size;
+            file.written += 1;
+        } else {
+            self.deduped += 1;
+            self.bytes_deduped += chunk.size;
+            file.deduped += 1;
+        }
+    }
+}
+
+/// Chunks of a single file handled by [`Deduper::write_chunks`], see [`WriteStats`].
+#[derive(Debug, Default)]
+pub struct FileWriteStats {
+    /// Path of the file relative to the source directory.
+    pub path: String,
+    /// Whether the chunks of the file were calculated, instead of taken from the cache.
+    pub hashed: bool,
+    /// Number of chunks of the file that were newly written to the chunk store.
+    pub written: usize,
+    /// Number of chunks of the file that were deduplicated.
+    pub deduped: usize,
+}
+
+/// Changes found by [`Deduper::sync`].
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Files that were not in the cache before.
+    pub added: Vec<String>,
+    /// Files whose size or modification time changed.
+    pub modified: Vec<String>,
+    /// Files that are no longer present in the source directory.
+    pub removed: Vec<String>,
+    /// Files that were moved from the first to the second path, without changing their content.
+    pub renamed: Vec<(String, String)>,
+    /// Number of chunk files that were newly written to the chunk store.
+    pub chunks_written: usize,
+}
+
+impl Deduper {
+    /// Initializes a new `Deduper`:
+    /// - Loads provided cache files in reverse order (so later ones override earlier),
+    /// - Prunes missing entries,
+    /// - Scans the source tree and updates or inserts modified/new files,
+    /// - Re-uses the chunks of renamed files (see [`DedupCache::detect_renames`]).
+    ///
+    /// See [`DeduperBuilder`] for more options.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a cache file was written by a newer, possibly incompatible version, see
+    /// [`DeduperBuilder::try_build`].
+    pub fn new(
+        source_path: impl Into<PathBuf>,
+        cache_paths: Vec<impl Into<PathBuf>>,
+        hashing_algorithm: HashingAlgorithm,
+        same_file_system: bool,
+    ) -> Self {
+        DeduperBuilder::new(source_path)
+            .cache_paths(cache_paths)
+            .hashing_algorithm(hashing_algorithm)
+            .same_file_system(same_file_system)
+            .build()
+    }
+
+    fn from_builder(builder: DeduperBuilder) -> Result<Self> {
+        let DeduperBuilder {
+            source_path,
+            additional_source_paths,
+            mut cache_paths,
+            cache_write_path,
+            hashing_algorithm,
+            chunking,
+            same_file_system,
+            declutter_segment_length,
+            max_open_files,
+            use_content_equality,
+            mut known_hashes,
+            #[cfg(feature = "parallel")]
+            hashing_parallelism,
+            baseline_cache_paths,
+            temp_dir,
+            chunk_mode,
+            retries,
+            min_savings_bytes,
+            min_file_size,
+            max_file_size,
+            canonicalize_source,
+            absolute_paths,
+            detect_mime,
+            file_hashes,
+            source_encoding,
+            normalize_paths,
+            chunk_exists,
+            on_chunk,
+            cancel_flag,
+            #[cfg(feature = "parallel")]
+            thread_pool,
+            chunk_base_path,
+            read_only_cache,
+            metadata_only,
+            assume_unchanged,
+            cache_format_version,
+            stdio_cache_compression,
+            hash_includes_size,
+            data_dir_name,
+        } = builder;
+
+        chunking.validate()?;
+
+        let assume_unchanged = if assume_unchanged.is_empty() {
+            None
+        } else {
+            let mut globs = globset::GlobSetBuilder::new();
+            for pattern in &assume_unchanged {
+                globs.add(
+                    globset::GlobBuilder::new(pattern)
+                        .literal_separator(true)
+                        .build()
+                        .map_err(Error::InvalidPathPattern)?,
+                );
+            }
+            Some(globs.build().map_err(Error::InvalidPathPattern)?)
+        };
+
+        let mut source_paths = vec![source_path];
+        source_paths.extend(additional_source_paths);
+        if canonicalize_source || absolute_paths {
+            for source_path in &mut source_paths {
+                *source_path = source_path.canonicalize().map_err(|source| Error::Scan {
+                    path: source_path.clone(),
+                    source,
+                })?;
+            }
+        }
+        if absolute_paths && source_paths.len() > 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Absolute paths cannot be recorded for multiple source directories",
+            )
+            .into());
+        }
+
+        let mut baseline_paths = HashSet::new();
+        for baseline_cache_path in baseline_cache_paths {
+            let _span = span!("cache_load");
+            // Unlike the caches of the deduper, the baseline has to exist, a missing one would
+            // silently turn the delta into a full copy.
+            let mut baseline = DedupCache::new();
+            if !baseline.read_from_file(&baseline_cache_path)? {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!(
+                        "Baseline cache {} does not exist",
+                        baseline_cache_path.display()
+                    ),
+                )
+                .into());
+            }
+
+            baseline_paths.extend(baseline.paths().map(String::from));
+
+            known_hashes.extend(
+                baseline
+                    .values()
+                    .filter_map(FileWithChunks::get_chunks)
+                    .flatten()
+                    .map(|chunk| chunk.hash.clone()),
+            );
+        }
+
+        // Empty paths stand for no cache, like on the command line.
+        cache_paths.retain(|path| !path.as_os_str().is_empty());
+
+        let mut cache = DedupCache::new();
+        {
+            let _span = span!("cache_load");
+            cache.read_from_files(&cache_paths, stdio_cache_compression, None)?;
+        }
+
+        let cache_path = cache_write_path
+            .filter(|path| !path.as_os_str().is_empty())
+            .or_else(|| {
+                cache_paths
+                    .into_iter()
+                    .next()
+                    .filter(|path| path != Path::new(cache::STDIO_PATH))
+            });
+
+        let store_layout = StoreLayout {
+            data_dir_name,
+            declutter_segment_length,
+            hash_includes_size,
+        };
+        cache.declutter_segment_length = declutter_segment_length;
+        cache.hash_includes_size = hash_includes_size;
+        cache.source_root = absolute_paths.then(|| source_paths[0].clone());
+        if cache_path.is_some() && !read_only_cache {
+            // Fail before writing any chunks, not only when writing the cache.
+            cache::check_supported(&cache, cache_format_version)?;
+        }
+
+        let mut deduper = Self {
+            source_paths,
+            cache_path,
+            hashing_algorithm,
+            chunking,
+            same_file_system,
+            open_files_limit: max_open_files.map(|max| Arc::new(OpenFilesLimit::new(max))),
+            use_content_equality,
+            known_hashes,
+            #[cfg(feature = "parallel")]
+            hashing_parallelism,
+            temp_dir,
+            chunk_mode,
+            retry_policy: RetryPolicy::new(retries),
+            min_savings_bytes,
+            min_file_size,
+            max_file_size,
+            baseline_paths,
+            detect_mime,
+            file_hashes,
+            source_encoding,
+            normalize_paths,
+            chunk_exists: chunk_exists.map(Mutex::new),
+            on_chunk: on_chunk.map(Mutex::new),
+            cancel_flag,
+            #[cfg(feature = "parallel")]
+            thread_pool,
+            chunk_base_path,
+            read_only_cache,
+            metadata_only,
+            assume_unchanged,
+            cache_format_version,
+            stdio_cache_compression,
+            store_layout,
+            timings: Default::default(),
+            cache,
+        };
+        deduper.scan()?;
+
+        Ok(deduper)
+    }
+
+    /// Prunes cache entries whose files vanished and scans the source tree for new or modified
+    /// files. The found changes are returned, sorted by path.
+    fn scan(&mut self) -> Result<SyncReport> {
+        let _span = span!("directory_scan");
+        let start = Instant::now();
+        let mut report = SyncReport::default();
+
+        let source_paths = &self.source_paths;
+        let source_encoding = self.source_encoding;
+        let normalize_paths = self.normalize_paths;
+        let min_file_size = self.min_file_size.unwrap_or(u64::MIN);
+        let max_file_size = self.max_file_size.unwrap_or(u64::MAX);
+        let in_size_range = |size: u64| (min_file_size..=max_file_size).contains(&size);
+
+        // Files outside the size range count as missing, so that they are pruned from the cache.
+        let is_missing = |path: &str| {
+            !resolve_source_file(source_paths, path, source_encoding).is_some_and(|file| {
+                is_source_file(&file)
+                    && file
+                        .metadata()
+                        .is_ok_and(|metadata| in_size_range(metadata.len()))
+            })
+        };
+
+        let mut removed = DedupCache::new();
+        if normalize_paths == NormalizationForm::None {
+            removed.files = self.cache.take_files(is_missing);
+        } else {
+            self.cache.normalize_paths(normalize_paths);
+        }
+        // With normalization, the cache keys may differ from the file names on disk, so missing
+        // files are only known after the walk.
+        let mut seen = HashSet::new();
+
+        let same_file_system = self.same_file_system;
+        let entries = source_paths
+            .iter()
+            .enumerate()
+            .flat_map(|(index, source_path)| {
+                WalkDir::new(source_path)
+                    .min_depth(1)
+                    .same_file_system(same_file_system)
+                    .into_iter()
+                    .map(move |entry| (index, source_path, entry))
+            });
+
+        for (index, source_path, entry) in entries {
+            let entry = entry
+                .map_err(|err| Error::Scan {
+                    path: err.path().unwrap_or(source_path).to_path_buf(),
+                    source: err.into(),
+                })?
+                .into_path();
+
+            if !is_source_file(&entry) {
+                continue;
+            }
+
+            let mut fwc = FileWithChunks::try_new(source_path, &entry, self.hashing_algorithm)
+                .map_err(|err| match err {
+                    Error::Io(source) => Error::Scan {
+                        path: entry.clone(),
+                        source,
+                    },
+                    err => err,
+                })?;
+            if !in_size_range(fwc.size) {
+                continue;
+            }
+            if let Ok(relative) = entry.strip_prefix(source_path) {
+                fwc.path = normalize_paths.normalize(&decode_path(relative, source_encoding));
+            }
+            if source_paths.len() > 1 {
+                fwc.path = format!("{index}/{}", fwc.path);
+            }
+            fwc.chunking = self.chunking;
+            fwc.hash_whole_file = self.file_hashes;
+            fwc.open_files_limit = self.open_files_limit.clone();
+            #[cfg(feature = "parallel")]
+            {
+                fwc.thread_pool = self.thread_pool.clone();
+            }
+
+            if normalize_paths != NormalizationForm::None {
+                seen.insert(fwc.path.clone());
+            }
+
+            // Files assumed to be unchanged keep their cache entry, see
+            // `DeduperBuilder::assume_unchanged`.
+            let assumed_unchanged = self
+                .assume_unchanged
+                .as_ref()
+                .is_some_and(|globs| globs.is_match(&fwc.path));
+
+            match self.cache.get_mut(&fwc.path) {
+                Some(fwc_cache) if fwc == *fwc_cache || assumed_unchanged => {
+                    fwc_cache.adopt_runtime_settings(fwc);
+                    if fwc_cache.hash_whole_file && fwc_cache.file_hash.get().is_none() {
+                        // Re-hash once to record the whole-file hash.
+                        fwc_cache.chunks = OnceLock::new();
+                    }
+                    continue;
+                }
+                Some(fwc_cache)
+                    if self.use_content_equality
+                        && !self.metadata_only
+                        && fwc_cache.get_chunks().is_some()
+                        && fwc.content_equal(fwc_cache).unwrap_or_default() =>
+                {
+                    // Only the metadata changed, the cached chunks are still valid.
+                    fwc_cache.mtime = fwc.mtime;
+                    if fwc_cache.file_hash.get().is_none() {
+                        // Calculated together with the chunks for the comparison, if enabled.
+                        fwc_cache.file_hash = fwc.file_hash.clone();
+                    }
+                    fwc_cache.adopt_runtime_settings(fwc);
+                    continue;
+                }
+                Some(_) => report.modified.push(fwc.path.clone()),
+                None => report.added.push(fwc.path.clone()),
+            }
+
+            self.cache.insert(fwc.path.clone(), fwc);
+        }
+
+        if normalize_paths != NormalizationForm::None {
+            removed.files = self
+                .cache
+                .take_files(|path| !seen.contains(path) && is_missing(path));
+        }
+
+        // Re-use the chunks of renamed files instead of re-calculating them. Without chunks,
+        // renamed files cannot be told apart from other files with the same metadata.
+        if !self.metadata_only {
+            report.renamed = self.cache.detect_renames(&removed);
+        }
+        for (old_path, new_path) in &report.renamed {
+            let old = removed.files.remove(old_path).unwrap();
+            let fwc = self.cache.get_mut(new_path).unwrap();
+            if fwc.chunks.get().is_none() && (!fwc.hash_whole_file || old.file_hash.get().is_some())
+            {
+                fwc.chunks = old.chunks;
+                fwc.file_hash = old.file_hash;
+            }
+            report.added.retain(|path| path != new_path);
+            report.modified.retain(|path| path != new_path);
+        }
+        report.removed = removed.files.into_keys().collect();
+
+        for path in &self.baseline_paths {
+            if !self.cache.contains_key(path) {
+                self.cache.mark_deleted(path.clone());
+            }
+        }
+
+        if self.detect_mime {
+            for fwc in self.cache.files.values_mut() {
+                if fwc.mime_type.is_none() {
+                    fwc.mime_type = detect_mime_type(&fwc.source_file);
+                }
+            }
+        }
+
+        report.added.sort();
+        report.modified.sort();
+        report.removed.sort();
+
+        self.timings.scan += start.elapsed();
+
+        Ok(report)
+    }
+
+    /// Returns the directory of the chunk store for the target directory `target_path`, which is
+    /// its `data` subdirectory unless [`DeduperBuilder::chunk_base_path`] or another
+    /// [`StoreLayout::data_dir_name`] is set.
+    fn data_dir(&self, target_path: &Path) -> PathBuf {
+        self.chunk_base_path
+            .clone()
+            .unwrap_or_else(|| target_path.join(&self.store_layout.data_dir_name))
+    }
+
+    /// Creates the directory of the chunk store for the target directory `target_path`, see
+    /// [`Self::data_dir`].
+    ///
+    /// Fails with [`Error::CircularPath`] if the target directory or the chunk store is inside a
+    /// source directory or the other way around, since the next scan would pick up the chunk
+    /// files as source files.
+    fn create_data_dir(&self, target_path: &Path) -> Result<PathBuf> {
+        let data_dir = self.data_dir(target_path);
+
+        for source_path in &self.source_paths {
+            let source = source_path.canonicalize()?;
+            for target in [target_path, &data_dir] {
+                let canonical = canonicalize_missing(target)?;
+                if canonical.starts_with(&source) || source.starts_with(&canonical) {
+                    return Err(Error::CircularPath {
+                        source_dir: source_path.clone(),
+                        target: target.to_path_buf(),
+                    });
+                }
+            }
+        }
+
+        self.create_chunk_dir(&data_dir)?;
+
+        Ok(data_dir)
+    }
+
+    /// Creates the chunk directory `dir` and its missing parents, applying
+    /// [`DeduperBuilder::chunk_mode`] to the created directories.
+    fn create_chunk_dir(&self, dir: &Path) -> std::io::Result<()> {
+        #[cfg(unix)]
+        if let Some(mode) = self.chunk_mode {
+            use std::os::unix::fs::PermissionsExt;
+
+            let missing = dir
+                .ancestors()
+                .take_while(|dir| !dir.exists())
+                .collect::<Vec<_>>();
+            std::fs::create_dir_all(dir)?;
+            // Directories need the execute bit to be traversed.
+            let dir_mode = mode | (mode & 0o444) >> 2;
+            for dir in missing {
+                std::fs::set_permissions(dir, std::fs::Permissions::from_mode(dir_mode))?;
+            }
+            return Ok(());
+        }
+
+        std::fs::create_dir_all(dir)
+    }
+
+    /// Re-scans the source directory and incrementally updates the cache, writing only the chunks
+    /// of added or modified files to `target_path/data`.
+    ///
+    /// This is meant to be used after an initial [`Deduper::write_chunks`] run, to keep the chunk
+    /// store up to date with the source directory. The cache is not written, call
+    /// [`Deduper::write_cache`] afterwards to persist the changes.
+    pub fn sync(&mut self, target_path: &Path, declutter_levels: usize) -> Result<SyncReport> {
+        let mut report = self.scan()?;
+        if self.metadata_only {
+            return Ok(report);
+        }
+
+        let _span = span!("chunk_write");
+        let data_dir = self.create_data_dir(target_path)?;
+        let copier = ChunkCopier::new()?;
+
+        let mut timings = TimingReport::default();
+        self.merge_unprofitable_chunks(&mut timings)?;
+
+        for path in report.added.iter().chain(&report.modified) {
+            // Cannot panic, the scan just inserted the file into the cache.
+            let fwc = self.cache.get(path).unwrap();
+
+            let start = Instant::now();
+            let dirty = fwc.get_chunks().is_none();
+            let chunks = fwc.get_or_calculate_chunks()?;
+            if dirty {
+                timings.hash += start.elapsed();
+                timings.bytes_hashed += fwc.size;
+            }
+
+            for chunk in chunks {
+                check_cancelled(&self.cancel_flag)?;
+                let start = Instant::now();
+                if self.write_chunk(&copier, &data_dir, fwc, chunk, declutter_levels)? {
+                    report.chunks_written += 1;
+                    timings.bytes_written += chunk.size;
+                }
+                timings.write += start.elapsed();
+            }
+        }
+
+        self.timings.add(&timings);
+
+        Ok(report)
+    }
+
+    /// Atomically writes the internal cache back to its backing file.
+    ///
+    /// The cache is first written to a temporary file, which is then renamed to the final path.
+    /// If a temporary directory on a different device was configured (see
+    /// [`DeduperBuilder::temp_dir`]), the temporary file is copied and removed instead, which is
+    /// not atomic.
+    ///
+    /// Does nothing if [`DeduperBuilder::read_only_cache`] is set, or if no cache files were given,
+    /// so that the deduper runs statelessly. The path `-` writes the cache to stdout, see
+    /// [`DeduperBuilder::stdio_cache_compression`].
+    pub fn write_cache(&self) -> Result<()> {
+        if self.read_only_cache {
+            return Ok(());
+        }
+        let _span = span!("cache_write");
+
+        let Some(cache_path) = &self.cache_path else {
+            return Ok(());
+        };
+        if cache_path == Path::new(cache::STDIO_PATH) {
+            return cache::write_to_file(
+                cache_path,
+                &self.cache,
+                self.cache_format_version,
+                self.stdio_cache_compression,
+            );
+        }
+        let Some(temp_path) = self.temp_cache_path(cache_path)? else {
+            return Ok(());
+        };
+        self.cache
+            .write_to_file_with_version(&temp_path, self.cache_format_version)?;
+        self.retry_policy
+            .run(|| Ok(rename_or_copy(&temp_path, cache_path)?))?;
+
+        Ok(())
+    }
+
+    /// Returns the temporary file to which the cache is written before it is moved to
+    /// `cache_path`, or `None` if `cache_path` does not name a file.
+    fn temp_cache_path(&self, cache_path: &Path) -> Result<Option<PathBuf>> {
+        let (Some(_), Some(parent)) = (cache_path.file_name(), cache_path.parent()) else {
+            return Ok(None);
+        };
+
+        let mut temp_path = cache_path.with_extension(format!(
+            "tmp.{}.{}",
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis(),
+            cache_path
+                .extension()
+                .unwrap_or("ext".as_ref())
+                .to_str()
+                .unwrap()
+        ));
+        if let Some(temp_dir) = &self.temp_dir {
+            // Cannot panic, the temporary path is derived from a path with a file name.
+            temp_path = temp_dir.join(temp_path.file_name().unwrap());
+            std::fs::create_dir_all(parent)?;
+        }
+
+        Ok(Some(temp_path))
+    }
+
+    /// Writes all chunks from the current cache to `target_path/data`, applying optional
+    /// decluttering (path splitting) to reduce directory entropy.
+    ///
+    /// Returns how many chunks were written and how many were deduplicated instead.
+    pub fn write_chunks(
+        &mut self,
+        target_path: impl Into<PathBuf>,
+        declutter_levels: usize,
+    ) -> Result<WriteStats> {
+        self.write_chunks_with(target_path.into(), declutter_levels, |_, _| Ok(()))
+    }
+
+    /// Like [`Self::write_chunks`], but additionally writes the cache every
+    /// `checkpoint_interval`, so that an interrupted run only has to redo the work since the last
+    /// checkpoint.
+    ///
+    /// The checkpoints are written atomically like in [`Self::write_cache`], but on a separate
+    /// thread, so that writing the chunks does not wait for them. That thread keeps its own copy
+    /// of the cache, taken once at the start, and only receives the files processed since the
+    /// previous checkpoint. If the previous checkpoint is still being written when the interval
+    /// elapses, the next one is taken after the next chunk. The final cache still has to be
+    /// written with [`Self::write_cache`].
+    ///
+    /// No checkpoints are written if [`DeduperBuilder::read_only_cache`] is set, if no cache files
+    /// were given, or if the cache is written to stdout.
+    pub fn write_chunks_with_checkpoint(
+        &mut self,
+        target_path: impl Into<PathBuf>,
+        declutter_levels: usize,
+        checkpoint_interval: Duration,
+    ) -> Result<WriteStats> {
+        let cache_path = match &self.cache_path {
+            Some(cache_path)
+                if !self.read_only_cache && cache_path != Path::new(cache::STDIO_PATH) =>
+            {
+                cache_path.clone()
+            }
+            _ => return self.write_chunks(target_path, declutter_levels),
+        };
+
+        let busy = AtomicBool::new(false);
+        let retry_policy = self.retry_policy;
+        let cache_format_version = self.cache_format_version;
+        let mut snapshot = self.cache.clone();
+        std::thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel::<(Vec<FileWithChunks>, PathBuf)>();
+
+            let writer = scope.spawn(|| -> Result<()> {
+                for (changed, temp_path) in receiver {
+                    for fwc in changed {
+                        snapshot.insert(fwc.path.clone(), fwc);
+                    }
+                    let written = cache::serialize(&snapshot, cache_format_version, &temp_path)
+                        .and_then(|data| Ok(cache::write_serialized(&temp_path, &data)?))
+                        .and_then(|()| {
+                            retry_policy.run(|| Ok(rename_or_copy(&temp_path, &cache_path)?))
+                        });
+                    busy.store(false, Ordering::Release);
+                    written?;
+                }
+                Ok(())
+            });
+
+            let mut last_checkpoint = Instant::now();
+            let mut changed = Vec::<FileWithChunks>::new();
+            let stats =
+                self.write_chunks_with(target_path.into(), declutter_levels, |deduper, fwc| {
+                    // The chunks of a file are all known before the first of them is written.
+                    if changed.last().is_none_or(|last| last.path != fwc.path) {
+                        changed.push(fwc.clone());
+                    }
+                    if last_checkpoint.elapsed() < checkpoint_interval
+                        || busy.load(Ordering::Acquire)
+                    {
+                        return Ok(());
+                    }
+                    let Some(temp_path) = deduper.temp_cache_path(&cache_path)? else {
+                        return Ok(());
+                    };
+
+                    busy.store(true, Ordering::Release);
+                    // If the writer has stopped, its error is returned when joining it below.
+                    let _ = sender.send((std::mem::take(&mut changed), temp_path));
+                    last_checkpoint = Instant::now();
+
+                    Ok(())
+                });
+
+            drop(sender);
+            // Cannot panic unless the writer panicked, in which case the panic is propagated.
+            let written = writer.join().unwrap();
+
+            let stats = stats?;
+            written?;
+            Ok(stats)
+        })
+    }
+
+    /// Writes all chunks like [`Self::write_chunks`], calling `after_chunk` with the file after
+    /// each of its chunks.
+    fn write_chunks_with(
+        &mut self,
+        target_path: PathBuf,
+        declutter_levels: usize,
+        mut after_chunk: impl FnMut(&Self, &FileWithChunks) -> Result<()>,
+    ) -> Result<WriteStats> {
+        if self.metadata_only {
+            return Ok(WriteStats::default());
+        }
+
+        let _span = span!("chunk_write");
+        let data_dir = self.create_data_dir(&target_path)?;
+        let copier = ChunkCopier::new()?;
+
+        let uncached = self.uncached_paths();
+        let mut timings = TimingReport::default();
+        self.merge_unprofitable_chunks(&mut timings)?;
+        #[cfg(feature = "parallel")]
+        if self.hashing_parallelism == HashingParallelism::PerFile {
+            self.precompute_chunks(&mut timings)?;
+        }
+
+        let mut stats = WriteStats::default();
+        for fwc in self.cache.iter_sorted() {
+            check_cancelled(&self.cancel_flag)?;
+
+            // Chunks are calculated lazily, so this is where the hashing happens.
+            let start = Instant::now();
+            let dirty = fwc.get_chunks().is_none();
+            let chunks = fwc.get_or_calculate_chunks()?;
+            if dirty {
+                timings.hash += start.elapsed();
+                timings.bytes_hashed += fwc.size;
+            }
+
+            for chunk in chunks {
+                check_cancelled(&self.cancel_flag)?;
+
+                let start = Instant::now();
+                let written = self.write_chunk(&copier, &data_dir, fwc, chunk, declutter_levels)?;
+                if written {
+                    timings.bytes_written += chunk.size;
+                }
+                stats.record(fwc, uncached.contains(&fwc.path), chunk, written);
+                timings.write += start.elapsed();
+
+                after_chunk(self, fwc)?;
+            }
+        }
+
+        self.timings.add(&timings);
+
+        Ok(stats)
+    }
+
+    /// Like [`Self::write_chunks`], but first calculates the chunks of all files and only then
+    /// writes the unique chunks, instead of interleaving hashing and writing.
+    ///
+    /// Separating the reads of the hashing phase from the writes of the chunk files avoids that
+    /// both compete with each other, which is faster on spinning disks or network storage. The
+    /// chunks are written in the order of the source files, so that they are read sequentially.
+    pub fn write_chunks_two_pass(
+        &mut self,
+        target_path: impl Into<PathBuf>,
+        declutter_levels: usize,
+    ) -> Result<WriteStats> {
+        if self.metadata_only {
+            return Ok(WriteStats::default());
+        }
+
+        let _span = span!("chunk_write");
+        let data_dir = self.create_data_dir(&target_path.into())?;
+        let copier = ChunkCopier::new()?;
+
+        let uncached = self.uncached_paths();
+        let mut timings = TimingReport::default();
+        self.merge_unprofitable_chunks(&mut timings)?;
+        self.precompute_chunks(&mut timings)?;
+
+        let start = Instant::now();
+        let mut files = self.cache.values().collect::<Vec<_>>();
+        files.sort_by(|a, b| a.source_file.cmp(&b.source_file));
+
+        let mut stats = WriteStats::default();
+        let mut seen = HashSet::new();
+        for fwc in files {
+            // Cannot panic, all chunks were calculated above.
+            for chunk in fwc.get_chunks().unwrap() {
+                check_cancelled(&self.cancel_flag)?;
+                let written = if !seen.insert(self.store_layout.chunk_key(chunk)) {
+                    self.emit_chunk_event(fwc, chunk, true);
+                    false
+                } else {
+                    self.write_chunk(&copier, &data_dir, fwc, chunk, declutter_levels)?
+                };
+                if written {
+                    timings.bytes_written += chunk.size;
+                }
+                stats.record(fwc, uncached.contains(&fwc.path), chunk, written);
+            }
+        }
+        timings.write += start.elapsed();
+
+        self.timings.add(&timings);
+
+        Ok(stats)
+    }
+
+    /// Writes all chunks as entries of a tar archive to `writer`, instead of files in a chunk
+    /// store directory. The entries are named like the chunk files below the deduplicated
+    /// directory, so unpacking the archive creates the same chunk store as [`Self::write_chunks`].
+    /// This allows to pipe the chunk store to remote storage, wrap `writer` in a
+    /// [`zstd::Encoder`] to compress it.
+    ///
+    /// Each chunk is written once, like with [`Self::write_chunks_two_pass`]. Since there is no
+    /// chunk store to check, [`DeduperBuilder::chunk_exists`] and
+    /// [`DeduperBuilder::chunk_base_path`] are not used.
+    pub fn write_chunks_to_writer<W: Write>(
+        &mut self,
+        writer: W,
+        declutter_levels: usize,
+    ) -> Result<()> {
+        if self.metadata_only {
+            // An empty archive.
+            tar::Builder::new(writer).into_inner()?;
+            return Ok(());
+        }
+
+        let _span = span!("chunk_write");
+        let mut timings = TimingReport::default();
+        self.merge_unprofitable_chunks(&mut timings)?;
+        self.precompute_chunks(&mut timings)?;
+
+        let start = Instant::now();
+        let data_dir = Path::new(&self.store_layout.data_dir_name);
+        let mut files = self.cache.values().collect::<Vec<_>>();
+        files.sort_by(|a, b| a.source_file.cmp(&b.source_file));
+
+        let mut archive = tar::Builder::new(writer);
+        let mut seen = HashSet::new();
+        for fwc in files {
+            let mut source = None;
+            // Cannot panic, all chunks were calculated above.
+            for chunk in fwc.get_chunks().unwrap() {
+                check_cancelled(&self.cancel_flag)?;
+                let chunk_path = self
+                    .store_layout
+                    .chunk_path(data_dir, chunk, declutter_levels);
+                if chunk.size == 0
+                    || self.known_hashes.contains(&chunk.hash)
+                    || !seen.insert(chunk_path.clone())
+                {
+                    self.emit_chunk_event(fwc, chunk, true);
+                    continue;
+                }
+
+                let source = match &mut source {
+                    Some(source) => source,
+                    None => source.insert(File::open(&fwc.source_file)?),
+                };
+                let data = read_at_chunk(source, chunk.start, chunk.size as usize)?;
+                if data.len() as u64 != chunk.size {
+                    return Err(Error::SourceTruncated {
+                        path: fwc.source_file.clone(),
+                        hash: chunk.hash.clone(),
+                    });
+                }
+
+                let mut header = tar::Header::new_gnu();
+                header.set_size(chunk.size);
+                header.set_mode(0o644);
+                archive.append_data(&mut header, chunk_path, data.as_slice())?;
+
+                timings.bytes_written += chunk.size;
+                self.emit_chunk_event(fwc, chunk, false);
+            }
+        }
+        archive.into_inner()?.flush()?;
+        timings.write += start.elapsed();
+
+        self.timings.add(&timings);
+
+        Ok(())
+    }
+
+    /// Calculates the chunks of all files that are not cached yet, without writing any chunks.
+    pub fn precompute_all_chunks(&mut self) -> Result<()> {
+        let mut timings = TimingReport::default();
+        self.precompute_chunks(&mut timings)?;
+        self.timings.add(&timings);
+
+        Ok(())
+    }
+
+    /// Returns the paths of the files whose chunks are not known yet and have to be calculated.
+    fn uncached_paths(&self) -> HashSet<String> {
+        self.cache
+            .values()
+            .filter(|fwc| fwc.get_chunks().is_none())
+            .map(|fwc| fwc.path.clone())
+            .collect()
+    }
+
+    /// Calculates all missing chunks and records the time spent in `timings`.
+    fn precompute_chunks(&self, timings: &mut TimingReport) -> Result<()> {
+        #[cfg(feature = "parallel")]
+        if self.hashing_parallelism == HashingParallelism::PerFile {
+            return self.precompute_chunks_per_file(timings);
+        }
+
+        let start = Instant::now();
+
+        for fwc in self.cache.values() {
+            if fwc.get_chunks().is_none() {
+                fwc.get_or_calculate_chunks()?;
+                timings.bytes_hashed += fwc.size;
+            }
+        }
+
+        timings.hash += start.elapsed();
+
+        Ok(())
+    }
+
+    /// Calculates all missing chunks like [`Self::precompute_chunks`], but hashes the files in
+    /// parallel and the chunks of each file one after the other, see
+    /// [`HashingParallelism::PerFile`].
+    #[cfg(feature = "parallel")]
+    fn precompute_chunks_per_file(&self, timings: &mut TimingReport) -> Result<()> {
+        let start = Instant::now();
+
+        let files = self.cache.files.par_iter().map(|(_, fwc)| {
+            if fwc.get_chunks().is_some() {
+                return Ok(0);
+            }
+            fwc.get_or_calculate_chunks_with(false)?;
+            Ok(fwc.size)
+        });
+        let bytes_hashed = match &self.thread_pool {
+            Some(thread_pool) => thread_pool.install(|| files.sum::<Result<u64>>())?,
+            None => files.sum::<Result<u64>>()?,
+        };
+
+        timings.bytes_hashed += bytes_hashed;
+        timings.hash += start.elapsed();
+
+        Ok(())
+    }
+
+    /// Determines the chunks that are absent from a remote chunk store, given the set of hashes
+    /// known to the remote. For each missing chunk, returns the hash, the source file containing
+    /// it, and its start offset and size within that file. Each hash is only listed once, even if
+    /// it occurs in several files. With [`DeduperBuilder::hash_includes_size`], chunks with the
+    /// same hash but different sizes are stored in different files and listed separately.
+    ///
+    /// This is the core primitive for pushing a delta to a remote store.
+    ///
+    /// Fails if the chunks of a file cannot be calculated, see [`DedupCache::get_chunks`].
+    pub fn missing_chunks(
+        &self,
+        remote: &HashSet<String>,
+    ) -> Result<Vec<(String, PathBuf, u64, u64)>> {
+        let mut missing = Vec::new();
+        for item in self.cache.get_chunks()? {
+            let (hash, chunk, _) = item?;
+            if chunk.size > 0 && !remote.contains(&hash) {
+                // Cannot panic, the chunk was taken from the cache.
+                let fwc = self.cache.get(chunk.path.as_deref().unwrap()).unwrap();
+                missing.push((hash, fwc.source_file.clone(), chunk.start, chunk.size));
+            }
+        }
+
+        // Sort before removing duplicates, so that the first occurrence of a hash is chosen
+        // deterministically.
+        missing.sort_by(|a, b| (&a.1, a.2).cmp(&(&b.1, b.2)));
+
+        let hash_includes_size = self.store_layout.hash_includes_size;
+        let mut seen = HashSet::new();
+        missing.retain(|(hash, _, _, size)| {
+            seen.insert((hash.clone(), hash_includes_size.then_some(*size)))
+        });
+
+        Ok(missing)
+    }
+
+    /// Merges the chunks of files that save less than the configured minimum through
+    /// deduplication into a single chunk, see [`DeduperBuilder::min_savings_bytes`].
+    fn merge_unprofitable_chunks(&mut self, timings: &mut TimingReport) -> Result<()> {
+        let Some(min_savings_bytes) = self.min_savings_bytes else {
+            return Ok(());
+        };
+
+        let start = Instant::now();
+
+        let mut occurrences = HashMap::<&str, usize>::new();
+        for fwc in self.cache.values() {
+            if fwc.get_chunks().is_none() {
+                timings.bytes_hashed += fwc.size;
+            }
+            for chunk in fwc.get_or_calculate_chunks()? {
+                *occurrences.entry(&chunk.hash).or_default() += 1;
+            }
+        }
+
+        let unprofitable = self
+            .cache
+            .values()
+            .filter(|fwc| {
+                // Cannot panic, all chunks were calculated above.
+                let chunks = fwc.get_chunks().unwrap();
+                let savings = chunks
+                    .iter()
+                    .filter(|chunk| {
+                        occurrences[chunk.hash.as_str()] > 1
+                            || self.known_hashes.contains(&chunk.hash)
+                    })
+                    .map(|chunk| chunk.size)
+                    .sum::<u64>();
+                chunks.len() > 1 && savings < min_savings_bytes
+            })
+            .map(|fwc| fwc.path.clone())
+            .collect::<Vec<_>>();
+
+        for path in unprofitable {
+            // Cannot panic, the path was just taken from the cache.
+            let fwc = self.cache.get_mut(&path).unwrap();
+            fwc.merge_chunks()?;
+            timings.bytes_hashed += fwc.size;
+        }
+
+        timings.hash += start.elapsed();
+
+        Ok(())
+    }
+
+    /// Returns the time spent in the different phases so far. See [`TimingReport`] for how the
+    /// phases are attributed.
+    pub fn timings(&self) -> &TimingReport {
+        &self.timings
+    }
+
+    /// Returns the cache of the scanned files. The cache can only be changed through the methods
+    /// of the deduper, so that the invariants hydration relies on are kept.
+    pub fn cache(&self) -> &DedupCache {
+        &self.cache
+    }
+
+    /// Returns the total size of all scanned files, see [`DedupCache::total_file_size`]. It is
+    /// known right after the scan, before any chunks are calculated, for example for progress
+    /// bars:
+    ///
+    /// ```rust no_run
+    /// let mut deduper = crazy_deduper::DeduperBuilder::new("source").build();
+    /// println!(
+    ///     "Deduplicating {} files with {} bytes",
+    ///     deduper.file_count(),
+    ///     deduper.total_input_bytes()
+    /// );
+    /// deduper.write_chunks("deduped", 3).unwrap();
+    /// ```
+    pub fn total_input_bytes(&self) -> u64 {
+        self.cache.total_file_size()
+    }
+
+    /// Returns the number of scanned files, see [`Self::total_input_bytes`].
+    pub fn file_count(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Reports the space efficiency of the deduplication like [`Hydrator::dedup_report`], but
+    /// from the chunks in the cache, without accessing the chunk store. Chunks with the same hash
+    /// are counted once.
+    ///
+    /// Files whose chunks are not calculated yet, like before [`Self::write_chunks`], are only
+    /// counted in [`DedupReport::total_file_bytes`].
+    pub fn dedup_report(&self) -> DedupReport {
+        let chunks = self
+            .cache
+            .values()
+            .filter_map(FileWithChunks::get_chunks)
+            .flatten()
+            .filter(|chunk| chunk.size > 0)
+            .map(|chunk| (chunk.hash.as_str(), chunk.size))
+            .collect::<HashMap<_, _>>();
+
+        DedupReport::new(
+            self.cache.total_file_size(),
+            chunks.len(),
+            chunks.values().sum(),
+        )
+    }
+
+    /// Removes `path` from the cache and records it as deleted, so that it is neither restored
+    /// nor re-added from older cache files.
+    pub fn mark_deleted(&mut self, path: impl Into<String>) {
+        self.cache.mark_deleted(path);
+    }
+
+    /// Groups the cache keys of files with identical content. Files are considered identical if
+    /// their ordered lists of chunk hashes are equal, which also implies equal whole-file hashes.
+    /// Chunks that are not computed yet are calculated.
+    ///
+    /// Only the content is compared, files that differ only in their modification time are
+    /// duplicates of each other. Empty files are not reported. Each group is sorted and the groups
+    /// are sorted by their first path, so the first path of a group is a stable choice for the
+    /// file to keep.
+    pub fn duplicate_files(&self) -> Result<Vec<Vec<String>>> {
+        let mut groups = HashMap::<Vec<&str>, Vec<String>>::new();
+        for fwc in self.cache.values().filter(|fwc| fwc.size > 0) {
+            let hashes = fwc
+                .get_or_calculate_chunks()?
+                .iter()
+                .map(|chunk| chunk.hash.as_str())
+                .collect();
+            groups.entry(hashes).or_default().push(fwc.path.clone());
+        }
+
+        let mut duplicates = groups
+            .into_values()
+            .filter(|paths| paths.len() > 1)
+            .map(|mut paths| {
+                paths.sort();
+                paths
+            })
+            .collect::<Vec<_>>();
+        duplicates.sort();
+
+        Ok(duplicates)
+    }
+
+    /// Copies a single chunk of `fwc` into the chunk store, if it is not already present. Returns
+    /// whether the chunk file was newly written.
+    ///
+    /// Empty chunks are never written, since empty files can be restored without them. Neither
+    /// are chunks that are known to be present elsewhere, see [`DeduperBuilder::known_hashes`].
+    /// If the source file became shorter since it was hashed, the chunk is not written and
+    /// [`Error::SourceTruncated`] is returned.
+    /// Whether the chunk is already present is decided by [`DeduperBuilder::chunk_exists`], if
+    /// set. The chunk is reported to [`DeduperBuilder::on_chunk`], if set.
+    fn write_chunk(
+        &self,
+        copier: &ChunkCopier,
+        data_dir: &Path,
+        fwc: &FileWithChunks,
+        chunk: &FileChunk,
+        declutter_levels: usize,
+    ) -> Result<bool> {
+        let written =
+            self.store_chunk(copier, data_dir, &fwc.source_file, chunk, declutter_levels)?;
+        self.emit_chunk_event(fwc, chunk, !written);
+
+        Ok(written)
+    }
+
+    /// Reports a chunk of `fwc` to [`DeduperBuilder::on_chunk`], if set.
+    fn emit_chunk_event(&self, fwc: &FileWithChunks, chunk: &FileChunk, deduped: bool) {
+        if let Some(on_chunk) = &self.on_chunk {
+            (on_chunk.lock().unwrap())(&ChunkEvent {
+                path: &fwc.path,
+                hash: &chunk.hash,
+                start: chunk.start,
+                size: chunk.size,
+                deduped,
+            });
+        }
+    }
+
+    /// Does the actual work of [`Self::write_chunk`].
+    fn store_chunk(
+        &self,
+        copier: &ChunkCopier,
+        data_dir: &Path,
+        source_file: &Path,
+        chunk: &FileChunk,
+        declutter_levels: usize,
+    ) -> Result<bool> {
+        if chunk.size == 0 || self.known_hashes.contains(&chunk.hash) {
+            return Ok(false);
+        }
+
+        let chunk_file = self
+            .store_layout
+            .chunk_path(data_dir, chunk, declutter_levels);
+
+        let exists = match &self.chunk_exists {
+            Some(chunk_exists) => (chunk_exists.lock().unwrap())(&chunk.hash),
+            None => chunk_file.exists(),
+        };
+        if exists {
+            return Ok(false);
+        }
+
+        self.create_chunk_dir(chunk_file.parent().unwrap())?;
+        self.retry_policy
+            .run(|| Ok(copier.copy(source_file, chunk.start, chunk.size, &chunk_file)?))
+            .map_err(|err| match err {
+                // The chunk file would not match its hash.
+                Error::Io(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    Error::SourceTruncated {
+                        path: source_file.to_path_buf(),
+                        hash: chunk.hash.clone(),
+                    }
+                }
+                err => err,
+            })?;
+        #[cfg(unix)]
+        if let Some(mode) = self.chunk_mode {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&chunk_file, std::fs::Permissions::from_mode(mode))?;
+        }
+
+        Ok(true)
+    }
+}
+
+/// How [`Hydrator::restore_files`] treats files that already exist in the target directory.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ResumeMode {
+    /// Always restore all files.
+    #[default]
+    Disabled,
+    /// Skip files whose size and modification time match the cache.
+    SizeAndMtime,
+    /// Like [`ResumeMode::SizeAndMtime`], but additionally re-hash the file to confirm that its
+    /// content matches the cached chunks. Files that fail the verification are restored again.
+    Verify,
+}
+
+/// Result of checking a single chunk with [`Hydrator::check_cache_verbose`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CacheCheckResult {
+    /// The chunk file is present and has the expected size.
+    Ok { hash: String },
+    /// The chunk file does not exist.
+    Missing { hash: String, path: PathBuf },
+    /// The chunk file exists, but its size differs from the cache.
+    SizeMismatch {
+        hash: String,
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+}
+
+impl CacheCheckResult {
+    /// Returns the hash of the checked chunk.
+    pub fn hash(&self) -> &str {
+        match self {
+            CacheCheckResult::Ok { hash }
+            | CacheCheckResult::Missing { hash, .. }
+            | CacheCheckResult::SizeMismatch { hash, .. } => hash,
+        }
+    }
+}
+
+/// Differences between two chunk stores found by [`Hydrator::compare_store`]. The hashes are
+/// sorted.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StoreDiff {
+    /// Chunks present in the chunk store of the hydrator, but missing from the other one.
+    pub missing_there: Vec<String>,
+    /// Chunks present in the other chunk store, but missing from the one of the hydrator.
+    pub missing_here: Vec<String>,
+}
+
+impl StoreDiff {
+    /// Returns whether both chunk stores contain the same chunks referenced by the cache.
+    pub fn is_empty(&self) -> bool {
+        self.missing_there.is_empty() && self.missing_here.is_empty()
+    }
+}
+
+/// Outcome of [`Hydrator::restore_files`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RestoreReport {
+    /// Number of files that were written.
+    pub restored: usize,
+    /// Number of files that were skipped because they were already restored.
+    pub skipped: usize,
+    /// Number of skipped files whose content was verified by re-hashing them.
+    pub reverified: usize,
+    /// Files that were restored, but whose modification time could not be set.
+    pub mtime_not_set: Vec<String>,
+    /// Files that were restored, but whose owner or group could not be set.
+    pub ownership_not_set: Vec<String>,
+    /// Number of files that were removed from the target because they are recorded as deleted.
+    pub removed: usize,
+}
+
+/// Space efficiency of a chunk store, see [`Hydrator::dedup_report`] and
+/// [`Deduper::dedup_report`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DedupReport {
+    /// Sum of the sizes of all files in the cache.
+    pub total_file_bytes: u64,
+    /// Number of distinct chunk files referenced by the cache.
+    pub unique_chunks: usize,
+    /// Sum of the sizes of all chunk files referenced by the cache.
+    pub unique_chunk_bytes: u64,
+    /// Ratio of `total_file_bytes` to `unique_chunk_bytes`, `1.0` if there are no chunks.
+    pub ratio: f64,
+    /// Difference of `total_file_bytes` and `unique_chunk_bytes`.
+    pub space_saved_bytes: i64,
+}
+
+impl DedupReport {
+    /// Creates a report for files of `total_file_bytes`, which are stored in `unique_chunks`
+    /// chunks of `unique_chunk_bytes`.
+    fn new(total_file_bytes: u64, unique_chunks: usize, unique_chunk_bytes: u64) -> Self {
+        Self {
+            total_file_bytes,
+            unique_chunks,
+            unique_chunk_bytes,
+            ratio: if unique_chunk_bytes > 0 {
+                total_file_bytes as f64 / unique_chunk_bytes as f64
+            } else {
+                1.0
+            },
+            space_saved_bytes: total_file_bytes as i64 - unique_chunk_bytes as i64,
+        }
+    }
+}
+
+/// Disk usage of a chunk store, see [`Hydrator::chunk_store_stats`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ChunkStoreStats {
+    /// Number of chunk files.
+    pub chunk_count: usize,
+    /// Sum of the sizes of all chunk files.
+    pub total_bytes: u64,
+    /// Number of files and directories in the chunk store, including the declutter directories.
+    pub inode_count: usize,
+}
+
+impl Display for ChunkStoreStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Chunks: {}", self.chunk_count)?;
+        writeln!(f, "Bytes:  {}", self.total_bytes)?;
+        write!(f, "Inodes: {}", self.inode_count)
+    }
+}
+
+/// Builder for a [`Hydrator`] with more configuration options than [`Hydrator::new`].
+pub struct HydratorBuilder {
+    source_path: PathBuf,
+    cache_paths: Vec<PathBuf>,
+    cache: Option<DedupCache>,
+    path_prefix: Option<String>,
+    resume: ResumeMode,
+    preserve_mtime: bool,
+    sparse: bool,
+    remove_deleted: bool,
+    absolute_paths: bool,
+    allow_absolute: bool,
+    prefetch_parallelism: usize,
+    retries: u32,
+    chunk_base_path: Option<PathBuf>,
+    ignore_mtime: bool,
+    preserve_ownership: bool,
+    store_layout: Option<StoreLayout>,
+    cancel_flag: Option<Arc<AtomicBool>>,
+    stdio_cache_compression: CacheCompression,
+}
+
+impl HydratorBuilder {
+    /// Creates a builder for a hydrator reading from the deduplicated `source_path`.
+    pub fn new(source_path: impl Into<PathBuf>) -> Self {
+        Self {
+            source_path: source_path.into(),
+            cache_paths: Vec::new(),
+            cache: None,
+            path_prefix: None,
+            resume: ResumeMode::default(),
+            preserve_mtime: true,
+            sparse: false,
+            remove_deleted: false,
+            absolute_paths: false,
+            allow_absolute: false,
+            prefetch_parallelism: 0,
+            retries: 0,
+            chunk_base_path: None,
+            ignore_mtime: false,
+            preserve_ownership: false,
+            store_layout: None,
+            cancel_flag: None,
+            stdio_cache_compression: CacheCompression::None,
+        }
+    }
+
+    /// Sets the cache files to read.
+    pub fn cache_paths(mut self, cache_paths: Vec<impl Into<PathBuf>>) -> Self {
+        self.cache_paths = cache_paths.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Starts from `cache` instead of an empty cache, for example one read with
+    /// [`DedupCache::from_manifest`]. Entries from the cache files given by
+    /// [`Self::cache_paths`] take precedence over its entries.
+    pub fn cache(mut self, cache: DedupCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Only loads the files below `path_prefix`, a path relative to the source directory like
+    /// `photos/2024`, to restore a subtree of a large backup. The other entries are dropped while
+    /// reading the caches, so they never take up memory. Prefixes match whole path components, so
+    /// `photos` does not match `photos2`.
+    pub fn path_prefix(mut self, path_prefix: impl Into<String>) -> Self {
+        let path_prefix = path_prefix.into().trim_matches('/').to_string();
+        self.path_prefix = (!path_prefix.is_empty()).then_some(path_prefix);
+        self
+    }
+
+    /// Sets how already existing files are handled, to resume an interrupted restore.
+    pub fn resume(mut self, resume: ResumeMode) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// If enabled, the modification time is not considered when resuming, for file systems where
+    /// it is unreliable. Existing files are then compared by size and content only, as with
+    /// [`ResumeMode::Verify`], so they are neither restored again only because of a different
+    /// modification time, nor skipped with different content but the same modification time.
+    pub fn ignore_mtime(mut self, ignore_mtime: bool) -> Self {
+        self.ignore_mtime = ignore_mtime;
+        self
+    }
+
+    /// Sets whether restored files get their original modification time back, which is the
+    /// default. Otherwise, they keep the time of the restore.
+    ///
+    /// Since resuming relies on the modification time, files restored without it are never
+    /// skipped by [`ResumeMode::SizeAndMtime`] or [`ResumeMode::Verify`], unless
+    /// [`Self::ignore_mtime`] is set.
+    pub fn preserve_mtime(mut self, preserve_mtime: bool) -> Self {
+        self.preserve_mtime = preserve_mtime;
+        self
+    }
+
+    /// Sets whether restored files get their original owner and group back, as recorded in the
+    /// cache. Only has an effect on Unix, and changing the owner usually requires root
+    /// privileges. Files whose ownership could not be set are still restored, they are listed in
+    /// [`RestoreReport::ownership_not_set`].
+    pub fn preserve_ownership(mut self, preserve_ownership: bool) -> Self {
+        self.preserve_ownership = preserve_ownership;
+        self
+    }
+
+    /// If enabled, chunks that consist of zeros only are not written, but skipped, leaving holes
+    /// in the restored files. On file systems that support sparse files, this reduces the disk
+    /// usage of files like disk images.
+    pub fn sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// If enabled, files that are recorded as deleted in the caches are removed from the target
+    /// directory, if they exist there from an earlier restore. Otherwise, they are only not
+    /// restored.
+    pub fn remove_deleted(mut self, remove_deleted: bool) -> Self {
+        self.remove_deleted = remove_deleted;
+        self
+    }
+
+    /// If enabled, files are restored to their original locations in the source directory
+    /// recorded in the cache, see [`DeduperBuilder::absolute_paths`], instead of into the target
+    /// directory.
+    ///
+    /// The target directory given to [`Hydrator::restore_files`] then only serves as the allowed
+    /// root: the recorded source directory must be inside of it, unless
+    /// [`Self::allow_absolute`] is set.
+    pub fn absolute_paths(mut self, absolute_paths: bool) -> Self {
+        self.absolute_paths = absolute_paths;
+        self
+    }
+
+    /// Allows [`Self::absolute_paths`] to restore files outside the target directory.
+    pub fn allow_absolute(mut self, allow_absolute: bool) -> Self {
+        self.allow_absolute = allow_absolute;
+        self
+    }
+
+    /// Sets how many chunks are read ahead of writing the restored files, by as many background
+    /// threads. This hides the latency of chunk stores on network or cloud storage. The default of
+    /// 0 disables prefetching and copies each chunk directly when it is written.
+    ///
+    /// Also sets how many chunks [`Hydrator::prefetch`] reads at the same time.
+    pub fn prefetch_parallelism(mut self, prefetch_parallelism: usize) -> Self {
+        self.prefetch_parallelism = prefetch_parallelism;
+        self
+    }
+
+    /// Retries reading a chunk in [`Hydrator::restore_files`] and [`Hydrator::prefetch`] up to
+    /// `retries` times if it fails with a transient IO error, see [`DeduperBuilder::retries`].
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Reads the chunk files directly from `chunk_base_path` instead of the `data` subdirectory
+    /// of the source directory, see [`DeduperBuilder::chunk_base_path`].
+    pub fn chunk_base_path(mut self, chunk_base_path: impl Into<PathBuf>) -> Self {
+        self.chunk_base_path = Some(chunk_base_path.into());
+        self
+    }
+
+    /// Reads the chunk files according to `store_layout` instead of the layout recorded in the
+    /// cache. This allows to hydrate from a chunk store that was reorganized after deduplication,
+    /// or one written with another data directory name, see [`DeduperBuilder::store_layout`].
+    pub fn store_layout(mut self, store_layout: StoreLayout) -> Self {
+        self.store_layout = Some(store_layout);
+        self
+    }
+
+    /// Stops restoring as soon as `cancel_flag` is set, failing with [`Error::Cancelled`]. The
+    /// flag is checked between files, so no file is left half-written. Restore again with
+    /// [`Self::resume`] to continue from there.
+    pub fn cancel_flag(mut self, cancel_flag: Arc<AtomicBool>) -> Self {
+        self.cancel_flag = Some(cancel_flag);
+        self
+    }
+
+    /// Sets the compression of a cache read from stdin, which is given as the path `-`, see
+    /// [`DeduperBuilder::stdio_cache_compression`].
+    pub fn stdio_cache_compression(mut self, stdio_cache_compression: CacheCompression) -> Self {
+        self.stdio_cache_compression = stdio_cache_compression;
+        self
+    }
+
+    /// Loads the caches and creates the hydrator.
+    ///
+    /// Fails if a cache file was written by a newer, possibly incompatible version.
+    pub fn try_build(self) -> Result<Hydrator> {
+        let mut cache = self.cache.unwrap_or_else(DedupCache::new);
+        let prefix = self.path_prefix.as_deref();
+        if let Some(prefix) = prefix {
+            cache
+                .files_mut()
+                .retain(|path, _| cache::in_prefix(path, prefix));
+            cache.deleted.retain(|path| cache::in_prefix(path, prefix));
+        }
+        cache.read_from_files(&self.cache_paths, self.stdio_cache_compression, prefix)?;
+
+        let store_layout = self.store_layout.unwrap_or_else(|| cache.store_layout());
+
+        Ok(Hydrator {
+            source_path: self.source_path,
+            resume: self.resume,
+            preserve_mtime: self.preserve_mtime,
+            sparse: self.sparse,
+            remove_deleted: self.remove_deleted,
+            absolute_paths: self.absolute_paths,
+            allow_absolute: self.allow_absolute,
+            prefetch_parallelism: self.prefetch_parallelism,
+            retry_policy: RetryPolicy::new(self.retries),
+            chunk_base_path: self.chunk_base_path,
+            ignore_mtime: self.ignore_mtime,
+            preserve_ownership: self.preserve_ownership,
+            store_layout,
+            cancel_flag: self.cancel_flag,
+            unpacked_store: None,
+            cache,
+        })
+    }
+
+    /// Loads the caches and creates the hydrator.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a cache file was written by a newer, possibly incompatible version. Use
+    /// [`Self::try_build`] to handle this case.
+    pub fn build(self) -> Hydrator {
+        self.try_build().unwrap()
+    }
+}
+
+/// Joins the relative `path` from a cache to `root`, refusing paths that would escape `root`.
+fn join_contained(root: &Path, path: impl AsRef<Path>) -> Result<PathBuf> {
+    let path = path.as_ref();
+    let contained = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !contained {
+        return Err(Error::OutsideTarget {
+            path: path.into(),
+            root: root.to_path_buf(),
+        });
+    }
+
+    Ok(root.join(path))
+}
+
+/// Checks if `target` is a file with the size and, unless `ignore_mtime` is set, the modification
+/// time recorded in the cache.
+fn is_restored(target: &Path, fwc: &FileWithChunks, ignore_mtime: bool) -> bool {
+    target
+        .metadata()
+        .ok()
+        .filter(|metadata| metadata.is_file() && metadata.len() == fwc.size)
+        .is_some_and(|metadata| {
+            ignore_mtime || metadata.modified().is_ok_and(|mtime| mtime == fwc.mtime)
+        })
+}
+
+/// Re-hashes `target` along the cached chunk boundaries and compares the hashes. If the cache has
+/// a hash of the whole file, it is compared instead.
+fn verify_restored(target: &Path, fwc: &FileWithChunks) -> Result<bool> {
+    let file = File::open(target)?;
+
+    if let Some(file_hash) = fwc.file_hash() {
+        let mut hasher = fwc.hashing_algorithm.select_hasher();
+        let mut reader = BufReader::new(file);
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+
+        return Ok(base16ct::lower::encode_string(&hasher.finalize()) == file_hash);
+    }
+
+    let Some(chunks) = fwc.get_chunks() else {
+        return Ok(false);
+    };
+
+    for chunk in chunks.iter().filter(|chunk| chunk.size > 0) {
+        let data = read_at_chunk(&file, chunk.start, chunk.size as usize)?;
+        if data.len() as u64 != chunk.size || fwc.hashing_algorithm.hash_hex(&data) != chunk.hash {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Opens the file of `chunk` in the chunk store and checks its size.
+fn open_chunk(chunk_file: &Path, chunk: &FileChunk) -> Result<File> {
+    let source = File::open(chunk_file).map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound => Error::ChunkMissing {
+            hash: chunk.hash.clone(),
+        },
+        _ => err.into(),
+    })?;
+
+    let actual = source.metadata()?.len();
+    if actual != chunk.size {
+        return Err(Error::ChunkSizeMismatch {
+            hash: chunk.hash.clone(),
+            expected: chunk.size,
+            actual,
+        });
+    }
+
+    Ok(source)
+}
+
+/// Reads the whole file of `chunk` from the chunk store, see [`open_chunk`].
+fn read_chunk(chunk_file: &Path, chunk: &FileChunk) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(chunk.size as usize);
+    open_chunk(chunk_file, chunk)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Chunk file to read by [`ChunkPrefetcher`], or the error to hand out in its place.
+type PrefetchJob = Result<(PathBuf, FileChunk)>;
+
+/// Hash and data of a prefetched chunk.
+type PrefetchResult = Result<(String, Vec<u8>)>;
+
+/// Prefetched chunk data, together with the permit that keeps it within the read-ahead window.
+type PrefetchedChunk = (usize, PrefetchResult, OwnedOpenFilePermit);
+
+/// Reads chunk files in background threads ahead of their use and hands them out in order, see
+/// [`Hydrator::prefetch`]. The threads are stopped and joined on drop.
+struct ChunkPrefetcher {
+    jobs: Arc<Mutex<std::vec::IntoIter<(usize, PrefetchJob)>>>,
+    receiver: Option<mpsc::Receiver<PrefetchedChunk>>,
+    pending: HashMap<usize, (PrefetchResult, OwnedOpenFilePermit)>,
+    next: usize,
+    threads: Vec<std::thread::JoinHandle<()>>,
+}
+
+impl ChunkPrefetcher {
+    /// Starts `parallelism` threads, but at least one, which read the chunk files of `jobs`. At
+    /// most as many chunks as threads are held in memory at once.
+    fn spawn(jobs: Vec<PrefetchJob>, parallelism: usize, retry_policy: RetryPolicy) -> Self {
+        let parallelism = parallelism.max(1);
+        let jobs = Arc::new(Mutex::new(
+            jobs.into_iter().enumerate().collect::<Vec<_>>().into_iter(),
+        ));
+        let window = Arc::new(OpenFilesLimit::new(parallelism));
+        let (sender, receiver) = mpsc::channel();
+
+        let threads = (0..parallelism)
+            .map(|_| {
+                let jobs = Arc::clone(&jobs);
+                let window = Arc::clone(&window);
+                let sender = sender.clone();
+                std::thread::spawn(move || {
+                    loop {
+                        let permit = window.acquire_owned();
+                        let Some((index, job)) = jobs.lock().unwrap().next() else {
+                            break;
+                        };
+
+                        let result = job.and_then(|(chunk_file, chunk)| {
+                            // Empty chunks are never written to the store.
+                            let data = match chunk.size {
+                                0 => Vec::new(),
+                                _ => retry_policy.run(|| read_chunk(&chunk_file, &chunk))?,
+                            };
+                            Ok((chunk.hash, data))
+                        });
+
+                        // The receiver is gone if the prefetcher was dropped.
+                        if sender.send((index, result, permit)).is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            jobs,
+            receiver: Some(receiver),
+            pending: HashMap::new(),
+            next: 0,
+            threads,
+        }
+    }
+}
+
+impl Iterator for ChunkPrefetcher {
+    type Item = PrefetchResult;
+
+    /// Returns the hash and data of the next chunk, waiting for it to be read if necessary.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((result, _permit)) = self.pending.remove(&self.next) {
+                self.next += 1;
+                return Some(result);
+            }
+
+            // Fails once all threads are done, which is after every job was sent.
+            let (index, result, permit) = self.receiver.as_ref()?.recv().ok()?;
+            self.pending.insert(index, (result, permit));
+        }
+    }
+}
+
+impl Drop for ChunkPrefetcher {
+    fn drop(&mut self) {
+        // Releasing the permits wakes up waiting threads, which then find no further jobs.
+        *self.jobs.lock().unwrap() = Vec::new().into_iter();
+        self.pending.clear();
+        self.receiver = None;
+
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Temporary directory a chunk store is unpacked into by [`Hydrator::from_tar_reader`], removed
+/// on drop.
+struct UnpackedStore(PathBuf);
+
+impl UnpackedStore {
+    /// Creates a new, empty directory in the temporary directory of the system.
+    fn create() -> Result<Self> {
+        static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "crazy-deduper-{}.{}.{}",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        // Fails instead of reusing a directory that already exists.
+        std::fs::create_dir(&path)?;
+
+        Ok(Self(path))
+    }
+}
+
+impl Drop for UnpackedStore {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Rebuilds original files from deduplicated chunk storage using a cache.
+pub struct Hydrator {
+    source_path: PathBuf,
+    resume: ResumeMode,
+    preserve_mtime: bool,
+    sparse: bool,
+    remove_deleted: bool,
+    absolute_paths: bool,
+    allow_absolute: bool,
+    prefetch_parallelism: usize,
+    retry_policy: RetryPolicy,
+    chunk_base_path: Option<PathBuf>,
+    ignore_mtime: bool,
+    preserve_ownership: bool,
+    store_layout: StoreLayout,
+    cancel_flag: Option<Arc<AtomicBool>>,
+    /// Temporary chunk store unpacked by [`Hydrator::from_tar_reader`], removed on drop.
+    unpacked_store: Option<UnpackedStore>,
+    pub cache: DedupCache,
+}
+
+impl Hydrator {
+    /// Loads the cache(s) and prepares for hydration.
+    ///
+    /// See [`HydratorBuilder`] for more options.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a cache file was written by a newer, possibly incompatible version, see
+    /// [`HydratorBuilder::try_build`].
+    pub fn new(source_path: impl Into<PathBuf>, cache_paths: Vec<impl Into<PathBuf>>) -> Self {
+        HydratorBuilder::new(source_path)
+            .cache_paths(cache_paths)
+            .build()
+    }
+
+    /// Unpacks a chunk store written by [`Deduper::write_chunks_to_writer`] from `reader` into a
+    /// temporary directory and prepares for hydration from there. The temporary directory is
+    /// removed when the hydrator is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the archive cannot be unpacked or a cache file cannot be read. Use
+    /// [`Self::try_from_tar_reader`] to handle these cases.
+    pub fn from_tar_reader<R: Read>(reader: R, cache_paths: Vec<PathBuf>) -> Self {
+        Self::try_from_tar_reader(reader, cache_paths).unwrap()
+    }
+
+    /// Like [`Self::from_tar_reader`], but returns an error instead of panicking.
+    pub fn try_from_tar_reader<R: Read>(reader: R, cache_paths: Vec<PathBuf>) -> Result<Self> {
+        let unpacked_store = UnpackedStore::create()?;
+        tar::Archive::new(reader).unpack(&unpacked_store.0)?;
+
+        let mut hydrator = HydratorBuilder::new(&unpacked_store.0)
+            .cache_paths(cache_paths)
+            .try_build()?;
+        hydrator.unpacked_store = Some(unpacked_store);
+
+        Ok(hydrator)
+    }
+
+    /// Returns the directory of the chunk store, which is the `data` subdirectory of the source
+    /// directory unless [`HydratorBuilder::chunk_base_path`] or another
+    /// [`StoreLayout::data_dir_name`] is set.
+    fn data_dir(&self) -> PathBuf {
+        self.chunk_base_path
+            .clone()
+            .unwrap_or_else(|| self.source_path.join(&self.store_layout.data_dir_name))
+    }
+
+    /// Restores files into `target_path` by concatenating their chunks. `declutter_levels` must
+    /// match the level used during deduplication.
+    ///
+    /// Depending on the [`ResumeMode`], files that were already restored by an earlier,
+    /// interrupted run are skipped. Failing to set the modification time of a file does not abort
+    /// the restore, the affected files are listed in the report instead. Files recorded as deleted
+    /// are not restored.
+    ///
+    /// Fails with [`Error::ChunkMissing`] or [`Error::ChunkSizeMismatch`] if a chunk file is not
+    /// usable, and with [`Error::OutsideTarget`] if a file would be written outside of
+    /// `target_path`.
+    pub fn restore_files(
+        &self,
+        target_path: impl Into<PathBuf>,
+        declutter_levels: usize,
+    ) -> Result<RestoreReport> {
+        let target_path = self.restore_root(target_path.into())?;
+
+        self.restore_matching(
+            |path| join_contained(&target_path, path),
+            declutter_levels,
+            |_| true,
+        )
+    }
+
+    /// Restores the files of a cache written with several source directories, see
+    /// [`DeduperBuilder::add_source`]. The files of each source directory are restored into the
+    /// entry of `base_targets` with the same index, and it works like [`Self::restore_files`]
+    /// otherwise.
+    ///
+    /// Fails with [`Error::MissingSourceTarget`] if there is no target for a source directory.
+    pub fn restore_all(
+        &self,
+        base_targets: &[PathBuf],
+        declutter_levels: usize,
+    ) -> Result<RestoreReport> {
+        for base_target in base_targets {
+            std::fs::create_dir_all(base_target)?;
+        }
+
+        self.restore_matching(
+            |path| {
+                let (index, relative_path) = split_source_index(Path::new(path))
+                    .filter(|(index, _)| *index < base_targets.len())
+                    .ok_or_else(|| Error::MissingSourceTarget { path: path.into() })?;
+                join_contained(&base_targets[index], relative_path)
+            },
+            declutter_levels,
+            |_| true,
+        )
+    }
+
+    /// Restores all files into a new zip archive at `out_path` instead of a directory, with their
+    /// paths relative to the source directory as entry names. The chunks of each file are
+    /// streamed into its entry, which is compressed with deflate.
+    ///
+    /// The resume, sparse and absolute path settings do not apply to archives. If
+    /// [`HydratorBuilder::preserve_mtime`] is set, the entries get the modification time of the
+    /// original files, within the limits of the zip format: times before 1980 cannot be stored,
+    /// those files are listed in [`RestoreReport::mtime_not_set`].
+    pub fn restore_to_zip(
+        &self,
+        out_path: impl Into<PathBuf>,
+        declutter_levels: usize,
+    ) -> Result<RestoreReport> {
+        let out_path = out_path.into();
+        let mut report = RestoreReport::default();
+
+        let data_dir = self.data_dir();
+
+        // Sorted for a reproducible archive.
+        let mut files = self.cache.values().collect::<Vec<_>>();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut zip = zip::ZipWriter::new(File::create(&out_path)?);
+        for fwc in files {
+            check_cancelled(&self.cancel_flag)?;
+            // Zip entries must not escape the extraction directory either.
+            let name = join_contained(&out_path, &fwc.path)?
+                .strip_prefix(&out_path)
+                .unwrap()
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            let mut options =
+                zip::write::SimpleFileOptions::default().large_file(fwc.size > u32::MAX as u64);
+            if self.preserve_mtime {
+                let mtime = time::OffsetDateTime::from(fwc.mtime);
+                match zip::DateTime::try_from(time::PrimitiveDateTime::new(
+                    mtime.date(),
+                    mtime.time(),
+                )) {
+                    Ok(mtime) => options = options.last_modified_time(mtime),
+                    Err(_) => report.mtime_not_set.push(fwc.path.clone()),
+                }
+            }
+            zip.start_file(name, options)?;
+
+            if fwc.size > 0 {
+                let chunks = fwc.get_chunks().ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("No chunks cached for {}", fwc.path),
+                    )
+                })?;
+                for chunk in chunks {
+                    let chunk_file =
+                        self.store_layout
+                            .chunk_path(&data_dir, chunk, declutter_levels);
+                    let mut source = open_chunk(&chunk_file, chunk)?;
+                    std::io::copy(&mut source, &mut zip)?;
+                }
+            }
+
+            report.restored += 1;
+        }
+        zip.finish()?;
+
+        Ok(report)
+    }
+
+    /// Restores only files whose MIME type matches the glob `mime_pattern`, like `image/*`, and
+    /// works like [`Self::restore_files`] otherwise.
+    ///
+    /// MIME types are only known if they were detected during deduplication, see
+    /// [`DeduperBuilder::detect_mime`]. Files without a MIME type never match. Fails with
+    /// [`Error::InvalidMimePattern`] if the pattern is not a valid glob.
+    pub fn restore_by_mime(
+        &self,
+        target_path: impl Into<PathBuf>,
+        declutter_levels: usize,
+        mime_pattern: &str,
+    ) -> Result<RestoreReport> {
+        let matcher = globset::Glob::new(mime_pattern)?.compile_matcher();
+        let target_path = self.restore_root(target_path.into())?;
+
+        self.restore_matching(
+            |path| join_contained(&target_path, path),
+            declutter_levels,
+            |fwc| {
+                fwc.mime_type
+                    .as_ref()
+                    .is_some_and(|mime_type| matcher.is_match(mime_type))
+            },
+        )
+    }
+
+    /// Restores all files for which `filter` returns `true` to the location returned by
+    /// `target_of` for their path, see [`Self::restore_files`].
+    fn restore_matching(
+        &self,
+        target_of: impl Fn(&str) -> Result<PathBuf>,
+        declutter_levels: usize,
+        filter: impl Fn(&FileWithChunks) -> bool,
+    ) -> Result<RestoreReport> {
+        let mut report = RestoreReport::default();
+
+        let data_dir = self.data_dir();
+
+        // Hashes of all-zero chunks, by hashing algorithm and chunk size.
+        let mut zero_hashes = HashMap::new();
+
+        // Files to restore, with their chunks and whether each chunk is left as a hole. They are
+        // determined up front, so that the chunks can be prefetched.
+        let mut planned = Vec::new();
+
+        for fwc in self.cache.values().filter(|fwc| filter(fwc)) {
+            let target = target_of(&fwc.path)?;
+
+            if self.resume != ResumeMode::Disabled && is_restored(&target, fwc, self.ignore_mtime) {
+                // Without a trustworthy modification time, only the content can tell.
+                if self.resume == ResumeMode::SizeAndMtime && !self.ignore_mtime {
+                    report.skipped += 1;
+                    continue;
+                }
+
+                if verify_restored(&target, fwc).unwrap_or_default() {
+                    report.skipped += 1;
+                    report.reverified += 1;
+                    continue;
+                }
+            }
+
+            // Empty files have no chunk files in the store, creating them is enough.
+            let chunks = match fwc.get_chunks() {
+                _ if fwc.size == 0 => &[][..],
+                Some(chunks) => chunks,
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("No chunks cached for {}", fwc.path),
+                    )
+                    .into());
+                }
+            };
+
+            let chunks = chunks
+                .iter()
+                .map(|chunk| {
+                    let hole = self.sparse && {
+                        let zero_hash = zero_hashes
+                            .entry((fwc.hashing_algorithm, chunk.size))
+                            .or_insert_with(|| {
+                                fwc.hashing_algorithm
+                                    .hash_hex(&vec![0; chunk.size as usize])
+                            });
+                        chunk.hash == *zero_hash
+                    };
+                    (chunk, hole)
+                })
+                .collect::<Vec<_>>();
+
+            planned.push((fwc, target, chunks));
+        }
+
+        let reads = planned
+            .iter()
+            .flat_map(|(_, _, chunks)| chunks)
+            .filter(|(_, hole)| !hole)
+            .map(|(chunk, _)| {
+                self.store_layout
+                    .chunk_path(&data_dir, chunk, declutter_levels)
+            })
+            .collect::<Vec<_>>();
+        let mut prefetcher = (self.prefetch_parallelism > 0).then(|| {
+            let jobs = planned
+                .iter()
+                .flat_map(|(_, _, chunks)| chunks)
+                .filter(|(_, hole)| !hole)
+                .zip(&reads)
+                .map(|((chunk, _), chunk_file)| Ok((chunk_file.clone(), (*chunk).clone())))
+                .collect();
+            ChunkPrefetcher::spawn(jobs, self.prefetch_parallelism, self.retry_policy)
+        });
+        let mut reads = reads.iter();
+
+        for (fwc, target, chunks) in &planned {
+            check_cancelled(&self.cancel_flag)?;
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut target_file = File::create(target)?;
+
+            for (chunk, hole) in chunks {
+                if *hole {
+                    target_file.seek(SeekFrom::Current(chunk.size as i64))?;
+                    continue;
+                }
+
+                // Cannot panic, there is one read for each chunk that is not a hole.
+                let chunk_file = reads.next().unwrap();
+                match &mut prefetcher {
+                    // Cannot panic either, the prefetcher hands out one result for each read.
+                    Some(prefetcher) => target_file.write_all(&prefetcher.next().unwrap()?.1)?,
+                    None => {
+                        let position = target_file.stream_position()?;
+                        self.retry_policy.run(|| {
+                            // Start over if a previous attempt copied part of the chunk.
+                            target_file.seek(SeekFrom::Start(position))?;
+                            let source = open_chunk(chunk_file, chunk)?;
+                            sendfile_copy(&source, &target_file, 0, chunk.size)?;
+                            Ok(())
+                        })?;
+                    }
+                }
+            }
+            if self.sparse {
+                // Skipped zero chunks at the end do not extend the file by themselves.
+                target_file.set_len(fwc.size)?;
+            }
+            if self.preserve_mtime && target_file.set_modified(fwc.mtime).is_err() {
+                report.mtime_not_set.push(fwc.path.clone());
+            }
+            #[cfg(unix)]
+            if self.preserve_ownership
+                && (fwc.uid.is_some() || fwc.gid.is_some())
+                && std::os::unix::fs::fchown(&target_file, fwc.uid, fwc.gid).is_err()
+            {
+                report.ownership_not_set.push(fwc.path.clone());
+            }
+
+            report.restored += 1;
+        }
+
+        if self.remove_deleted {
+            for path in self.cache.deleted_paths() {
+                let target = target_of(path)?;
+                if is_source_file(&target) {
+                    std::fs::remove_file(target)?;
+                    report.removed += 1;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Creates `target_path` and returns the directory the files are restored into, which is
+    /// either `target_path` or, with absolute paths, the source directory recorded in the cache.
+    fn restore_root(&self, target_path: PathBuf) -> Result<PathBuf> {
+        std::fs::create_dir_all(&target_path)?;
+        if !self.absolute_paths {
+            return Ok(target_path);
+        }
+
+        let source_root = self.cache.source_root().ok_or(Error::NoSourceRoot)?;
+        if !self.allow_absolute {
+            let allowed_root = target_path.canonicalize()?;
+            if !source_root.starts_with(&allowed_root) {
+                return Err(Error::OutsideTarget {
+                    path: source_root.to_path_buf(),
+                    root: allowed_root,
+                });
+            }
+        }
+        std::fs::create_dir_all(source_root)?;
+
+        Ok(source_root.to_path_buf())
+    }
+
+    /// Reads the chunks with the given hashes from the chunk store in background threads and
+    /// returns their data in the order of `hashes`. This hides the latency of chunk stores on
+    /// network or cloud storage. The threads are stopped when the iterator is dropped.
+    ///
+    /// [`HydratorBuilder::prefetch_parallelism`] chunks, but at least one, are read at the same
+    /// time, and at most as many are held in memory until they are consumed. Hashes that are not
+    /// referenced by the cache result in [`Error::ChunkMissing`].
+    pub fn prefetch(
+        &self,
+        hashes: impl Iterator<Item = String>,
+        declutter_levels: usize,
+    ) -> impl Iterator<Item = Result<(String, Vec<u8>)>> {
+        let data_dir = self.data_dir();
+
+        let chunks = self
+            .cache
+            .values()
+            .filter_map(FileWithChunks::get_chunks)
+            .flatten()
+            .map(|chunk| (chunk.hash.as_str(), chunk))
+            .collect::<HashMap<_, _>>();
+        let jobs = hashes
+            .map(|hash| match chunks.get(hash.as_str()) {
+                Some(&chunk) => Ok((
+                    self.store_layout
+                        .chunk_path(&data_dir, chunk, declutter_levels),
+                    chunk.clone(),
+                )),
+                None => Err(Error::ChunkMissing { hash }),
+            })
+            .collect::<Vec<_>>();
+
+        ChunkPrefetcher::spawn(jobs, self.prefetch_parallelism, self.retry_policy)
+    }
+
+    /// Compares the chunk store with the one in `other_data_dir`, like a replica, which must use
+    /// the same [`StoreLayout`] and `declutter_levels`.
+    ///
+    /// Only chunks referenced by the cache are compared. A chunk counts as present if its file
+    /// exists with the expected size, see [`Self::check_cache_verbose`]. Chunks missing from both
+    /// stores are not part of the difference.
+    ///
+    /// Fails if the chunks of a cached file cannot be calculated, see [`DedupCache::get_chunks`].
+    pub fn compare_store(
+        &self,
+        other_data_dir: impl Into<PathBuf>,
+        declutter_levels: usize,
+    ) -> Result<StoreDiff> {
+        let here = self.check_chunks(declutter_levels)?;
+        let there = self.check_chunks_in(other_data_dir.into(), declutter_levels)?;
+
+        let mut diff = StoreDiff::default();
+        for (here, there) in here.zip(there) {
+            let present_here = matches!(here, CacheCheckResult::Ok { .. });
+            let present_there = matches!(there, CacheCheckResult::Ok { .. });
+            match (present_here, present_there) {
+                (true, false) => diff.missing_there.push(here.hash().to_string()),
+                (false, true) => diff.missing_here.push(here.hash().to_string()),
+                _ => {}
+            }
+        }
+
+        Ok(diff)
+    }
+
+    /// Checks all chunks referenced by the cache, yielding one result per unique chunk.
+    ///
+    /// Empty chunks are never written to the store, so they are never reported as missing.
+    fn check_chunks(
+        &self,
+        declutter_levels: usize,
+    ) -> Result<impl Iterator<Item = CacheCheckResult>> {
+        self.check_chunks_in(self.data_dir(), declutter_levels)
+    }
+
+    /// Like [`Self::check_chunks`], but for the chunk store in `data_dir`. The results are sorted
+    /// by hash and size.
+    fn check_chunks_in(
+        &self,
+        data_dir: PathBuf,
+        declutter_levels: usize,
+    ) -> Result<impl Iterator<Item = CacheCheckResult>> {
+        let mut hashes_and_chunks = Vec::new();
+        for item in self.cache.get_chunks()? {
+            let (hash, chunk, _) = item?;
+            if chunk.size > 0 {
+                hashes_and_chunks.push((hash, chunk));
+            }
+        }
+        hashes_and_chunks.sort_by(|a, b| (&a.0, a.1.size).cmp(&(&b.0, b.1.size)));
+        hashes_and_chunks.dedup_by(|a, b| a.0 == b.0 && a.1.size == b.1.size);
+
+        Ok(hashes_and_chunks
+            .into_iter()
+            .map(move |(_, chunk)| {
+                (
+                    self.store_layout
+                        .chunk_path(&data_dir, &chunk, declutter_levels),
+                    chunk,
+                )
+            })
+            .map(|(path, chunk)| {
+                let hash = chunk.hash;
+                match path.metadata() {
+                    Err(_) => CacheCheckResult::Missing { hash, path },
+                    Ok(metadata) if metadata.len() != chunk.size => {
+                        CacheCheckResult::SizeMismatch {
+                            hash,
+                            path,
+                            expected: chunk.size,
+                            actual: metadata.len(),
+                        }
+                    }
+                    Ok(_) => CacheCheckResult::Ok { hash },
+                }
+            }))
+    }
+
+    /// List missing chunks or chunks with wrong size.
+    ///
+    /// Empty chunks are never written to the store, so they are never reported as missing.
+    ///
+    /// Fails if the chunks of a cached file cannot be calculated, see [`DedupCache::get_chunks`].
+    pub fn list_missing_chunks(
+        &self,
+        declutter_levels: usize,
+    ) -> Result<impl Iterator<Item = (PathBuf, String)>> {
+        Ok(self
+            .check_chunks(declutter_levels)?
+            .filter_map(|result| match result {
+                CacheCheckResult::Ok { .. } => None,
+                CacheCheckResult::Missing { path, .. } => {
+                    Some((path, "Does not exist".to_string()))
+                }
+                CacheCheckResult::SizeMismatch { path, expected, .. } => {
+                    Some((path, format!("Does not have expected size of {expected}")))
+                }
+            }))
+    }
+
+    /// Checks all chunk files listed in the cache and returns one result per unique chunk, so
+    /// that callers can report problems as they see fit.
+    ///
+    /// Fails if the chunks of a cached file cannot be calculated, see [`DedupCache::get_chunks`].
+    pub fn check_cache_verbose(&self, declutter_levels: usize) -> Result<Vec<CacheCheckResult>> {
+        Ok(self.check_chunks(declutter_levels)?.collect())
+    }
+
+    /// Check if all chunk files listed in the cache are present in source directory. Also returns
+    /// `false` if the chunks of a cached file cannot be calculated.
+    pub fn check_cache(&self, declutter_levels: usize) -> bool {
+        self.check_cache_verbose(declutter_levels)
+            .is_ok_and(|results| {
+                results
+                    .iter()
+                    .all(|result| matches!(result, CacheCheckResult::Ok { .. }))
+            })
+    }
+
+    /// Reports the space efficiency of the chunk store, by comparing the sizes of all files in the
+    /// cache with the sizes of the chunk files they reference. Each chunk file is counted once.
+    ///
+    /// Fails if a chunk file is missing or has the wrong size, see [`Hydrator::check_cache`].
+    pub fn dedup_report(&self, declutter_levels: usize) -> Result<DedupReport> {
+        let data_dir = self.data_dir();
+
+        let total_file_bytes = self.cache.values().map(|fwc| fwc.size).sum::<u64>();
+
+        let mut chunks = HashMap::new();
+        for item in self.cache.get_chunks()? {
+            let (_, chunk, _) = item?;
+            if chunk.size > 0 {
+                let chunk_file = self
+                    .store_layout
+                    .chunk_path(&data_dir, &chunk, declutter_levels);
+                chunks.entry(chunk_file).or_insert(chunk);
+            }
+        }
+
+        let unique_chunks = chunks.len();
+        let mut unique_chunk_bytes = 0;
+        for (
+            chunk_file,
+            FileChunk {
+                hash,
+                size: expected,
+                ..
+            },
+        ) in chunks
+        {
+            let actual = match chunk_file.metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(Error::ChunkMissing { hash });
+                }
+                Err(err) => return Err(err.into()),
+            };
+            if actual != expected {
+                return Err(Error::ChunkSizeMismatch {
+                    hash,
+                    expected,
+                    actual,
+                });
+            }
+            unique_chunk_bytes += actual;
+        }
+
+        Ok(DedupReport::new(
+            total_file_bytes,
+            unique_chunks,
+            unique_chunk_bytes,
+        ))
+    }
+
+    /// Measures the chunk store on disk, without consulting the cache. Unlike
+    /// [`Self::dedup_report`], this also includes chunk files that are not referenced by the
+    /// cache anymore.
+    ///
+    /// Files at the depth given by `declutter_levels` are counted as chunks, and every file and
+    /// directory below the data directory as an inode.
+    pub fn chunk_store_stats(&self, declutter_levels: usize) -> Result<ChunkStoreStats> {
+        let mut stats = ChunkStoreStats::default();
+
+        for entry in WalkDir::new(self.data_dir()).min_depth(1) {
+            let entry = entry.map_err(std::io::Error::from)?;
+            stats.inode_count += 1;
+            if entry.file_type().is_file() && entry.depth() == declutter_levels + 1 {
+                stats.chunk_count += 1;
+                stats.total_bytes += entry.metadata().map_err(std::io::Error::from)?.len();
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// List files in source directory that are not listed in cache.
+    ///
+    /// If the chunks of some cached files are not computed yet, they are calculated from the
+    /// original files, which must then be available. Use [`Hydrator::list_extra_files_partial`]
+    /// to avoid that. Fails if they cannot be calculated, see [`DedupCache::get_chunks`].
+    pub fn list_extra_files(
+        &self,
+        declutter_levels: usize,
+    ) -> Result<impl Iterator<Item = PathBuf>> {
+        let data_dir = self.data_dir();
+
+        let files_in_cache = if self.cache.values().all(|fwc| fwc.get_chunks().is_some()) {
+            self.computed_chunk_paths(&data_dir, declutter_levels).0
+        } else {
+            let mut files_in_cache = HashSet::new();
+            for item in self.cache.get_chunks()? {
+                let (_, chunk, _) = item?;
+                if chunk.size > 0 {
+                    files_in_cache.insert(self.store_layout.chunk_path(
+                        &data_dir,
+                        &chunk,
+                        declutter_levels,
+                    ));
+                }
+            }
+            files_in_cache
+        };
+
+        Ok(Self::files_not_in(data_dir, files_in_cache))
+    }
+
+    /// Like [`Hydrator::list_extra_files`], but only uses chunks that are already computed, so no
+    /// file is hashed. This allows to audit a chunk store without access to the original files,
+    /// for example after a failed dedup run.
+    ///
+    /// Returns the extra files and the paths of the cached files whose chunks were not computed.
+    /// Chunk files that only belong to the latter are listed as extra files as well.
+    pub fn list_extra_files_partial(&self, declutter_levels: usize) -> (Vec<PathBuf>, Vec<String>) {
+        let data_dir = self.data_dir();
+
+        let (files_in_cache, mut skipped) = self.computed_chunk_paths(&data_dir, declutter_levels);
+        skipped.sort();
+
+        (
+            Self::files_not_in(data_dir, files_in_cache).collect(),
+            skipped,
+        )
+    }
+
+    /// Collects the paths of all chunk files already computed, and the cached files without
+    /// computed chunks.
+    fn computed_chunk_paths(
+        &self,
+        data_dir: &Path,
+        declutter_levels: usize,
+    ) -> (HashSet<PathBuf>, Vec<String>) {
+        let mut files_in_cache = HashSet::new();
+        let mut skipped = Vec::new();
+
+        for fwc in self.cache.values() {
+            match fwc.get_chunks() {
+                Some(chunks) => files_in_cache.extend(
+                    chunks.iter().filter(|chunk| chunk.size > 0).map(|chunk| {
+                        self.store_layout
+                            .chunk_path(data_dir, chunk, declutter_levels)
+                    }),
+                ),
+                None => skipped.push(fwc.path.clone()),
+            }
+        }
+
+        (files_in_cache, skipped)
+    }
+
+    fn files_not_in(
+        data_dir: PathBuf,
+        files_in_cache: HashSet<PathBuf>,
+    ) -> impl Iterator<Item = PathBuf> {
+        WalkDir::new(data_dir)
+            .min_depth(1)
+            .same_file_system(false)
+            .into_iter()
+            .filter(move |entry| {
+                entry
+                    .as_ref()
+                    .map(|entry| {
+                        entry.file_type().is_file() && !files_in_cache.contains(entry.path())
+                    })
+                    .unwrap_or_default()
+            })
+            .flatten()
+            .map(|entry| entry.into_path())
+    }
+
+    /// Delete files in source directory that are not listed in cache.
+    pub fn delete_extra_files(&self, declutter_levels: usize) -> anyhow::Result<()> {
+        for path in self.list_extra_files(declutter_levels)? {
+            std::fs::remove_file(&path)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use assert_fs::fixture::ChildPath;
+    use assert_fs::prelude::*;
+    use assert_fs::{NamedTempFile, TempDir};
+
+    use super::*;
+
+    fn setup() -> anyhow::Result<(TempDir, ChildPath, ChildPath, ChildPath)> {
+        let temp = TempDir::new()?;
+
+        let origin = temp.child("origin");
+        origin.create_dir_all()?;
+        origin.child("README.md").write_str("Hello, world!")?;
+
+        let deduped = temp.child("deduped");
+        deduped.create_dir_all()?;
+
+        let cache = temp.child("cache.json");
+
+        {
+            let mut deduper = Deduper::new(
+                origin.to_path_buf(),
+                vec![cache.to_path_buf()],
+                HashingAlgorithm::MD5,
+                true,
+            );
+            deduper.write_chunks(deduped.to_path_buf(), 3)?;
+            deduper.write_cache()?;
+        }
+
+        Ok((temp, origin, deduped, cache))
+    }
+
+    #[test]
+    fn compare_filechunk_objects() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+
+        let file_1 = temp.child("file_1");
+        std::fs::write(&file_1, "content_1")?;
+
+        let file_2 = temp.child("file_2");
+        std::fs::write(&file_2, "content_2")?;
+
+        let fwc_1 = FileWithChunks::try_new(temp.path(), file_1.path(), HashingAlgorithm::MD5)?;
+        let fwc_1_same =
+            FileWithChunks::try_new(temp.path(), file_1.path(), HashingAlgorithm::MD5)?;
+        let fwc_2 = FileWithChunks::try_new(temp.path(), file_2.path(), HashingAlgorithm::MD5)?;
+
+        assert_eq!(fwc_1, fwc_1);
+        assert_eq!(fwc_1, fwc_1_same);
+        assert_ne!(fwc_1, fwc_2);
+
+        // Some operating systems like Windows need write access to the file to be able to set the
+        // modified time.
+        OpenOptions::new()
+            .write(true)
+            .open(&file_1)?
+            .set_modified(SystemTime::now())?;
+
+        let fwc_1_new = FileWithChunks::try_new(temp.path(), file_1.path(), HashingAlgorithm::MD5)?;
+
+        assert_ne!(fwc_1, fwc_1_new);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_all_hashing_algorithms() -> anyhow::Result<()> {
+        let algorithms = &[
+            (HashingAlgorithm::MD5, "0fb073cd346f46f60c15e719f3820482"),
+            (
+                HashingAlgorithm::SHA1,
+                "5503f5edc1bba66a7733c5ec38f4e9d449021be9",
+            ),
+            (
+                HashingAlgorithm::SHA256,
+                "e8c73ac958a87f17906b092bd99f37038788ee23b271574aad6d5bf1c76cc61c",
+            ),
+            (
+                HashingAlgorithm::SHA512,
+                "e6eda213df25f96ca380dd07640df530574e380c1b93d5d863fec05d5908a4880a3075fef4a438cfb1023cc51affb4624002f54b4790fe8362c7de032eb39aaa",
+            ),
+            (HashingAlgorithm::CRC32, "0409b44b"),
+        ];
+
+        let temp = TempDir::new()?;
+        let file = temp.child("file");
+        std::fs::write(&file, "hello rust")?;
+
+        for (algorithm, expected_hash) in algorithms.iter().copied() {
+            let cache_file = NamedTempFile::new("cache.json")?;
+
+            let chunks = Deduper::new(temp.path(), vec![cache_file.path()], algorithm, true)
+                .cache
+                .get_chunks()?
+                .collect::<Result<Vec<_>>>()?;
+
+            assert_eq!(chunks.len(), 1, "Too many chunks");
+
+            let (hash, _, _) = &chunks[0];
+            assert_eq!(
+                hash, &expected_hash,
+                "Algorithm {:?} does not produce expected hash",
+                algorithm
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_crc32_round_trip() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("a").write_str("hello rust")?;
+        origin
+            .child("b")
+            .write_binary(&vec![7; 3 * 1024 * 1024 + 5])?;
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .hashing_algorithm(HashingAlgorithm::CRC32)
+            .build();
+        deduper.write_chunks(deduped.path(), 0)?;
+        deduper.write_cache()?;
+
+        let names = std::fs::read_dir(deduped.child("data"))?
+            .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        // The identical 1 MiB chunks of `b` are deduplicated.
+        assert_eq!(names.len(), 3);
+        assert!(names.iter().all(|name| name.len() == 8));
+        assert!(names.contains(&"0409b44b".to_string()));
+
+        let restored = temp.child("restored");
+        HydratorBuilder::new(deduped.path())
+            .cache_paths(vec![cache.path()])
+            .build()
+            .restore_files(restored.path(), 0)?;
+        restored.child("a").assert("hello rust");
+        assert_eq!(
+            std::fs::read(restored.child("b"))?,
+            std::fs::read(origin.child("b"))?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_hashing_algorithm_names() {
+        for algorithm in HashingAlgorithm::ALL {
+            let name = algorithm.to_string();
+            assert_eq!(name, name.to_lowercase());
+            assert_eq!(name.parse::<HashingAlgorithm>().unwrap(), algorithm);
+            assert_eq!(
+                name.to_uppercase().parse::<HashingAlgorithm>().unwrap(),
+                algorithm
+            );
+        }
+
+        assert!(matches!(
+            "sha3".parse::<HashingAlgorithm>(),
+            Err(Error::UnknownHashingAlgorithm(name)) if name == "sha3"
+        ));
+    }
+
+    #[test]
+    fn check_path_separators() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let cache = temp.child("cache.json");
+        origin.child("dir/sub/file").write_str("Hello, world!")?;
+
+        let deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .cache_format_version(CacheVersion::V0)
+            .build();
+        assert!(deduper.cache.contains_key("dir/sub/file"));
+        deduper.write_cache()?;
+
+        // The version 0 format stores the keys as they are.
+        assert!(std::fs::read_to_string(&cache)?.contains(r#""path":"dir/sub/file""#));
+
+        let deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .build();
+        deduper.write_cache()?;
+
+        let mut dedup_cache = DedupCache::new();
+        dedup_cache.read_from_file(&cache)?;
+        assert_eq!(
+            dedup_cache.paths().collect::<Vec<_>>(),
+            vec!["dir/sub/file"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_normalize_paths() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let cache = temp.child("cache.json");
+
+        let nfc = "caf\u{e9}";
+        let nfd = "cafe\u{301}";
+        origin.child(nfd).write_str("Hello, world!")?;
+        origin.child("other").write_str("Other")?;
+
+        // A cache written with decomposed paths, as on HFS+.
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .build();
+        deduper.write_chunks(temp.child("deduped").path(), 0)?;
+        deduper.write_cache()?;
+        assert!(deduper.cache.contains_key(nfd));
+
+        for _ in 0..2 {
+            let deduper = DeduperBuilder::new(origin.path())
+                .cache_paths(vec![cache.path()])
+                .normalize_paths(NormalizationForm::NFC)
+                .build();
+            assert_eq!(deduper.cache.len(), 2);
+            // The cached chunks are re-used.
+            assert!(deduper.cache.get(nfc).unwrap().get_chunks().is_some());
+            deduper.write_cache()?;
+        }
+
+        std::fs::remove_file(origin.child(nfd))?;
+        let deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .normalize_paths(NormalizationForm::NFC)
+            .build();
+        assert_eq!(
+            deduper
+                .cache
+                .values()
+                .map(|fwc| fwc.path.as_str())
+                .collect::<Vec<_>>(),
+            vec!["other"]
+        );
+
+        assert_eq!("NFD".parse::<NormalizationForm>()?, NormalizationForm::NFD);
+        assert_eq!(NormalizationForm::NFD.normalize(nfc), nfd);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn check_source_encoding() -> anyhow::Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let cache = temp.child("cache.json");
+
+        // "café/menü" in Latin-1, which is invalid UTF-8.
+        let dir = origin.join(std::ffi::OsStr::from_bytes(b"caf\xe9"));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(
+            dir.join(std::ffi::OsStr::from_bytes(b"men\xfc")),
+            "Hello, world!",
+        )?;
+
+        let deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .build();
+        assert_eq!(
+            deduper.cache.files.keys().collect::<Vec<_>>(),
+            vec!["caf\u{FFFD}/men\u{FFFD}"]
+        );
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .source_encoding(encoding_rs::WINDOWS_1252)
+            .build();
+        assert_eq!(
+            deduper.cache.files.keys().collect::<Vec<_>>(),
+            vec!["café/menü"]
+        );
+        deduper.write_chunks(temp.child("deduped").path(), 0)?;
+        deduper.write_cache()?;
+
+        // The decoded cache keys are mapped back to the source files, so they are not re-hashed.
+        let deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .source_encoding(encoding_rs::WINDOWS_1252)
+            .build();
+        assert!(
+            deduper
+                .cache
+                .get("café/menü")
+                .unwrap()
+                .get_chunks()
+                .is_some()
+        );
+
+        Hydrator::new(temp.child("deduped").path(), vec![cache.path()])
+            .restore_files(temp.child("restored").path(), 0)?;
+        temp.child("restored/café/menü")
+            .assert(predicates::str::contains("Hello, world!"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_duplicate_files() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+
+        let large = (0..u8::MAX)
+            .cycle()
+            .take(FIXED_CHUNK_SIZE as usize * 3 / 2)
+            .collect::<Vec<_>>();
+        origin.child("large").write_binary(&large)?;
+        origin.child("sub/large").write_binary(&large)?;
+        origin.child("b").write_str("Hello, world!")?;
+        origin.child("a").write_str("Hello, world!")?;
+        origin.child("c").write_str("Hello, world!")?;
+        origin.child("unique").write_str("Unique")?;
+        origin.child("empty-1").touch()?;
+        origin.child("empty-2").touch()?;
+
+        // Only the first chunk is shared with the large files.
+        let mut prefix = large[..FIXED_CHUNK_SIZE as usize].to_vec();
+        prefix.extend_from_slice(b"different tail");
+        origin.child("prefix").write_binary(&prefix)?;
+
+        // Modification times are not considered.
+        OpenOptions::new()
+            .write(true)
+            .open(origin.child("c"))?
+            .set_modified(SystemTime::UNIX_EPOCH)?;
+
+        let deduper = DeduperBuilder::new(origin.path()).build();
+        assert_eq!(
+            deduper.duplicate_files()?,
+            vec![vec!["a", "b", "c"], vec!["large", "sub/large"]]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_file_hashes() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let cache = temp.child("cache.json");
+
+        let large = (0..u8::MAX)
+            .cycle()
+            .take(FIXED_CHUNK_SIZE as usize * 5 / 2)
+            .collect::<Vec<_>>();
+        origin.child("large").write_binary(&large)?;
+        origin.child("copy").write_binary(&large)?;
+        origin.child("small").write_str("Hello, world!")?;
+        origin.child("empty").touch()?;
+
+        // Without the option, no whole-file hashes are recorded.
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .build();
+        deduper.write_chunks(temp.child("deduped").path(), 0)?;
+        deduper.write_cache()?;
+        assert!(deduper.cache.values().all(|fwc| fwc.file_hash().is_none()));
+
+        for chunking in [
+            Chunking::Fixed,
+            Chunking::ContentDefined {
+                min_size: 64 * 1024,
+                avg_size: 256 * 1024,
+                max_size: 1024 * 1024,
+            },
+        ] {
+            // Existing entries without whole-file hash are re-hashed.
+            let mut deduper = DeduperBuilder::new(origin.path())
+                .cache_paths(vec![cache.path()])
+                .chunking(chunking)
+                .file_hashes(true)
+                .build();
+            deduper.write_chunks(temp.child("deduped").path(), 0)?;
+            deduper.write_cache()?;
+
+            let mut dedup_cache = DedupCache::new();
+            dedup_cache.read_from_file(&cache)?;
+            let file_hash = |path| dedup_cache.get(path).unwrap().file_hash();
+            let large_hash = HashingAlgorithm::SHA1.hash_hex(&large);
+            assert_eq!(file_hash("large"), Some(large_hash.as_str()));
+            assert_eq!(
+                file_hash("small"),
+                Some(HashingAlgorithm::SHA1.hash_hex(b"Hello, world!").as_str())
+            );
+            assert_eq!(
+                file_hash("empty"),
+                Some(HashingAlgorithm::SHA1.hash_hex(b"").as_str())
+            );
+
+            let mut duplicates = dedup_cache
+                .find_by_file_hash(&large_hash)
+                .map(|fwc| fwc.path.as_str())
+                .collect::<Vec<_>>();
+            duplicates.sort();
+            assert_eq!(duplicates, vec!["copy", "large"]);
+
+            std::fs::remove_file(&cache)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_stream_chunks() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let content = (0..u8::MAX)
+            .cycle()
+            .take(FIXED_CHUNK_SIZE as usize * 9 / 2)
+            .collect::<Vec<_>>();
+        origin.child("file").write_binary(&content)?;
+        origin.child("empty").touch()?;
+
+        for chunking in [
+            Chunking::Fixed,
+            Chunking::ContentDefined {
+                min_size: 64 * 1024,
+                avg_size: 256 * 1024,
+                max_size: 1024 * 1024,
+            },
+        ] {
+            let deduper = DeduperBuilder::new(origin.path())
+                .chunking(chunking)
+                .try_build()?;
+
+            for path in ["file", "empty"] {
+                let fwc = deduper.cache.get(path).unwrap();
+                let streamed = fwc.stream_chunks(2)?.collect::<Result<Vec<_>>>()?;
+                assert!(fwc.get_chunks().is_none(), "Streamed chunks are not stored");
+
+                let calculated = fwc.get_or_calculate_chunks()?;
+                assert_eq!(streamed.len(), calculated.len());
+                for (streamed, calculated) in streamed.iter().zip(calculated) {
+                    assert_eq!(
+                        (streamed.start, streamed.size, &streamed.hash),
+                        (calculated.start, calculated.size, &calculated.hash)
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn check_custom_thread_pool() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let content = (0..u8::MAX)
+            .cycle()
+            .take(FIXED_CHUNK_SIZE as usize * 7 / 2)
+            .collect::<Vec<_>>();
+        origin.child("file").write_binary(&content)?;
+
+        let chunks = |deduper: Deduper| -> anyhow::Result<Vec<(String, u64, u64)>> {
+            let fwc = deduper.cache.get("file").unwrap();
+            Ok(fwc
+                .get_or_calculate_chunks()?
+                .iter()
+                .map(|chunk| (chunk.hash.clone(), chunk.start, chunk.size))
+                .collect())
+        };
+
+        let thread_pool = rayon::ThreadPoolBuilder::new().num_threads(2).build()?;
+        let with_pool = chunks(
+            DeduperBuilder::new(origin.path())
+                .thread_pool(Arc::new(thread_pool))
+                .file_hashes(true)
+                .try_build()?,
+        )?;
+        let without_pool = chunks(DeduperBuilder::new(origin.path()).try_build()?)?;
+
+        assert_eq!(with_pool.len(), 4);
+        assert_eq!(with_pool, without_pool);
+        assert_eq!(
+            with_pool[3],
+            (
+                HashingAlgorithm::SHA1.hash_hex(&content[3 * FIXED_CHUNK_SIZE as usize..]),
+                3 * FIXED_CHUNK_SIZE,
+                FIXED_CHUNK_SIZE / 2
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_cache() -> anyhow::Result<()> {
+        let (_temp, _origin, deduped, cache) = setup()?;
+
+        assert!(
+            Hydrator::new(deduped.to_path_buf(), vec![cache.to_path_buf()]).check_cache(3),
+            "Cache checking failed when it shouldn't"
+        );
+
+        std::fs::remove_dir_all(deduped.child("data").read_dir()?.next().unwrap()?.path())?;
+
+        assert!(
+            !Hydrator::new(deduped.to_path_buf(), vec![cache.to_path_buf()]).check_cache(3),
+            "Cache checking didn't fail when it should"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_chunk_path() {
+        let data_dir = Path::new("data");
+
+        assert_eq!(
+            chunk_path(data_dir, "abcdef", 0, 1),
+            data_dir.join("abcdef")
+        );
+        assert_eq!(
+            chunk_path(data_dir, "abcdef", 2, 1),
+            data_dir.join("a").join("b").join("abcdef")
+        );
+        assert_eq!(
+            chunk_path(data_dir, "abcdef", 2, 2),
+            data_dir.join("ab").join("cd").join("abcdef")
+        );
+        assert_eq!(
+            chunk_path(data_dir, "abc", 3, 2),
+            data_dir.join("ab").join("c").join("abc")
+        );
+    }
+
+    #[test]
+    fn check_declutter_segment_length_from_cache() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+
+        let origin = temp.child("origin");
+        origin.child("README.md").write_str("Hello, world!")?;
+
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .hashing_algorithm(HashingAlgorithm::MD5)
+            .declutter_segment_length(2)
+            .build();
+        deduper.write_chunks(deduped.path(), 2)?;
+        deduper.write_cache()?;
+
+        // Hash of "Hello, world!" is 6cd3556deb0da54bca060b4c39479839.
+        deduped
+            .child("data/6c/d3/6cd3556deb0da54bca060b4c39479839")
+            .assert(predicates::path::is_file());
+
+        let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+        assert_eq!(hydrator.cache.declutter_segment_length(), 2);
+        assert!(hydrator.check_cache(2), "Cache checking failed");
+        assert_eq!(hydrator.list_extra_files(2)?.count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_hash_includes_size() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+
+        let origin = temp.child("origin");
+        origin.child("README.md").write_str("Hello, world!")?;
+        origin.child("copy.md").write_str("Hello, world!")?;
+
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .hashing_algorithm(HashingAlgorithm::MD5)
+            .hash_includes_size(true)
+            .build();
+        deduper.write_chunks(deduped.path(), 2)?;
+        deduper.write_cache()?;
+
+        // Hash of "Hello, world!" is 6cd3556deb0da54bca060b4c39479839, stored only once.
+        deduped
+            .child("data/6/c/6cd3556deb0da54bca060b4c39479839-13")
+            .assert(predicates::path::is_file());
+        assert_eq!(std::fs::read_dir(deduped.child("data/6/c"))?.count(), 1);
+
+        let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+        assert!(hydrator.cache.hash_includes_size());
+        assert!(hydrator.check_cache(2), "Cache checking failed");
+        assert_eq!(hydrator.list_extra_files(2)?.count(), 0);
+        assert_eq!(hydrator.dedup_report(2)?.unique_chunk_bytes, 13);
+
+        hydrator.restore_files(temp.child("restored").path(), 2)?;
+        temp.child("restored/copy.md").assert("Hello, world!");
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_hash_includes_size_same_hash() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+
+        let origin = temp.child("origin");
+        origin.child("short").write_str("short")?;
+        origin.child("longer").write_str("longer")?;
+
+        // Both chunks claim the same hash, like with a hash collision, so that only their size
+        // tells them apart.
+        let hash = "0".repeat(32);
+        let files = ["short", "longer"].map(|path| -> anyhow::Result<_> {
+            let metadata = origin.child(path).metadata()?;
+            Ok(FileWithChunks::from_chunks(
+                path,
+                metadata.len(),
+                metadata.modified()?,
+                HashingAlgorithm::MD5,
+                vec![FileChunk::new(0, metadata.len(), hash.clone())],
+            ))
+        });
+        let cache = temp.child("cache.json");
+        DedupCacheBuilder::new()
+            .files(files.into_iter().collect::<anyhow::Result<Vec<_>>>()?)
+            .build()
+            .write_to_file(cache.path())?;
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .hashing_algorithm(HashingAlgorithm::MD5)
+            .hash_includes_size(true)
+            .try_build()?;
+
+        let mut missing = deduper.missing_chunks(&HashSet::new())?;
+        missing.sort_by_key(|(.., size)| *size);
+        assert_eq!(
+            missing
+                .iter()
+                .map(|(hash, _, start, size)| (hash.as_str(), *start, *size))
+                .collect::<Vec<_>>(),
+            vec![(hash.as_str(), 0, 5), (hash.as_str(), 0, 6)]
+        );
+
+        let deduped = temp.child("deduped");
+        let stats = deduper.write_chunks_two_pass(deduped.path(), 0)?;
+        assert_eq!((stats.written, stats.deduped), (2, 0));
+        deduped.child(format!("data/{hash}-5")).assert("short");
+        deduped.child(format!("data/{hash}-6")).assert("longer");
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_hash_includes_size_needs_cache_version_2() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+
+        let origin = temp.child("origin");
+        origin.child("README.md").write_str("Hello, world!")?;
+        let cache = temp.child("cache.json");
+
+        let build = |cache_format_version| {
+            DeduperBuilder::new(origin.path())
+                .cache_paths(vec![cache.path()])
+                .hash_includes_size(true)
+                .cache_format_version(cache_format_version)
+                .try_build()
+        };
+        for version in [CacheVersion::V0, CacheVersion::V1] {
+            assert!(matches!(
+                build(version),
+                Err(Error::UnsupportedCacheVersion { version: v, .. }) if v == version
+            ));
+        }
+
+        let mut deduper = build(CacheVersion::V2)?;
+        deduper.write_chunks(temp.child("deduped").path(), 0)?;
+        for version in [CacheVersion::V0, CacheVersion::V1] {
+            assert!(matches!(
+                deduper
+                    .cache
+                    .write_to_file_with_version(temp.child("old.json").path(), version),
+                Err(Error::UnsupportedCacheVersion { .. })
+            ));
+        }
+        temp.child("old.json").assert(predicates::path::missing());
+        deduper.write_cache()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_store_layout() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+
+        let origin = temp.child("origin");
+        origin.child("README.md").write_str("Hello, world!")?;
+
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let layout = StoreLayout {
+            data_dir_name: "chunks".into(),
+            declutter_segment_length: 2,
+            hash_includes_size: true,
+        };
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .hashing_algorithm(HashingAlgorithm::MD5)
+            .store_layout(layout.clone())
+            .build();
+        deduper.write_chunks(deduped.path(), 1)?;
+        deduper.write_cache()?;
+
+        // Hash of "Hello, world!" is 6cd3556deb0da54bca060b4c39479839.
+        let chunk = "6cd3556deb0da54bca060b4c39479839-13";
+        deduped
+            .child("chunks/6c")
+            .child(chunk)
+            .assert(predicates::path::is_file());
+        deduped.child("data").assert(predicates::path::missing());
+
+        let hydrator = HydratorBuilder::new(deduped.path())
+            .cache_paths(vec![cache.path()])
+            .store_layout(layout)
+            .build();
+        assert!(hydrator.check_cache(1), "Cache checking failed");
+
+        // The cache records everything but the data directory name.
+        let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+        assert_eq!(
+            hydrator.cache.store_layout(),
+            StoreLayout {
+                declutter_segment_length: 2,
+                hash_includes_size: true,
+                ..Default::default()
+            }
+        );
+
+        // Reorganize the store into a flat one and read it with its own layout.
+        deduped.child("flat").create_dir_all()?;
+        std::fs::rename(
+            deduped.child("chunks/6c").child(chunk),
+            deduped.child("flat").child(chunk),
+        )?;
+        let hydrator = HydratorBuilder::new(deduped.path())
+            .cache_paths(vec![cache.path()])
+            .store_layout(StoreLayout {
+                data_dir_name: "flat".into(),
+                declutter_segment_length: 1,
+                hash_includes_size: true,
+            })
+            .build();
+        hydrator.restore_files(temp.child("restored").path(), 0)?;
+        temp.child("restored/README.md").assert("Hello, world!");
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_write_chunks_to_writer() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+
+        let origin = temp.child("origin");
+        origin.child("README.md").write_str("Hello, world!")?;
+        origin.child("copy.md").write_str("Hello, world!")?;
+
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let mut archive = Vec::new();
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .hashing_algorithm(HashingAlgorithm::MD5)
+            .build();
+        deduper.write_chunks_to_writer(&mut archive, 2)?;
+        deduper.write_cache()?;
+
+        // Unpacking the archive creates the same chunk store as writing it directly.
+        tar::Archive::new(archive.as_slice()).unpack(&deduped)?;
+        deduped
+            .child("data/6/c/6cd3556deb0da54bca060b4c39479839")
+            .assert("Hello, world!");
+
+        let hydrator = Hydrator::from_tar_reader(archive.as_slice(), vec![cache.to_path_buf()]);
+        let unpacked = hydrator.source_path.clone();
+        assert!(hydrator.check_cache(2), "Cache checking failed");
+        hydrator.restore_files(temp.child("restored").path(), 2)?;
+        temp.child("restored/copy.md").assert("Hello, world!");
+
+        drop(hydrator);
+        assert!(!unpacked.exists(), "Unpacked store is removed");
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_retry_policy() {
+        let policy = RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_millis(1),
+        };
+        let failing = |kind: std::io::ErrorKind, failures: u32| {
+            let mut attempts = 0;
+            let result = policy.run(|| {
+                attempts += 1;
+                if attempts <= failures {
+                    return Err(std::io::Error::from(kind).into());
+                }
+                Ok(())
+            });
+            (result, attempts)
+        };
+
+        // Transient errors are retried until the operation succeeds.
+        let (result, attempts) = failing(std::io::ErrorKind::TimedOut, 2);
+        assert!(result.is_ok());
+        assert_eq!(attempts, 3);
+
+        // The last error is returned after all retries failed.
+        let (result, attempts) = failing(std::io::ErrorKind::Other, 5);
+        assert!(matches!(result, Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::Other));
+        assert_eq!(attempts, 3);
+
+        // Missing files are not retried.
+        let (result, attempts) = failing(std::io::ErrorKind::NotFound, 1);
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        // Neither are other errors of the crate.
+        let mut attempts = 0;
+        let result = policy.run(|| -> Result<()> {
+            attempts += 1;
+            Err(Error::Cancelled)
+        });
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn check_cancel_flag() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+
+        let origin = temp.child("origin");
+        origin.child("file-a").write_str("a")?;
+        origin.child("file-b").write_str("b")?;
+        origin.child("file-c").write_str("c")?;
+
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        // Cancel after the first chunk.
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .cancel_flag(cancel_flag.clone())
+            .on_chunk({
+                let cancel_flag = cancel_flag.clone();
+                move |_| cancel_flag.store(true, Ordering::Relaxed)
+            })
+            .build();
+        assert!(matches!(
+            deduper.write_chunks(deduped.path(), 0),
+            Err(Error::Cancelled)
+        ));
+        deduper.write_cache()?;
+        assert_eq!(std::fs::read_dir(deduped.child("data"))?.count(), 1);
+
+        // Resume without cancelling.
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .build();
+        deduper.write_chunks(deduped.path(), 0)?;
+        deduper.write_cache()?;
+        assert_eq!(std::fs::read_dir(deduped.child("data"))?.count(), 3);
+
+        let restored = temp.child("restored");
+        let hydrator = HydratorBuilder::new(deduped.path())
+            .cache_paths(vec![cache.path()])
+            .cancel_flag(Arc::new(AtomicBool::new(true)))
+            .build();
+        assert!(matches!(
+            hydrator.restore_files(restored.path(), 0),
+            Err(Error::Cancelled)
+        ));
+        restored.child("file-a").assert(predicates::path::missing());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_chunk_store_stats() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+
+        let origin = temp.child("origin");
+        origin.child("file-a").write_str("same")?;
+        origin.child("file-b").write_str("same")?;
+        origin.child("file-c").write_str("other")?;
+        origin.child("empty").touch()?;
+
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .hashing_algorithm(HashingAlgorithm::MD5)
+            .build();
+        deduper.write_chunks(deduped.path(), 1)?;
+        deduper.write_cache()?;
+
+        let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+        let stats = hydrator.chunk_store_stats(1)?;
+        assert_eq!(stats.chunk_count, 2);
+        assert_eq!(stats.total_bytes, 9);
+        // Two chunk files, each in its own declutter directory `5` and `7`.
+        assert_eq!(stats.inode_count, 4);
+
+        // Files that are not referenced by the cache are measured as well.
+        let hash = HashingAlgorithm::MD5.hash_hex(b"stray");
+        deduped
+            .child("data")
+            .child(&hash[..1])
+            .child(&hash)
+            .write_str("stray")?;
+        let stats = hydrator.chunk_store_stats(1)?;
+        assert_eq!(stats.chunk_count, 3);
+        assert_eq!(stats.total_bytes, 14);
+        assert_eq!(stats.inode_count, 6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_compare_store() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+
+        let origin = temp.child("origin");
+        origin.child("file-a").write_str("a")?;
+        origin.child("file-b").write_str("b")?;
+        origin.child("file-c").write_str("c")?;
+
+        let deduped = temp.child("deduped");
+        let replica = temp.child("replica");
+        let cache = temp.child("cache.json");
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .hashing_algorithm(HashingAlgorithm::MD5)
+            .build();
+        deduper.write_chunks(deduped.path(), 0)?;
+        deduper.write_chunks(replica.path(), 0)?;
+        deduper.write_cache()?;
+
+        let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+        let diff = hydrator.compare_store(replica.child("data").path(), 0)?;
+        assert!(diff.is_empty());
+
+        let hash = |content: &[u8]| HashingAlgorithm::MD5.hash_hex(content);
+        std::fs::remove_file(replica.child("data").child(hash(b"a")))?;
+        replica.child("data").child(hash(b"b")).write_str("bb")?;
+        std::fs::remove_file(deduped.child("data").child(hash(b"c")))?;
+
+        let diff = hydrator.compare_store(replica.child("data").path(), 0)?;
+        let mut missing_there = vec![hash(b"a"), hash(b"b")];
+        missing_there.sort();
+        assert_eq!(
+            diff,
+            StoreDiff {
+                missing_there,
+                missing_here: vec![hash(b"c")],
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_content_defined_chunking() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let content = (0..10 * 1024 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+
+        let chunking = Chunking::ContentDefined {
+            min_size: 16 * 1024,
+            avg_size: 64 * 1024,
+            max_size: 256 * 1024,
+        };
+
+        let chunks_of = |content: &[u8]| -> anyhow::Result<Vec<FileChunk>> {
+            let file = temp.child("file");
+            file.write_binary(content)?;
+            let mut fwc = FileWithChunks::try_new(temp.path(), file.path(), Default::default())?;
+            fwc.chunking = chunking;
+            Ok(fwc.get_or_calculate_chunks()?.clone())
+        };
+
+        let original = chunks_of(&content)?;
+        let original_hashes = original.iter().map(|c| &c.hash).collect::<HashSet<_>>();
+
+        let shifted = chunks_of(&[&[42], content.as_slice()].concat())?;
+        assert_eq!(
+            shifted.iter().map(|c| c.size).sum::<u64>(),
+            content.len() as u64 + 1
+        );
+
+        let changed = shifted
+            .iter()
+            .filter(|c| !original_hashes.contains(&c.hash))
+            .count();
+        assert!(
+            changed * 20 < shifted.len(),
+            "{changed} of {} chunks changed",
+            shifted.len()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_chunk_size_validation() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("file").write_str("content")?;
+
+        let chunking = |min_size, avg_size, max_size| Chunking::ContentDefined {
+            min_size,
+            avg_size,
+            max_size,
+        };
+
+        Chunking::Fixed.validate()?;
+        Chunking::CONTENT_DEFINED.validate()?;
+        chunking(64, 256, 1024).validate()?;
+
+        for invalid in [
+            chunking(16, 256, 1024),
+            chunking(64, 256, 32 * 1024 * 1024),
+            chunking(4096, 1024, 8192),
+            chunking(1024, 4096, 2048),
+        ] {
+            assert!(matches!(
+                invalid.validate(),
+                Err(Error::InvalidChunkSizes(_))
+            ));
+            assert!(matches!(
+                DeduperBuilder::new(origin.path())
+                    .chunking(invalid)
+                    .try_build(),
+                Err(Error::InvalidChunkSizes(_))
+            ));
+            assert!(matches!(
+                invalid.chunk_reader(&b"content"[..], HashingAlgorithm::default()),
+                Err(Error::InvalidChunkSizes(_))
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_content_defined_chunking_from_cache() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+
+        let origin = temp.child("origin");
+        origin.child("README.md").write_str("Hello, world!")?;
+
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .chunking(Chunking::CONTENT_DEFINED)
+            .build();
+        deduper.write_chunks(deduped.path(), 0)?;
+        deduper.write_cache()?;
+
+        let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+        let fwc = hydrator.cache.get("README.md").unwrap();
+        assert_eq!(fwc.chunking, Chunking::CONTENT_DEFINED);
+        assert!(hydrator.check_cache(0), "Cache checking failed");
+
+        hydrator.restore_files(temp.child("restored").path(), 0)?;
+        temp.child("restored/README.md").assert("Hello, world!");
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_min_savings_bytes() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+
+        let random = |mut state: u64, len: usize| {
+            (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Encrypted data does not deduplicate at all.
+        origin
+            .child("encrypted-1")
+            .write_binary(&random(1, 3 * 1024 * 1024))?;
+        origin
+            .child("encrypted-2")
+            .write_binary(&random(2, 3 * 1024 * 1024))?;
+
+        // Both copies share all their chunks.
+        let shared = random(3, 2 * 1024 * 1024);
+        origin.child("shared-1").write_binary(&shared)?;
+        origin.child("shared-2").write_binary(&shared)?;
+
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .min_savings_bytes(1024 * 1024)
+            .build();
+        deduper.write_chunks(deduped.path(), 0)?;
+        deduper.write_cache()?;
+
+        for (path, expected_chunks) in [
+            ("encrypted-1", 1),
+            ("encrypted-2", 1),
+            ("shared-1", 2),
+            ("shared-2", 2),
+        ] {
+            let chunks = deduper.cache.get(path).unwrap().get_chunks().unwrap();
+            assert_eq!(
+                chunks.len(),
+                expected_chunks,
+                "Unexpected chunks for {path}"
+            );
+        }
+
+        // One blob per encrypted file, plus the two shared chunks.
+        assert_eq!(std::fs::read_dir(deduped.child("data"))?.count(), 4);
+
+        let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+        hydrator.restore_files(temp.child("restored").path(), 0)?;
+        assert_eq!(
+            std::fs::read(temp.child("restored/encrypted-2"))?,
+            random(2, 3 * 1024 * 1024)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_detect_cache_version() -> anyhow::Result<()> {
+        let (temp, _origin, _deduped, cache) = setup()?;
+
+        assert_eq!(detect_cache_version(&cache)?, CacheVersion::V2);
+        assert_eq!(detect_cache_version(&cache)?, CacheVersion::LATEST);
+
+        let cache_v1 = temp.child("v1.json");
+        cache_v1.write_str(r#"{"v":"1","c":{"f":{},"h":"MD5"}}"#)?;
+        assert_eq!(detect_cache_version(&cache_v1)?, CacheVersion::V1);
+
+        let cache_v0 = temp.child("v0.json");
+        cache_v0.write_str(" \n[]")?;
+        assert_eq!(detect_cache_version(&cache_v0)?, CacheVersion::V0);
+
+        let cache_unknown = temp.child("unknown.json");
+        cache_unknown.write_str(r#"{"v":"99","c":{}}"#)?;
+        assert!(matches!(
+            detect_cache_version(&cache_unknown),
+            Err(Error::CacheParse { .. })
+        ));
+
+        assert!(matches!(
+            detect_cache_version(temp.child("missing.json")),
+            Err(Error::Io(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_incompatible_cache_version() -> anyhow::Result<()> {
+        let (temp, origin, _deduped, cache) = setup()?;
+
+        // Caches written by the running version can be read.
+        let cache_content = std::fs::read_to_string(&cache)?;
+        assert!(cache_content.contains(env!("CARGO_PKG_VERSION")));
+        assert!(
+            HydratorBuilder::new(temp.path())
+                .cache_paths(vec![cache.path()])
+                .try_build()
+                .is_ok()
+        );
+
+        let future_cache = temp.child("future.json");
+        future_cache.write_str(&cache_content.replace(env!("CARGO_PKG_VERSION"), "999.0.0"))?;
+
+        let result = HydratorBuilder::new(temp.path())
+            .cache_paths(vec![future_cache.path()])
+            .try_build();
+        assert!(matches!(
+            result,
+            Err(Error::IncompatibleCacheVersion { cache_version, .. }) if cache_version == "999.0.0"
+        ));
+
+        let result = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![future_cache.path()])
+            .try_build();
+        assert!(matches!(
+            result,
+            Err(Error::IncompatibleCacheVersion { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_cache_format_version() -> anyhow::Result<()> {
+        let (temp, origin, deduped, _cache) = setup()?;
+        origin.child("sub/file").write_str("Some content")?;
+
+        for (version, extension) in CacheVersion::ALL
+            .into_iter()
+            .flat_map(|version| ["json", "msgpack", "msgpack.zst"].map(|ext| (version, ext)))
+        {
+            let cache = temp.child(format!("cache-v{version}.{extension}"));
+            let mut deduper = DeduperBuilder::new(origin.path())
+                .cache_paths(vec![cache.path()])
+                .hashing_algorithm(HashingAlgorithm::MD5)
+                .cache_format_version(version)
+                .build();
+            deduper.write_chunks(deduped.path(), 3)?;
+            deduper.write_cache()?;
+            assert_eq!(detect_cache_version(&cache)?, version);
+
+            let mut dedup_cache = DedupCache::new();
+            dedup_cache.read_from_file(&cache)?;
+            assert_eq!(dedup_cache.len(), deduper.cache.len());
+            for fwc in deduper.cache.values() {
+                let read = dedup_cache.get(&fwc.path).unwrap();
+                let hashes = |fwc: &FileWithChunks| {
+                    fwc.get_chunks()
+                        .unwrap()
+                        .iter()
+                        .map(|chunk| (chunk.start, chunk.size, chunk.hash.clone()))
+                        .collect::<Vec<_>>()
+                };
+                assert_eq!(hashes(read), hashes(fwc));
+                assert_eq!(read.hashing_algorithm, HashingAlgorithm::MD5);
+            }
+
+            let restored = temp.child(format!("restored-v{version}.{extension}"));
+            Hydrator::new(deduped.path(), vec![cache.path()]).restore_files(restored.path(), 3)?;
+            restored
+                .child("sub/file")
+                .assert(predicates::path::eq_file(origin.child("sub/file").path()));
+        }
+
+        assert!(matches!(
+            "3".parse::<CacheVersion>(),
+            Err(Error::UnknownCacheVersion(version)) if version == "3"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_msgpack_cache() -> anyhow::Result<()> {
+        let (temp, origin, deduped, json_cache) = setup()?;
+
+        let cache = temp.child("cache.msgpack");
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .hashing_algorithm(HashingAlgorithm::MD5)
+            .build();
+        deduper.write_chunks(deduped.path(), 3)?;
+        deduper.write_cache()?;
+
+        // The same cache as MessagePack is smaller than as JSON.
+        let content = std::fs::read(&cache)?;
+        assert_ne!(content[0], b'{');
+        assert!(content.len() < std::fs::metadata(&json_cache)?.len() as usize);
+        assert_eq!(detect_cache_version(&cache)?, CacheVersion::LATEST);
+
+        let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+        assert_eq!(hydrator.cache.len(), deduper.cache.len());
+
+        // The format is determined by the file name, so JSON in a `.msgpack` file is rejected.
+        let mismatched = temp.child("mismatched.msgpack");
+        std::fs::copy(&json_cache, &mismatched)?;
+        assert!(matches!(
+            HydratorBuilder::new(deduped.path())
+                .cache_paths(vec![mismatched.path()])
+                .try_build(),
+            Err(Error::CacheParse { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_temp_dir() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("file").write_str("Hello, world!")?;
+
+        let temp_dir = temp.child("tmp");
+        temp_dir.create_dir_all()?;
+        let cache = temp.child("cache/cache.json.zst");
+
+        let deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .temp_dir(temp_dir.path())
+            .build();
+        deduper.write_cache()?;
+
+        // The temporary file was moved to the final path.
+        assert_eq!(std::fs::read_dir(&temp_dir)?.count(), 0);
+        assert_eq!(std::fs::read_dir(temp.child("cache"))?.count(), 1);
+
+        let hydrator = Hydrator::new(temp.path(), vec![cache.path()]);
+        assert!(hydrator.cache.contains_key("file"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn check_chunk_mode() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("file").write_str("Hello, world!")?;
+        let deduped = temp.child("deduped");
+
+        let mut deduper = DeduperBuilder::new(origin.path()).chunk_mode(0o640).build();
+        deduper.write_chunks(deduped.path(), 2)?;
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let data_dir = deduped.child("data");
+        let chunk_file = WalkDir::new(&data_dir)
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .find(|path| path.is_file())
+            .unwrap();
+        assert_eq!(mode(&chunk_file), 0o640);
+        for dir in chunk_file.parent().unwrap().ancestors() {
+            assert_eq!(mode(dir), 0o750, "{}", dir.display());
+            if dir == data_dir.path() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn check_temp_dir_on_other_device() -> anyhow::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp = TempDir::new()?;
+        // Usually a tmpfs, which is a different device than the regular temporary directory.
+        let Ok(temp_dir) = TempDir::new_in("/dev/shm") else {
+            return Ok(());
+        };
+        if temp.metadata()?.dev() == temp_dir.metadata()?.dev() {
+            return Ok(());
+        }
+
+        let origin = temp.child("origin");
+        origin.child("file").write_str("Hello, world!")?;
+        let cache = temp.child("cache/cache.json.zst");
+
+        let deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .temp_dir(temp_dir.path())
+            .build();
+        deduper.write_cache()?;
+
+        // The temporary file could not be renamed, it was copied and removed instead.
+        assert_eq!(std::fs::read_dir(&temp_dir)?.count(), 0);
+        assert_eq!(std::fs::read_dir(temp.child("cache"))?.count(), 1);
+
+        let hydrator = Hydrator::new(temp.path(), vec![cache.path()]);
+        assert!(hydrator.cache.contains_key("file"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_chunk_base_path() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("README.md").write_str("Hello, world!")?;
+        origin.child("dir/file").write_str("content")?;
+
+        let deduped = temp.child("deduped");
+        let chunks = temp.child("chunks");
+        let cache = temp.child("cache.json");
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .hashing_algorithm(HashingAlgorithm::MD5)
+            .chunk_base_path(chunks.path())
+            .build();
+        deduper.write_chunks(deduped.path(), 3)?;
+        deduper.write_cache()?;
+
+        // Hash of "Hello, world!" is 6cd3556deb0da54bca060b4c39479839.
+        chunks
+            .child("6/c/d/6cd3556deb0da54bca060b4c39479839")
+            .assert(predicates::path::is_file());
+        deduped.child("data").assert(predicates::path::missing());
+
+        // Without the override, the chunks are not found.
+        let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+        assert!(!hydrator.check_cache(3));
+
+        let hydrator = HydratorBuilder::new(deduped.path())
+            .cache_paths(vec![cache.path()])
+            .chunk_base_path(chunks.path())
+            .build();
+        assert!(hydrator.check_cache(3));
+        assert_eq!(hydrator.list_extra_files(3)?.count(), 0);
+
+        let restored = temp.child("restored");
+        hydrator.restore_files(restored.path(), 3)?;
+        restored
+            .child("README.md")
+            .assert(predicates::str::contains("Hello, world!"));
+        restored
+            .child("dir/file")
+            .assert(predicates::str::contains("content"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_empty_files() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("empty").touch()?;
+
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let mut deduper = Deduper::new(
+            origin.path(),
+            vec![cache.path()],
+            HashingAlgorithm::MD5,
+            true,
+        );
+        deduper.write_chunks(deduped.path(), 3)?;
+        deduper.write_cache()?;
+
+        assert_eq!(deduped.child("data").read_dir()?.count(), 0);
+
+        let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+        assert!(
+            hydrator.check_cache(3),
+            "Cache checking failed for empty file"
+        );
+        assert_eq!(hydrator.list_extra_files(3)?.count(), 0);
+
+        let hydrated = temp.child("hydrated");
+        hydrator.restore_files(hydrated.path(), 3)?;
+        assert_eq!(hydrated.child("empty").metadata()?.len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_multiple_empty_files() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("empty-1").touch()?;
+        origin.child("empty-2").touch()?;
+        origin.child("sub/empty").touch()?;
+        origin.child("file").write_str("content")?;
+
+        for chunking in [Chunking::Fixed, Chunking::CONTENT_DEFINED] {
+            for two_pass in [false, true] {
+                let run = temp.child(format!("run-{chunking:?}-{two_pass}"));
+                let deduped = run.child("deduped");
+                let cache = run.child("cache.json");
+
+                let mut deduper = DeduperBuilder::new(origin.path())
+                    .cache_paths(vec![cache.path()])
+                    .chunking(chunking)
+                    .hashing_algorithm(HashingAlgorithm::MD5)
+                    .try_build()?;
+                let stats = if two_pass {
+                    deduper.write_chunks_two_pass(deduped.path(), 2)?
+                } else {
+                    deduper.write_chunks(deduped.path(), 2)?
+                };
+                deduper.write_cache()?;
+
+                // All empty files share the empty chunk, which is never written.
+                let empty_hash = HashingAlgorithm::MD5.hash_hex(&[]);
+                for path in ["empty-1", "empty-2", "sub/empty"] {
+                    let chunks = deduper.cache().get(path).unwrap().get_chunks().unwrap();
+                    assert_eq!(chunks.len(), 1);
+                    assert_eq!((chunks[0].size, chunks[0].hash.as_str()), (0, &*empty_hash));
+                }
+                assert_eq!((stats.written, stats.deduped), (1, 3));
+                let chunk_files = WalkDir::new(deduped.child("data"))
+                    .into_iter()
+                    .filter(|entry| {
+                        entry
+                            .as_ref()
+                            .is_ok_and(|entry| entry.file_type().is_file())
+                    })
+                    .count();
+                assert_eq!(chunk_files, 1);
+
+                let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+                assert!(hydrator.check_cache(2));
+                assert_eq!(hydrator.list_missing_chunks(2)?.count(), 0);
+                assert_eq!(hydrator.list_extra_files(2)?.count(), 0);
+
+                let hydrated = run.child("hydrated");
+                let report = hydrator.restore_files(hydrated.path(), 2)?;
+                assert_eq!(report.restored, 4);
+                for path in ["empty-1", "empty-2", "sub/empty"] {
+                    assert_eq!(hydrated.child(path).metadata()?.len(), 0);
+                }
+                assert_eq!(std::fs::read(hydrated.child("file"))?, b"content");
+
+                let sparse = run.child("sparse");
+                HydratorBuilder::new(deduped.path())
+                    .cache_paths(vec![cache.path()])
+                    .sparse(true)
+                    .try_build()?
+                    .restore_files(sparse.path(), 2)?;
+                assert_eq!(sparse.child("sub/empty").metadata()?.len(), 0);
+
+                let zip = run.child("restored.zip");
+                hydrator.restore_to_zip(zip.path(), 2)?;
+                let mut archive = zip::ZipArchive::new(File::open(&zip)?)?;
+                assert_eq!(archive.len(), 4);
+                assert_eq!(archive.by_name("sub/empty")?.size(), 0);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_resume_restore() -> anyhow::Result<()> {
+        let (temp, _origin, deduped, cache) = setup()?;
+        let hydrated = temp.child("hydrated");
+
+        let hydrator = |resume| {
+            HydratorBuilder::new(deduped.path())
+                .cache_paths(vec![cache.path()])
+                .resume(resume)
+                .build()
+        };
+
+        let report = hydrator(ResumeMode::SizeAndMtime).restore_files(hydrated.path(), 3)?;
+        assert_eq!(report.restored, 1);
+        assert_eq!(report.skipped, 0);
+
+        let report = hydrator(ResumeMode::SizeAndMtime).restore_files(hydrated.path(), 3)?;
+        assert_eq!(report.restored, 0);
+        assert_eq!(report.skipped, 1);
+
+        let report = hydrator(ResumeMode::Verify).restore_files(hydrated.path(), 3)?;
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.reverified, 1);
+
+        // Corrupt the file, but keep size and modification time.
+        let file = hydrated.child("README.md");
+        let mtime = file.metadata()?.modified()?;
+        file.write_str("Hello, World!")?;
+        OpenOptions::new()
+            .write(true)
+            .open(&file)?
+            .set_modified(mtime)?;
+
+        let report = hydrator(ResumeMode::SizeAndMtime).restore_files(hydrated.path(), 3)?;
+        assert_eq!(report.skipped, 1, "Size and mtime should be trusted");
+
+        let report = hydrator(ResumeMode::Verify).restore_files(hydrated.path(), 3)?;
+        assert_eq!(report.restored, 1, "Corrupted file was not restored");
+        assert_eq!(report.reverified, 0);
+        file.assert("Hello, world!");
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_resume_ignoring_mtime() -> anyhow::Result<()> {
+        let (temp, _origin, deduped, cache) = setup()?;
+        let hydrated = temp.child("hydrated");
+
+        let hydrator = || {
+            HydratorBuilder::new(deduped.path())
+                .cache_paths(vec![cache.path()])
+                .resume(ResumeMode::SizeAndMtime)
+                .ignore_mtime(true)
+                .build()
+        };
+
+        let report = hydrator().restore_files(hydrated.path(), 3)?;
+        assert_eq!(report.restored, 1);
+
+        // A different modification time alone does not cause a restore.
+        let file = hydrated.child("README.md");
+        let mtime = file.metadata()?.modified()?;
+        OpenOptions::new()
+            .write(true)
+            .open(&file)?
+            .set_modified(mtime + Duration::from_secs(60))?;
+
+        let report = hydrator().restore_files(hydrated.path(), 3)?;
+        assert_eq!(report.restored, 0);
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.reverified, 1);
+
+        // Different content with the original modification time is restored.
+        file.write_str("Hello, World!")?;
+        OpenOptions::new()
+            .write(true)
+            .open(&file)?
+            .set_modified(mtime)?;
+
+        let report = hydrator().restore_files(hydrated.path(), 3)?;
+        assert_eq!(report.restored, 1);
+        assert_eq!(report.skipped, 0);
+        file.assert("Hello, world!");
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_preserve_mtime() -> anyhow::Result<()> {
+        let (temp, _origin, deduped, cache) = setup()?;
+
+        let hydrator = |preserve_mtime| {
+            HydratorBuilder::new(deduped.path())
+                .cache_paths(vec![cache.path()])
+                .preserve_mtime(preserve_mtime)
+                .build()
+        };
+
+        let cached_mtime = hydrator(true).cache.get("README.md").unwrap().mtime;
+
+        let report = hydrator(true).restore_files(temp.child("preserved").path(), 3)?;
+        assert!(report.mtime_not_set.is_empty());
+        let mtime = temp.child("preserved/README.md").metadata()?.modified()?;
+        assert_eq!(mtime, cached_mtime);
+
+        let report = hydrator(false).restore_files(temp.child("not-preserved").path(), 3)?;
+        assert!(report.mtime_not_set.is_empty());
+        let mtime = temp
+            .child("not-preserved/README.md")
+            .metadata()?
+            .modified()?;
+        assert!(mtime > cached_mtime);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn check_preserve_ownership() -> anyhow::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let (temp, origin, deduped, cache) = setup()?;
+
+        let metadata = origin.child("README.md").metadata()?;
+        let mut hydrator = HydratorBuilder::new(deduped.path())
+            .cache_paths(vec![cache.path()])
+            .preserve_ownership(true)
+            .build();
+        let fwc = hydrator.cache.get_mut("README.md").unwrap();
+        assert_eq!(fwc.uid, Some(metadata.uid()));
+        assert_eq!(fwc.gid, Some(metadata.gid()));
+
+        let report = hydrator.restore_files(temp.child("own").path(), 3)?;
+        assert!(report.ownership_not_set.is_empty());
+
+        // Without privileges, the file is restored with a warning instead.
+        let foreign_uid = metadata.uid() + 1;
+        hydrator.cache.get_mut("README.md").unwrap().uid = Some(foreign_uid);
+        let report = hydrator.restore_files(temp.child("foreign").path(), 3)?;
+        let restored = temp.child("foreign/README.md");
+        restored.assert("Hello, world!");
+        // Either the owner was changed, or the failure is reported.
+        assert_ne!(
+            report.ownership_not_set.is_empty(),
+            restored.metadata()?.uid() != foreign_uid
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_sparse_restore() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+
+        let mib = 1024 * 1024;
+        let mut content = vec![0; 4 * mib];
+        content[..mib].fill(1);
+        content[2 * mib..3 * mib].fill(2);
+        origin.child("image").write_binary(&content)?;
+
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .build();
+        deduper.write_chunks(deduped.path(), 0)?;
+        deduper.write_cache()?;
+
+        let report = HydratorBuilder::new(deduped.path())
+            .cache_paths(vec![cache.path()])
+            .sparse(true)
+            .build()
+            .restore_files(temp.child("restored").path(), 0)?;
+        assert_eq!(report.restored, 1);
+
+        let restored = temp.child("restored/image");
+        assert_eq!(std::fs::read(&restored)?, content);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            // Blocks are counted in units of 512 bytes. Only the 2 non-zero chunks are allocated.
+            let allocated = restored.metadata()?.blocks() * 512;
+            assert!(allocated < 3 * mib as u64, "{allocated} bytes allocated");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_prefetch_restore() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+
+        let mib = 1024 * 1024;
+        let files = (0..8u8)
+            .map(|i| {
+                let mut content = vec![0; (i as usize % 4 + 1) * mib + i as usize];
+                content[..mib].fill(i);
+                (format!("dir_{}/file_{i}", i % 2), content)
+            })
+            .collect::<Vec<_>>();
+        for (path, content) in &files {
+            origin.child(path).write_binary(content)?;
+        }
+        origin.child("empty").touch()?;
+
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .build();
+        deduper.write_chunks(deduped.path(), 0)?;
+        deduper.write_cache()?;
+
+        for sparse in [false, true] {
+            let restored = temp.child(format!("restored_{sparse}"));
+            let report = HydratorBuilder::new(deduped.path())
+                .cache_paths(vec![cache.path()])
+                .sparse(sparse)
+                .prefetch_parallelism(3)
+                .build()
+                .restore_files(restored.path(), 0)?;
+            assert_eq!(report.restored, files.len() + 1);
+
+            for (path, content) in &files {
+                assert_eq!(&std::fs::read(restored.child(path))?, content);
+            }
+            assert_eq!(std::fs::read(restored.child("empty"))?.len(), 0);
+        }
+
+        // Errors while prefetching are reported like without prefetching.
+        let hash = &deduper
+            .cache
+            .get("dir_1/file_3")
+            .unwrap()
+            .get_chunks()
+            .unwrap()[0]
+            .hash;
+        std::fs::remove_file(deduped.child("data").child(hash))?;
+        let result = HydratorBuilder::new(deduped.path())
+            .cache_paths(vec![cache.path()])
+            .prefetch_parallelism(3)
+            .build()
+            .restore_files(temp.child("restored_missing").path(), 0);
+        assert!(matches!(result, Err(Error::ChunkMissing { hash: missing }) if missing == *hash));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_prefetch_api() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let contents = (0..6u8)
+            .map(|i| vec![i; 1000 * (i as usize + 1)])
+            .collect::<Vec<_>>();
+        for (i, content) in contents.iter().enumerate() {
+            origin.child(format!("file_{i}")).write_binary(content)?;
+        }
+        origin.child("empty").touch()?;
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .build();
+        deduper.write_chunks(deduped.path(), 2)?;
+        deduper.write_cache()?;
+
+        let mut hashes = contents
+            .iter()
+            .map(|content| HashingAlgorithm::SHA1.hash_hex(content))
+            .collect::<Vec<_>>();
+        hashes.push(HashingAlgorithm::SHA1.hash_hex(b""));
+
+        for parallelism in [0, 1, 4] {
+            let hydrator = HydratorBuilder::new(deduped.path())
+                .cache_paths(vec![cache.path()])
+                .prefetch_parallelism(parallelism)
+                .build();
+
+            let prefetched = hydrator
+                .prefetch(hashes.clone().into_iter(), 2)
+                .collect::<Result<Vec<_>>>()?;
+            let expected = contents
+                .iter()
+                .chain([&Vec::new()])
+                .map(|content| (HashingAlgorithm::SHA1.hash_hex(content), content.clone()))
+                .collect::<Vec<_>>();
+            assert_eq!(prefetched, expected);
+
+            // Dropping the iterator early stops the threads instead of leaving them blocked.
+            let mut first = hydrator.prefetch(hashes.clone().into_iter(), 2);
+            assert_eq!(first.next().transpose()?, Some(expected[0].clone()));
+            drop(first);
+
+            let unknown = hydrator
+                .prefetch(["unknown".to_string()].into_iter(), 2)
+                .collect::<Vec<_>>();
+            assert!(matches!(
+                unknown.as_slice(),
+                [Err(Error::ChunkMissing { hash })] if hash == "unknown"
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_cache_verbose() -> anyhow::Result<()> {
+        let (_temp, origin, deduped, cache) = setup()?;
+
+        // Hash of "Hello, world!" is 6cd3556deb0da54bca060b4c39479839.
+        let hash = "6cd3556deb0da54bca060b4c39479839".to_string();
+        let chunk = deduped.child("data/6/c/d").child(&hash);
+
+        let check = || {
+            Hydrator::new(deduped.path(), vec![cache.path()])
+                .check_cache_verbose(3)
                 .unwrap()
-                .map(|(hash, ..)| PathBuf::from(hash)),
+        };
+
+        assert_eq!(check(), vec![CacheCheckResult::Ok { hash: hash.clone() }]);
+
+        chunk.write_str("Hello")?;
+        assert_eq!(
+            check(),
+            vec![CacheCheckResult::SizeMismatch {
+                hash: hash.clone(),
+                path: chunk.to_path_buf(),
+                expected: 13,
+                actual: 5,
+            }]
+        );
+
+        std::fs::remove_file(&chunk)?;
+        assert_eq!(
+            check(),
+            vec![CacheCheckResult::Missing {
+                hash: hash.clone(),
+                path: chunk.to_path_buf(),
+            }]
+        );
+
+        std::fs::copy(origin.child("README.md"), &chunk)?;
+        assert!(Hydrator::new(deduped.path(), vec![cache.path()]).check_cache(3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_restore_errors() -> anyhow::Result<()> {
+        let (temp, _origin, deduped, cache) = setup()?;
+
+        // Hash of "Hello, world!" is 6cd3556deb0da54bca060b4c39479839.
+        let hash = "6cd3556deb0da54bca060b4c39479839".to_string();
+        let chunk = deduped.child("data/6/c/d").child(&hash);
+
+        let restore = || {
+            Hydrator::new(deduped.path(), vec![cache.path()])
+                .restore_files(temp.child("hydrated").path(), 3)
+        };
+
+        chunk.write_str("Hello")?;
+        assert!(matches!(
+            restore(),
+            Err(Error::ChunkSizeMismatch { hash: h, expected: 13, actual: 5 }) if h == hash
+        ));
+
+        std::fs::remove_file(&chunk)?;
+        assert!(matches!(restore(), Err(Error::ChunkMissing { hash: h }) if h == hash));
+
+        cache.write_str("not a cache")?;
+        let result = HydratorBuilder::new(deduped.path())
+            .cache_paths(vec![cache.path()])
+            .try_build();
+        assert!(matches!(result, Err(Error::CacheParse { path }) if path == cache.path()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_cache_priority() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+
+        // Each cache knows a different version of the shared file, plus one file of its own.
+        let caches = ["Oldest", "Older!", "Latest!!"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, content)| {
+                origin.child("shared").write_str(content)?;
+                origin.child(format!("only-{i}")).write_str(content)?;
+                let cache = temp.child(format!("cache-{i}.json"));
+                let mut deduper = DeduperBuilder::new(origin.path())
+                    .cache_paths(vec![cache.path()])
+                    .build();
+                deduper.write_chunks(temp.child("deduped").path(), 0)?;
+                deduper.write_cache()?;
+                std::fs::remove_file(origin.child(format!("only-{i}")))?;
+                Ok(cache.to_path_buf())
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let shared_hash = |cache_paths: Vec<PathBuf>| {
+            let hydrator = HydratorBuilder::new(temp.path())
+                .cache_paths(cache_paths)
+                .build();
+            assert_eq!(hydrator.cache.len(), 4);
+            hydrator.cache.get("shared").unwrap().get_chunks().unwrap()[0]
+                .hash
+                .clone()
+        };
+
+        let hash = |content: &str| HashingAlgorithm::default().hash_hex(content.as_bytes());
+
+        // Earlier cache files take precedence.
+        let reversed = caches.iter().rev().cloned().collect();
+        assert_eq!(shared_hash(reversed), hash("Latest!!"));
+        assert_eq!(shared_hash(caches), hash("Oldest"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_dedup_report() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let content = (0..u8::MAX)
+            .cycle()
+            .take(FIXED_CHUNK_SIZE as usize)
+            .collect::<Vec<_>>();
+        origin.child("file-1").write_binary(&content)?;
+        origin.child("file-2").write_binary(&content)?;
+        origin.child("empty").touch()?;
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .build();
+        deduper.write_chunks(deduped.path(), 2)?;
+        deduper.write_cache()?;
+
+        let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+        let report = hydrator.dedup_report(2)?;
+        assert_eq!(report.total_file_bytes, 2 * FIXED_CHUNK_SIZE);
+        assert_eq!(report.unique_chunks, 1);
+        assert_eq!(report.unique_chunk_bytes, FIXED_CHUNK_SIZE);
+        assert!((report.ratio - 2.0).abs() < f64::EPSILON);
+        assert_eq!(report.space_saved_bytes, FIXED_CHUNK_SIZE as i64);
+
+        // The same report without the chunk store.
+        assert_eq!(deduper.dedup_report(), report);
+
+        std::fs::remove_dir_all(deduped.child("data"))?;
+        assert!(matches!(
+            hydrator.dedup_report(2),
+            Err(Error::ChunkMissing { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_list_extra() -> anyhow::Result<()> {
+        let (_temp, _origin, deduped, cache) = setup()?;
+
+        assert_eq!(
+            Hydrator::new(deduped.to_path_buf(), vec![cache.to_path_buf()])
+                .list_extra_files(3)?
+                .count(),
+            0,
+            "Extra files present when there shouldn't be"
+        );
+
+        deduped
+            .child("data")
+            .child("extra_file")
+            .write_str("Hello, world!")?;
+
+        assert_eq!(
+            Hydrator::new(deduped.to_path_buf(), vec![cache.to_path_buf()])
+                .list_extra_files(3)?
+                .count(),
+            1,
+            "Number of extra files present is not 1"
+        );
+
+        deduped
+            .child("data")
+            .child("e")
+            .child("x")
+            .child("t")
+            .child("extra_file")
+            .write_str("Hello, world!")?;
+
+        assert_eq!(
+            Hydrator::new(deduped.to_path_buf(), vec![cache.to_path_buf()])
+                .list_extra_files(3)?
+                .count(),
+            2,
+            "Number of extra files present is not 2"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_list_extra_partial() -> anyhow::Result<()> {
+        let (temp, origin, deduped, cache) = setup()?;
+        origin.child("unhashed").write_str("Not hashed yet")?;
+
+        // Scanning without writing chunks leaves the new file without computed chunks.
+        let partial_cache = temp.child("partial.json");
+        Deduper::new(
+            origin.path(),
+            vec![partial_cache.path(), cache.path()],
+            HashingAlgorithm::MD5,
+            true,
         )
-        .base(&self.source_path.join("data"))
-        .levels(declutter_levels)
-        .map(|(_, path)| path)
-        .collect::<HashSet<_>>();
+        .write_cache()?;
+
+        // The original files are not needed.
+        std::fs::remove_dir_all(&origin)?;
+
+        let hydrator = Hydrator::new(deduped.path(), vec![partial_cache.path()]);
+        assert_eq!(
+            hydrator.list_extra_files_partial(3),
+            (vec![], vec!["unhashed".to_string()])
+        );
+
+        let extra_file = deduped.child("data").child("extra_file");
+        extra_file.write_str("Hello, world!")?;
+        assert_eq!(
+            hydrator.list_extra_files_partial(3),
+            (vec![extra_file.to_path_buf()], vec!["unhashed".to_string()])
+        );
+
+        // With all chunks computed, nothing is skipped.
+        let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+        assert_eq!(
+            hydrator.list_extra_files_partial(3),
+            (vec![extra_file.to_path_buf()], vec![])
+        );
+        assert_eq!(
+            hydrator.list_extra_files(3)?.collect::<Vec<_>>(),
+            vec![extra_file.to_path_buf()]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_timings() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("file").write_str("Hello, world!")?;
+        origin.child("copy").write_str("Hello, world!")?;
+
+        let mut deduper = DeduperBuilder::new(origin.path()).build();
+        deduper.write_chunks(temp.child("deduped").path(), 0)?;
+
+        let timings = deduper.timings();
+        assert_eq!(timings.bytes_hashed, 26);
+        assert_eq!(timings.bytes_written, 13);
+
+        // Everything is cached now, so nothing should be hashed or written a second time.
+        deduper.write_chunks(temp.child("deduped").path(), 0)?;
+        assert_eq!(deduper.timings().bytes_hashed, 26);
+        assert_eq!(deduper.timings().bytes_written, 13);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn check_hashing_parallelism() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let large = (0..u8::MAX)
+            .cycle()
+            .take(FIXED_CHUNK_SIZE as usize * 5 / 2)
+            .collect::<Vec<_>>();
+        origin.child("large").write_binary(&large)?;
+        for file in 0..20 {
+            origin
+                .child(format!("small/{file}"))
+                .write_str(&format!("small file {}", file % 7))?;
+        }
+
+        let write = |hashing_parallelism, file_hashes, two_pass| -> anyhow::Result<_> {
+            let deduped = TempDir::new()?;
+            let mut deduper = DeduperBuilder::new(origin.path())
+                .hashing_parallelism(hashing_parallelism)
+                .file_hashes(file_hashes)
+                .try_build()?;
+            let stats = if two_pass {
+                deduper.write_chunks_two_pass(deduped.path(), 0)?
+            } else {
+                deduper.write_chunks(deduped.path(), 0)?
+            };
+            assert!(deduper.cache.values().all(|fwc| fwc.get_chunks().is_some()));
+            let chunks = deduper
+                .cache
+                .iter_sorted()
+                .map(|fwc| {
+                    let chunks = fwc.get_chunks().unwrap().iter();
+                    let chunks = chunks.map(|chunk| (chunk.start, chunk.size, chunk.hash.clone()));
+                    (
+                        fwc.path.clone(),
+                        fwc.file_hash().map(String::from),
+                        chunks.collect(),
+                    )
+                })
+                .collect::<Vec<(String, Option<String>, Vec<_>)>>();
+            Ok((
+                stats.written,
+                stats.deduped,
+                deduper.timings().bytes_hashed,
+                chunks,
+            ))
+        };
+
+        for file_hashes in [false, true] {
+            for two_pass in [false, true] {
+                let per_chunk = write(HashingParallelism::PerChunk, file_hashes, two_pass)?;
+                let per_file = write(HashingParallelism::PerFile, file_hashes, two_pass)?;
+                assert_eq!(per_file, per_chunk);
+                assert_eq!((per_file.0, per_file.1), (10, 13));
+                assert_eq!(per_file.2, large.len() as u64 + 20 * 12);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_chunk_copier() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let data = (0..u8::MAX).cycle().take(700 * 1024).collect::<Vec<_>>();
+        let source = temp.child("source");
+        source.write_binary(&data)?;
+
+        // Chunks larger than the copy buffer are copied in several steps.
+        let copier = ChunkCopier::new()?;
+        copier.copy(
+            source.path(),
+            1000,
+            600 * 1024,
+            temp.child("chunk-1").path(),
+        )?;
+        copier.copy(source.path(), 0, 10, temp.child("chunk-2").path())?;
+        assert_eq!(
+            std::fs::read(temp.child("chunk-1"))?,
+            &data[1000..1000 + 600 * 1024]
+        );
+        assert_eq!(std::fs::read(temp.child("chunk-2"))?, &data[..10]);
+
+        let err = copier
+            .copy(
+                source.path(),
+                600 * 1024,
+                200 * 1024,
+                temp.child("chunk-3").path(),
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        temp.child("chunk-3").assert(predicates::path::missing());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_missing_chunks() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("known").write_str("known")?;
+        origin.child("new").write_str("new")?;
+        origin.child("new-copy").write_str("new")?;
+        origin.child("empty").touch()?;
+
+        let deduper = DeduperBuilder::new(origin.path()).build();
+
+        let remote = HashSet::from([HashingAlgorithm::default().hash_hex(b"known")]);
+        let missing = deduper.missing_chunks(&remote)?;
+
+        assert_eq!(missing.len(), 1);
+        let (hash, path, start, size) = &missing[0];
+        assert_eq!(hash, &HashingAlgorithm::default().hash_hex(b"new"));
+        assert_eq!(path, origin.child("new").path());
+        assert_eq!((*start, *size), (0, 3));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn check_sendfile_copy() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+
+        let len = 100 * 1024 * 1024;
+        let content = (0..len + 3)
+            .map(|i: u32| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect::<Vec<_>>();
+        temp.child("source").write_binary(&content)?;
+
+        let src = File::open(temp.child("source"))?;
+        let dst = File::create(temp.child("target"))?;
+        let copied = sendfile_copy(&src, &dst, 3, len as u64)?;
+        assert_eq!(copied, len as u64);
+
+        let expected = HashingAlgorithm::SHA256.hash_hex(&content[3..]);
+        let actual = HashingAlgorithm::SHA256.hash_hex(&std::fs::read(temp.child("target"))?);
+        assert_eq!(actual, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_open_files_limit() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let limit = OpenFilesLimit::new(2);
+        let open = AtomicUsize::new(0);
+        let max_open = AtomicUsize::new(0);
+
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let _permit = limit.acquire();
+                    let now_open = open.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_open.fetch_max(now_open, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(10));
+                    open.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        assert_eq!(open.load(Ordering::SeqCst), 0);
+        assert!(max_open.load(Ordering::SeqCst) <= 2, "Too many files open");
+    }
+
+    #[test]
+    fn check_max_open_files() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        for file in 0..4 {
+            origin
+                .child(format!("file-{file}"))
+                .write_str(&format!("content {file}"))?;
+        }
+
+        let mut deduper = DeduperBuilder::new(origin.path()).max_open_files(1).build();
+        deduper.write_chunks(temp.child("deduped").path(), 0)?;
+
+        assert_eq!(deduper.cache.get_chunks()?.count(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_source_with_trailing_slash() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("dir/file").write_str("Hello, world!")?;
+
+        let source = format!("{}{}", origin.display(), std::path::MAIN_SEPARATOR);
+        let deduper = DeduperBuilder::new(&source).try_build()?;
+        assert!(
+            deduper
+                .cache
+                .contains_key(&format!("dir{}file", std::path::MAIN_SEPARATOR))
+        );
+
+        let deduper = DeduperBuilder::new(&source)
+            .canonicalize_source(true)
+            .try_build()?;
+        assert_eq!(deduper.source_paths, vec![origin.canonicalize()?]);
+        assert!(
+            deduper
+                .cache
+                .contains_key(&format!("dir{}file", std::path::MAIN_SEPARATOR))
+        );
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_symlinked_source() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("README.md").write_str("Hello, world!")?;
+        let link = temp.child("link");
+        link.symlink_to_dir(&origin)?;
+
+        let deduper = DeduperBuilder::new(link.path()).try_build()?;
+        assert!(deduper.cache.contains_key("README.md"));
+
+        let deduper = DeduperBuilder::new(link.path())
+            .canonicalize_source(true)
+            .try_build()?;
+        assert_eq!(deduper.source_paths, vec![origin.canonicalize()?]);
+        assert!(deduper.cache.contains_key("README.md"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_source_errors() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.create_dir_all()?;
+        let outside = temp.child("outside");
+        outside.write_str("Hello, world!")?;
+
+        let result = FileWithChunks::try_new(origin.path(), outside.path(), HashingAlgorithm::MD5);
+        assert!(matches!(result, Err(Error::OutsideSource { .. })));
+
+        let result = DeduperBuilder::new(temp.child("missing").path())
+            .canonicalize_source(true)
+            .try_build();
+        assert!(matches!(result, Err(Error::Scan { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_find_missing_files() -> anyhow::Result<()> {
+        let (_temp, origin, _deduped, cache) = setup()?;
+
+        let mut dedup_cache = DedupCache::new();
+        dedup_cache.read_from_file(&cache)?;
+        let len = dedup_cache.len();
+
+        assert!(dedup_cache.find_missing_files(&origin).is_empty());
+
+        std::fs::remove_file(origin.child("README.md"))?;
+
+        assert_eq!(dedup_cache.find_missing_files(&origin), vec!["README.md"]);
+        assert_eq!(dedup_cache.len(), len, "Entries must not be removed");
 
-        WalkDir::new(&self.source_path.join("data"))
-            .min_depth(1)
-            .same_file_system(false)
+        Ok(())
+    }
+
+    #[test]
+    fn check_prune_missing() -> anyhow::Result<()> {
+        let (_temp, origin, _deduped, cache) = setup()?;
+
+        let mut dedup_cache = DedupCache::new();
+        dedup_cache.read_from_file(&cache)?;
+        let len = dedup_cache.len();
+
+        std::fs::remove_file(origin.child("README.md"))?;
+        dedup_cache.prune_missing(&origin);
+
+        assert_eq!(dedup_cache.len(), len - 1);
+        assert!(!dedup_cache.contains_key("README.md"));
+        assert!(dedup_cache.find_missing_files(&origin).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_cache_iterators() -> anyhow::Result<()> {
+        let (_temp, origin, _deduped, cache) = setup()?;
+
+        let mut dedup_cache = DedupCache::new();
+        dedup_cache.read_from_file(&cache)?;
+
+        let mut paths = Vec::new();
+        for (path, fwc) in &dedup_cache {
+            assert_eq!(path, &fwc.path);
+            paths.push(path.clone());
+        }
+        assert_eq!(paths, vec!["README.md"]);
+
+        let collected = dedup_cache
             .into_iter()
-            .filter(move |entry| {
-                entry
-                    .as_ref()
-                    .map(|entry| {
-                        entry.file_type().is_file() && !files_in_cache.contains(entry.path())
-                    })
-                    .unwrap_or_default()
+            .chain([(
+                "README.md".to_string(),
+                FileWithChunks::try_new(
+                    origin.path(),
+                    origin.child("README.md").path(),
+                    HashingAlgorithm::SHA1,
+                )?,
+            )])
+            .collect::<DedupCache>();
+        assert_eq!(collected.len(), 1);
+        assert_eq!(
+            collected.get("README.md").unwrap().hashing_algorithm,
+            HashingAlgorithm::SHA1,
+            "Later entries must replace earlier ones"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_cache_paths() -> anyhow::Result<()> {
+        let (_temp, origin, _deduped, cache) = setup()?;
+        origin.child("dir/file").write_str("Hello again!")?;
+
+        let deduper = Deduper::new(
+            origin.to_path_buf(),
+            vec![cache.to_path_buf()],
+            HashingAlgorithm::MD5,
+            true,
+        );
+
+        let mut paths = deduper.cache.paths().collect::<Vec<_>>();
+        paths.sort();
+        let file = format!("dir{}file", std::path::MAIN_SEPARATOR);
+        assert_eq!(paths, vec!["README.md", file.as_str()]);
+
+        assert!(deduper.cache.contains_path("README.md"));
+        assert!(deduper.cache.contains_path(&file));
+        assert!(!deduper.cache.contains_path("dir"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_deleted_precedence() -> anyhow::Result<()> {
+        let (temp, origin, _deduped, cache) = setup()?;
+
+        let mut delta = DedupCache::new();
+        delta.mark_deleted("README.md");
+        let cache_delta = temp.child("delta.json");
+        delta.write_to_file(&cache_delta)?;
+
+        // The tombstone survives a write and read cycle.
+        let mut dedup_cache = DedupCache::new();
+        dedup_cache.read_from_file(&cache_delta)?;
+        assert!(dedup_cache.is_deleted("README.md"));
+
+        // A tombstone in an earlier cache hides the file of a later one.
+        let mut dedup_cache = DedupCache::new();
+        dedup_cache.read_from_files(
+            &[cache_delta.to_path_buf(), cache.to_path_buf()],
+            CacheCompression::None,
+            None,
+        )?;
+        assert!(!dedup_cache.contains_path("README.md"));
+        assert_eq!(
+            dedup_cache.deleted_paths().collect::<Vec<_>>(),
+            vec!["README.md"]
+        );
+
+        // A file in an earlier cache overrides the tombstone of a later one.
+        let mut dedup_cache = DedupCache::new();
+        dedup_cache.read_from_files(
+            &[cache.to_path_buf(), cache_delta.to_path_buf()],
+            CacheCompression::None,
+            None,
+        )?;
+        assert!(dedup_cache.contains_path("README.md"));
+        assert!(!dedup_cache.is_deleted("README.md"));
+
+        // A file that reappears in the source replaces the tombstone.
+        let deduper = Deduper::new(
+            origin.to_path_buf(),
+            vec![cache_delta.to_path_buf()],
+            HashingAlgorithm::MD5,
+            true,
+        );
+        assert!(deduper.cache.contains_path("README.md"));
+        assert!(!deduper.cache.is_deleted("README.md"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_restore_outside_target() -> anyhow::Result<()> {
+        let (temp, _origin, deduped, cache) = setup()?;
+
+        let mut hydrator = Hydrator::new(deduped.to_path_buf(), vec![cache.to_path_buf()]);
+        let mut fwc = hydrator.cache.files.remove("README.md").unwrap();
+        fwc.path = format!("..{}escaped", std::path::MAIN_SEPARATOR);
+        hydrator.cache.insert(fwc.path.clone(), fwc);
+
+        let result = hydrator.restore_files(temp.child("hydrated").path(), 3);
+        assert!(matches!(result, Err(Error::OutsideTarget { .. })));
+        temp.child("escaped").assert(predicates::path::missing());
+
+        let hydrator = HydratorBuilder::new(deduped.path())
+            .cache_paths(vec![cache.path()])
+            .absolute_paths(true)
+            .build();
+        let result = hydrator.restore_files(temp.child("hydrated").path(), 3);
+        assert!(matches!(result, Err(Error::NoSourceRoot)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_restore_to_zip() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let large = (0..u8::MAX)
+            .cycle()
+            .take(FIXED_CHUNK_SIZE as usize * 3 / 2)
+            .collect::<Vec<_>>();
+        origin.child("dir/large").write_binary(&large)?;
+        origin.child("README.md").write_str("Hello, world!")?;
+        origin.child("empty").touch()?;
+
+        {
+            let mut deduper = DeduperBuilder::new(origin.path())
+                .cache_paths(vec![cache.path()])
+                .try_build()?;
+            deduper.write_chunks(deduped.path(), 2)?;
+            deduper.write_cache()?;
+        }
+
+        let zip_file = temp.child("restored.zip");
+        let report =
+            Hydrator::new(deduped.path(), vec![cache.path()]).restore_to_zip(zip_file.path(), 2)?;
+        assert_eq!(report.restored, 3);
+        assert!(report.mtime_not_set.is_empty());
+
+        let mut archive = zip::ZipArchive::new(File::open(&zip_file)?)?;
+        assert_eq!(
+            archive.file_names().collect::<BTreeSet<_>>(),
+            BTreeSet::from(["README.md", "dir/large", "empty"])
+        );
+
+        let mut read_entry = |name| -> anyhow::Result<(Vec<u8>, Option<zip::DateTime>)> {
+            let mut entry = archive.by_name(name)?;
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            Ok((content, entry.last_modified()))
+        };
+
+        let (content, mtime) = read_entry("dir/large")?;
+        assert_eq!(content, large);
+        let expected =
+            time::OffsetDateTime::from(origin.child("dir/large").metadata()?.modified()?);
+        let mtime = mtime.unwrap();
+        assert_eq!(
+            (mtime.year(), mtime.month(), mtime.day()),
+            (
+                expected.year() as u16,
+                expected.month() as u8,
+                expected.day()
+            )
+        );
+        assert_eq!(read_entry("README.md")?.0, b"Hello, world!");
+        assert!(read_entry("empty")?.0.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_restore_by_mime() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        origin
+            .child("image.png")
+            .write_binary(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR")?;
+        origin
+            .child("document.pdf")
+            .write_binary(b"%PDF-1.7\n%\xe2\xe3\xcf\xd3\n")?;
+        origin.child("text.txt").write_str("Hello, world!")?;
+
+        {
+            let mut deduper = DeduperBuilder::new(origin.path())
+                .cache_paths(vec![cache.path()])
+                .detect_mime(true)
+                .try_build()?;
+            deduper.write_chunks(deduped.path(), 0)?;
+            deduper.write_cache()?;
+        }
+
+        let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+        let mime_type = |path| hydrator.cache.get(path).unwrap().mime_type.as_deref();
+        assert_eq!(mime_type("image.png"), Some("image/png"));
+        assert_eq!(mime_type("document.pdf"), Some("application/pdf"));
+        assert_eq!(mime_type("text.txt"), None);
+
+        let images = temp.child("images");
+        let report = hydrator.restore_by_mime(images.path(), 0, "image/*")?;
+        assert_eq!(report.restored, 1);
+        images
+            .child("image.png")
+            .assert(predicates::path::is_file());
+        images
+            .child("document.pdf")
+            .assert(predicates::path::missing());
+        images.child("text.txt").assert(predicates::path::missing());
+
+        let documents = temp.child("documents");
+        let report = hydrator.restore_by_mime(documents.path(), 0, "application/pdf")?;
+        assert_eq!(report.restored, 1);
+        documents
+            .child("document.pdf")
+            .assert(predicates::path::is_file());
+
+        let result = hydrator.restore_by_mime(temp.child("invalid").path(), 0, "image/[");
+        assert!(matches!(result, Err(Error::InvalidMimePattern(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_cache_write_path() -> anyhow::Result<()> {
+        let (temp, origin, deduped, cache) = setup()?;
+        let shared = std::fs::read(&cache)?;
+        let local = temp.child("local.json");
+
+        origin.child("new").write_str("new")?;
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .cache_write_path(local.path())
+            .hashing_algorithm(HashingAlgorithm::MD5)
+            .try_build()?;
+        deduper.write_chunks(deduped.path(), 3)?;
+        deduper.write_cache()?;
+
+        assert_eq!(std::fs::read(&cache)?, shared);
+
+        let mut written = DedupCache::new();
+        written.read_from_file(&local)?;
+        assert!(written.contains_key("README.md"));
+        assert!(written.contains_key("new"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_file_size_range() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let cache = temp.child("cache.json");
+        origin.child("small").write_str("Hi")?;
+        origin.child("medium").write_str("Hello, world!")?;
+        origin.child("large").write_binary(&[0; 100])?;
+
+        let paths = |deduper: &Deduper| {
+            let mut paths = deduper
+                .cache()
+                .paths()
+                .map(String::from)
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+
+        let deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .try_build()?;
+        deduper.write_cache()?;
+        assert_eq!(paths(&deduper), ["large", "medium", "small"]);
+
+        // Cached files outside the range are pruned.
+        let deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .min_file_size(3)
+            .max_file_size(50)
+            .try_build()?;
+        assert_eq!(paths(&deduper), ["medium"]);
+
+        let deduper = DeduperBuilder::new(origin.path())
+            .min_file_size(13)
+            .try_build()?;
+        assert_eq!(paths(&deduper), ["large", "medium"]);
+
+        let deduper = DeduperBuilder::new(origin.path())
+            .max_file_size(13)
+            .try_build()?;
+        assert_eq!(paths(&deduper), ["medium", "small"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_validate_coverage() -> anyhow::Result<()> {
+        let (_temp, _origin, _deduped, cache) = setup()?;
+        let mut dedup_cache = DedupCache::new();
+        dedup_cache.read_from_file(&cache)?;
+        assert_eq!(dedup_cache.validate_coverage(), vec![]);
+
+        let file = |path: &str, size, chunks: &[(u64, u64)]| {
+            FileWithChunks::from_chunks(
+                path,
+                size,
+                SystemTime::UNIX_EPOCH,
+                HashingAlgorithm::MD5,
+                chunks
+                    .iter()
+                    .map(|&(start, size)| FileChunk::new(start, size, "hash".into()))
+                    .collect(),
+            )
+        };
+        let dedup_cache = DedupCacheBuilder::new()
+            .files([
+                file("tiled", 10, &[(4, 6), (0, 4)]),
+                file("empty", 0, &[]),
+                file("gaps", 10, &[(2, 4)]),
+                file("overlap", 10, &[(0, 6), (4, 6)]),
+                file("long", 10, &[(0, 12)]),
+            ])
+            .build();
+        assert_eq!(
+            dedup_cache.validate_coverage(),
+            vec![
+                CoverageError::Gap {
+                    path: "gaps".into(),
+                    start: 0,
+                    end: 2
+                },
+                CoverageError::Gap {
+                    path: "gaps".into(),
+                    start: 6,
+                    end: 10
+                },
+                CoverageError::BeyondEnd {
+                    path: "long".into(),
+                    size: 10,
+                    end: 12
+                },
+                CoverageError::Overlap {
+                    path: "overlap".into(),
+                    start: 4,
+                    end: 6
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_cache_totals() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let deduped = temp.child("deduped");
+        origin.child("a").write_str("Hello, world!")?;
+        origin.child("b").write_str("Hi")?;
+
+        let mtime = SystemTime::UNIX_EPOCH;
+        let cache = DedupCacheBuilder::new()
+            .files([
+                FileWithChunks::from_chunks(
+                    "x",
+                    10,
+                    mtime,
+                    HashingAlgorithm::MD5,
+                    vec![
+                        FileChunk::new(0, 4, "h1".into()),
+                        FileChunk::new(4, 6, "h2".into()),
+                    ],
+                ),
+                FileWithChunks::from_chunks(
+                    "y",
+                    4,
+                    mtime,
+                    HashingAlgorithm::MD5,
+                    vec![FileChunk::new(0, 4, "h1".into())],
+                ),
+            ])
+            .build();
+        assert_eq!(cache.total_chunk_count(), Some(3));
+        assert_eq!(cache.total_file_size(), 14);
+
+        let mut deduper = DeduperBuilder::new(origin.path()).try_build()?;
+        assert_eq!(deduper.cache.total_chunk_count(), None);
+        assert_eq!(deduper.cache.total_file_size(), 15);
+        assert_eq!(deduper.total_input_bytes(), 15);
+        assert_eq!(deduper.file_count(), 2);
+        // The totals did not trigger any hashing.
+        assert_eq!(deduper.cache.total_chunk_count(), None);
+
+        deduper.write_chunks(deduped.path(), 0)?;
+        assert_eq!(deduper.cache.total_chunk_count(), Some(2));
+        assert_eq!(deduper.cache.total_file_size(), 15);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_file_chunk_data() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let data = b"Hello, world!";
+        let chunk = FileChunk::new(0, data.len() as u64, "abcdef".into());
+
+        temp.child("data/a/b/abcdef").write_binary(data)?;
+        assert_eq!(chunk.data(temp.path(), 2)?, data);
+
+        let mut read = Vec::new();
+        chunk.reader(temp.path(), 2)?.read_to_end(&mut read)?;
+        assert_eq!(read, data);
+
+        assert!(matches!(
+            chunk.data(temp.path(), 0),
+            Err(Error::ChunkMissing { hash }) if hash == "abcdef"
+        ));
+
+        temp.child("data/abcdef").write_binary(b"Hello")?;
+        assert!(matches!(
+            chunk.data(temp.path(), 0),
+            Err(Error::ChunkSizeMismatch {
+                expected: 13,
+                actual: 5,
+                ..
             })
-            .flatten()
-            .map(|entry| entry.into_path())
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn check_parallel_cache_construction() {
+        let file = |path: &str, size: u64| {
+            FileWithChunks::from_chunks(
+                path,
+                size,
+                SystemTime::UNIX_EPOCH,
+                HashingAlgorithm::MD5,
+                vec![FileChunk::new(0, size, "hash".into())],
+            )
+        };
+        // Every path occurs twice, the later records must win.
+        let files = (0..1000u64)
+            .map(|i| file(&format!("file-{}", i % 500), i))
+            .collect::<Vec<_>>();
+
+        let cache = DedupCacheBuilder::new()
+            .declutter_segment_length(2)
+            .par_files(files.clone())
+            .build();
+        assert_eq!(cache.len(), 500);
+        assert_eq!(cache.get("file-7").unwrap().size, 507);
+        assert_eq!(cache.store_layout().declutter_segment_length, 2);
+
+        let cache = files
+            .into_par_iter()
+            .map(|fwc| (fwc.path.clone(), fwc))
+            .collect::<DedupCache>();
+        assert_eq!(cache.len(), 500);
+        assert_eq!(cache.get("file-499").unwrap().size, 999);
+    }
+
+    #[test]
+    fn check_file_age() -> anyhow::Result<()> {
+        let hour = Duration::from_secs(60 * 60);
+        let file = |path: &str, mtime| {
+            FileWithChunks::from_chunks(path, 0, mtime, HashingAlgorithm::MD5, vec![])
+        };
+        let now = SystemTime::now();
+        let cache = DedupCacheBuilder::new()
+            .files([
+                file("old", now - 2 * hour),
+                file("recent", now - hour / 2),
+                file("future", now + hour),
+            ])
+            .build();
+
+        let age = cache.get("old").unwrap().age()?;
+        assert!(age >= 2 * hour);
+        assert!(age < 3 * hour);
+        assert!(matches!(
+            cache.get("future").unwrap().age(),
+            Err(Error::FutureModificationTime { path }) if path == "future"
+        ));
+
+        let old = cache
+            .older_than(hour)
+            .map(|fwc| fwc.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(old, ["old"]);
+        assert_eq!(cache.older_than(Duration::ZERO).count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "tracing")]
+    fn check_tracing_spans() -> anyhow::Result<()> {
+        use tracing_subscriber::layer::{Context, SubscriberExt};
+
+        /// Records the names of all created spans.
+        struct SpanNames(Arc<Mutex<Vec<&'static str>>>);
+
+        impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanNames {
+            fn on_new_span(
+                &self,
+                attrs: &tracing::span::Attributes<'_>,
+                _id: &tracing::span::Id,
+                _ctx: Context<'_, S>,
+            ) {
+                self.0.lock().unwrap().push(attrs.metadata().name());
+            }
+        }
+
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("a").write_str("Hello, world!")?;
+        origin.child("b").write_str("Hi")?;
+
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(SpanNames(names.clone()));
+        tracing::subscriber::with_default(subscriber, || -> anyhow::Result<()> {
+            let mut deduper = DeduperBuilder::new(origin.path())
+                .cache_paths(vec![temp.child("cache.json").path()])
+                .try_build()?;
+            deduper.write_chunks(temp.child("deduped").path(), 0)?;
+            deduper.write_cache()?;
+            Ok(())
+        })?;
+
+        assert_eq!(
+            *names.lock().unwrap(),
+            [
+                "cache_load",
+                "directory_scan",
+                "chunk_write",
+                "chunk_hash",
+                "chunk_hash",
+                "cache_write"
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_iter_sorted() -> anyhow::Result<()> {
+        let file = |path: &str| {
+            FileWithChunks::from_chunks(
+                path,
+                1,
+                SystemTime::UNIX_EPOCH,
+                HashingAlgorithm::MD5,
+                vec![FileChunk::new(0, 1, path.into())],
+            )
+        };
+        let paths = ["b", "a/c", "c", "a", "B", "a/b"];
+        let cache = DedupCacheBuilder::new().files(paths.map(file)).build();
+
+        let sorted = cache
+            .iter_sorted()
+            .map(|fwc| fwc.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(sorted, ["B", "a", "a/b", "a/c", "b", "c"]);
+        assert!(
+            cache
+                .iter_sorted()
+                .map(|fwc| fwc.path.as_str())
+                .eq(sorted.iter().copied())
+        );
+
+        let chunks = cache
+            .get_chunks()?
+            .map(|item| item.map(|(hash, ..)| hash))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(chunks, sorted);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_chunk_reader() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let content = (0..5 * 1024 * 1024 / 2)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+        let file = temp.child("file");
+        file.write_binary(&content)?;
+
+        let chunking = Chunking::ContentDefined {
+            min_size: 16 * 1024,
+            avg_size: 64 * 1024,
+            max_size: 256 * 1024,
+        };
+        for chunking in [Chunking::Fixed, chunking] {
+            let mut fwc = FileWithChunks::try_new(temp.path(), file.path(), HashingAlgorithm::MD5)?;
+            fwc.chunking = chunking;
+            let expected = fwc
+                .get_or_calculate_chunks()?
+                .iter()
+                .map(|chunk| (chunk.start, chunk.size, chunk.hash.clone()))
+                .collect::<Vec<_>>();
+
+            let chunks = chunking.chunk_reader(content.as_slice(), HashingAlgorithm::MD5)?;
+            let chunks = chunks
+                .into_iter()
+                .map(|chunk| (chunk.start, chunk.size, chunk.hash))
+                .collect::<Vec<_>>();
+            assert_eq!(chunks, expected);
+        }
+
+        let chunks = Chunking::Fixed.chunk_reader(std::io::empty(), HashingAlgorithm::MD5)?;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].size, 0);
+        assert_eq!(chunks[0].hash, HashingAlgorithm::MD5.hash_hex(&[]));
+
+        // Data stored from a reader can be restored with a cache recording the chunks.
+        let deduped = temp.child("deduped");
+        let layout = StoreLayout::default();
+        let chunks = layout.store_reader(
+            content.as_slice(),
+            &deduped.child(&layout.data_dir_name),
+            2,
+            HashingAlgorithm::MD5,
+            Chunking::Fixed,
+        )?;
+        assert_eq!(chunks.len(), 3);
+
+        let cache = temp.child("cache.json");
+        DedupCacheBuilder::new()
+            .file(FileWithChunks::from_chunks(
+                "stream",
+                content.len() as u64,
+                SystemTime::now(),
+                HashingAlgorithm::MD5,
+                chunks,
+            ))
+            .build()
+            .write_to_file(&cache)?;
+        let restored = temp.child("restored");
+        Hydrator::new(deduped.path(), vec![cache.path()]).restore_files(restored.path(), 2)?;
+        assert_eq!(std::fs::read(restored.child("stream"))?, content);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_files_modified_between() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let file = |path: &str, secs| {
+            FileWithChunks::from_chunks(path, 0, at(secs), HashingAlgorithm::MD5, vec![])
+        };
+        let mut cache = DedupCacheBuilder::new()
+            .files([
+                file("d", 40),
+                file("a", 10),
+                file("c", 20),
+                file("b", 20),
+                file("e", 50),
+            ])
+            .build();
+
+        let between = |cache: &DedupCache, start, end| {
+            cache
+                .files_modified_between(at(start), at(end))
+                .into_iter()
+                .map(|fwc| fwc.path.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(between(&cache, 10, 40), ["a", "b", "c"]);
+        assert_eq!(between(&cache, 20, 41), ["b", "c", "d"]);
+        assert_eq!(between(&cache, 0, 100), ["a", "b", "c", "d", "e"]);
+        assert!(between(&cache, 21, 40).is_empty());
+        assert!(between(&cache, 40, 40).is_empty());
+        assert!(between(&cache, 50, 10).is_empty());
+
+        // Changes to the files are reflected.
+        cache.get_mut("a").unwrap().mtime = at(30);
+        cache.mark_deleted("c");
+        cache.insert("f".into(), file("f", 25));
+        assert_eq!(between(&cache, 10, 40), ["b", "f", "a"]);
+
+        // None of the files exist, so pruning removes all of them.
+        cache.prune_missing(Path::new("/nonexistent"));
+        assert!(between(&cache, 0, 100).is_empty());
+    }
+
+    #[test]
+    fn check_write_chunks_with_checkpoint() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+
+        let origin = temp.child("origin");
+        for i in 0..10 {
+            origin
+                .child(format!("file-{i}"))
+                .write_str(&i.to_string())?;
+        }
+
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        // Slow down writing the chunks and look at the cache on disk halfway through the run.
+        let checkpointed = Arc::new(Mutex::new(None));
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .on_chunk({
+                let cache = cache.path().to_path_buf();
+                let checkpointed = checkpointed.clone();
+                let mut count = 0;
+                move |_| {
+                    std::thread::sleep(Duration::from_millis(20));
+                    count += 1;
+                    if count != 5 {
+                        return;
+                    }
+                    let start = Instant::now();
+                    while !cache.exists() && start.elapsed() < Duration::from_secs(5) {
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                    let mut on_disk = DedupCache::new();
+                    on_disk.read_from_file(&cache).unwrap();
+                    let hashed = on_disk
+                        .values()
+                        .filter(|fwc| fwc.chunks.get().is_some())
+                        .count();
+                    *checkpointed.lock().unwrap() = Some(hashed);
+                }
+            })
+            .build();
+        deduper.write_chunks_with_checkpoint(deduped.path(), 0, Duration::from_millis(10))?;
+
+        // The checkpoint contains some, but not all of the hashed files.
+        let hashed = checkpointed.lock().unwrap().unwrap();
+        assert!((1..5).contains(&hashed), "{hashed} files were checkpointed");
+
+        deduper.write_cache()?;
+        let mut on_disk = DedupCache::new();
+        on_disk.read_from_file(cache.path())?;
+        assert_eq!(on_disk.len(), 10);
+        assert!(on_disk.values().all(|fwc| fwc.chunks.get().is_some()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_chunked_size() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin
+            .child("file")
+            .write_binary(&vec![1; 2 * 1024 * 1024 + 3])?;
+        let deduped = temp.child("deduped");
+
+        let deduper = DeduperBuilder::new(origin.path()).build();
+        let fwc = deduper.cache.get("file").unwrap();
+        assert_eq!(fwc.chunked_size()?, fwc.size);
+        assert_eq!(fwc.get_chunks().unwrap().len(), 3);
+
+        // The file is modified after it was scanned, but before it is hashed.
+        let mut deduper = DeduperBuilder::new(origin.path()).build();
+        origin.child("file").write_str("changed")?;
+        let chunks = deduper.cache.get_chunks()?.collect::<Vec<_>>();
+        assert!(matches!(
+            chunks.as_slice(),
+            [Err(Error::ChunkedSizeMismatch { path, .. })] if path == "file"
+        ));
+        assert!(matches!(
+            deduper.write_chunks(deduped.path(), 0),
+            Err(Error::ChunkedSizeMismatch { path, expected, actual })
+                if path == "file" && expected == 2 * 1024 * 1024 + 3 && actual == 7
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_metadata_only() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("a").write_str("Hello")?;
+        origin.child("sub/b").write_str("world")?;
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .metadata_only(true)
+            .build();
+        let stats = deduper.write_chunks(deduped.path(), 0)?;
+        deduper.write_cache()?;
+
+        assert_eq!(stats.written + stats.deduped, 0);
+        deduped.assert(predicates::path::missing());
+        assert_eq!(deduper.cache.len(), 2);
+        assert!(deduper.cache.is_metadata_only());
+        assert_eq!(deduper.cache.get("sub/b").unwrap().size, 5);
+
+        // The metadata is read back from the cache, without chunks.
+        let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+        assert!(hydrator.cache.is_metadata_only());
+        assert_eq!(hydrator.cache.total_file_size(), 10);
+
+        // A regular run calculates the chunks.
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .build();
+        deduper.write_chunks(deduped.path(), 0)?;
+        assert!(!deduper.cache.is_metadata_only());
+
+        Ok(())
     }
 
-    /// Delete files in source directory that are not listed in cache.
-    pub fn delete_extra_files(&self, declutter_levels: usize) -> anyhow::Result<()> {
-        for path in self.list_extra_files(declutter_levels) {
-            std::fs::remove_file(&path)?;
+    #[test]
+    fn check_assume_unchanged() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("stable/a").write_str("Hello")?;
+        origin.child("stable/nested/b").write_str("world")?;
+        origin.child("other").write_str("other")?;
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.json");
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .build();
+        deduper.write_chunks(deduped.path(), 0)?;
+        deduper.write_cache()?;
+        let cached_hash = |deduper: &Deduper, path: &str| {
+            deduper.cache.get(path).unwrap().get_chunks().unwrap()[0]
+                .hash
+                .clone()
+        };
+        let old_hash = cached_hash(&deduper, "stable/nested/b");
+        let old_mtime = deduper.cache.get("stable/a").unwrap().mtime;
+
+        // Touch all files and change one of the assumed unchanged ones behind the tool's back.
+        origin.child("stable/nested/b").write_str("WORLD")?;
+        for path in ["stable/a", "stable/nested/b", "other"] {
+            OpenOptions::new()
+                .write(true)
+                .open(origin.child(path))?
+                .set_modified(old_mtime + Duration::from_secs(60))?;
         }
 
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .assume_unchanged(["stable/**"])
+            .build();
+        deduper.write_chunks(deduped.path(), 0)?;
+
+        // The cache entries are trusted as they are, without re-hashing.
+        assert_eq!(deduper.cache.get("stable/a").unwrap().mtime, old_mtime);
+        assert_eq!(cached_hash(&deduper, "stable/nested/b"), old_hash);
+        assert_eq!(
+            deduper.cache.get("other").unwrap().mtime,
+            old_mtime + Duration::from_secs(60)
+        );
+
+        // Without a cache entry, matching files are hashed as usual.
+        origin.child("stable/new").write_str("new")?;
+        let deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .assume_unchanged(["stable/*"])
+            .build();
+        assert!(
+            deduper
+                .cache
+                .get("stable/new")
+                .unwrap()
+                .get_chunks()
+                .is_none()
+        );
+        // `*` does not match across directories.
+        assert_eq!(
+            deduper.cache.get("stable/nested/b").unwrap().mtime,
+            old_mtime + Duration::from_secs(60)
+        );
+
+        assert!(matches!(
+            DeduperBuilder::new(origin.path())
+                .assume_unchanged(["stable/["])
+                .try_build(),
+            Err(Error::InvalidPathPattern(_))
+        ));
+
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::fs::OpenOptions;
+    #[test]
+    fn check_get_chunks_for_file() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("a").write_str("Hello, world!")?;
+        origin.child("b").write_str("Hi")?;
 
-    use assert_fs::fixture::ChildPath;
-    use assert_fs::prelude::*;
-    use assert_fs::{NamedTempFile, TempDir};
+        let deduper = DeduperBuilder::new(origin.path()).try_build()?;
 
-    use super::*;
+        let chunks = deduper.cache.get_chunks_for_file("a")?.collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 1);
+        let (hash, chunk, dirty) = &chunks[0];
+        assert_eq!(
+            hash,
+            &HashingAlgorithm::default().hash_hex(b"Hello, world!")
+        );
+        assert_eq!(chunk.path.as_deref(), Some("a"));
+        assert_eq!(chunk.size, 13);
+        assert!(dirty);
+
+        // Chunks are only calculated once, the second iteration is clean.
+        let (_, _, dirty) = deduper.cache.get_chunks_for_file("a")?.next().unwrap();
+        assert!(!dirty);
+        assert!(deduper.cache.get("b").unwrap().get_chunks().is_none());
+
+        assert!(matches!(
+            deduper.cache.get_chunks_for_file("c"),
+            Err(Error::FileNotFound { path }) if path == "c"
+        ));
 
-    fn setup() -> anyhow::Result<(TempDir, ChildPath, ChildPath, ChildPath)> {
-        let temp = TempDir::new()?;
+        Ok(())
+    }
 
+    #[test]
+    fn check_on_chunk_events() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
         let origin = temp.child("origin");
-        origin.create_dir_all()?;
-        origin.child("README.md").write_str("Hello, world!")?;
+        let deduped = temp.child("deduped");
+
+        origin.child("file-a").write_str("same")?;
+        origin.child("file-b").write_str("same")?;
+
+        let hash = HashingAlgorithm::MD5.hash_hex(b"same");
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .hashing_algorithm(HashingAlgorithm::MD5)
+            .on_chunk({
+                let events = events.clone();
+                move |event| {
+                    events.lock().unwrap().push((
+                        event.path.to_string(),
+                        event.hash.to_string(),
+                        event.start,
+                        event.size,
+                        event.deduped,
+                    ));
+                }
+            })
+            .try_build()?;
+        deduper.write_chunks_two_pass(deduped.path(), 0)?;
+
+        let events = events.lock().unwrap().clone();
+        assert_eq!(
+            events,
+            vec![
+                ("file-a".to_string(), hash.clone(), 0, 4, false),
+                ("file-b".to_string(), hash.clone(), 0, 4, true),
+            ]
+        );
+
+        Ok(())
+    }
 
+    #[test]
+    fn check_chunk_exists_callback() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
         let deduped = temp.child("deduped");
-        deduped.create_dir_all()?;
 
+        origin.child("file-a").write_str("a")?;
+        origin.child("file-b").write_str("b")?;
+
+        let hash_a = HashingAlgorithm::MD5.hash_hex(b"a");
+        let hash_b = HashingAlgorithm::MD5.hash_hex(b"b");
+
+        let queried = Arc::new(Mutex::new(Vec::new()));
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .hashing_algorithm(HashingAlgorithm::MD5)
+            .chunk_exists({
+                let queried = queried.clone();
+                let hash_b = hash_b.clone();
+                move |hash| {
+                    queried.lock().unwrap().push(hash.to_string());
+                    hash == hash_b
+                }
+            })
+            .try_build()?;
+        deduper.write_chunks(deduped.path(), 0)?;
+
+        let mut queried = queried.lock().unwrap().clone();
+        queried.sort();
+        let mut expected = vec![hash_a.clone(), hash_b.clone()];
+        expected.sort();
+        assert_eq!(queried, expected);
+
+        deduped
+            .child("data")
+            .child(&hash_a)
+            .assert(predicates::path::is_file());
+        deduped
+            .child("data")
+            .child(&hash_b)
+            .assert(predicates::path::missing());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_multiple_sources() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin_a = temp.child("origin-a");
+        let origin_b = temp.child("origin-b");
+        let deduped = temp.child("deduped");
         let cache = temp.child("cache.json");
 
+        origin_a.child("shared").write_str("shared content")?;
+        origin_a.child("dir/only-a").write_str("a")?;
+        origin_b.child("shared").write_str("shared content")?;
+
         {
-            let mut deduper = Deduper::new(
-                origin.to_path_buf(),
-                vec![cache.to_path_buf()],
-                HashingAlgorithm::MD5,
-                true,
-            );
-            deduper.write_chunks(deduped.to_path_buf(), 3)?;
-            deduper.write_cache();
+            let mut deduper = DeduperBuilder::new(origin_a.path())
+                .add_source(origin_b.path())
+                .cache_paths(vec![cache.path()])
+                .try_build()?;
+
+            let mut paths = deduper.cache.paths().collect::<Vec<_>>();
+            paths.sort();
+            assert_eq!(paths, vec!["0/dir/only-a", "0/shared", "1/shared"]);
+
+            deduper.write_chunks(deduped.path(), 0)?;
+            deduper.write_cache()?;
         }
 
-        Ok((temp, origin, deduped, cache))
+        // The shared content is only stored once.
+        assert_eq!(std::fs::read_dir(deduped.child("data"))?.count(), 2);
+
+        let hydrator = Hydrator::new(deduped.path(), vec![cache.path()]);
+
+        let result = hydrator.restore_all(&[temp.child("only-one").to_path_buf()], 0);
+        assert!(matches!(result, Err(Error::MissingSourceTarget { .. })));
+
+        let hydrated_a = temp.child("hydrated-a");
+        let hydrated_b = temp.child("hydrated-b");
+        let report =
+            hydrator.restore_all(&[hydrated_a.to_path_buf(), hydrated_b.to_path_buf()], 0)?;
+        assert_eq!(report.restored, 3);
+        hydrated_a.child("shared").assert("shared content");
+        hydrated_a.child("dir/only-a").assert("a");
+        hydrated_b.child("shared").assert("shared content");
+        hydrated_b.child("dir").assert(predicates::path::missing());
+
+        let result = DeduperBuilder::new(origin_a.path())
+            .add_source(origin_b.path())
+            .absolute_paths(true)
+            .try_build();
+        assert!(result.is_err());
+
+        Ok(())
     }
 
     #[test]
-    fn compare_filechunk_objects() -> anyhow::Result<()> {
+    fn check_cache_from_records() -> anyhow::Result<()> {
         let temp = TempDir::new()?;
+        let store = temp.child("store");
+        let cache = temp.child("cache.json");
 
-        let file_1 = temp.child("file_1");
-        std::fs::write(&file_1, "content_1")?;
+        let hash_a = HashingAlgorithm::MD5.hash_hex(b"Hello, ");
+        let hash_b = HashingAlgorithm::MD5.hash_hex(b"world!");
+        store
+            .child("data")
+            .child(&hash_a[..2])
+            .child(&hash_a)
+            .write_str("Hello, ")?;
+        store
+            .child("data")
+            .child(&hash_b[..2])
+            .child(&hash_b)
+            .write_str("world!")?;
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        DedupCacheBuilder::new()
+            .declutter_segment_length(2)
+            .files([
+                FileWithChunks::from_chunks(
+                    "greeting",
+                    13,
+                    mtime,
+                    HashingAlgorithm::MD5,
+                    vec![
+                        FileChunk::new(0, 7, hash_a.clone()),
+                        FileChunk::new(7, 6, hash_b.clone()),
+                    ],
+                ),
+                FileWithChunks::from_chunks(
+                    "dir/world",
+                    6,
+                    mtime,
+                    HashingAlgorithm::MD5,
+                    vec![FileChunk::new(0, 6, hash_b.clone())],
+                ),
+            ])
+            .build()
+            .write_to_file(&cache)?;
+
+        let hydrated = temp.child("hydrated");
+        let hydrator = Hydrator::new(store.path(), vec![cache.path()]);
+        let report = hydrator.restore_files(hydrated.path(), 1)?;
+        assert_eq!(report.restored, 2);
+
+        hydrated.child("greeting").assert("Hello, world!");
+        hydrated.child("dir/world").assert("world!");
+        assert_eq!(hydrated.child("greeting").metadata()?.modified()?, mtime);
 
-        let file_2 = temp.child("file_2");
-        std::fs::write(&file_2, "content_2")?;
+        Ok(())
+    }
 
-        let fwc_1 = FileWithChunks::try_new(&temp.path(), &file_1.path(), HashingAlgorithm::MD5)?;
-        let fwc_1_same =
-            FileWithChunks::try_new(&temp.path(), &file_1.path(), HashingAlgorithm::MD5)?;
-        let fwc_2 = FileWithChunks::try_new(&temp.path(), &file_2.path(), HashingAlgorithm::MD5)?;
+    #[test]
+    fn check_manifest_round_trip() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin
+            .child("a")
+            .write_binary(&vec![1; FIXED_CHUNK_SIZE as usize + 5])?;
+        origin.child("dir/b").write_str("content")?;
+        origin.child("empty").touch()?;
+        let deduped = temp.child("deduped");
 
-        assert_eq!(fwc_1, fwc_1);
-        assert_eq!(fwc_1, fwc_1_same);
-        assert_ne!(fwc_1, fwc_2);
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .hashing_algorithm(HashingAlgorithm::SHA256)
+            .build();
+        deduper.write_chunks(deduped.path(), 0)?;
+
+        let mut manifest = Vec::new();
+        deduper.cache().write_manifest(&mut manifest)?;
+        let cache = DedupCache::from_manifest(manifest.as_slice())?;
+        assert_eq!(cache.len(), 3);
+        for fwc in deduper.cache().values() {
+            let imported = cache.get(&fwc.path).unwrap();
+            assert_eq!(imported, fwc);
+            let chunks = |fwc: &FileWithChunks| {
+                fwc.get_chunks()
+                    .unwrap()
+                    .iter()
+                    .map(|chunk| (chunk.start, chunk.size, chunk.hash.clone()))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(chunks(imported), chunks(fwc));
+            assert_eq!(imported.hashing_algorithm, fwc.hashing_algorithm);
+        }
 
-        // Some operating systems like Windows need write access to the file to be able to set the
-        // modified time.
+        let hydrated = temp.child("hydrated");
+        let hydrator = HydratorBuilder::new(deduped.path()).cache(cache).build();
+        let report = hydrator.restore_files(hydrated.path(), 0)?;
+        assert_eq!(report.restored, 3);
+        assert_eq!(
+            std::fs::read(hydrated.child("a"))?,
+            vec![1; FIXED_CHUNK_SIZE as usize + 5]
+        );
+        hydrated.child("dir/b").assert("content");
+        hydrated.child("empty").assert("");
+
+        assert!(matches!(
+            DedupCache::from_manifest(&b"{}"[..]),
+            Err(Error::ManifestParse(_))
+        ));
+        assert!(matches!(
+            DedupCache::from_manifest(
+                &br#"[{"path": "a", "size": 1, "mtime": 0, "chunks": [{"start": 0, "size": 1, "hash": "abc"}]}]"#[..]
+            ),
+            Err(Error::ManifestParse(_))
+        ));
+        assert!(matches!(
+            DedupCache::from_manifest(
+                &br#"[{"path": "a", "size": 2, "mtime": 0, "chunks": [{"start": 0, "size": 1, "hash": "0409b44b"}]}]"#[..]
+            ),
+            Err(Error::ChunkedSizeMismatch { expected: 2, actual: 1, .. })
+        ));
+        for entry in [
+            r#"[{"path": "a", "size": 0, "mtime": 0, "mtime_nanos": 1000000000}]"#,
+            r#"[{"path": "a", "size": 0, "mtime": 18446744073709551615}]"#,
+        ] {
+            assert!(matches!(
+                DedupCache::from_manifest(entry.as_bytes()),
+                Err(Error::ManifestParse(_))
+            ));
+        }
+        let cache = DedupCache::from_manifest(
+            &br#"[{"path": "a", "size": 0, "mtime": 1, "mtime_nanos": 999999999}]"#[..],
+        )?;
+        assert_eq!(
+            cache.get("a").unwrap().mtime,
+            SystemTime::UNIX_EPOCH + Duration::new(1, 999_999_999)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_path_prefix() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        origin.child("photos/2024/a").write_str("a")?;
+        origin.child("photos/2024/nested/b").write_str("b")?;
+        origin.child("photos/2025/c").write_str("c")?;
+        origin.child("photos2024/d").write_str("d")?;
+        origin.child("e").write_str("e")?;
+        let deduped = temp.child("deduped");
+        let cache = temp.child("cache.msgpack.zst");
+
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .build();
+        deduper.write_chunks(deduped.path(), 0)?;
+        deduper.write_cache()?;
+
+        let load = |path_prefix: &str| {
+            let hydrator = HydratorBuilder::new(deduped.path())
+                .cache_paths(vec![cache.path()])
+                .path_prefix(path_prefix)
+                .build();
+            hydrator
+                .cache
+                .iter_sorted()
+                .map(|fwc| fwc.path.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            load("photos/2024"),
+            ["photos/2024/a", "photos/2024/nested/b"]
+        );
+        assert_eq!(load("/photos/2024/"), load("photos/2024"));
+        assert_eq!(load("photos/2024/nested/b"), ["photos/2024/nested/b"]);
+        assert_eq!(load("photos/20"), Vec::<String>::new());
+        assert_eq!(load("").len(), 5);
+
+        let restored = temp.child("restored");
+        let report = HydratorBuilder::new(deduped.path())
+            .cache_paths(vec![cache.path()])
+            .path_prefix("photos/2025")
+            .build()
+            .restore_files(restored.path(), 0)?;
+        assert_eq!(report.restored, 1);
+        restored.child("photos/2025/c").assert("c");
+        restored.child("e").assert(predicates::path::missing());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_truncated_source() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let deduped = temp.child("deduped");
+
+        let content = (0..u8::MAX)
+            .cycle()
+            .take(FIXED_CHUNK_SIZE as usize * 5 / 2)
+            .collect::<Vec<_>>();
+        origin.child("file").write_binary(&content)?;
+
+        let mut deduper = DeduperBuilder::new(origin.path()).build();
+        deduper
+            .cache
+            .get("file")
+            .unwrap()
+            .get_or_calculate_chunks()?;
+
+        // Truncate the file after hashing, in the middle of the second chunk.
         OpenOptions::new()
             .write(true)
-            .open(&file_1)?
-            .set_modified(SystemTime::now())?;
+            .open(origin.child("file"))?
+            .set_len(FIXED_CHUNK_SIZE * 3 / 2)?;
 
-        let fwc_1_new =
-            FileWithChunks::try_new(&temp.path(), &file_1.path(), HashingAlgorithm::MD5)?;
+        assert!(matches!(
+            deduper.write_chunks(deduped.path(), 0),
+            Err(Error::SourceTruncated { path, .. }) if path == origin.child("file").path()
+        ));
 
-        assert_ne!(fwc_1, fwc_1_new);
+        // Only complete chunks were written.
+        for entry in std::fs::read_dir(deduped.child("data"))? {
+            let entry = entry?;
+            assert_eq!(
+                HashingAlgorithm::SHA1.hash_hex(&std::fs::read(entry.path())?),
+                entry.file_name().to_string_lossy()
+            );
+        }
 
         Ok(())
     }
 
     #[test]
-    fn check_all_hashing_algorithms() -> anyhow::Result<()> {
-        let algorithms = &[
-            (HashingAlgorithm::MD5, "0fb073cd346f46f60c15e719f3820482"),
-            (
-                HashingAlgorithm::SHA1,
-                "5503f5edc1bba66a7733c5ec38f4e9d449021be9",
-            ),
-            (
-                HashingAlgorithm::SHA256,
-                "e8c73ac958a87f17906b092bd99f37038788ee23b271574aad6d5bf1c76cc61c",
-            ),
-            (
-                HashingAlgorithm::SHA512,
-                "e6eda213df25f96ca380dd07640df530574e380c1b93d5d863fec05d5908a4880a3075fef4a438cfb1023cc51affb4624002f54b4790fe8362c7de032eb39aaa",
-            ),
-        ];
+    fn check_write_stats() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let deduped = temp.child("deduped");
+        origin.child("a").write_str("Hello, world!")?;
+        origin.child("b").write_str("Hello, world!")?;
+        origin.child("c").write_str("Hi")?;
+
+        let mut deduper = DeduperBuilder::new(origin.path()).try_build()?;
+        let stats = deduper.write_chunks(deduped.path(), 0)?;
+        assert_eq!(stats.written, 2);
+        assert_eq!(stats.deduped, 1);
+        assert_eq!(stats.bytes_written, 15);
+        assert_eq!(stats.bytes_deduped, 13);
+
+        // Only the new file is written into the partially filled chunk store.
+        origin.child("d").write_str("Bye")?;
+        let mut deduper = DeduperBuilder::new(origin.path()).try_build()?;
+        let stats = deduper.write_chunks_two_pass(deduped.path(), 0)?;
+        assert_eq!(stats.written, 1);
+        assert_eq!(stats.deduped, 3);
+        assert_eq!(stats.bytes_written, 3);
+        assert_eq!(stats.bytes_deduped, 28);
+
+        // Per file, the chunks of cached files are not calculated again.
+        let cache = temp.child("cache.json");
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .try_build()?;
+        deduper.write_chunks(deduped.path(), 0)?;
+        deduper.write_cache()?;
+
+        origin.child("e").write_str("Hello, world!")?;
+        let mut deduper = DeduperBuilder::new(origin.path())
+            .cache_paths(vec![cache.path()])
+            .try_build()?;
+        let stats = deduper.write_chunks(deduped.path(), 0)?;
+        let files = stats
+            .files
+            .iter()
+            .map(|file| (file.path.as_str(), file.hashed, file.written, file.deduped))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            [
+                ("a", false, 0, 1),
+                ("b", false, 0, 1),
+                ("c", false, 0, 1),
+                ("d", false, 0, 1),
+                ("e", true, 0, 1),
+            ]
+        );
+
+        Ok(())
+    }
 
+    #[test]
+    fn check_write_chunks_two_pass() -> anyhow::Result<()> {
         let temp = TempDir::new()?;
-        let file = temp.child("file");
-        std::fs::write(&file, "hello rust")?;
+        let origin = temp.child("origin");
 
-        for (algorithm, expected_hash) in algorithms.iter().copied() {
-            let cache_file = NamedTempFile::new("cache.json")?;
+        let mut bytes = (0..u8::MAX).cycle();
+        for file in 0..=1 {
+            origin
+                .child(format!("file-{file}"))
+                .write_binary(&bytes.by_ref().take(1500 * 1024).collect::<Vec<_>>())?;
+        }
+        std::fs::copy(origin.child("file-0"), origin.child("file-9"))?;
 
-            let chunks = Deduper::new(temp.path(), vec![cache_file.path()], algorithm, true)
-                .cache
-                .get_chunks()?
+        let list_chunks = |target: &ChildPath| -> anyhow::Result<Vec<_>> {
+            let mut chunks = WalkDir::new(target)
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_file())
+                .map(|entry| entry.path().strip_prefix(target).unwrap().to_path_buf())
                 .collect::<Vec<_>>();
+            chunks.sort();
+            Ok(chunks)
+        };
 
-            assert_eq!(chunks.len(), 1, "Too many chunks");
+        let interleaved = temp.child("interleaved");
+        let mut deduper = DeduperBuilder::new(origin.path()).try_build()?;
+        deduper.write_chunks(interleaved.path(), 2)?;
 
-            let (hash, _, _) = &chunks[0];
-            assert_eq!(
-                hash, &expected_hash,
-                "Algorithm {:?} does not produce expected hash",
-                algorithm
-            );
-        }
+        let two_pass = temp.child("two-pass");
+        let mut deduper = DeduperBuilder::new(origin.path()).try_build()?;
+        deduper.write_chunks_two_pass(two_pass.path(), 2)?;
+
+        assert_eq!(list_chunks(&two_pass)?.len(), 4);
+        assert_eq!(list_chunks(&two_pass)?, list_chunks(&interleaved)?);
+        assert_eq!(deduper.timings().bytes_hashed, 3 * 1500 * 1024);
+        assert_eq!(deduper.timings().bytes_written, 2 * 1500 * 1024);
 
         Ok(())
     }
 
     #[test]
-    fn check_cache() -> anyhow::Result<()> {
-        let (_temp, _origin, deduped, cache) = setup()?;
+    fn check_rename_detection() -> anyhow::Result<()> {
+        let (_temp, origin, _deduped, cache) = setup()?;
 
-        assert!(
-            Hydrator::new(deduped.to_path_buf(), vec![cache.to_path_buf()]).check_cache(3),
-            "Cache checking failed when it shouldn't"
-        );
+        std::fs::rename(origin.child("README.md"), origin.child("README.txt"))?;
 
-        std::fs::remove_dir_all(deduped.child("data").read_dir()?.next().unwrap()?.path())?;
+        let deduper = Deduper::new(
+            origin.to_path_buf(),
+            vec![cache.to_path_buf()],
+            HashingAlgorithm::MD5,
+            true,
+        );
 
+        assert!(deduper.cache.get("README.md").is_none());
         assert!(
-            !Hydrator::new(deduped.to_path_buf(), vec![cache.to_path_buf()]).check_cache(3),
-            "Cache checking didn't fail when it should"
+            deduper
+                .cache
+                .get("README.txt")
+                .unwrap()
+                .get_chunks()
+                .is_some(),
+            "Chunks of renamed file were not re-used"
         );
 
         Ok(())
     }
 
     #[test]
-    fn check_list_extra() -> anyhow::Result<()> {
-        let (_temp, _origin, deduped, cache) = setup()?;
+    fn check_rename_detection_with_same_metadata() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        let origin = temp.child("origin");
+        let cache = temp.child("cache.json");
 
-        assert_eq!(
-            Hydrator::new(deduped.to_path_buf(), vec![cache.to_path_buf()])
-                .list_extra_files(3)
-                .count(),
-            0,
-            "Extra files present when there shouldn't be"
-        );
+        // Both files have the same size and modification time, so only the content can tell them
+        // apart.
+        for name in ["a", "b"] {
+            let file = origin.child(name);
+            file.write_str(&format!("content_{name}"))?;
+            OpenOptions::new()
+                .write(true)
+                .open(&file)?
+                .set_modified(SystemTime::UNIX_EPOCH)?;
+        }
 
-        deduped
-            .child("data")
-            .child("extra_file")
-            .write_str("Hello, world!")?;
+        let hash_of = |deduper: &Deduper, path: &str| {
+            deduper
+                .cache
+                .get(path)
+                .unwrap()
+                .get_or_calculate_chunks()
+                .unwrap()[0]
+                .hash
+                .clone()
+        };
 
-        assert_eq!(
-            Hydrator::new(deduped.to_path_buf(), vec![cache.to_path_buf()])
-                .list_extra_files(3)
-                .count(),
-            1,
-            "Number of extra files present is not 1"
+        let deduper = Deduper::new(
+            origin.path(),
+            vec![cache.path()],
+            HashingAlgorithm::MD5,
+            true,
         );
+        let hash_a = hash_of(&deduper, "a");
+        let hash_b = hash_of(&deduper, "b");
+        deduper.write_cache()?;
+
+        std::fs::rename(origin.child("a"), origin.child("renamed_a"))?;
+        std::fs::rename(origin.child("b"), origin.child("renamed_b"))?;
+
+        let mut deduper = Deduper::new(
+            origin.path(),
+            vec![cache.path()],
+            HashingAlgorithm::MD5,
+            true,
+        );
+        assert_eq!(hash_of(&deduper, "renamed_a"), hash_a);
+        assert_eq!(hash_of(&deduper, "renamed_b"), hash_b);
 
-        deduped
-            .child("data")
-            .child("e")
-            .child("x")
-            .child("t")
-            .child("extra_file")
-            .write_str("Hello, world!")?;
+        std::fs::rename(origin.child("renamed_a"), origin.child("a"))?;
 
+        let report = deduper.sync(temp.child("deduped").path(), 0)?;
         assert_eq!(
-            Hydrator::new(deduped.to_path_buf(), vec![cache.to_path_buf()])
-                .list_extra_files(3)
-                .count(),
-            2,
-            "Number of extra files present is not 2"
+            report.renamed,
+            vec![("renamed_a".to_string(), "a".to_string())]
         );
+        assert!(report.added.is_empty());
+        assert!(report.removed.is_empty());
 
         Ok(())
     }
@@ -956,18 +9931,18 @@ mod tests {
             );
 
             assert!(
-                deduper.cache.get(&filename_with_newline).is_some(),
+                deduper.cache.get(filename_with_newline).is_some(),
                 "File with newline is missing from cache"
             );
 
             assert!(
-                deduper.cache.get(&filename_with_japanese).is_some(),
+                deduper.cache.get(filename_with_japanese).is_some(),
                 "File with Japanese is missing from cache"
             );
 
             deduper.write_chunks(deduped.to_path_buf(), 3)?;
 
-            deduper.write_cache();
+            deduper.write_cache()?;
 
             Ok(())
         };
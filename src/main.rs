@@ -1,28 +1,100 @@
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use clap::{Parser, ValueEnum};
-use crazy_deduper::{Deduper, HashingAlgorithm, Hydrator};
+use anyhow::{Result, bail};
+use clap::Parser;
+use clap::builder::{OsStringValueParser, PossibleValuesParser, TypedValueParser};
+use crazy_deduper::{
+    CacheCompression, CacheVersion, Chunking, DEFAULT_CDC_AVG_SIZE, DEFAULT_CDC_MAX_SIZE,
+    DEFAULT_CDC_MIN_SIZE, DEFAULT_DECLUTTER_SEGMENT_LENGTH, DedupCache, DedupReport,
+    DeduperBuilder, HashingAlgorithm, HydratorBuilder, NormalizationForm, ResumeMode, StoreLayout,
+    detect_cache_version,
+};
+use encoding_rs::Encoding;
+use fastcdc::v2020::{
+    AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX, MINIMUM_MIN,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     /// Source directory
-    source: PathBuf,
+    #[arg(required_unless_present = "detect_cache_version")]
+    source: Option<PathBuf>,
 
     /// Target directory
-    target: PathBuf,
+    #[arg(required_unless_present_any = ["detect_cache_version", "zip", "chunk_store_stats", "compare_store"])]
+    target: Option<PathBuf>,
 
     /// Path to cache file
     ///
     /// Can be used multiple times. The files are read in reverse order, so they should be sorted
-    /// with the most accurate ones in the beginning. The first given will be written.
-    #[arg(long)]
+    /// with the most accurate ones in the beginning. The first given will be written, unless
+    /// --cache-write-file is given. An empty path disables the cache like --no-cache, and - reads
+    /// the cache from stdin, which is never written.
+    #[arg(long, value_parser = OsStringValueParser::new().map(PathBuf::from))]
     cache_file: Vec<PathBuf>,
 
+    /// Write the cache to this file instead of the first --cache-file, which is then only read
+    ///
+    /// The written cache contains all entries, including those read from the other cache files.
+    /// Use - to write the cache to stdout.
+    #[arg(long, visible_alias = "output-cache", conflicts_with_all = ["decode", "no_cache_write"])]
+    cache_write_file: Option<PathBuf>,
+
+    /// Compression of caches read from stdin or written to stdout
+    ///
+    /// Cache files are compressed according to their extension, but stdin and stdout have none.
+    #[arg(long, default_value_t = CacheCompression::None, value_parser = cache_compression_parser())]
+    stdio_cache_compression: CacheCompression,
+
+    /// Run statelessly: hash all files and do not read or write any cache
+    ///
+    /// Useful for one-off deduplication, where the cache would only waste disk space. This is
+    /// also the behavior if no --cache-file is given, or only empty ones.
+    #[arg(
+        long,
+        conflicts_with_all = ["decode", "cache_file", "cache_write_file", "since_cache"]
+    )]
+    no_cache: bool,
+
+    /// Trust the cache for files matching this glob, even if their size or modification time
+    /// changed
+    ///
+    /// Can be used multiple times. Matching files are not re-hashed if they are in the cache,
+    /// which helps against modification times that are touched without changing the content. Use
+    /// with care: if the content did change, the cache and the chunks silently become wrong. The
+    /// patterns match paths relative to the source directory, * does not cross directories, ** does.
+    #[arg(long, conflicts_with = "decode", value_name = "GLOB")]
+    assume_unchanged: Vec<String>,
+
     /// Hashing algorithm to use for chunk filenames
-    #[arg(long, value_enum, default_value_t = HashingAlgorithmArgument::SHA1)]
-    hashing_algorithm: HashingAlgorithmArgument,
+    ///
+    /// crc32 is only meant for performance: it is much faster, but not collision-resistant, so
+    /// different chunks may be mixed up. Only use it for local stores where this is acceptable.
+    #[arg(long, default_value_t = HashingAlgorithm::SHA1, value_parser = hashing_algorithm_parser())]
+    hashing_algorithm: HashingAlgorithm,
+
+    /// Use content-defined chunks of variable size instead of fixed 1 MiB chunks
+    ///
+    /// Inserting or removing data in a file then only changes the chunks around the modification.
+    /// Only used when deduping, the chunk sizes are stored in the cache.
+    #[arg(long)]
+    cdc: bool,
+
+    /// Minimum size of content-defined chunks in bytes
+    #[arg(long, requires = "cdc", default_value_t = DEFAULT_CDC_MIN_SIZE, value_parser = clap::value_parser!(u32).range(MINIMUM_MIN as i64..=MINIMUM_MAX as i64))]
+    cdc_min_size: u32,
+
+    /// Average size of content-defined chunks in bytes
+    #[arg(long, requires = "cdc", default_value_t = DEFAULT_CDC_AVG_SIZE, value_parser = clap::value_parser!(u32).range(AVERAGE_MIN as i64..=AVERAGE_MAX as i64))]
+    cdc_avg_size: u32,
+
+    /// Maximum size of content-defined chunks in bytes
+    #[arg(long, requires = "cdc", default_value_t = DEFAULT_CDC_MAX_SIZE, value_parser = clap::value_parser!(u32).range(MAXIMUM_MIN as i64..=MAXIMUM_MAX as i64))]
+    cdc_max_size: u32,
 
     /// Limit file listing to same file system
     #[arg(long)]
@@ -32,51 +104,636 @@ struct Cli {
     #[arg(long, default_value_t = 0)]
     declutter_levels: usize,
 
+    /// Number of hash characters per declutter level
+    ///
+    /// Only used when deduping, the value is stored in the cache and read from there when
+    /// hydrating.
+    #[arg(long, default_value_t = DEFAULT_DECLUTTER_SEGMENT_LENGTH)]
+    declutter_segment_length: usize,
+
+    /// Name chunk files after their hash and size, to avoid collisions of chunks with different
+    /// sizes
+    ///
+    /// Only used when deduping, the setting is stored in the cache and read from there when
+    /// hydrating.
+    #[arg(long, conflicts_with = "decode")]
+    hash_includes_size: bool,
+
+    /// Directory of the chunk files, instead of the data subdirectory of the deduped directory
+    ///
+    /// Allows to keep the chunks on another file system. Needs to be given when deduping and
+    /// hydrating.
+    #[arg(long)]
+    chunk_base_path: Option<PathBuf>,
+
+    /// Only write chunks that are not referenced by this baseline cache
+    ///
+    /// Can be used to create an incremental delta store relative to an earlier run. The baseline
+    /// cache has to exist.
+    #[arg(long)]
+    since_cache: Option<PathBuf>,
+
+    /// Store files as a single chunk if deduplication saves less than this many bytes
+    ///
+    /// Avoids lots of useless chunk files for data that does not deduplicate, like encrypted or
+    /// compressed files.
+    #[arg(long)]
+    min_savings_bytes: Option<u64>,
+
+    /// Only consider files with at least this size, like 4K or 1.5MiB
+    ///
+    /// Units are powers of 1024. Cached files outside the size range are dropped from the cache.
+    #[arg(long, conflicts_with = "decode", value_parser = parse_file_size)]
+    min_size: Option<u64>,
+
+    /// Only consider files with at most this size, like 1G, see --min-size
+    #[arg(long, conflicts_with = "decode", value_parser = parse_file_size)]
+    max_size: Option<u64>,
+
+    /// Directory for temporary files, instead of next to the cache file
+    ///
+    /// If it is on a different device than the cache file, the cache is copied into place instead
+    /// of renamed, so the write is no longer atomic.
+    #[arg(long)]
+    temp_dir: Option<PathBuf>,
+
+    /// Octal permissions of created chunk files, like 0640 (Unix only)
+    ///
+    /// Created chunk directories get the same permissions, plus the execute bit wherever the read
+    /// bit is set.
+    #[arg(long, conflicts_with = "decode", value_parser = parse_mode)]
+    chunk_mode: Option<u32>,
+
+    /// Only read the cache files, do not update them
+    ///
+    /// For caches on read-only file systems, or auditing runs that must not change them.
+    #[arg(long)]
+    no_cache_write: bool,
+
+    /// Format version in which the cache file is written
+    ///
+    /// Allows to create cache files for older versions of crazy-deduper. Older formats cannot
+    /// record everything, like the chunking settings or deleted files.
+    #[arg(long, default_value_t = CacheVersion::LATEST, value_parser = cache_version_parser())]
+    cache_format_version: CacheVersion,
+
+    /// Record the absolute source directory in the cache, or restore files to it when decoding
+    ///
+    /// When decoding, the files are written to their original locations instead of into the
+    /// target directory. The original locations must then be inside the target directory, which
+    /// can be `/` to allow any location.
+    #[arg(long)]
+    absolute_paths: bool,
+
+    /// Allow --absolute-paths to restore files outside the target directory
+    #[arg(long, requires_all = ["decode", "absolute_paths"])]
+    allow_absolute: bool,
+
+    /// Detect the MIME type of each file and store it in the cache
+    ///
+    /// Allows to restore only some types of files with --filter-mime.
+    #[arg(long, conflicts_with = "decode")]
+    detect_mime: bool,
+
+    /// Record the hash of the whole content of each file in the cache, besides the chunk hashes
+    ///
+    /// When resuming a restore with --verify-resumed, files are then verified against it.
+    #[arg(long, conflicts_with = "decode")]
+    file_hashes: bool,
+
+    /// Encoding of the file names in the source directory, like latin1 or windows-1252
+    ///
+    /// The names are decoded from it and stored as UTF-8 in the cache. By default, names are
+    /// expected to be UTF-8 and invalid characters are replaced.
+    #[arg(long, conflicts_with = "decode", value_parser = parse_encoding)]
+    source_encoding: Option<&'static Encoding>,
+
+    /// Unicode normalization form of the file names in the cache
+    ///
+    /// Allows to re-use caches between file systems that store names in different forms, like
+    /// HFS+, which decomposes them.
+    #[arg(long, default_value_t = NormalizationForm::None, value_parser = normalization_form_parser())]
+    normalize_paths: NormalizationForm,
+
+    /// Only restore files whose MIME type matches this glob pattern, like image/*
+    ///
+    /// Requires that the MIME types were detected with --detect-mime when deduping. Files without
+    /// a detected MIME type, like plain text files, are never restored with this option.
+    #[arg(long, requires = "decode")]
+    filter_mime: Option<String>,
+
+    /// Print groups of files with identical content after deduping
+    ///
+    /// The groups are separated by empty lines. Modification times are not considered, and empty
+    /// files are not reported.
+    #[arg(long, conflicts_with_all = ["decode", "quiet"])]
+    find_duplicates: bool,
+
+    /// Stream one JSON object per processed chunk to this file while deduping, use - for stdout
+    ///
+    /// Each line contains the path, hash, start and size of the chunk, and whether it was
+    /// deduplicated instead of written.
+    #[arg(long, conflicts_with = "decode", value_name = "PATH")]
+    json_lines: Option<PathBuf>,
+
+    /// Write metrics of the dedup run to this file in the Prometheus text format
+    ///
+    /// The gauges cover the number and size of the files, the unique chunks, the dedup ratio and
+    /// the duration of the run. The file is replaced atomically, so it can be picked up by the
+    /// textfile collector of the node exporter.
+    #[arg(long, conflicts_with = "decode", value_name = "PATH")]
+    metrics: Option<PathBuf>,
+
+    /// Write the deduplicated files to this file as a JSON manifest
+    ///
+    /// The manifest can be restored with --import-manifest and read by other tools, which do not
+    /// need to understand the cache formats. Use - to write it to stdout.
+    #[arg(long, conflicts_with = "decode", value_name = "PATH")]
+    export_manifest: Option<PathBuf>,
+
+    /// Maximum number of source files to keep open at the same time while hashing
+    #[arg(long)]
+    max_open_files: Option<usize>,
+
+    /// Write the chunk store as a zstd-compressed tar archive to the target instead of a directory
+    ///
+    /// Use - as target to write the archive to stdout, for example to pipe it to remote storage.
+    /// No other output may be written to stdout then. Unpacking the archive creates the same
+    /// directory as deduping without this option.
+    #[arg(long, conflicts_with_all = ["decode", "find_duplicates", "timings", "verbose"])]
+    tar: bool,
+
+    /// Print the time spent scanning, hashing and writing chunks, and the achieved throughput
+    #[arg(long)]
+    timings: bool,
+
+    /// Suppress all output except errors
+    #[arg(long, short, conflicts_with_all = ["verbose", "timings", "detect_cache_version"])]
+    quiet: bool,
+
+    /// Print additional diagnostic output
+    ///
+    /// Given twice, also list each file with its number of chunks, how many of them were new or
+    /// deduplicated, and whether they were hashed or taken from the cache.
+    #[arg(long, short, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Print the number and size of the chunk files and the number of inodes in the chunk store
+    /// and exit
+    ///
+    /// Unlike the cache, this measures the actual chunk store on disk.
+    #[arg(long, requires = "decode")]
+    chunk_store_stats: bool,
+
+    /// Compare the chunk store with the one in another deduplicated directory and exit
+    ///
+    /// Lists the chunks referenced by the cache that are only present in one of both stores, for
+    /// example to verify a replica. Fails if the stores differ.
+    #[arg(long, requires = "decode", conflicts_with = "chunk_store_stats")]
+    compare_store: Option<PathBuf>,
+
+    /// Print the format version of each cache file and exit
+    #[arg(long, requires = "cache_file")]
+    detect_cache_version: bool,
+
     /// Invert behavior, restore tree from deduplicated data
     #[arg(long, short, visible_alias = "hydrate")]
     decode: bool,
+
+    /// Resume an interrupted restore, skip files whose size and modification time already match
+    #[arg(long, requires = "decode")]
+    resume: bool,
+
+    /// Do not write chunks that consist of zeros only, create sparse files with holes instead
+    #[arg(long, requires = "decode")]
+    sparse: bool,
+
+    /// Do not restore the original modification times, keep the time of the restore
+    #[arg(long, requires = "decode")]
+    no_preserve_mtime: bool,
+
+    /// Restore the original owner and group of the files, usually requires root privileges
+    ///
+    /// Files whose ownership cannot be set are restored anyway, with a warning.
+    #[arg(long, requires = "decode")]
+    preserve_ownership: bool,
+
+    /// Remove files from the target that are recorded as deleted in the cache files
+    ///
+    /// Deleted files are recorded when deduping with --since-cache. Without this flag, they are
+    /// only not restored.
+    #[arg(long, requires = "decode")]
+    remove_deleted: bool,
+
+    /// Restore the files listed in this JSON manifest, in addition to those in the cache files
+    ///
+    /// The manifest is an array of files like [{"path", "size", "mtime", "chunks": [{"start",
+    /// "size", "hash"}]}], as written by --export-manifest or by other tools from a chunk store
+    /// without a cache.
+    #[arg(long, requires = "decode", value_name = "PATH")]
+    import_manifest: Option<PathBuf>,
+
+    /// Only restore the files below this path relative to the source directory
+    ///
+    /// The other entries are skipped while reading the caches, which saves memory when restoring
+    /// a subtree of a large backup.
+    #[arg(long, requires = "decode", value_name = "PATH")]
+    path_prefix: Option<String>,
+
+    /// Restore into a zip archive at this path instead of a target directory
+    #[arg(long, requires = "decode", conflicts_with_all = ["target", "filter_mime"])]
+    zip: Option<PathBuf>,
+
+    /// Read this many chunks ahead of writing the restored files, in as many background threads
+    ///
+    /// Hides the latency of chunk stores on network or cloud storage. Disabled with 0.
+    #[arg(long, requires = "decode", default_value_t = 0)]
+    prefetch: usize,
+
+    /// Retry chunk and cache IO this many times on transient errors, with exponential backoff
+    ///
+    /// For flaky network mounts. Errors like missing files are not retried.
+    #[arg(long, default_value_t = 0)]
+    retries: u32,
+
+    /// Re-hash skipped files when resuming, to verify that their content matches
+    #[arg(long, requires = "resume")]
+    verify_resumed: bool,
+
+    /// Ignore modification times when resuming, compare existing files by size and content only
+    ///
+    /// For file systems with unreliable modification times.
+    #[arg(long, requires = "resume")]
+    ignore_mtime: bool,
 }
 
-#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, ValueEnum)]
-pub enum HashingAlgorithmArgument {
-    MD5,
-    SHA1,
-    SHA256,
-    SHA512,
+fn cache_version_parser() -> impl TypedValueParser<Value = CacheVersion> {
+    PossibleValuesParser::new(["0", "1", "2"])
+        .map(|version| version.parse::<CacheVersion>().unwrap())
 }
 
-impl From<HashingAlgorithmArgument> for HashingAlgorithm {
-    fn from(value: HashingAlgorithmArgument) -> Self {
-        match value {
-            HashingAlgorithmArgument::MD5 => HashingAlgorithm::MD5,
-            HashingAlgorithmArgument::SHA1 => HashingAlgorithm::SHA1,
-            HashingAlgorithmArgument::SHA256 => HashingAlgorithm::SHA256,
-            HashingAlgorithmArgument::SHA512 => HashingAlgorithm::SHA512,
-        }
+fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| format!("unknown encoding: {label}"))
+}
+
+fn parse_file_size(size: &str) -> Result<u64, String> {
+    parse_size::Config::new()
+        .with_binary()
+        .parse_size(size)
+        .map_err(|err| err.to_string())
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("invalid octal mode: {mode}")),
     }
 }
 
+fn cache_compression_parser() -> impl TypedValueParser<Value = CacheCompression> {
+    PossibleValuesParser::new(CacheCompression::ALL.map(|compression| compression.name()))
+        .map(|name| name.parse::<CacheCompression>().unwrap())
+}
+
+fn normalization_form_parser() -> impl TypedValueParser<Value = NormalizationForm> {
+    PossibleValuesParser::new(NormalizationForm::ALL.map(|form| form.name()))
+        .map(|name| name.parse::<NormalizationForm>().unwrap())
+}
+
+fn hashing_algorithm_parser() -> impl TypedValueParser<Value = HashingAlgorithm> {
+    PossibleValuesParser::new(HashingAlgorithm::ALL.map(|algorithm| algorithm.name()))
+        .map(|name| name.parse::<HashingAlgorithm>().unwrap())
+}
+
+/// Writes the metrics of a dedup run to `path` in the Prometheus text format, via a temporary
+/// file so that collectors never read a partial file.
+fn write_metrics(
+    path: &Path,
+    files: usize,
+    report: &DedupReport,
+    duration: Duration,
+) -> Result<()> {
+    let metrics = [
+        ("files", "Number of deduplicated files", files as f64),
+        (
+            "file_bytes",
+            "Total size of the deduplicated files in bytes",
+            report.total_file_bytes as f64,
+        ),
+        (
+            "unique_chunks",
+            "Number of distinct chunks",
+            report.unique_chunks as f64,
+        ),
+        (
+            "unique_chunk_bytes",
+            "Total size of the distinct chunks in bytes",
+            report.unique_chunk_bytes as f64,
+        ),
+        (
+            "dedup_ratio",
+            "Ratio of the file size to the size of the distinct chunks",
+            report.ratio,
+        ),
+        (
+            "run_duration_seconds",
+            "Duration of the dedup run in seconds",
+            duration.as_secs_f64(),
+        ),
+    ];
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let mut file = File::create(&temp_path)?;
+    for (name, help, value) in metrics {
+        writeln!(file, "# HELP crazy_deduper_{name} {help}")?;
+        writeln!(file, "# TYPE crazy_deduper_{name} gauge")?;
+        writeln!(file, "crazy_deduper_{name} {value}")?;
+    }
+    drop(file);
+    std::fs::rename(&temp_path, path)?;
+
+    Ok(())
+}
+
 fn main() -> Result<()> {
+    let start = Instant::now();
     let args = Cli::parse();
 
-    let source = args.source;
-    let target = args.target;
-    let cache_files = args.cache_file;
+    #[cfg(feature = "cli")]
+    if std::env::var_os("RUST_LOG").is_some() {
+        tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+            .with_writer(std::io::stderr)
+            .init();
+    }
+
+    if args.detect_cache_version {
+        for cache_file in &args.cache_file {
+            let version = detect_cache_version(cache_file)?;
+            println!("{}: {version}", cache_file.display());
+        }
+
+        return Ok(());
+    }
+
+    // Cannot panic, the source is required unless detecting the cache version. The target is
+    // required unless restoring into a zip archive, or printing chunk store stats, which does not
+    // use it.
+    let source = args.source.unwrap();
+    let target = args.zip.clone().or(args.target).unwrap_or_default();
+    // Without cache files, the deduper neither reads nor writes a cache.
+    let cache_files = if args.no_cache {
+        Vec::new()
+    } else {
+        args.cache_file
+    };
     let same_file_system = args.same_file_system;
     let declutter_levels = args.declutter_levels;
 
     if !args.decode {
-        let mut deduper = Deduper::new(
-            source,
-            cache_files,
-            args.hashing_algorithm.into(),
-            same_file_system,
-        );
-        deduper.write_chunks(target, declutter_levels)?;
-        deduper.write_cache();
+        // Anything else written to stdout would corrupt the archive.
+        if args.tar && target.as_os_str() == "-" {
+            for (option, path) in [
+                ("--json-lines", &args.json_lines),
+                ("--cache-write-file", &args.cache_write_file),
+                ("--export-manifest", &args.export_manifest),
+            ] {
+                if path.as_ref().is_some_and(|path| path.as_os_str() == "-") {
+                    bail!("Cannot write both the --tar archive and {option} to stdout");
+                }
+            }
+        }
+
+        let chunking = if args.cdc {
+            anyhow::ensure!(
+                args.cdc_min_size <= args.cdc_avg_size && args.cdc_avg_size <= args.cdc_max_size,
+                "Chunk sizes must satisfy --cdc-min-size <= --cdc-avg-size <= --cdc-max-size"
+            );
+
+            Chunking::ContentDefined {
+                min_size: args.cdc_min_size,
+                avg_size: args.cdc_avg_size,
+                max_size: args.cdc_max_size,
+            }
+        } else {
+            Chunking::Fixed
+        };
+
+        let mut deduper_builder = DeduperBuilder::new(source)
+            .cache_paths(cache_files)
+            .hashing_algorithm(args.hashing_algorithm)
+            .chunking(chunking)
+            .same_file_system(same_file_system)
+            .declutter_segment_length(args.declutter_segment_length)
+            .hash_includes_size(args.hash_includes_size)
+            .absolute_paths(args.absolute_paths)
+            .detect_mime(args.detect_mime)
+            .file_hashes(args.file_hashes)
+            .normalize_paths(args.normalize_paths)
+            .read_only_cache(args.no_cache_write)
+            .assume_unchanged(args.assume_unchanged)
+            .cache_format_version(args.cache_format_version)
+            .stdio_cache_compression(args.stdio_cache_compression)
+            .retries(args.retries);
+        if let Some(cache_write_file) = args.cache_write_file {
+            deduper_builder = deduper_builder.cache_write_path(cache_write_file);
+        }
+        if let Some(since_cache) = args.since_cache {
+            deduper_builder = deduper_builder.since_cache(since_cache);
+        }
+        if let Some(source_encoding) = args.source_encoding {
+            deduper_builder = deduper_builder.source_encoding(source_encoding);
+        }
+        if let Some(chunk_base_path) = &args.chunk_base_path {
+            deduper_builder = deduper_builder.chunk_base_path(chunk_base_path);
+        }
+        if let Some(min_savings_bytes) = args.min_savings_bytes {
+            deduper_builder = deduper_builder.min_savings_bytes(min_savings_bytes);
+        }
+        if let Some(min_size) = args.min_size {
+            deduper_builder = deduper_builder.min_file_size(min_size);
+        }
+        if let Some(max_size) = args.max_size {
+            deduper_builder = deduper_builder.max_file_size(max_size);
+        }
+        if let Some(temp_dir) = args.temp_dir {
+            deduper_builder = deduper_builder.temp_dir(temp_dir);
+        }
+        if let Some(chunk_mode) = args.chunk_mode {
+            deduper_builder = deduper_builder.chunk_mode(chunk_mode);
+        }
+        if let Some(max_open_files) = args.max_open_files {
+            deduper_builder = deduper_builder.max_open_files(max_open_files);
+        }
+        if let Some(json_lines) = &args.json_lines {
+            let output: Box<dyn Write + Send> = if json_lines.as_os_str() == "-" {
+                Box::new(std::io::stdout())
+            } else {
+                Box::new(File::create(json_lines)?)
+            };
+            let mut output = LineWriter::new(output);
+            deduper_builder = deduper_builder.on_chunk(move |event| {
+                // A closed output, like a pipe into head, must not abort the deduplication.
+                if serde_json::to_writer(&mut output, event).is_ok() {
+                    let _ = writeln!(output);
+                }
+            });
+        }
+        let mut deduper = deduper_builder.try_build()?;
+        let stats = if args.tar {
+            let writer: Box<dyn Write> = if target.as_os_str() == "-" {
+                Box::new(std::io::stdout().lock())
+            } else {
+                Box::new(File::create(&target)?)
+            };
+            let mut encoder = zstd::Encoder::new(writer, 0)?;
+            deduper.write_chunks_to_writer(&mut encoder, declutter_levels)?;
+            encoder.finish()?.flush()?;
+            None
+        } else {
+            let stats = deduper.write_chunks(&target, declutter_levels)?;
+            if args.verbose > 1 {
+                for file in &stats.files {
+                    println!(
+                        "{}: {} chunks, {} new, {} deduplicated, {}",
+                        file.path,
+                        file.written + file.deduped,
+                        file.written,
+                        file.deduped,
+                        if file.hashed { "hashed" } else { "cached" }
+                    );
+                }
+            }
+            Some(stats)
+        };
+        deduper.write_cache()?;
+        if let Some(manifest) = &args.export_manifest {
+            let mut output: Box<dyn Write> = if manifest.as_os_str() == "-" {
+                Box::new(std::io::stdout().lock())
+            } else {
+                Box::new(BufWriter::new(File::create(manifest)?))
+            };
+            deduper.cache().write_manifest(&mut output)?;
+            output.flush()?;
+        }
+
+        if args.verbose > 0 {
+            println!(
+                "Deduplicated {} files into {}",
+                deduper.cache().len(),
+                target.display()
+            );
+            if let Some(stats) = stats {
+                println!(
+                    "Wrote {} new chunks ({} bytes), skipped {} existing chunks ({} bytes)",
+                    stats.written, stats.bytes_written, stats.deduped, stats.bytes_deduped
+                );
+            }
+        }
+        if args.find_duplicates {
+            for (index, group) in deduper.duplicate_files()?.iter().enumerate() {
+                if index > 0 {
+                    println!();
+                }
+                for path in group {
+                    println!("{path}");
+                }
+            }
+        }
+        if args.timings {
+            println!("{}", deduper.timings());
+        }
+        if let Some(metrics) = &args.metrics {
+            write_metrics(
+                metrics,
+                deduper.file_count(),
+                &deduper.dedup_report(),
+                start.elapsed(),
+            )?;
+        }
     } else {
-        let hydrator = Hydrator::new(source, cache_files);
-        hydrator.restore_files(target, declutter_levels);
+        let resume = match (args.resume, args.verify_resumed) {
+            (false, _) => ResumeMode::Disabled,
+            (true, false) => ResumeMode::SizeAndMtime,
+            (true, true) => ResumeMode::Verify,
+        };
+
+        let mut hydrator_builder = HydratorBuilder::new(&source)
+            .cache_paths(cache_files)
+            .stdio_cache_compression(args.stdio_cache_compression)
+            .resume(resume)
+            .ignore_mtime(args.ignore_mtime)
+            .preserve_mtime(!args.no_preserve_mtime)
+            .preserve_ownership(args.preserve_ownership)
+            .sparse(args.sparse)
+            .remove_deleted(args.remove_deleted)
+            .absolute_paths(args.absolute_paths)
+            .allow_absolute(args.allow_absolute)
+            .prefetch_parallelism(args.prefetch)
+            .retries(args.retries);
+        if let Some(chunk_base_path) = &args.chunk_base_path {
+            hydrator_builder = hydrator_builder.chunk_base_path(chunk_base_path);
+        }
+        if let Some(path_prefix) = &args.path_prefix {
+            hydrator_builder = hydrator_builder.path_prefix(path_prefix);
+        }
+        if let Some(manifest) = &args.import_manifest {
+            let manifest = BufReader::new(File::open(manifest)?);
+            hydrator_builder = hydrator_builder.cache(DedupCache::from_manifest(manifest)?);
+        }
+        let hydrator = hydrator_builder.try_build()?;
+        if args.chunk_store_stats {
+            println!("{}", hydrator.chunk_store_stats(declutter_levels)?);
+            return Ok(());
+        }
+        if let Some(compare_store) = args.compare_store {
+            let diff = hydrator.compare_store(
+                compare_store.join(StoreLayout::default().data_dir_name),
+                declutter_levels,
+            )?;
+            if !args.quiet {
+                for hash in &diff.missing_there {
+                    println!("Missing in {}: {hash}", compare_store.display());
+                }
+                for hash in &diff.missing_here {
+                    println!("Missing in {}: {hash}", source.display());
+                }
+            }
+            if !diff.is_empty() {
+                bail!("Chunk stores differ");
+            }
+            return Ok(());
+        }
+        let report = match args.filter_mime {
+            _ if args.zip.is_some() => hydrator.restore_to_zip(target, declutter_levels)?,
+            Some(mime_pattern) => {
+                hydrator.restore_by_mime(target, declutter_levels, &mime_pattern)?
+            }
+            None => hydrator.restore_files(target, declutter_levels)?,
+        };
+
+        if args.quiet {
+            return Ok(());
+        }
+
+        for path in &report.mtime_not_set {
+            eprintln!("Warning: Could not set modification time of {path}");
+        }
+        for path in &report.ownership_not_set {
+            eprintln!("Warning: Could not set owner of {path}");
+        }
+
+        if args.resume || args.verbose > 0 {
+            println!(
+                "Restored: {}, skipped: {}, re-verified: {}",
+                report.restored, report.skipped, report.reverified
+            );
+        }
+        if args.remove_deleted && args.verbose > 0 {
+            println!("Removed: {}", report.removed);
+        }
     }
 
     Ok(())
@@ -1,4 +1,6 @@
 use assert_cmd::Command;
+use assert_fs::TempDir;
+use assert_fs::prelude::*;
 use predicates::prelude::*;
 
 mod common;
@@ -22,3 +24,416 @@ fn correct_version() {
         .success()
         .stdout(format!("{} {}\n", &*common::BIN_NAME, version));
 }
+
+#[test]
+fn timings_are_printed() -> anyhow::Result<()> {
+    let temp = TempDir::new()?;
+    let source = temp.child("source");
+    source.child("file").write_str("content")?;
+
+    Command::new(&*common::BIN_PATH)
+        .arg(source.path())
+        .arg(temp.child("target").path())
+        .arg("--timings")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Scanning:"))
+        .stdout(predicate::str::contains("Hashing:"))
+        .stdout(predicate::str::contains("Writing:"));
+
+    Ok(())
+}
+
+#[test]
+fn cache_version_is_detected() -> anyhow::Result<()> {
+    let temp = TempDir::new()?;
+    let source = temp.child("source");
+    source.child("file").write_str("content")?;
+    let cache = temp.child("cache.json.zst");
+
+    Command::new(&*common::BIN_PATH)
+        .arg(source.path())
+        .arg(temp.child("target").path())
+        .arg("--cache-file")
+        .arg(cache.path())
+        .assert()
+        .success();
+
+    Command::new(&*common::BIN_PATH)
+        .arg("--detect-cache-version")
+        .arg("--cache-file")
+        .arg(cache.path())
+        .assert()
+        .success()
+        .stdout(format!("{}: 2\n", cache.path().display()));
+
+    Ok(())
+}
+
+#[test]
+fn quiet_suppresses_output() -> anyhow::Result<()> {
+    let temp = TempDir::new()?;
+    let source = temp.child("source");
+    source.child("file").write_str("content")?;
+    let deduped = temp.child("deduped");
+    let cache = temp.child("cache.json.zst");
+
+    Command::new(&*common::BIN_PATH)
+        .arg(source.path())
+        .arg(deduped.path())
+        .arg("--cache-file")
+        .arg(cache.path())
+        .arg("--quiet")
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+
+    // Resuming prints statistics, unless quiet.
+    for quiet in [false, true] {
+        let mut command = Command::new(&*common::BIN_PATH);
+        command
+            .arg("--decode")
+            .arg(deduped.path())
+            .arg(temp.child("restored").path())
+            .arg("--cache-file")
+            .arg(cache.path())
+            .arg("--resume");
+        if quiet {
+            command.arg("-q").assert().success().stdout("").stderr("");
+        } else {
+            command
+                .assert()
+                .success()
+                .stdout(predicate::str::contains("Restored:"));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn verbose_prints_summary() -> anyhow::Result<()> {
+    let temp = TempDir::new()?;
+    let source = temp.child("source");
+    source.child("file").write_str("content")?;
+    let deduped = temp.child("deduped");
+
+    Command::new(&*common::BIN_PATH)
+        .arg(source.path())
+        .arg(deduped.path())
+        .arg("--verbose")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Deduplicated 1 files"))
+        .stdout(predicate::str::contains(
+            "Wrote 1 new chunks (7 bytes), skipped 0 existing chunks (0 bytes)",
+        ));
+
+    source.child("copy").write_str("content")?;
+    Command::new(&*common::BIN_PATH)
+        .arg(source.path())
+        .arg(deduped.path())
+        .arg("-vv")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "copy: 1 chunks, 0 new, 1 deduplicated, hashed\n\
+             file: 1 chunks, 0 new, 1 deduplicated, hashed\n\
+             Deduplicated 2 files",
+        ))
+        .stdout(predicate::str::contains(
+            "Wrote 0 new chunks (0 bytes), skipped 2 existing chunks (14 bytes)",
+        ));
+
+    Command::new(&*common::BIN_PATH)
+        .arg(source.path())
+        .arg(deduped.path())
+        .arg("--verbose")
+        .arg("--quiet")
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+#[test]
+fn find_duplicates_prints_groups() -> anyhow::Result<()> {
+    let temp = TempDir::new()?;
+    let source = temp.child("source");
+    source.child("a").write_str("content")?;
+    source.child("b").write_str("content")?;
+    source.child("c").write_str("other")?;
+    source.child("d").write_str("other")?;
+    source.child("e").write_str("unique")?;
+
+    Command::new(&*common::BIN_PATH)
+        .arg(source.path())
+        .arg(temp.child("deduped").path())
+        .arg("--find-duplicates")
+        .assert()
+        .success()
+        .stdout("a\nb\n\nc\nd\n");
+
+    Ok(())
+}
+
+#[test]
+fn json_lines_streams_chunk_events() -> anyhow::Result<()> {
+    let temp = TempDir::new()?;
+    let source = temp.child("source");
+    source.child("a").write_str("content")?;
+    source.child("b").write_str("content")?;
+
+    let output = Command::new(&*common::BIN_PATH)
+        .arg(source.path())
+        .arg(temp.child("deduped").path())
+        .args(["--json-lines", "-"])
+        .output()?;
+    assert!(output.status.success());
+
+    let mut events = String::from_utf8(output.stdout)?
+        .lines()
+        .map(serde_json::from_str::<serde_json::Value>)
+        .collect::<Result<Vec<_>, _>>()?;
+    events.sort_by_key(|event| event["path"].to_string());
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0]["path"], "a");
+    assert_eq!(events[1]["path"], "b");
+    assert_eq!(events[0]["size"], 7);
+    assert_eq!(events[0]["hash"], events[1]["hash"]);
+    // Only one of the identical chunks is written.
+    assert_ne!(events[0]["deduped"], events[1]["deduped"]);
+
+    Ok(())
+}
+
+#[test]
+fn metrics_are_written() -> anyhow::Result<()> {
+    let temp = TempDir::new()?;
+    let source = temp.child("source");
+    source.child("a").write_str("content")?;
+    source.child("b").write_str("content")?;
+    source.child("c").write_str("other")?;
+    let metrics = temp.child("metrics.prom");
+
+    Command::new(&*common::BIN_PATH)
+        .arg(source.path())
+        .arg(temp.child("deduped").path())
+        .arg("--metrics")
+        .arg(metrics.path())
+        .assert()
+        .success();
+
+    let content = std::fs::read_to_string(&metrics)?;
+    let lines = content.lines().collect::<Vec<_>>();
+    for expected in [
+        "# TYPE crazy_deduper_files gauge",
+        "crazy_deduper_files 3",
+        "crazy_deduper_file_bytes 19",
+        "crazy_deduper_unique_chunks 2",
+        "crazy_deduper_unique_chunk_bytes 12",
+    ] {
+        assert!(lines.contains(&expected), "{expected} missing in {content}");
+    }
+    let value = |name: &str| {
+        lines
+            .iter()
+            .find_map(|line| line.strip_prefix(&format!("crazy_deduper_{name} ")))
+            .unwrap()
+            .parse::<f64>()
+            .unwrap()
+    };
+    assert!((value("dedup_ratio") - 19.0 / 12.0).abs() < 1e-9);
+    assert!(value("run_duration_seconds") > 0.0);
+    temp.child("metrics.prom.tmp")
+        .assert(predicate::path::missing());
+
+    Ok(())
+}
+
+#[test]
+fn manifest_is_imported() -> anyhow::Result<()> {
+    let temp = TempDir::new()?;
+    let source = temp.child("source");
+    source.child("file").write_str("Hello, world!")?;
+    let deduped = temp.child("deduped");
+
+    Command::new(&*common::BIN_PATH)
+        .arg(source.path())
+        .arg(deduped.path())
+        .assert()
+        .success();
+
+    let hash = crazy_deduper::HashingAlgorithm::SHA1.hash_hex(b"Hello, world!");
+    let manifest = temp.child("manifest.json");
+    manifest.write_str(&format!(
+        r#"[{{"path": "dir/copy", "size": 13, "mtime": 1000000,
+            "chunks": [{{"start": 0, "size": 13, "hash": "{hash}"}}]}}]"#
+    ))?;
+
+    let restored = temp.child("restored");
+    Command::new(&*common::BIN_PATH)
+        .arg("--decode")
+        .arg(deduped.path())
+        .arg(restored.path())
+        .arg("--import-manifest")
+        .arg(manifest.path())
+        .assert()
+        .success();
+
+    restored.child("dir/copy").assert("Hello, world!");
+    assert_eq!(
+        restored.child("dir/copy").metadata()?.modified()?,
+        std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn manifest_is_exported() -> anyhow::Result<()> {
+    let temp = TempDir::new()?;
+    let source = temp.child("source");
+    source.child("dir/file").write_str("Hello, world!")?;
+    source.child("empty").touch()?;
+    let deduped = temp.child("deduped");
+    let manifest = temp.child("manifest.json");
+
+    Command::new(&*common::BIN_PATH)
+        .arg(source.path())
+        .arg(deduped.path())
+        .arg("--export-manifest")
+        .arg(manifest.path())
+        .assert()
+        .success();
+
+    // Without a cache file, the manifest alone describes the files.
+    let restored = temp.child("restored");
+    Command::new(&*common::BIN_PATH)
+        .arg("--decode")
+        .arg(deduped.path())
+        .arg(restored.path())
+        .arg("--import-manifest")
+        .arg(manifest.path())
+        .assert()
+        .success();
+
+    restored.child("dir/file").assert("Hello, world!");
+    restored.child("empty").assert("");
+    assert_eq!(
+        restored.child("dir/file").metadata()?.modified()?,
+        source.child("dir/file").metadata()?.modified()?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn path_prefix_restores_subtree() -> anyhow::Result<()> {
+    let temp = TempDir::new()?;
+    let source = temp.child("source");
+    source.child("dir/a").write_str("a")?;
+    source.child("other/b").write_str("b")?;
+    let deduped = temp.child("deduped");
+    let cache = temp.child("cache.json");
+
+    Command::new(&*common::BIN_PATH)
+        .arg(source.path())
+        .arg(deduped.path())
+        .arg("--cache-file")
+        .arg(cache.path())
+        .assert()
+        .success();
+
+    let restored = temp.child("restored");
+    Command::new(&*common::BIN_PATH)
+        .arg("--decode")
+        .arg(deduped.path())
+        .arg(restored.path())
+        .arg("--cache-file")
+        .arg(cache.path())
+        .arg("--path-prefix")
+        .arg("dir")
+        .assert()
+        .success();
+
+    restored.child("dir/a").assert("a");
+    restored.child("other").assert(predicate::path::missing());
+
+    Ok(())
+}
+
+#[test]
+fn chunk_store_stats_prints_usage() -> anyhow::Result<()> {
+    let temp = TempDir::new()?;
+    let source = temp.child("source");
+    source.child("a").write_str("content")?;
+    source.child("b").write_str("content")?;
+    let deduped = temp.child("deduped");
+    let cache = temp.child("cache.json");
+
+    Command::new(&*common::BIN_PATH)
+        .arg(source.path())
+        .arg(deduped.path())
+        .arg("--cache-file")
+        .arg(cache.path())
+        .assert()
+        .success();
+
+    Command::new(&*common::BIN_PATH)
+        .arg(deduped.path())
+        .arg("--cache-file")
+        .arg(cache.path())
+        .args(["--decode", "--chunk-store-stats"])
+        .assert()
+        .success()
+        .stdout("Chunks: 1\nBytes:  7\nInodes: 1\n");
+
+    Ok(())
+}
+
+#[test]
+fn compare_store_lists_differences() -> anyhow::Result<()> {
+    let temp = TempDir::new()?;
+    let source = temp.child("source");
+    source.child("a").write_str("content")?;
+    let deduped = temp.child("deduped");
+    let replica = temp.child("replica");
+    let cache = temp.child("cache.json");
+
+    for target in [&deduped, &replica] {
+        Command::new(&*common::BIN_PATH)
+            .arg(source.path())
+            .arg(target.path())
+            .arg("--cache-file")
+            .arg(cache.path())
+            .assert()
+            .success();
+    }
+
+    let compare = || {
+        let mut command = Command::new(&*common::BIN_PATH);
+        command
+            .arg(deduped.path())
+            .arg("--cache-file")
+            .arg(cache.path())
+            .arg("--decode")
+            .arg("--compare-store")
+            .arg(replica.path());
+        command
+    };
+    compare().assert().success().stdout("");
+
+    std::fs::remove_dir_all(replica.child("data"))?;
+    compare()
+        .assert()
+        .failure()
+        .stdout(format!(
+            "Missing in {}: 040f06fd774092478d450774f5ba30c5da78acc8\n",
+            replica.path().display()
+        ))
+        .stderr(predicate::str::contains("Chunk stores differ"));
+
+    Ok(())
+}
@@ -1,5 +1,6 @@
 use std::fs;
 use std::fs::OpenOptions;
+use std::io::Read;
 use std::ops::Add;
 use std::path::PathBuf;
 use std::time::{Duration, SystemTime};
@@ -9,6 +10,7 @@ use assert_cmd::Command;
 use assert_fs::TempDir;
 use assert_fs::fixture::ChildPath;
 use assert_fs::prelude::*;
+use predicates::prelude::*;
 use sha2::{Digest, Sha256};
 use walkdir::WalkDir;
 
@@ -83,8 +85,7 @@ fn fixture_impl(
         .min_depth(1)
         .sort_by_file_name();
 
-    for (file_origin, file_rehydrated) in files_origin.into_iter().zip(files_rehydrated.into_iter())
-    {
+    for (file_origin, file_rehydrated) in files_origin.into_iter().zip(files_rehydrated) {
         let file_origin = file_origin?;
         let file_rehydrated = file_rehydrated?;
 
@@ -161,11 +162,8 @@ fn empty_file() -> Result<()> {
     }
 
     fn check_dedup(path_dedup: &ChildPath) -> Result<()> {
-        let items = fs::read_dir(path_dedup.child("data"))?
-            .flatten()
-            .collect::<Vec<_>>();
-        assert_eq!(items.len(), 1);
-        assert_eq!(items.first().unwrap().metadata()?.len(), 0);
+        // Empty files do not need any chunk files.
+        assert_eq!(fs::read_dir(path_dedup.child("data"))?.count(), 0);
         Ok(())
     }
 
@@ -203,6 +201,26 @@ fn small_files() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn retries_round_trip() -> Result<()> {
+    fn setup_origin(path_origin: &ChildPath) -> Result<()> {
+        path_origin.child("file-a").write_str("same")?;
+        path_origin.child("file-b").write_str("same")?;
+
+        Ok(())
+    }
+
+    fn check_dedup(path_dedup: &ChildPath) -> Result<()> {
+        assert_eq!(fs::read_dir(path_dedup.child("data"))?.count(), 1);
+
+        Ok(())
+    }
+
+    fixture_with_additional_args(setup_origin, check_dedup, vec!["--retries", "3"])?;
+
+    Ok(())
+}
+
 #[test]
 fn big_files() -> Result<()> {
     fn setup_origin(path_origin: &ChildPath) -> Result<()> {
@@ -307,6 +325,16 @@ fn modified_files_same_cache() -> Result<()> {
 
     fixture_with_cache_file(setup_origin_1, check_dedup, cache_file.to_path_buf())?;
 
+    let temp = TempDir::new()?;
+    let cache_file = temp.child("cache.json.br");
+
+    fixture_with_cache_file(setup_origin_1, check_dedup, cache_file.to_path_buf())?;
+    fixture_with_cache_file(setup_origin_12, check_dedup, cache_file.to_path_buf())?;
+
+    let mut json = String::new();
+    brotli::Decompressor::new(fs::File::open(&cache_file)?, 4096).read_to_string(&mut json)?;
+    assert!(json.starts_with('{') && json.contains("\"file\""));
+
     Ok(())
 }
 
@@ -339,7 +367,7 @@ fn multiple_unrelated_sources_with_same_cache() -> Result<()> {
 #[test]
 fn file_declutter() -> Result<()> {
     fn setup_origin(path_origin: &ChildPath) -> Result<()> {
-        path_origin.child("empty").touch()?;
+        fs::write(path_origin.child("file"), "content")?;
         Ok(())
     }
 
@@ -378,6 +406,83 @@ fn file_declutter() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn file_declutter_segment_length() -> Result<()> {
+    fn setup_origin(path_origin: &ChildPath) -> Result<()> {
+        fs::write(path_origin.child("file"), "content")?;
+        Ok(())
+    }
+
+    fn check_dedup(path_dedup: &ChildPath) -> Result<()> {
+        // There should be 2 levels of subdirectories with 2 characters each inside data
+
+        let mut dir = path_dedup.child("data").to_path_buf();
+
+        for _ in 0..2 {
+            let dirs = fs::read_dir(dir)?.flatten().collect::<Vec<_>>();
+            assert_eq!(dirs.len(), 1);
+            assert!(dirs.first().unwrap().file_type()?.is_dir());
+            assert_eq!(dirs.first().unwrap().file_name().len(), 2);
+            dir = dirs.first().unwrap().path();
+        }
+
+        let files = fs::read_dir(dir)?.flatten().collect::<Vec<_>>();
+        assert_eq!(files.len(), 1);
+        assert!(files.first().unwrap().file_type()?.is_file());
+
+        Ok(())
+    }
+
+    fixture_with_additional_args(
+        setup_origin,
+        check_dedup,
+        vec!["--declutter-levels", "2", "--declutter-segment-length", "2"],
+    )?;
+
+    Ok(())
+}
+
+#[test]
+fn content_defined_chunking() -> Result<()> {
+    fn setup_origin(path_origin: &ChildPath) -> Result<()> {
+        let mut bytes = (0..u8::MAX).cycle();
+        fs::write(
+            path_origin.child("file"),
+            bytes.by_ref().take(100 * 1024).collect::<Vec<_>>(),
+        )?;
+
+        Ok(())
+    }
+
+    fn check_dedup(path_dedup: &ChildPath) -> Result<()> {
+        let chunks = fs::read_dir(path_dedup.child("data"))?
+            .flatten()
+            .map(|entry| entry.metadata().map(|metadata| metadata.len()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|&size| size <= 8192));
+
+        Ok(())
+    }
+
+    fixture_with_additional_args(
+        setup_origin,
+        check_dedup,
+        vec![
+            "--cdc",
+            "--cdc-min-size",
+            "1024",
+            "--cdc-avg-size",
+            "4096",
+            "--cdc-max-size",
+            "8192",
+        ],
+    )?;
+
+    Ok(())
+}
+
 #[test]
 fn turn_file_into_folder_same_cache() -> Result<()> {
     fn setup_origin_1(path_origin: &ChildPath) -> Result<()> {
@@ -390,7 +495,7 @@ fn turn_file_into_folder_same_cache() -> Result<()> {
     fn setup_origin_2(path_origin: &ChildPath) -> Result<()> {
         let child = path_origin.child("file");
         child.create_dir_all()?;
-        fs::write(&child.child("file"), "1")?;
+        fs::write(child.child("file"), "1")?;
         Ok(())
     }
 
@@ -413,3 +518,616 @@ fn turn_file_into_folder_same_cache() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn since_cache_only_writes_new_chunks() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let path_origin = temp.child("origin");
+    let cache_baseline = temp.child("baseline.json");
+    let cache_delta = temp.child("delta.json");
+
+    path_origin.child("file-1").write_str("1")?;
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(temp.child("baseline").path())
+        .arg("--cache-file")
+        .arg(cache_baseline.path())
+        .assert()
+        .success();
+
+    path_origin.child("file-2").write_str("2")?;
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(temp.child("delta").path())
+        .arg("--cache-file")
+        .arg(cache_delta.path())
+        .arg("--since-cache")
+        .arg(cache_baseline.path())
+        .assert()
+        .success();
+
+    // Only the chunk of the new file is written into the delta store.
+    let chunks = fs::read_dir(temp.child("delta").child("data"))?
+        .flatten()
+        .map(|entry| fs::read_to_string(entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(chunks, vec!["2"]);
+
+    Ok(())
+}
+
+#[test]
+fn since_cache_fails_for_missing_baseline() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let path_origin = temp.child("origin");
+    path_origin.child("file-1").write_str("1")?;
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(temp.child("delta").path())
+        .arg("--cache-file")
+        .arg(temp.child("delta.json").path())
+        .arg("--since-cache")
+        .arg(temp.child("missing.json").path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("missing.json does not exist"));
+
+    temp.child("delta").assert(predicate::path::missing());
+
+    Ok(())
+}
+
+#[test]
+fn no_cache_write_keeps_cache() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let path_origin = temp.child("origin");
+    let cache = temp.child("cache.json");
+
+    path_origin.child("file-1").write_str("1")?;
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(temp.child("dedup").path())
+        .arg("--cache-file")
+        .arg(cache.path())
+        .assert()
+        .success();
+
+    // Backdate the cache, so that any write would be noticed.
+    let mtime = SystemTime::now() - Duration::from_secs(3600);
+    OpenOptions::new()
+        .write(true)
+        .open(&cache)?
+        .set_modified(mtime)?;
+    let content = fs::read(&cache)?;
+
+    path_origin.child("file-2").write_str("2")?;
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(temp.child("dedup").path())
+        .arg("--cache-file")
+        .arg(cache.path())
+        .arg("--no-cache-write")
+        .assert()
+        .success();
+
+    assert_eq!(cache.metadata()?.modified()?, mtime);
+    assert_eq!(fs::read(&cache)?, content);
+    temp.child("dedup/data").assert(predicate::path::is_dir());
+    assert_eq!(
+        fs::read_dir(temp.path())?.count(),
+        3,
+        "No temporary file is left"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn no_cache_runs_statelessly() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let path_origin = temp.child("origin");
+    path_origin.child("file-1").write_str("1")?;
+
+    for args in [vec!["--no-cache"], vec!["--cache-file", ""]] {
+        Command::new(&*common::BIN_PATH)
+            .current_dir(temp.path())
+            .arg(path_origin.path())
+            .arg(temp.child("dedup").path())
+            .args(args)
+            .assert()
+            .success();
+    }
+
+    temp.child("dedup/data").assert(predicate::path::is_dir());
+    assert_eq!(
+        fs::read_dir(temp.path())?.count(),
+        2,
+        "No cache file is written"
+    );
+
+    for conflicting in ["--cache-file", "--since-cache"] {
+        Command::new(&*common::BIN_PATH)
+            .arg(path_origin.path())
+            .arg(temp.child("dedup").path())
+            .arg("--no-cache")
+            .arg(conflicting)
+            .arg(temp.child("cache.json").path())
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("cannot be used with"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn nested_source_and_target_fail() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let path_dedup = temp.child("dedup");
+    let path_origin = path_dedup.child("origin");
+    path_origin.child("file").write_str("content")?;
+
+    for (source, target) in [
+        (path_origin.path(), path_dedup.path()),
+        (path_dedup.path(), path_origin.child("dedup").path()),
+    ] {
+        Command::new(&*common::BIN_PATH)
+            .arg(source)
+            .arg(target)
+            .arg("--no-cache")
+            .timeout(Duration::from_secs(60))
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("are inside each other"));
+    }
+
+    path_dedup.child("data").assert(predicate::path::missing());
+    path_origin
+        .child("dedup")
+        .assert(predicate::path::missing());
+
+    Ok(())
+}
+
+#[test]
+fn size_range_filters_files() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let path_origin = temp.child("origin");
+    path_origin.child("small").write_str("small")?;
+    path_origin.child("medium").write_binary(&[1; 2048])?;
+    path_origin.child("large").write_binary(&[2; 8192])?;
+
+    let cache_file = temp.child("cache.json");
+    let path_dedup = temp.child("dedup");
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(path_dedup.path())
+        .arg("--cache-file")
+        .arg(cache_file.path())
+        .args(["--min-size", "1K", "--max-size", "4KiB"])
+        .assert()
+        .success();
+
+    let path_rehydrated = temp.child("rehydrate");
+    Command::new(&*common::BIN_PATH)
+        .arg(path_dedup.path())
+        .arg(path_rehydrated.path())
+        .arg("--cache-file")
+        .arg(cache_file.path())
+        .arg("-d")
+        .assert()
+        .success();
+
+    path_rehydrated
+        .child("medium")
+        .assert(predicate::path::is_file());
+    path_rehydrated
+        .child("small")
+        .assert(predicate::path::missing());
+    path_rehydrated
+        .child("large")
+        .assert(predicate::path::missing());
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(path_dedup.path())
+        .args(["--min-size", "lots"])
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+#[test]
+fn cache_write_file_keeps_read_caches() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let path_origin = temp.child("origin");
+    let shared = temp.child("shared.json");
+    let local = temp.child("local.json");
+
+    path_origin.child("file-1").write_str("1")?;
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(temp.child("dedup").path())
+        .arg("--cache-file")
+        .arg(shared.path())
+        .assert()
+        .success();
+    let content = fs::read(&shared)?;
+
+    path_origin.child("file-2").write_str("2")?;
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(temp.child("dedup").path())
+        .arg("--cache-file")
+        .arg(shared.path())
+        .arg("--cache-write-file")
+        .arg(local.path())
+        .assert()
+        .success();
+
+    assert_eq!(fs::read(&shared)?, content);
+    local.assert(predicate::str::contains("file-1").and(predicate::str::contains("file-2")));
+
+    Ok(())
+}
+
+#[test]
+fn since_cache_records_deleted_files() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let path_origin = temp.child("origin");
+    let path_deduped = temp.child("deduped");
+    let cache_baseline = temp.child("baseline.json");
+    let cache_delta = temp.child("delta.json");
+
+    path_origin.child("file-1").write_str("1")?;
+    path_origin.child("file-2").write_str("2")?;
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(path_deduped.path())
+        .arg("--cache-file")
+        .arg(cache_baseline.path())
+        .assert()
+        .success();
+
+    let path_hydrated = temp.child("hydrated");
+    Command::new(&*common::BIN_PATH)
+        .arg(path_deduped.path())
+        .arg(path_hydrated.path())
+        .arg("--cache-file")
+        .arg(cache_baseline.path())
+        .arg("--decode")
+        .assert()
+        .success();
+    path_hydrated
+        .child("file-1")
+        .assert(predicate::path::exists());
+
+    fs::remove_file(path_origin.child("file-1"))?;
+    path_origin.child("file-3").write_str("3")?;
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(path_deduped.path())
+        .arg("--cache-file")
+        .arg(cache_delta.path())
+        .arg("--since-cache")
+        .arg(cache_baseline.path())
+        .assert()
+        .success();
+
+    // Without removing, deleted files are just not restored.
+    let path_hydrated_fresh = temp.child("hydrated-fresh");
+    Command::new(&*common::BIN_PATH)
+        .arg(path_deduped.path())
+        .arg(path_hydrated_fresh.path())
+        .arg("--cache-file")
+        .arg(cache_delta.path())
+        .arg("--cache-file")
+        .arg(cache_baseline.path())
+        .arg("--decode")
+        .assert()
+        .success();
+    path_hydrated_fresh
+        .child("file-1")
+        .assert(predicate::path::missing());
+    path_hydrated_fresh.child("file-2").assert("2");
+    path_hydrated_fresh.child("file-3").assert("3");
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_deduped.path())
+        .arg(path_hydrated.path())
+        .arg("--cache-file")
+        .arg(cache_delta.path())
+        .arg("--cache-file")
+        .arg(cache_baseline.path())
+        .arg("--decode")
+        .arg("--remove-deleted")
+        .assert()
+        .success();
+    path_hydrated
+        .child("file-1")
+        .assert(predicate::path::missing());
+    path_hydrated.child("file-2").assert("2");
+    path_hydrated.child("file-3").assert("3");
+
+    Ok(())
+}
+
+#[test]
+fn absolute_paths() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let path_origin = temp.child("origin");
+    let path_deduped = temp.child("deduped");
+    let path_elsewhere = temp.child("elsewhere");
+    let cache_file = temp.child("cache.json");
+
+    path_origin.child("dir/file").write_str("content")?;
+    path_elsewhere.create_dir_all()?;
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(path_deduped.path())
+        .arg("--cache-file")
+        .arg(cache_file.path())
+        .arg("--absolute-paths")
+        .assert()
+        .success();
+
+    fs::remove_dir_all(path_origin.path())?;
+
+    // The original location is not inside the target directory.
+    Command::new(&*common::BIN_PATH)
+        .arg(path_deduped.path())
+        .arg(path_elsewhere.path())
+        .arg("--cache-file")
+        .arg(cache_file.path())
+        .arg("--decode")
+        .arg("--absolute-paths")
+        .assert()
+        .failure();
+    path_origin.assert(predicate::path::missing());
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_deduped.path())
+        .arg(temp.path())
+        .arg("--cache-file")
+        .arg(cache_file.path())
+        .arg("--decode")
+        .arg("--absolute-paths")
+        .assert()
+        .success();
+    path_origin.child("dir/file").assert("content");
+
+    fs::remove_dir_all(path_origin.path())?;
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_deduped.path())
+        .arg(path_elsewhere.path())
+        .arg("--cache-file")
+        .arg(cache_file.path())
+        .arg("--decode")
+        .arg("--absolute-paths")
+        .arg("--allow-absolute")
+        .assert()
+        .success();
+    path_origin.child("dir/file").assert("content");
+    assert_eq!(fs::read_dir(path_elsewhere.path())?.count(), 0);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn source_encoding_decodes_file_names() -> Result<()> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let temp = TempDir::new()?;
+
+    let path_origin = temp.child("origin");
+    let path_deduped = temp.child("deduped");
+    let path_hydrated = temp.child("hydrated");
+    let cache = temp.child("cache.json");
+
+    path_origin.create_dir_all()?;
+    fs::write(path_origin.join(OsStr::from_bytes(b"na\xefve")), "1")?;
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(path_deduped.path())
+        .arg("--cache-file")
+        .arg(cache.path())
+        .arg("--source-encoding")
+        .arg("latin1")
+        .assert()
+        .success();
+
+    cache.assert(predicate::str::contains("naïve"));
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_deduped.path())
+        .arg(path_hydrated.path())
+        .arg("--cache-file")
+        .arg(cache.path())
+        .arg("--decode")
+        .assert()
+        .success();
+
+    path_hydrated.child("naïve").assert("1");
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(path_deduped.path())
+        .arg("--cache-file")
+        .arg(cache.path())
+        .arg("--source-encoding")
+        .arg("no-such-encoding")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "unknown encoding: no-such-encoding",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn tar_writes_chunk_store_archive() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let path_origin = temp.child("origin");
+    let cache = temp.child("cache.json");
+    path_origin.child("file-1").write_str("content")?;
+    path_origin.child("dir/file-2").write_str("content")?;
+    path_origin.child("file-3").write_str("other")?;
+
+    let output = Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg("-")
+        .arg("--cache-file")
+        .arg(cache.path())
+        .args(["--tar", "--declutter-levels", "2"])
+        .output()?;
+    assert!(output.status.success());
+
+    let mut archive = tar::Archive::new(zstd::Decoder::new(output.stdout.as_slice())?);
+    let mut entries = archive
+        .entries()?
+        .map(|entry| Ok(entry?.path()?.into_owned()))
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort();
+    assert_eq!(entries.len(), 2, "Each chunk is written once");
+    assert!(entries.iter().all(|entry| entry.starts_with("data")));
+
+    let hydrator = crazy_deduper::Hydrator::from_tar_reader(
+        zstd::Decoder::new(output.stdout.as_slice())?,
+        vec![cache.to_path_buf()],
+    );
+    let path_restored = temp.child("restored");
+    hydrator.restore_files(path_restored.path(), 2)?;
+
+    path_restored.child("file-1").assert("content");
+    path_restored.child("dir/file-2").assert("content");
+    path_restored.child("file-3").assert("other");
+
+    Ok(())
+}
+
+#[test]
+fn tar_to_stdout_rejects_other_stdout_outputs() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let path_origin = temp.child("origin");
+    path_origin.child("file").write_str("content")?;
+
+    for option in ["--json-lines", "--cache-write-file", "--export-manifest"] {
+        Command::new(&*common::BIN_PATH)
+            .arg(path_origin.path())
+            .arg("-")
+            .arg("--tar")
+            .args([option, "-"])
+            .assert()
+            .failure()
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::contains(option));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn cache_via_stdin_and_stdout() -> Result<()> {
+    let temp = TempDir::new()?;
+
+    let path_origin = temp.child("origin");
+    let path_deduped = temp.child("deduped");
+    let path_restored = temp.child("restored");
+    path_origin.child("file-1").write_str("1")?;
+    path_origin.child("dir/file-2").write_str("2")?;
+
+    let output = Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(path_deduped.path())
+        .args(["--output-cache", "-", "--stdio-cache-compression", "zstd"])
+        .output()?;
+    assert!(output.status.success());
+    let json = zstd::decode_all(output.stdout.as_slice())?;
+    assert!(String::from_utf8(json)?.contains("file-2"));
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_deduped.path())
+        .arg(path_restored.path())
+        .args(["--decode", "--cache-file", "-"])
+        .args(["--stdio-cache-compression", "zstd"])
+        .write_stdin(output.stdout)
+        .assert()
+        .success();
+
+    path_restored.child("file-1").assert("1");
+    path_restored.child("dir/file-2").assert("2");
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn chunk_mode_sets_permissions() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = TempDir::new()?;
+
+    let path_origin = temp.child("origin");
+    let path_deduped = temp.child("deduped");
+    let cache = temp.child("cache.json");
+
+    path_origin.child("file").write_str("content")?;
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(path_deduped.path())
+        .arg("--cache-file")
+        .arg(cache.path())
+        .args(["--chunk-mode", "0640"])
+        .assert()
+        .success();
+
+    for entry in WalkDir::new(path_deduped.child("data")) {
+        let entry = entry?;
+        let expected = if entry.file_type().is_dir() {
+            0o750
+        } else {
+            0o640
+        };
+        assert_eq!(entry.metadata()?.permissions().mode() & 0o777, expected);
+    }
+
+    Command::new(&*common::BIN_PATH)
+        .arg(path_origin.path())
+        .arg(path_deduped.path())
+        .arg("--cache-file")
+        .arg(cache.path())
+        .args(["--chunk-mode", "0980"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid octal mode: 0980"));
+
+    Ok(())
+}
@@ -1,9 +1,11 @@
+use std::fs::OpenOptions;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
 use anyhow::Result;
 use assert_fs::TempDir;
 use assert_fs::prelude::*;
-use crazy_deduper::{Deduper, HashingAlgorithm};
+use crazy_deduper::{Deduper, DeduperBuilder, HashingAlgorithm};
 
 #[test]
 fn check_public_properties() -> Result<()> {
@@ -17,14 +19,14 @@ fn check_public_properties() -> Result<()> {
     std::fs::write(&file, "content")?;
 
     let source_path = source.to_path_buf();
-    let mut deduper = Deduper::new(
+    let deduper = Deduper::new(
         source_path,
         vec![cache_file.path()],
         HashingAlgorithm::MD5,
         true,
     );
 
-    let cache = &mut deduper.cache;
+    let cache = deduper.cache();
     assert_eq!(cache.len(), 1, "Expected file count is not 1");
 
     let fcw = cache.values().next().unwrap();
@@ -33,9 +35,120 @@ fn check_public_properties() -> Result<()> {
     let chunks = fcw.get_or_calculate_chunks()?;
     assert_eq!(chunks.len(), 1, "Number of chunks is not 1");
 
-    let chunk = chunks.get(0).unwrap();
+    let chunk = chunks.first().unwrap();
     assert_ne!(chunk.size, 0, "Chunk size is 0");
     assert_ne!(chunk.hash, "", "Chunk hash is empty");
 
     Ok(())
 }
+
+#[test]
+fn sync_only_writes_changed_files() -> Result<()> {
+    let temp = TempDir::new()?;
+    let source = temp.child("source");
+    let target = temp.child("target");
+    let cache_file = temp.child("cache.json");
+
+    source.child("unchanged").write_str("unchanged")?;
+    source.child("modified").write_str("original")?;
+    source.child("removed").write_str("removed")?;
+
+    let mut deduper = Deduper::new(
+        source.path(),
+        vec![cache_file.path()],
+        HashingAlgorithm::MD5,
+        true,
+    );
+    deduper.write_chunks(target.path(), 0)?;
+
+    let chunk_of = |deduper: &Deduper, path: &str| -> Result<PathBuf> {
+        let hash = &deduper.cache().get(path).unwrap().get_chunks().unwrap()[0].hash;
+        Ok(target.child("data").child(hash).to_path_buf())
+    };
+
+    // Remove the chunk of the unchanged file, so that we can detect if it gets rewritten.
+    let unchanged_chunk = chunk_of(&deduper, "unchanged")?;
+    std::fs::remove_file(&unchanged_chunk)?;
+
+    source.child("modified").write_str("modified")?;
+    OpenOptions::new()
+        .write(true)
+        .open(source.child("modified"))?
+        .set_modified(SystemTime::now() + Duration::from_secs(1))?;
+    source.child("added").write_str("added")?;
+    std::fs::remove_file(source.child("removed"))?;
+
+    let report = deduper.sync(target.path(), 0)?;
+
+    assert_eq!(report.added, vec!["added"]);
+    assert_eq!(report.modified, vec!["modified"]);
+    assert_eq!(report.removed, vec!["removed"]);
+    assert_eq!(report.chunks_written, 2);
+
+    assert!(chunk_of(&deduper, "modified")?.is_file());
+    assert!(chunk_of(&deduper, "added")?.is_file());
+    assert!(
+        !unchanged_chunk.exists(),
+        "Chunk of unchanged file was rewritten"
+    );
+    assert!(deduper.cache().get("removed").is_none());
+
+    Ok(())
+}
+
+#[test]
+fn content_equality_ignores_touched_files() -> Result<()> {
+    let temp = TempDir::new()?;
+    let source = temp.child("source");
+    let target = temp.child("target");
+    let cache_file = temp.child("cache.json");
+
+    source.child("touched").write_str("content")?;
+
+    let build_deduper = || {
+        DeduperBuilder::new(source.path())
+            .cache_paths(vec![cache_file.path()])
+            .use_content_equality(true)
+            .build()
+    };
+
+    let mut deduper = build_deduper();
+    deduper.write_chunks(target.path(), 0)?;
+    deduper.write_cache()?;
+
+    let hash = deduper
+        .cache()
+        .get("touched")
+        .unwrap()
+        .get_chunks()
+        .unwrap()[0]
+        .hash
+        .clone();
+    let chunk = target.child("data").child(&hash);
+
+    // Remove the chunk, so that we can detect if it gets written again.
+    std::fs::remove_file(&chunk)?;
+
+    OpenOptions::new()
+        .write(true)
+        .open(source.child("touched"))?
+        .set_modified(SystemTime::now() + Duration::from_secs(1))?;
+
+    let mut deduper = build_deduper();
+    let report = deduper.sync(target.path(), 0)?;
+
+    assert!(
+        report.modified.is_empty(),
+        "Touched file is treated as modified"
+    );
+    assert!(
+        !chunk.exists(),
+        "Touched file was re-added to the chunk store"
+    );
+    assert_eq!(
+        deduper.cache().get("touched").unwrap().mtime,
+        std::fs::metadata(source.child("touched"))?.modified()?
+    );
+
+    Ok(())
+}